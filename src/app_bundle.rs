@@ -1,25 +1,101 @@
 use anyhow::{Context, Result, bail};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::fs::File;
+use std::fs::{self, File};
 use flate2::write::GzEncoder;
 use flate2::Compression;
+use serde::Serialize;
 
-use crate::spec::ImageSpec;
+use crate::spec::{AppSpec, BundleRepoProvider, BundleRepoSpec, ConfigFileSpec, ImageSpec, ServiceType};
 use crate::spec_loader;
-use crate::bundle_repo;
+use crate::bundle_repo::{self, integrity};
+use crate::diagnostics;
+
+// External side effects of bundle creation, replaced by a recording mock in tests
+trait Publisher {
+    fn docker(&self, args: &[&str]) -> Result<()>;
+    fn upload(&self, repo: &BundleRepoSpec, version: &str, bundle: &Path) -> Result<()>;
+}
+
+struct CliPublisher;
+
+impl Publisher for CliPublisher {
+    fn docker(&self, args: &[&str]) -> Result<()> {
+        let status = Command::new("docker")
+            .args(args)
+            .status()
+            .with_context(|| format!("Failed to execute docker {}", args.join(" ")))?;
+        if !status.success() {
+            bail!("docker {} failed", args.join(" "));
+        }
+        Ok(())
+    }
+
+    fn upload(&self, repo: &BundleRepoSpec, version: &str, bundle: &Path) -> Result<()> {
+        bundle_repo::open(repo).upload(version, bundle)
+    }
+}
 
 pub fn create_app_bundle(
     registry: &Option<String>,
     push_images: bool,
-    _upload: &Option<String>,
+    upload: &Option<String>,
     upload_bundle_to: &Option<String>,
     gh_repo: &Option<String>,
     gh_tag_prefix: &Option<String>,
+    sign_key: &Option<String>,
 ) -> Result<()> {
-    let app_spec = spec_loader::load_app_spec_from_dir(Path::new("."), None)?;
-    println!("Creating bundle for {} v{}", app_spec.name, app_spec.version);
+    let upload_to = upload_target(upload, upload_bundle_to, gh_repo, gh_tag_prefix.as_deref())?;
+    let spec_path = spec_loader::project_app_spec_path()?;
+    create_in(&spec_path, Path::new("."), registry.as_deref(), push_images, upload_to.as_ref(), sign_key.as_deref().map(Path::new), &CliPublisher)?;
+    Ok(())
+}
+
+// Repository the bundle is uploaded to: `--upload gh:owner/repo` or
+// `--upload oci://registry/path`, or the older `--upload-bundle-to
+// github-release --github-repo owner/repo`.
+fn upload_target(upload: &Option<String>, upload_bundle_to: &Option<String>, gh_repo: &Option<String>, tag_prefix: Option<&str>) -> Result<Option<BundleRepoSpec>> {
+    let (provider, repo) = if let Some(target) = upload {
+        if let Some(repo) = target.strip_prefix("gh:") {
+            if !repo.contains('/') {
+                bail!("Upload target must be gh:owner/repo, got {}", target);
+            }
+            (BundleRepoProvider::Github, repo.to_string())
+        } else if target.starts_with("oci://") {
+            let reference = bundle_repo::oci::Reference::parse(target)?;
+            (BundleRepoProvider::Oci, reference.to_string())
+        } else {
+            bail!("Unknown upload target: {}. Expected gh:owner/repo or oci://registry/path", target);
+        }
+    } else {
+        match upload_bundle_to.as_deref() {
+            Some("github-release") => {
+                let repo = gh_repo.as_ref().context("--github-repo is required when uploading to github-release")?;
+                (BundleRepoProvider::Github, repo.clone())
+            }
+            Some(target) => bail!("Unknown upload target: {}. Only 'github-release' is supported.", target),
+            None => return Ok(None),
+        }
+    };
+    Ok(Some(bundle_repo::command_line_spec(provider, repo, tag_prefix.map(str::to_string))))
+}
+
+/// Tags (and pushes) the app images with the app version, writes
+/// `<app>.<version>.tar.gz` with the appspec at `spec_path` into `dir`, with
+/// its `.sha256` and, given `sign_key`, its `.sig`, and uploads them to
+/// `upload_to`. Returns the bundle path.
+fn create_in(
+    spec_path: &Path,
+    dir: &Path,
+    registry: Option<&str>,
+    push_images: bool,
+    upload_to: Option<&BundleRepoSpec>,
+    sign_key: Option<&Path>,
+    publisher: &dyn Publisher,
+) -> Result<PathBuf> {
+    let app_spec = spec_loader::load_app_spec(spec_path, None)?;
+    log::info!("Creating bundle for {} v{}", app_spec.name, app_spec.version);
 
     let mut registry_map = HashMap::new();
     if let Some(reg_str) = registry {
@@ -32,8 +108,12 @@ pub fn create_app_bundle(
 
     for service in app_spec.app_services {
         let images: Vec<String> = match service.image {
-            ImageSpec::Exact(img) => vec![img],
-            ImageSpec::Variants(variants) => variants.into_iter().map(|v| v.image).collect(),
+            Some(ImageSpec::Exact(img)) => vec![img],
+            Some(ImageSpec::Variants(variants)) => variants.into_iter().map(|v| v.image).collect(),
+            None => {
+                diagnostics::warn("image_not_published", format!("service {} has no image to publish, it is only built locally", service.name));
+                vec![]
+            }
         };
         for source_image in &images {
              let (base_name, _) = source_image.split_once(':').unwrap_or((source_image, ""));
@@ -64,64 +144,366 @@ pub fn create_app_bundle(
                  bail!("No registry match for image {}, available registries: {}", source_image, available);
              }
 
-             println!("Tagging {} as {}", source_image, target_image);
-             
-             let status = Command::new("docker")
-                 .arg("tag")
-                 .arg(source_image)
-                 .arg(&target_image)
-                 .status()
-                 .with_context(|| format!("Failed to execute docker tag for {}", source_image))?;
-                 
-             if !status.success() {
-                 bail!("Docker tag failed for {}", source_image);
-             }
+             log::info!("Tagging {} as {}", source_image, target_image);
+             publisher.docker(&["tag", source_image, &target_image])?;
              
              if push_images {
-                 println!("Pushing {}", target_image);
-                 let status = Command::new("docker")
-                     .arg("push")
-                     .arg(&target_image)
-                     .status()
-                     .with_context(|| format!("Failed to execute docker push for {}", target_image))?;
-                     
-                 if !status.success() {
-                     bail!("Docker push failed for {}", target_image);
-                 }
+                 log::info!("Pushing {}", target_image);
+                 publisher.docker(&["push", &target_image])?;
              }
         }
     }
 
     let filename = format!("{}.{}.tar.gz", app_spec.name, app_spec.version);
-    let file = File::create(&filename).context("Failed to create bundle file")?;
+    let bundle_path = dir.join(&filename);
+    let file = File::create(&bundle_path).context("Failed to create bundle file")?;
     let enc = GzEncoder::new(file, Compression::default());
     let mut tar = tar::Builder::new(enc);
     
-    let path_yaml = Path::new("appspec.yaml");
-    let path_yml = Path::new("appspec.yml");
-    if path_yaml.exists() {
-        tar.append_path("appspec.yaml")?;
-    } else if path_yml.exists() {
-        tar.append_path("appspec.yml")?;
-    } else {
-        bail!("appspec.yaml or appspec.yml not found");
+    // Stored as appspec.yaml whatever `--appspec` named it, so the bundle loads
+    tar.append_path_with_name(spec_path, "appspec.yaml")?;
+    // with the config files the app ships, in the layout they have next to it
+    for config in &app_spec.configs {
+        for file in &config.files {
+            if let ConfigFileSpec::DirFile { name, path } = file {
+                let entry = format!("{}/{}/{}", spec_loader::SHIPPED_CONFIGS_DIR, config.name, name);
+                tar.append_path_with_name(path, &entry).context(format!("Failed to add {:?} to the bundle", path))?;
+            }
+        }
     }
 
-
     let archive = tar.into_inner().context("Failed to write bundle file")?;
 
     archive.finish().context("Failed to finish bundle file")?;
     
-    println!("Created artifact: {}", filename);
+    log::info!("Created artifact: {}", filename);
+    integrity::write_checksum(&bundle_path)?;
+    if let Some(key) = sign_key {
+        let (_, public_key) = integrity::sign(&bundle_path, key)?;
+        log::info!("Signed {} with the key whose public key is {}", filename, public_key);
+    }
     
-    if let Some(target) = upload_bundle_to {
-        if target == "github-release" {
-            let repo = gh_repo.as_ref().context("--github-repo is required when uploading to github-release")?;
-            bundle_repo::gh_release::upload(repo, &app_spec.version.to_string(), &filename, gh_tag_prefix.as_deref())?;
-        } else {
-            bail!("Unknown upload target: {}. Only 'github-release' is supported.", target);
+    if let Some(repo) = upload_to {
+        publisher.upload(repo, &app_spec.version.to_string(), &bundle_path)
+            .with_context(|| format!("Upload failed; the bundle {} was created and left in place", filename))?;
+    }
+
+    Ok(bundle_path)
+}
+
+/// Prints the app version, or changes it with `bump` (patch, minor or major)
+/// or `set`. Only the `version:` line of the appspec is rewritten, so the
+/// rest of the file keeps its formatting and comments.
+pub fn version(bump: Option<&str>, set: Option<&str>) -> Result<()> {
+    let path = spec_loader::project_app_spec_path()?;
+    let app_spec = spec_loader::load_app_spec(&path, None)
+        .context("The appspec must be valid before its version is changed")?;
+    let old = app_spec.version;
+
+    let new = match (bump, set) {
+        (None, None) => {
+            println!("{}", old);
+            return Ok(());
+        }
+        (_, Some(set)) => semver::Version::parse(set).context(format!("Invalid version: {}", set))?,
+        (Some(bump), None) => bump_version(&old, bump)?,
+    };
+
+    let content = fs::read_to_string(&path).context(format!("Failed to read {:?}", path))?;
+    let updated = replace_version_line(&content, &old, &new)
+        .context(format!("Failed to update the version in {:?}", path))?;
+    fs::write(&path, updated).context(format!("Failed to write {:?}", path))?;
+
+    println!("{} → {}", old, new);
+    Ok(())
+}
+
+// A prerelease is released by the bump that would reach it: 1.3.0-rc.1 bumps
+// to 1.3.0 for minor and patch, 2.0.0-rc.1 to 2.0.0 for any bump.
+fn bump_version(version: &semver::Version, bump: &str) -> Result<semver::Version> {
+    let mut next = semver::Version::new(version.major, version.minor, version.patch);
+    let prerelease = !version.pre.is_empty();
+    match bump {
+        "patch" if prerelease => {}
+        "patch" => next.patch += 1,
+        "minor" if prerelease && next.patch == 0 => {}
+        "minor" => {
+            next.minor += 1;
+            next.patch = 0;
+        }
+        "major" if prerelease && next.minor == 0 && next.patch == 0 => {}
+        "major" => {
+            next.major += 1;
+            next.minor = 0;
+            next.patch = 0;
+        }
+        other => bail!("Unknown version bump: {} (expected patch, minor or major)", other),
+    }
+    Ok(next)
+}
+
+// Replaces `old` with `new` on the top-level `version:` line, leaving quotes,
+// trailing comments and line endings as they are.
+fn replace_version_line(content: &str, old: &semver::Version, new: &semver::Version) -> Result<String> {
+    let old = old.to_string();
+    let mut replaced = false;
+    let mut result = String::with_capacity(content.len());
+    for line in content.split_inclusive('\n') {
+        match line.strip_prefix("version:") {
+            Some(rest) if !replaced && rest.contains(&old) => {
+                result.push_str("version:");
+                result.push_str(&rest.replacen(&old, &new.to_string(), 1));
+                replaced = true;
+            }
+            _ => result.push_str(line),
         }
     }
+    if !replaced {
+        bail!("No top-level 'version: {}' line found", old);
+    }
+    Ok(result)
+}
+
+// What `app-bundle inspect` reports about a bundle
+#[derive(Serialize)]
+struct BundleSummary {
+    path: String,
+    name: String,
+    version: String,
+    services: Vec<ServiceSummary>,
+    external_env: Vec<String>,
+    optional_env: Vec<String>,
+    secrets: Vec<String>,
+    configs: Vec<String>,
+    // files in the archive besides the appspec
+    files: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ServiceSummary {
+    name: String,
+    #[serde(rename = "type")]
+    service_type: ServiceType,
+    images: Vec<String>,
+}
+
+/// Prints what a tar.gz bundle contains without unpacking it. `bundle` is a
+/// path, or a version downloaded from the env spec's bundle repository.
+pub fn inspect(bundle: &str, format: &str) -> Result<()> {
+    let path = if Path::new(bundle).exists() {
+        PathBuf::from(bundle)
+    } else {
+        let env_spec = spec_loader::load_env_spec(Path::new("."))?;
+        bundle_repo::locate(bundle, &env_spec, bundle_repo::app_of(&env_spec.deployments)?)?
+    };
+    if path.is_dir() {
+        bail!("{:?} is a directory, app-bundle inspect reads .tar.gz bundles", path);
+    }
+    let (app_spec, files) = spec_loader::read_bundle(&path)?;
+    let summary = summarize(&path, app_spec, files);
 
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(&summary)?),
+        _ => print!("{}", summary_table(&summary)),
+    }
     Ok(())
 }
+
+fn summarize(path: &Path, app_spec: AppSpec, files: Vec<String>) -> BundleSummary {
+    let services = app_spec.app_services.into_iter()
+        .map(|service| ServiceSummary {
+            name: service.name,
+            service_type: service.service_type,
+            images: match service.image {
+                Some(ImageSpec::Exact(image)) => vec![image],
+                Some(ImageSpec::Variants(variants)) => variants.into_iter().map(|v| format!("{} ({})", v.image, v.variant_name)).collect(),
+                None => vec![],
+            },
+        })
+        .collect();
+    BundleSummary {
+        path: path.display().to_string(),
+        name: app_spec.name,
+        version: app_spec.version.to_string(),
+        services,
+        external_env: app_spec.environment.external.into_iter().map(|v| v.name).collect(),
+        optional_env: app_spec.environment.optional.into_iter().map(|v| v.name).collect(),
+        secrets: app_spec.secrets.into_iter().map(|s| s.secret_name).collect(),
+        configs: app_spec.configs.into_iter().map(|c| c.name).collect(),
+        files,
+    }
+}
+
+fn summary_table(summary: &BundleSummary) -> String {
+    let mut out = format!("{} v{} ({})\n", summary.name, summary.version, summary.path);
+    if !summary.services.is_empty() {
+        out.push_str("\nServices\n");
+        let width = summary.services.iter().map(|s| s.name.len()).max().unwrap_or(0);
+        for service in &summary.services {
+            let service_type = match service.service_type {
+                ServiceType::Public => "public",
+                ServiceType::Internal => "internal",
+                ServiceType::Job => "job",
+            };
+            let images = if service.images.is_empty() { "built locally".to_string() } else { service.images.join(", ") };
+            out.push_str(&format!("  {:width$}  {:8}  {}\n", service.name, service_type, images));
+        }
+    }
+    for (title, items) in [
+        ("External env", &summary.external_env),
+        ("Optional env", &summary.optional_env),
+        ("Secrets", &summary.secrets),
+        ("Configs", &summary.configs),
+        ("Other files", &summary.files),
+    ] {
+        if !items.is_empty() {
+            out.push_str(&format!("\n{}\n", title));
+            for item in items {
+                out.push_str(&format!("  {}\n", item));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct RecordingPublisher {
+        calls: RefCell<Vec<String>>,
+        fail_upload: bool,
+    }
+
+    impl Publisher for RecordingPublisher {
+        fn docker(&self, args: &[&str]) -> Result<()> {
+            self.calls.borrow_mut().push(format!("docker {}", args.join(" ")));
+            Ok(())
+        }
+
+        fn upload(&self, repo: &BundleRepoSpec, version: &str, bundle: &Path) -> Result<()> {
+            let name = bundle.file_name().unwrap().to_string_lossy();
+            self.calls.borrow_mut().push(format!("upload {:?} {} {} {} {:?}", repo.provider, repo.repo, version, name, repo.tag_prefix));
+            if self.fail_upload {
+                bail!("502 Bad Gateway");
+            }
+            Ok(())
+        }
+    }
+
+    fn app_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), "name: shop\nversion: 1.4.0\napp_services:\n  api:\n    type: public\n    image: acme/api:dev\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn create_tags_pushes_bundles_and_uploads() {
+        let dir = app_dir();
+        let publisher = RecordingPublisher::default();
+        let upload = upload_target(&Some("gh:acme/shop".to_string()), &None, &None, Some("v")).unwrap();
+
+        let bundle = create_in(&dir.path().join("appspec.yaml"), dir.path(), Some("acme=registry.example.com"), true, upload.as_ref(), None, &publisher).unwrap();
+
+        assert_eq!(*publisher.calls.borrow(), vec![
+            "docker tag acme/api:dev registry.example.com/acme/api:1.4.0",
+            "docker push registry.example.com/acme/api:1.4.0",
+            "upload Github acme/shop 1.4.0 shop.1.4.0.tar.gz Some(\"v\")",
+        ]);
+        let spec = spec_loader::load_app_spec(&bundle, None).unwrap();
+        assert_eq!(spec.version.to_string(), "1.4.0");
+        integrity::verify_checksum(&bundle).unwrap();
+    }
+
+    #[test]
+    fn failed_upload_keeps_the_bundle() {
+        let dir = app_dir();
+        let publisher = RecordingPublisher { fail_upload: true, ..Default::default() };
+
+        let upload = upload_target(&Some("oci://registry.example.com/acme/shop".to_string()), &None, &None, None).unwrap().unwrap();
+        assert_eq!((upload.provider, upload.repo.as_str(), upload.token_env.as_str()), (BundleRepoProvider::Oci, "registry.example.com/acme/shop", "OCI_TOKEN"));
+
+        let err = create_in(&dir.path().join("appspec.yaml"), dir.path(), None, false, Some(&upload), None, &publisher).unwrap_err();
+
+        assert!(format!("{:#}", err).contains("left in place"));
+        assert!(dir.path().join("shop.1.4.0.tar.gz").exists());
+        assert!(upload_target(&Some("acme/shop".to_string()), &None, &None, None).is_err());
+    }
+
+    #[test]
+    fn bundles_carry_the_config_files_the_app_ships() {
+        let app_dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(app_dir.path().join("configs/nginx/conf.d")).unwrap();
+        fs::write(app_dir.path().join("configs/nginx/conf.d/site.conf"), "listen 80;").unwrap();
+        fs::write(app_dir.path().join("configs/nginx/gzip.conf"), "gzip on;").unwrap();
+        fs::write(app_dir.path().join("configs/nginx/notes.txt"), "not listed").unwrap();
+        fs::write(app_dir.path().join("appspec.yaml"), test_support::app_spec("version: 1.4.0\nconfigs:\n  nginx: [conf.d/*.conf, gzip.conf, tls.conf]\napp_services:\n  web:\n    image: acme/web\n    configs:\n      - nginx: /etc/nginx\n")).unwrap();
+        let bundle = create_in(&app_dir.path().join("appspec.yaml"), app_dir.path(), None, false, None, None, &RecordingPublisher::default()).unwrap();
+
+        // deployed from another directory, with only the file the app doesn't ship
+        let env_dir = tempfile::tempdir().unwrap();
+        fs::write(env_dir.path().join("tls.conf"), "ssl on;").unwrap();
+        fs::write(env_dir.path().join("envspec.yaml"), test_support::env_spec("docker", "deployments:\n  prod:\n    configs:\n      nginx: [tls.conf]\n")).unwrap();
+        let env_spec = spec_loader::load_env_spec(env_dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(&bundle, Some(&env_spec)).unwrap();
+        crate::validator::validate(&env_spec, &app_spec, "prod").unwrap();
+        let resolved = crate::resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+
+        let files: Vec<(&str, &[u8])> = resolved.current_deployment.configs[0].files.iter().map(|f| (f.name.as_str(), f.content.as_slice())).collect();
+        assert_eq!(files, [("conf.d/site.conf", &b"listen 80;"[..]), ("gzip.conf", b"gzip on;"), ("tls.conf", b"ssl on;")]);
+
+        // and the generator writes them out next to the deploy script
+        let crate::spec::DeploymentEnvType::Docker(docker_spec) = &env_spec.env_type else { unreachable!() };
+        let output_dir = env_dir.path().join("out");
+        let generated = crate::docker_generator::render(&resolved, docker_spec, &output_dir).unwrap();
+        let config_dir = output_dir.join("configs/shop-nginx");
+        assert_eq!(generated.get(config_dir.join("conf.d/site.conf")), Some("listen 80;"));
+        assert_eq!(generated.get(config_dir.join("gzip.conf")), Some("gzip on;"));
+        assert_eq!(generated.get(config_dir.join("tls.conf")), Some("ssl on;"));
+        let deploy = generated.get(output_dir.join("deploy.sh")).unwrap();
+        assert!(deploy.contains("configs/shop-nginx:/etc/nginx"), "{}", deploy);
+    }
+
+    #[test]
+    fn version_line_is_rewritten_in_place() {
+        let content = "name: shop\r\n# release version\r\nversion: \"1.2.9\" # bumped by CI\r\nservices:\r\n  api:\r\n    version: 1.2.9\r\n";
+        let old = semver::Version::parse("1.2.9").unwrap();
+        let new = bump_version(&old, "patch").unwrap();
+        assert_eq!(new.to_string(), "1.2.10");
+
+        let updated = replace_version_line(content, &old, &new).unwrap();
+        assert_eq!(updated, content.replacen("version: \"1.2.9\"", "version: \"1.2.10\"", 1));
+
+        let rc = semver::Version::parse("1.3.0-rc.1").unwrap();
+        assert_eq!(bump_version(&rc, "patch").unwrap().to_string(), "1.3.0");
+        assert_eq!(bump_version(&rc, "minor").unwrap().to_string(), "1.3.0");
+        assert_eq!(bump_version(&rc, "major").unwrap().to_string(), "2.0.0");
+        assert!(replace_version_line("name: shop\n", &old, &new).is_err());
+    }
+
+    #[test]
+    fn inspect_lists_bundle_contents_and_names_bad_archives() {
+        let dir = app_dir();
+        let bundle = create_in(&dir.path().join("appspec.yaml"), dir.path(), None, false, None, None, &RecordingPublisher::default()).unwrap();
+        let (app_spec, files) = spec_loader::read_bundle(&bundle).unwrap();
+        let summary = summarize(&bundle, app_spec, files);
+        assert_eq!((summary.name.as_str(), summary.version.as_str()), ("shop", "1.4.0"));
+        assert_eq!(summary.services[0].images, vec!["acme/api:dev"]);
+        assert!(summary_table(&summary).contains("  api  public    acme/api:dev"));
+
+        let corrupted = dir.path().join("corrupted.tar.gz");
+        fs::write(&corrupted, b"not a gzip stream").unwrap();
+        let err = format!("{:#}", spec_loader::read_bundle(&corrupted).unwrap_err());
+        assert!(err.contains("is not a valid tar.gz archive") && err.contains("corrupted.tar.gz"), "{}", err);
+
+        let empty = dir.path().join("empty.tar.gz");
+        let mut tar = tar::Builder::new(GzEncoder::new(File::create(&empty).unwrap(), Compression::default()));
+        tar.append_path_with_name(dir.path().join("appspec.yaml"), "docs/readme.yaml").unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+        let err = format!("{:#}", spec_loader::read_bundle(&empty).unwrap_err());
+        assert!(err.contains("appspec.yaml not found in bundle") && err.contains("empty.tar.gz"), "{}", err);
+    }
+}
@@ -1,140 +1,476 @@
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::HeaderMap;
 use std::env;
-use std::fs::File;
-use std::path::Path;
-use serde::Deserialize;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+use crate::bundle_repo::progress::{Progress, ProgressReader};
+use crate::bundle_repo::{integrity, BundleRepo};
+use crate::error::Failure;
+use crate::spec::BundleRepoSpec;
+
+// A rate limit that lifts within this is waited out, a later one fails
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 #[derive(Deserialize)]
 struct Asset {
+    id: u64,
     name: String,
-    url: String,
+    size: u64,
+    // "uploaded" once the upload completed
+    #[serde(default)]
+    state: String,
 }
 
 #[derive(Deserialize)]
 struct Release {
-    #[allow(dead_code)]
     id: u64,
+    #[serde(default)]
+    tag_name: String,
+    #[serde(default)]
+    draft: bool,
     upload_url: String,
     #[serde(default)]
     assets: Vec<Asset>,
 }
 
-pub fn download(repo: &str, ver: &str, app_name: &str, tag_prefix: Option<&str>) -> Result<String> {
-    let filename = format!("{}.{}.tar.gz", app_name, ver);
-    let tag = format!("{}{}", tag_prefix.unwrap_or(""), ver);
-    
-    // Check GITHUB_TOKEN
-    let token = env::var("GITHUB_TOKEN").context("GITHUB_TOKEN is not set. It is required for downloading releases.")?;
+#[derive(Serialize)]
+struct NewRelease<'a> {
+    tag_name: &'a str,
+    name: &'a str,
+    body: String,
+}
 
-    let client = reqwest::blocking::Client::new();
+/// GitHub releases of `spec.repo`, one per version, tagged
+/// `<tag_prefix><version>`.
+pub struct GithubRelease<'a> {
+    pub spec: &'a BundleRepoSpec,
+}
 
-    // 1. Get release info
-    let url = format!("https://api.github.com/repos/{}/releases/tags/{}", repo, tag);
-    println!("Fetching release info from {}", url);
+impl BundleRepo for GithubRelease<'_> {
+    fn upload(&self, version: &str, bundle: &Path) -> Result<()> {
+        upload(self.spec, version, bundle)
+    }
 
-    let response = client
-        .get(&url)
-        .header("Authorization", format!("Bearer {}", token))
-        .header("User-Agent", "simpled")
-        .send()
-        .context("Failed to get release info")?;
+    fn download(&self, app_name: &str, version: &str, dest_dir: &Path) -> Result<PathBuf> {
+        download(self.spec, app_name, version, dest_dir)
+    }
 
-    if !response.status().is_success() {
-        bail!("Failed to get release info from {}: status code {}", url, response.status());
+    fn list(&self, app_name: &str) -> Result<Vec<String>> {
+        list_releases(self.spec, app_name).context(Failure::Download)
     }
+}
+
+// Outcome of one attempt: done, or worth another try after the wait
+enum Step<T> {
+    Done(T),
+    Retry(Duration, String),
+}
 
-    let release: Release = response.json().context("Failed to parse release info")?;
+// GitHub API client that retries connection errors and 5xx responses with
+// exponential backoff, and waits out rate limits that lift soon
+struct Api<'a> {
+    client: Client,
+    token: String,
+    repo: &'a str,
+    attempts: u32,
+}
 
-    // 2. Find asset
-    let asset = release.assets.into_iter()
-        .find(|a| a.name == filename)
-        .context(format!("Asset {} not found in release {}", filename, tag))?;
+impl<'a> Api<'a> {
+    // Asset downloads redirect to storage on another host; reqwest follows
+    // them and drops the Authorization header on the way.
+    fn new(spec: &'a BundleRepoSpec, purpose: &str) -> Result<Api<'a>> {
+        let token = env::var(&spec.token_env).context(format!("{} is not set. It is required for {}.", spec.token_env, purpose))?;
+        let client = Client::builder()
+            .timeout(spec.timeout)
+            .redirect(reqwest::redirect::Policy::limited(10))
+            .build()
+            .context("Failed to create the HTTP client")?;
+        Ok(Api { client, token, repo: &spec.repo, attempts: spec.retries + 1 })
+    }
 
-    // 3. Download asset
-    println!("Downloading asset from {}", asset.url);
-    
-    let mut response = client
-        .get(&asset.url)
-        .header("Authorization", format!("Bearer {}", token))
-        .header("User-Agent", "simpled")
-        .header("Accept", "application/octet-stream")
-        .send()
-        .context("Failed to download asset")?;
-
-    if !response.status().is_success() {
-        bail!("Failed to download asset from {}: status code {}", asset.url, response.status());
-    }
-
-    let mut dest = File::create(Path::new(&filename)).context("Failed to create file")?;
-    response.copy_to(&mut dest).context("Failed to write content to file")?;
-
-    Ok(filename)
-}
-
-pub fn upload(repo: &str, ver: &str, filename: &str, tag_prefix: Option<&str>) -> Result<()> {
-    let token = env::var("GITHUB_TOKEN").context("GITHUB_TOKEN is not set")?;
-    let client = reqwest::blocking::Client::new();
-    let tag = format!("{}{}", tag_prefix.unwrap_or(""), ver);
-
-    // 1. Get release by tag
-    let url = format!("https://api.github.com/repos/{}/releases/tags/{}", repo, tag);
-    let response = client.get(&url)
-        .header("Authorization", format!("Bearer {}", token))
-        .header("User-Agent", "simpled")
-        .send()
-        .context("Failed to get release")?;
-
-    let release: Release = if response.status().is_success() {
-        bail!("Release {} already exists. Increase the app version number.", tag);
-    } else if response.status().as_u16() == 404 {
-        // Create release
-        println!("Release {} not found, creating...", tag);
-        let create_url = format!("https://api.github.com/repos/{}/releases", repo);
-        let body = format!(r#"{{ "tag_name": "{}", "name": "{}", "body": "Release {}" }}"#, tag, tag, tag);
-        
-        let response = client.post(&create_url)
-            .header("Authorization", format!("Bearer {}", token))
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+            .header("Authorization", format!("Bearer {}", self.token))
             .header("User-Agent", "simpled")
-            .header("Content-Type", "application/json")
-            .body(body)
-            .send()
-            .context("Failed to create release")?;
-            
+    }
+
+    fn with_retries<T>(&self, url: &str, mut attempt: impl FnMut(u32) -> Result<Step<T>>) -> Result<(T, u32)> {
+        let mut failure = String::new();
+        for n in 1..=self.attempts {
+            match attempt(n)? {
+                Step::Done(value) => return Ok((value, n)),
+                Step::Retry(wait, reason) => {
+                    failure = reason;
+                    if n < self.attempts {
+                        log::warn!("{} failed ({}), attempt {} of {}; retrying in {}s", url, failure, n, self.attempts, wait.as_secs());
+                        thread::sleep(wait);
+                    }
+                }
+            }
+        }
+        bail!("{} failed after {} attempt(s): {}", url, self.attempts, failure)
+    }
+
+    // The response and the number of attempts it took
+    fn send(&self, url: &str, request: impl Fn(&Client) -> RequestBuilder) -> Result<(Response, u32)> {
+        self.with_retries(url, |n| Ok(classify(self.authorize(request(&self.client)).send(), n)))
+    }
+
+    fn get(&self, url: &str) -> Result<(Response, u32)> {
+        self.send(url, |c| c.get(url).header("Accept", "application/vnd.github+json"))
+    }
+
+    // The latest 100 releases, drafts included when the token may see them
+    fn releases(&self) -> Result<Vec<Release>> {
+        let url = format!("https://api.github.com/repos/{}/releases?per_page=100", self.repo);
+        let (response, attempts) = self.get(&url)?;
+        if !response.status().is_success() {
+            return Err(status_error("Failed to list releases", &url, response, attempts));
+        }
+        response.json().context("Failed to parse the release list")
+    }
+
+    // The release tagged `tag`. A draft has no tag until it is published, so
+    // `releases/tags/` misses it and it is looked up in the release list.
+    fn find_release(&self, tag: &str) -> Result<Option<Release>> {
+        let url = format!("https://api.github.com/repos/{}/releases/tags/{}", self.repo, tag);
+        log::info!("Fetching release info from {}", url);
+        let (response, attempts) = self.get(&url)?;
+        match response.status().as_u16() {
+            404 => Ok(self.releases()?.into_iter().find(|r| r.tag_name == tag)),
+            _ if response.status().is_success() => Ok(Some(response.json().context("Failed to parse release info")?)),
+            _ => Err(status_error("Failed to get release info", &url, response, attempts)),
+        }
+    }
+
+    // Written next to the destination and renamed, so an interrupted download
+    // never looks like a cached file. A transfer cut off midway is retried.
+    fn fetch_asset(&self, asset: &Asset, dest_path: &Path) -> Result<()> {
+        let url = format!("https://api.github.com/repos/{}/releases/assets/{}", self.repo, asset.id);
+        log::info!("Downloading {} from {}", asset.name, url);
+        let part_path = integrity::sidecar(dest_path, ".part");
+
+        self.with_retries(&url, |n| {
+            let request = self.authorize(self.client.get(&url)).header("Accept", "application/octet-stream");
+            let response = match classify(request.send(), n) {
+                Step::Done(response) => response,
+                Step::Retry(wait, reason) => return Ok(Step::Retry(wait, reason)),
+            };
+            if !response.status().is_success() {
+                return Err(status_error("Failed to download asset", &url, response, n));
+            }
+            let mut dest = File::create(&part_path).context("Failed to create file")?;
+            let total = response.content_length();
+            let mut body = ProgressReader::new(response, Progress::new(&asset.name, total));
+            Ok(match io::copy(&mut body, &mut dest) {
+                Ok(_) => Step::Done(()),
+                Err(e) => Step::Retry(backoff(n), format!("transfer interrupted: {}", e)),
+            })
+        })?;
+        fs::rename(&part_path, dest_path).context(format!("Failed to move downloaded asset to {:?}", dest_path))
+    }
+
+    fn release_asset(&self, release_id: u64, name: &str) -> Result<Option<Asset>> {
+        let url = format!("https://api.github.com/repos/{}/releases/{}/assets?per_page=100", self.repo, release_id);
+        let (response, attempts) = self.get(&url)?;
         if !response.status().is_success() {
-             bail!("Failed to create release: {}", response.status());
+            return Err(status_error("Failed to list release assets", &url, response, attempts));
+        }
+        let assets: Vec<Asset> = response.json().context("Failed to parse the asset list")?;
+        Ok(assets.into_iter().find(|a| a.name == name))
+    }
+
+    // Uploads are not idempotent, so before another attempt the release is
+    // checked for the asset: complete, it was stored after all; partial, it
+    // is deleted first. The file is streamed from disk on every attempt.
+    fn post_asset(&self, release: &Release, path: &Path, name: &str, content_type: &str) -> Result<()> {
+        // upload_url looks like "https://uploads.github.com/repos/octocat/Hello-World/releases/1/assets{?name,label}"
+        let upload_url = release.upload_url.split('{').next().unwrap_or(&release.upload_url);
+        let target_url = format!("{}?name={}", upload_url, name);
+        log::info!("Uploading {} to {}", name, target_url);
+        let size = fs::metadata(path).context(format!("Failed to open {:?} for upload", path))?.len();
+
+        let (response, attempts) = self.with_retries(&target_url, |n| {
+            if n > 1 {
+                match self.release_asset(release.id, name)? {
+                    Some(asset) if asset.size == size && asset.state == "uploaded" => {
+                        log::info!("{} was stored by the failed attempt", name);
+                        return Ok(Step::Done(None));
+                    }
+                    Some(asset) => {
+                        let url = format!("https://api.github.com/repos/{}/releases/assets/{}", self.repo, asset.id);
+                        let (response, attempts) = self.send(&url, |c| c.delete(&url))?;
+                        if !response.status().is_success() {
+                            return Err(status_error("Failed to delete the partial asset", &url, response, attempts));
+                        }
+                    }
+                    None => {}
+                }
+            }
+            let file = File::open(path).context(format!("Failed to open {:?} for upload", path))?;
+            let body = ProgressReader::new(file, Progress::new(name, Some(size)));
+            let request = self.authorize(self.client.post(&target_url))
+                .header("Content-Type", content_type)
+                .body(reqwest::blocking::Body::sized(body, size));
+            Ok(match classify(request.send(), n) {
+                Step::Done(response) => Step::Done(Some(response)),
+                Step::Retry(wait, reason) => Step::Retry(wait, reason),
+            })
+        })?;
+        match response {
+            Some(response) if !response.status().is_success() => Err(status_error("Failed to upload asset", &target_url, response, attempts)),
+            _ => Ok(()),
+        }
+    }
+
+    // Creating twice would fail on the taken tag, so before another attempt
+    // the release the failed one may have created is looked up.
+    fn create_release(&self, tag: &str) -> Result<Release> {
+        let url = format!("https://api.github.com/repos/{}/releases", self.repo);
+        let (response, attempts) = self.with_retries(&url, |n| {
+            if n > 1 {
+                if let Some(release) = self.find_release(tag)? {
+                    return Ok(Step::Done(Err(release)));
+                }
+            }
+            let request = self.authorize(self.client.post(&url))
+                .json(&NewRelease { tag_name: tag, name: tag, body: format!("Release {}", tag) });
+            Ok(match classify(request.send(), n) {
+                Step::Done(response) => Step::Done(Ok(response)),
+                Step::Retry(wait, reason) => Step::Retry(wait, reason),
+            })
+        })?;
+        match response {
+            Err(release) => Ok(release),
+            Ok(response) if response.status().is_success() => response.json().context("Failed to parse created release info"),
+            Ok(response) => Err(status_error("Failed to create release", &url, response, attempts)),
         }
-        let text = response.text()?;
-        serde_yaml::from_str(&text).context("Failed to parse created release info")?
-    } else {
-        bail!("Failed to get release info: {}", response.status());
+    }
+}
+
+// A connection error or a 5xx is retried with backoff, a 403 or 429 rate
+// limit after it lifts, when that is soon
+fn classify(result: reqwest::Result<Response>, attempt: u32) -> Step<Response> {
+    match result {
+        Err(e) => Step::Retry(backoff(attempt), e.to_string()),
+        Ok(response) if response.status().is_server_error() => Step::Retry(backoff(attempt), format!("status {}", response.status())),
+        Ok(response) if matches!(response.status().as_u16(), 403 | 429) => match rate_limit_wait(response.headers(), now_secs()) {
+            Some(wait) if wait <= MAX_RATE_LIMIT_WAIT => Step::Retry(wait, format!("status {}, rate limited", response.status())),
+            _ => Step::Done(response),
+        },
+        Ok(response) => Step::Done(response),
+    }
+}
+
+// 1s, 2s, 4s, ... up to MAX_BACKOFF
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.saturating_sub(1).min(5)).min(MAX_BACKOFF)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// How long a rate-limited response asks to wait: its Retry-After, or until
+// the reset of an exhausted limit
+fn rate_limit_wait(headers: &HeaderMap, now: u64) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(secs) = header("retry-after").and_then(|v| v.trim().parse::<u64>().ok()) {
+        return Some(Duration::from_secs(secs));
+    }
+    if header("x-ratelimit-remaining") == Some("0") {
+        let reset = header("x-ratelimit-reset")?.trim().parse::<u64>().ok()?;
+        return Some(Duration::from_secs(reset.saturating_sub(now) + 1));
+    }
+    None
+}
+
+fn status_error(what: &str, url: &str, response: Response, attempts: u32) -> anyhow::Error {
+    let status = response.status();
+    let limit = match (status.as_u16(), rate_limit_wait(response.headers(), now_secs())) {
+        (403 | 429, Some(wait)) => format!(", rate limited for another {}s", wait.as_secs()),
+        _ => String::new(),
     };
+    let message = response.json::<serde_json::Value>().ok()
+        .and_then(|body| body.get("message").and_then(|m| m.as_str()).map(|m| format!(": {}", m)))
+        .unwrap_or_default();
+    anyhow!("{}: {} answered {} after {} attempt(s){}{}", what, url, status, attempts, limit, message)
+}
+
+fn find_asset<'a>(release: &'a Release, filename: &str) -> Result<&'a Asset> {
+    release.assets.iter().find(|a| a.name == filename).ok_or_else(|| {
+        let present: Vec<&str> = release.assets.iter().map(|a| a.name.as_str()).collect();
+        if present.is_empty() {
+            anyhow!("Asset {} not found: release {} has no assets", filename, release.tag_name)
+        } else {
+            anyhow!("Asset {} not found in release {}; it has {}", filename, release.tag_name, present.join(", "))
+        }
+    })
+}
+
+// The `.sha256` asset of bundle `filename`, which `app-bundle create` always
+// uploads along with it
+fn checksum_asset<'a>(release: &'a Release, filename: &str) -> Result<&'a Asset> {
+    let name = format!("{}{}", filename, integrity::CHECKSUM_SUFFIX);
+    release.assets.iter().find(|a| a.name == name).ok_or_else(|| {
+        anyhow!("Release {} has no {} asset, so the bundle can't be verified; upload the checksum written by `simpled app-bundle create` next to the bundle", release.tag_name, name)
+    })
+}
+
+// Versions whose release has the app's bundle, from the latest 100 releases
+fn list_releases(spec: &BundleRepoSpec, app_name: &str) -> Result<Vec<String>> {
+    let releases = Api::new(spec, "listing releases")?.releases()?;
+    Ok(releases.into_iter()
+        .filter_map(|release| {
+            let version = release.tag_name.strip_prefix(spec.tag_prefix.as_deref().unwrap_or(""))?.to_string();
+            let filename = format!("{}.{}.tar.gz", app_name, version);
+            release.assets.iter().any(|a| a.name == filename).then_some(version)
+        })
+        .collect())
+}
+
+/// Downloads the `<app>.<ver>.tar.gz` asset of the release, a draft too,
+/// through the assets API, so private repositories work, into `dest_dir`,
+/// with its `.sha256` asset and its `.sig` when the release has one, and
+/// checks the bundle against the checksum; a release without the checksum is
+/// refused. A file already there that matches the checksum is reused without
+/// downloading. The API token is read from the `token_env` variable.
+pub fn download(spec: &BundleRepoSpec, app_name: &str, ver: &str, dest_dir: &Path) -> Result<PathBuf> {
+    download_asset(spec, app_name, ver, dest_dir).context(Failure::Download)
+}
+
+fn download_asset(spec: &BundleRepoSpec, app_name: &str, ver: &str, dest_dir: &Path) -> Result<PathBuf> {
+    let filename = format!("{}.{}.tar.gz", app_name, ver);
+    let tag = format!("{}{}", spec.tag_prefix.as_deref().unwrap_or(""), ver);
+    let api = Api::new(spec, "downloading releases")?;
+
+    // 1. Get release info
+    let release = api.find_release(&tag)?
+        .context(format!("Release {} not found in {}", tag, spec.repo))?;
 
-    // 2. Upload asset
-    // upload_url looks like "https://uploads.github.com/repos/octocat/Hello-World/releases/1/assets{?name,label}"
-    let upload_url_template = release.upload_url;
-    let upload_url = upload_url_template.split('{').next().unwrap_or(&upload_url_template);
-    let target_url = format!("{}?name={}", upload_url, filename);
-    
-    println!("Uploading {} to {}", filename, target_url);
+    // 2. Find assets
+    let asset = find_asset(&release, &filename)?;
+    let checksum = checksum_asset(&release, &filename)?;
+    let signature = release.assets.iter().find(|a| a.name == format!("{}{}", filename, integrity::SIGNATURE_SUFFIX));
 
-    let mut file = File::open(filename).context("Failed to open file for upload")?;
-    let mut content = Vec::new();
-    file.read_to_end(&mut content)?;
+    // The checksum and signature are small and fetched every time, so a
+    // cached bundle is checked against the release too.
+    fs::create_dir_all(dest_dir).context(format!("Failed to create directory {:?}", dest_dir))?;
+    let dest_path = dest_dir.join(&filename);
+    api.fetch_asset(checksum, &integrity::sidecar(&dest_path, integrity::CHECKSUM_SUFFIX))?;
+    let signature_path = integrity::sidecar(&dest_path, integrity::SIGNATURE_SUFFIX);
+    match signature {
+        Some(signature) => api.fetch_asset(signature, &signature_path)?,
+        None if signature_path.exists() => fs::remove_file(&signature_path).context(format!("Failed to remove stale {:?}", signature_path))?,
+        None => {}
+    }
+
+    if dest_path.exists() && integrity::verify_checksum(&dest_path).is_ok() {
+        log::info!("Using cached bundle {:?}", dest_path);
+        return Ok(dest_path);
+    }
+
+    // 3. Download asset
+    api.fetch_asset(asset, &dest_path)?;
+    integrity::verify_checksum(&dest_path)?;
+
+    Ok(dest_path)
+}
+
+pub fn upload(spec: &BundleRepoSpec, ver: &str, path: &Path) -> Result<()> {
+    upload_asset(spec, ver, path).context(Failure::Upload)
+}
 
-    let response = client.post(&target_url)
-        .header("Authorization", format!("Bearer {}", token))
-        .header("User-Agent", "simpled")
-        .header("Content-Type", "application/gzip")
-        .body(content)
-        .send()
-        .context("Failed to upload asset")?;
+fn upload_asset(spec: &BundleRepoSpec, ver: &str, path: &Path) -> Result<()> {
+    let filename = path.file_name()
+        .and_then(|n| n.to_str())
+        .context(format!("Invalid bundle path {:?}", path))?;
+    let api = Api::new(spec, "uploading releases")?;
+    let tag = format!("{}{}", spec.tag_prefix.as_deref().unwrap_or(""), ver);
 
-    if !response.status().is_success() {
-        bail!("Failed to upload asset: {}", response.text().unwrap_or_default());
+    // 1. Create the release, unless the tag is taken
+    if let Some(existing) = api.find_release(&tag)? {
+        let kind = if existing.draft { "draft release" } else { "Release" };
+        bail!("{} {} already exists. Increase the app version number.", kind, tag);
+    }
+    log::info!("Release {} not found, creating...", tag);
+    let release = api.create_release(&tag)?;
+
+    // 2. Upload the bundle, then its checksum and signature when written
+    api.post_asset(&release, path, filename, "application/gzip")?;
+    for suffix in [integrity::CHECKSUM_SUFFIX, integrity::SIGNATURE_SUFFIX] {
+        let sidecar = integrity::sidecar(path, suffix);
+        if sidecar.exists() {
+            api.post_asset(&release, &sidecar, &format!("{}{}", filename, suffix), "text/plain")?;
+        }
     }
 
-    println!("Upload successful");
+    log::info!("Upload successful");
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn missing_assets_name_the_ones_present() {
+        let release: Release = serde_json::from_str(r#"{
+            "id": 1, "tag_name": "v1.4.0", "draft": true,
+            "upload_url": "https://uploads.github.com/repos/acme/shop/releases/1/assets{?name,label}",
+            "assets": [{ "id": 7, "name": "shop.1.4.0.tar.gz", "size": 10, "url": "https://api.github.com/repos/acme/shop/releases/assets/7" }]
+        }"#).unwrap();
+        assert_eq!(find_asset(&release, "shop.1.4.0.tar.gz").unwrap().id, 7);
+        let err = find_asset(&release, "shop.1.5.0.tar.gz").map(|a| a.id).unwrap_err();
+        assert_eq!(err.to_string(), "Asset shop.1.5.0.tar.gz not found in release v1.4.0; it has shop.1.4.0.tar.gz");
+
+        let empty: Release = serde_json::from_str(r#"{ "id": 2, "tag_name": "v1.5.0", "upload_url": "" }"#).unwrap();
+        assert!(find_asset(&empty, "shop.1.5.0.tar.gz").map(|a| a.id).unwrap_err().to_string().contains("has no assets"));
+    }
+
+    #[test]
+    fn bundles_without_a_checksum_are_refused() {
+        let release: Release = serde_json::from_str(r#"{
+            "id": 1, "tag_name": "v1.4.0", "upload_url": "",
+            "assets": [
+                { "id": 7, "name": "shop.1.4.0.tar.gz", "size": 10, "url": "https://api.github.com/repos/acme/shop/releases/assets/7" },
+                { "id": 8, "name": "shop.1.4.0.tar.gz.sha256", "size": 84, "url": "https://api.github.com/repos/acme/shop/releases/assets/8" }
+            ]
+        }"#).unwrap();
+        assert_eq!(checksum_asset(&release, "shop.1.4.0.tar.gz").unwrap().id, 8);
+
+        let unchecked: Release = serde_json::from_str(r#"{
+            "id": 1, "tag_name": "v1.4.0", "upload_url": "",
+            "assets": [{ "id": 7, "name": "shop.1.4.0.tar.gz", "size": 10, "url": "https://api.github.com/repos/acme/shop/releases/assets/7" }]
+        }"#).unwrap();
+        let err = checksum_asset(&unchecked, "shop.1.4.0.tar.gz").map(|a| a.id).unwrap_err();
+        assert!(err.to_string().starts_with("Release v1.4.0 has no shop.1.4.0.tar.gz.sha256 asset"), "{}", err);
+    }
+
+    #[test]
+    fn rate_limits_and_backoff_set_the_wait() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in pairs {
+                map.insert(*name, HeaderValue::from_static(value));
+            }
+            map
+        };
+        assert_eq!(rate_limit_wait(&headers(&[("retry-after", "30")]), 1000), Some(Duration::from_secs(30)));
+        assert_eq!(rate_limit_wait(&headers(&[("x-ratelimit-remaining", "0"), ("x-ratelimit-reset", "1020")]), 1000), Some(Duration::from_secs(21)));
+        assert_eq!(rate_limit_wait(&headers(&[("x-ratelimit-remaining", "12"), ("x-ratelimit-reset", "1020")]), 1000), None);
+        assert_eq!(rate_limit_wait(&headers(&[]), 1000), None);
+
+        let waits: Vec<u64> = (1..=7).map(|attempt| backoff(attempt).as_secs()).collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 30, 30]);
+    }
+}
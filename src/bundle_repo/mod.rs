@@ -1 +1,179 @@
+use anyhow::{Context, Result, bail};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::spec::{BundleRepoProvider, BundleRepoSpec, DeploymentEnvironmentSpec, DeploymentSpec};
+
 pub mod gh_release;
+pub mod integrity;
+pub mod oci;
+pub mod progress;
+
+// Directory, relative to the env spec, where bundles downloaded by version are kept
+pub const CACHE_DIR: &str = "bundle-cache";
+
+// Unless `bundle_repo.timeout` and `bundle_repo.retries` say otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Where app bundles are published, one per app version, and fetched from.
+pub trait BundleRepo {
+    /// Publishes `bundle` as `version`, with the checksum and signature files
+    /// written next to it.
+    fn upload(&self, version: &str, bundle: &Path) -> Result<()>;
+    /// Downloads the bundle of `version` into `dest_dir`, with its signature
+    /// when one was published, and returns its path.
+    fn download(&self, app_name: &str, version: &str, dest_dir: &Path) -> Result<PathBuf>;
+    /// Versions of the app published.
+    fn list(&self, app_name: &str) -> Result<Vec<String>>;
+}
+
+/// The backend of a bundle repository spec.
+pub fn open(spec: &BundleRepoSpec) -> Box<dyn BundleRepo + '_> {
+    match spec.provider {
+        BundleRepoProvider::Github => Box::new(gh_release::GithubRelease { spec }),
+        BundleRepoProvider::Oci => Box::new(oci::OciRegistry { spec }),
+    }
+}
+
+/// A bundle repository named on the command line, with the defaults of an
+/// env spec `bundle_repo` section.
+pub fn command_line_spec(provider: BundleRepoProvider, repo: String, tag_prefix: Option<String>) -> BundleRepoSpec {
+    BundleRepoSpec {
+        provider,
+        repo,
+        tag_prefix,
+        token_env: default_token_env(provider).to_string(),
+        public_key: None,
+        timeout: DEFAULT_TIMEOUT,
+        retries: DEFAULT_RETRIES,
+    }
+}
+
+/// Env variable the API token is read from unless `bundle_repo.token_env` names another.
+pub fn default_token_env(provider: BundleRepoProvider) -> &'static str {
+    match provider {
+        BundleRepoProvider::Github => "GITHUB_TOKEN",
+        BundleRepoProvider::Oci => "OCI_TOKEN",
+    }
+}
+
+/// Downloads the app bundle of version `ver` from the env spec's bundle
+/// repository into the cache in `root`, the env spec's directory. When the
+/// version is missing, the error names the published ones.
+pub fn download(repo: &BundleRepoSpec, app_name: &str, ver: &str, root: &Path) -> Result<PathBuf> {
+    let backend = open(repo);
+    backend.download(app_name, ver, &root.join(CACHE_DIR)).map_err(|err| match backend.list(app_name) {
+        Ok(versions) if !versions.is_empty() && !versions.iter().any(|v| v == ver) => {
+            err.context(format!("Version {} is not published in {}; available: {}", ver, repo.repo, versions.join(", ")))
+        }
+        _ => err,
+    })
+}
+
+/// The env spec's bundle repository with `--github-repo` and
+/// `--github-tag-prefix` from the command line over it. Without a
+/// `bundle_repo` section, or over one of another provider, a repo flag names
+/// a GitHub repository. The env spec's `public_key` applies whatever the
+/// flags say, so they cannot turn signature checks off.
+pub fn with_overrides(spec: Option<&BundleRepoSpec>, repo: Option<&str>, tag_prefix: Option<&str>) -> Option<BundleRepoSpec> {
+    let mut merged = match (spec, repo) {
+        (Some(spec), None) => spec.clone(),
+        (Some(spec), Some(_)) if spec.provider == BundleRepoProvider::Github => spec.clone(),
+        (_, Some(repo)) => command_line_spec(BundleRepoProvider::Github, repo.to_string(), None),
+        (None, None) => return None,
+    };
+    if let Some(spec) = spec {
+        merged.public_key = spec.public_key.clone();
+    }
+    if let Some(repo) = repo {
+        merged.repo = repo.to_string();
+    }
+    if let Some(tag_prefix) = tag_prefix {
+        merged.tag_prefix = Some(tag_prefix.to_string());
+    }
+    Some(merged)
+}
+
+/// A bundle given on the command line: an existing path, or a version of
+/// `app_name` downloaded from the env spec's bundle repository.
+pub fn locate(bundle: &str, env_spec: &DeploymentEnvironmentSpec, app_name: &str) -> Result<PathBuf> {
+    if Path::new(bundle).exists() {
+        return Ok(PathBuf::from(bundle));
+    }
+    semver::Version::parse(bundle)
+        .context(format!("{} is neither an existing bundle path nor an app version", bundle))?;
+    let repo = env_spec.bundle_repo.as_ref()
+        .context("Using a bundle by version requires a 'bundle_repo' section in the env spec")?;
+    download(repo, app_name, bundle, &env_spec.root)
+}
+
+/// The app `deployments` deploy, whose versions a bundle version names.
+/// Fails when they deploy different apps.
+pub fn app_of<'a>(deployments: impl IntoIterator<Item = &'a DeploymentSpec>) -> Result<&'a str> {
+    let mut apps: Vec<&str> = deployments.into_iter().map(|d| d.application.name.as_str()).collect();
+    apps.sort();
+    apps.dedup();
+    match apps.as_slice() {
+        [] => bail!("No deployments defined in env spec"),
+        [app] => Ok(app),
+        _ => bail!("Deployments of different apps ({}) are selected, so a bundle version is ambiguous", apps.join(", ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_flags_override_the_spec() {
+        let spec = BundleRepoSpec {
+            provider: BundleRepoProvider::Github,
+            repo: "acme/shop".to_string(),
+            tag_prefix: Some("bundle-v".to_string()),
+            token_env: "GH_BUNDLE_TOKEN".to_string(),
+            public_key: None,
+            timeout: Duration::from_secs(30),
+            retries: 1,
+        };
+        let merged = with_overrides(Some(&spec), Some("acme/shop-fork"), None).unwrap();
+        assert_eq!((merged.repo.as_str(), merged.tag_prefix.as_deref(), merged.token_env.as_str()), ("acme/shop-fork", Some("bundle-v"), "GH_BUNDLE_TOKEN"));
+        assert_eq!((merged.timeout, merged.retries), (Duration::from_secs(30), 1));
+
+        let merged = with_overrides(None, Some("acme/shop"), Some("v")).unwrap();
+        assert_eq!((merged.tag_prefix.as_deref(), merged.token_env.as_str()), (Some("v"), "GITHUB_TOKEN"));
+        assert!(with_overrides(None, None, Some("v")).is_none());
+
+        let oci = BundleRepoSpec { provider: BundleRepoProvider::Oci, repo: "registry.example.com/acme/shop".to_string(), public_key: Some(vec![7; 32]), ..spec };
+        let merged = with_overrides(Some(&oci), Some("acme/shop"), None).unwrap();
+        assert_eq!((merged.provider, merged.repo.as_str()), (BundleRepoProvider::Github, "acme/shop"));
+        assert_eq!(merged.public_key, Some(vec![7; 32]), "a repo flag keeps the required signature");
+    }
+
+    #[test]
+    fn bundle_versions_are_of_the_selected_deployments_app() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("envspec.yaml"), r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments:
+  blog:
+    primary_host: web
+    application:
+      name: blog
+  shop:
+    primary_host: web
+    application:
+      name: shop
+"#).unwrap();
+        let env_spec = crate::spec_loader::load_env_spec(dir.path()).unwrap();
+        let shop = env_spec.deployments.iter().filter(|d| d.name == "shop");
+        assert_eq!(app_of(shop).unwrap(), "shop");
+        let err = app_of(&env_spec.deployments).unwrap_err().to_string();
+        assert_eq!(err, "Deployments of different apps (blog, shop) are selected, so a bundle version is ambiguous");
+    }
+}
@@ -0,0 +1,477 @@
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine as _, engine::general_purpose};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, LOCATION, WWW_AUTHENTICATE};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::bundle_repo::{integrity, BundleRepo};
+use crate::error::Failure;
+use crate::spec::BundleRepoSpec;
+
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const ARTIFACT_TYPE: &str = "application/vnd.simpled.bundle.v1";
+// The layer types and title annotation `oras pull` writes files from
+const BUNDLE_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const SIGNATURE_MEDIA_TYPE: &str = "application/vnd.simpled.bundle.signature.v1";
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+const EMPTY_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+const EMPTY_CONFIG: &[u8] = b"{}";
+
+// Basic auth variables, used when the token variable is not set
+pub const USERNAME_ENV: &str = "OCI_USERNAME";
+pub const PASSWORD_ENV: &str = "OCI_PASSWORD";
+
+/// Bundles stored as OCI artifacts in `spec.repo`, one tag
+/// `<tag_prefix><version>` per version, next to the app images.
+pub struct OciRegistry<'a> {
+    pub spec: &'a BundleRepoSpec,
+}
+
+impl BundleRepo for OciRegistry<'_> {
+    fn upload(&self, version: &str, bundle: &Path) -> Result<()> {
+        push(self.spec, version, bundle).context(Failure::Upload)
+    }
+
+    fn download(&self, app_name: &str, version: &str, dest_dir: &Path) -> Result<PathBuf> {
+        pull(self.spec, app_name, version, dest_dir).context(Failure::Download)
+    }
+
+    fn list(&self, _app_name: &str) -> Result<Vec<String>> {
+        list_tags(self.spec).context(Failure::Download)
+    }
+}
+
+/// `registry/path` of an OCI repository, e.g. `registry.example.com/org/app`,
+/// optionally written `oci://registry.example.com/org/app`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+}
+
+impl Reference {
+    pub fn parse(input: &str) -> Result<Reference> {
+        let trimmed = input.strip_prefix("oci://").unwrap_or(input);
+        let (registry, repository) = trimmed.split_once('/')
+            .filter(|(registry, repository)| {
+                (registry.contains('.') || registry.contains(':') || *registry == "localhost") && !repository.is_empty()
+            })
+            .ok_or_else(|| anyhow!("OCI repository must be registry/path, e.g. registry.example.com/org/app, got {}", input))?;
+        if repository.contains(':') || repository.contains('@') {
+            bail!("OCI repository {} must not have a tag or digest; bundles are tagged <tag_prefix><version>", input);
+        }
+        if !repository.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"._-/".contains(&b)) {
+            bail!("OCI repository path {} may only have lowercase letters, digits, '.', '_', '-' and '/'", repository);
+        }
+        Ok(Reference { registry: registry.to_string(), repository: repository.to_string() })
+    }
+
+    // A registry on this machine is spoken to over plain http, like `docker` does
+    fn base_url(&self) -> String {
+        let host = self.registry.split(':').next().unwrap_or("");
+        let scheme = if host == "localhost" || host == "127.0.0.1" { "http" } else { "https" };
+        format!("{}://{}", scheme, self.registry)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/v2/{}/{}", self.base_url(), self.repository, path)
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+}
+
+impl Descriptor {
+    fn title(&self) -> Option<&str> {
+        self.annotations.get(TITLE_ANNOTATION).map(String::as_str)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    schema_version: u32,
+    #[serde(default)]
+    media_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    artifact_type: Option<String>,
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Credentials {
+    Anonymous,
+    // used as the bearer token as is
+    Token(String),
+    // sent as is, or exchanged for a token when the registry asks for one
+    Basic { username: String, password: String },
+}
+
+impl Credentials {
+    // The token variable, else the username and password variables, else
+    // what `docker login` stored for the registry
+    fn from_env(registry: &str, token_env: &str) -> Credentials {
+        if let Ok(token) = env::var(token_env) {
+            return Credentials::Token(token);
+        }
+        if let (Ok(username), Ok(password)) = (env::var(USERNAME_ENV), env::var(PASSWORD_ENV)) {
+            return Credentials::Basic { username, password };
+        }
+        let config_dir = env::var_os("DOCKER_CONFIG").map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".docker")));
+        config_dir
+            .and_then(|dir| fs::read_to_string(dir.join("config.json")).ok())
+            .and_then(|config| docker_auth(&config, registry))
+            .unwrap_or(Credentials::Anonymous)
+    }
+}
+
+// Credentials `docker login` stored in config.json without a credential helper
+fn docker_auth(config: &str, registry: &str) -> Option<Credentials> {
+    let config: serde_json::Value = serde_json::from_str(config).ok()?;
+    let auths = config.get("auths")?;
+    let auth = auths.get(registry).or_else(|| auths.get(format!("https://{}", registry)))?.get("auth")?.as_str()?;
+    let decoded = String::from_utf8(general_purpose::STANDARD.decode(auth).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some(Credentials::Basic { username: username.to_string(), password: password.to_string() })
+}
+
+#[derive(Debug, PartialEq)]
+struct Challenge {
+    realm: String,
+    service: Option<String>,
+    scope: Option<String>,
+}
+
+// `Bearer realm="...",service="...",scope="repository:org/app:pull,push"`;
+// values are quoted and may contain commas.
+fn parse_challenge(header: &str) -> Option<Challenge> {
+    let (scheme, params) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let mut values = BTreeMap::new();
+    let mut rest = params.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let (value, remaining) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => after.split_once(',').map_or((after, ""), |(value, remaining)| (value, remaining)),
+        };
+        values.insert(key.trim().to_ascii_lowercase(), value.to_string());
+        rest = remaining.trim_start_matches([',', ' ']);
+    }
+    Some(Challenge {
+        realm: values.remove("realm")?,
+        service: values.remove("service"),
+        scope: values.remove("scope"),
+    })
+}
+
+// Registry API client that answers bearer challenges with a token, fetched
+// with the basic credentials, and retries
+struct Registry<'a> {
+    client: Client,
+    reference: &'a Reference,
+    token_env: &'a str,
+    credentials: Credentials,
+    token: RefCell<Option<String>>,
+}
+
+impl<'a> Registry<'a> {
+    fn new(reference: &'a Reference, spec: &'a BundleRepoSpec) -> Result<Registry<'a>> {
+        Ok(Registry {
+            client: Client::builder().timeout(spec.timeout).build().context("Failed to create the HTTP client")?,
+            reference,
+            token_env: &spec.token_env,
+            credentials: Credentials::from_env(&reference.registry, &spec.token_env),
+            token: RefCell::new(None),
+        })
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request.header("User-Agent", "simpled");
+        if let Some(token) = self.token.borrow().as_ref() {
+            return request.bearer_auth(token);
+        }
+        match &self.credentials {
+            Credentials::Anonymous => request,
+            Credentials::Token(token) => request.bearer_auth(token),
+            Credentials::Basic { username, password } => request.basic_auth(username, Some(password)),
+        }
+    }
+
+    fn send(&self, what: &str, request: impl Fn(&Client) -> RequestBuilder) -> Result<Response> {
+        let mut response = self.authorize(request(&self.client)).send().context(format!("Failed to {}", what))?;
+        if response.status().as_u16() == 401 && !matches!(self.credentials, Credentials::Token(_)) {
+            let challenge = response.headers().get(WWW_AUTHENTICATE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_challenge);
+            if let Some(challenge) = challenge {
+                *self.token.borrow_mut() = Some(self.fetch_token(&challenge)?);
+                response = self.authorize(request(&self.client)).send().context(format!("Failed to {}", what))?;
+            }
+        }
+        if matches!(response.status().as_u16(), 401 | 403) {
+            bail!(
+                "Registry {} refused to {} ({}); set {}, or {} and {}, or run docker login {}",
+                self.reference.registry, what, response.status(), self.token_env, USERNAME_ENV, PASSWORD_ENV, self.reference.registry
+            );
+        }
+        Ok(response)
+    }
+
+    fn fetch_token(&self, challenge: &Challenge) -> Result<String> {
+        let mut request = self.client.get(&challenge.realm).header("User-Agent", "simpled");
+        for (key, value) in [("service", &challenge.service), ("scope", &challenge.scope)] {
+            if let Some(value) = value {
+                request = request.query(&[(key, value)]);
+            }
+        }
+        if let Credentials::Basic { username, password } = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = request.send().context(format!("Failed to get a registry token from {}", challenge.realm))?;
+        if !response.status().is_success() {
+            bail!(
+                "{} refused a token for {} ({}); set {}, or {} and {}, or run docker login {}",
+                challenge.realm, self.reference, response.status(), self.token_env, USERNAME_ENV, PASSWORD_ENV, self.reference.registry
+            );
+        }
+        let body: serde_json::Value = response.json().context("Failed to parse the registry token")?;
+        body.get("token").or_else(|| body.get("access_token"))
+            .and_then(|token| token.as_str())
+            .map(str::to_string)
+            .context(format!("{} answered without a token", challenge.realm))
+    }
+
+    // Uploads a blob unless the registry has it, and returns its descriptor
+    fn push_blob(&self, media_type: &str, content: Vec<u8>, title: Option<&str>) -> Result<Descriptor> {
+        let digest = format!("sha256:{}", integrity::sha256_of(&content));
+        let descriptor = Descriptor {
+            media_type: media_type.to_string(),
+            digest: digest.clone(),
+            size: content.len() as u64,
+            annotations: title.map(|t| BTreeMap::from([(TITLE_ANNOTATION.to_string(), t.to_string())])).unwrap_or_default(),
+        };
+        let blob_url = self.reference.url(&format!("blobs/{}", digest));
+        if self.send("check for a blob", |c| c.head(&blob_url))?.status().is_success() {
+            return Ok(descriptor);
+        }
+
+        let response = self.send("start a blob upload", |c| c.post(self.reference.url("blobs/uploads/")))?;
+        if response.status().as_u16() != 202 {
+            bail!("Registry {} did not start the blob upload: {}", self.reference.registry, response.status());
+        }
+        let location = response.headers().get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .context("Registry answered the blob upload without a Location")?;
+        let location = if location.starts_with('/') { format!("{}{}", self.reference.base_url(), location) } else { location.to_string() };
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let upload_url = format!("{}{}digest={}", location, separator, digest.replace(':', "%3A"));
+        let response = self.send("upload a blob", |c| {
+            c.put(&upload_url).header(CONTENT_TYPE, "application/octet-stream").body(content.clone())
+        })?;
+        if !response.status().is_success() {
+            bail!("Failed to upload blob {}: {}", digest, response.text().unwrap_or_default());
+        }
+        Ok(descriptor)
+    }
+
+    // Written next to the destination and renamed once the digest matches
+    fn fetch_blob(&self, descriptor: &Descriptor, dest_path: &Path) -> Result<()> {
+        log::info!("Downloading {} from {}", descriptor.digest, self.reference);
+        let blob_url = self.reference.url(&format!("blobs/{}", descriptor.digest));
+        let response = self.send("download a blob", |c| c.get(&blob_url))?;
+        if !response.status().is_success() {
+            bail!("Failed to download blob {} from {}: {}", descriptor.digest, self.reference, response.status());
+        }
+        let content = response.bytes().context("Failed to read the blob")?;
+        let actual = format!("sha256:{}", integrity::sha256_of(&content));
+        if actual != descriptor.digest {
+            bail!("Checksum mismatch for {:?}: expected {}, got {}", dest_path, descriptor.digest, actual);
+        }
+        let part_path = integrity::sidecar(dest_path, ".part");
+        fs::write(&part_path, &content).context(format!("Failed to write {:?}", part_path))?;
+        fs::rename(&part_path, dest_path).context(format!("Failed to move downloaded blob to {:?}", dest_path))
+    }
+}
+
+fn push(spec: &BundleRepoSpec, version: &str, bundle: &Path) -> Result<()> {
+    let reference = Reference::parse(&spec.repo)?;
+    let registry = Registry::new(&reference, spec)?;
+    let filename = bundle.file_name()
+        .and_then(|n| n.to_str())
+        .context(format!("Invalid bundle path {:?}", bundle))?;
+    let tag = format!("{}{}", spec.tag_prefix.as_deref().unwrap_or(""), version);
+    let manifest_url = reference.url(&format!("manifests/{}", tag));
+
+    let response = registry.send("look up the tag", |c| c.head(&manifest_url).header(ACCEPT, MANIFEST_MEDIA_TYPE))?;
+    if response.status().is_success() {
+        bail!("Tag {} already exists in {}. Increase the app version number.", tag, reference);
+    } else if response.status().as_u16() != 404 {
+        bail!("Failed to look up tag {} in {}: {}", tag, reference, response.status());
+    }
+
+    log::info!("Pushing {} to {}:{}", filename, reference, tag);
+    let config = registry.push_blob(EMPTY_CONFIG_MEDIA_TYPE, EMPTY_CONFIG.to_vec(), None)?;
+    let content = fs::read(bundle).context(format!("Failed to read {:?}", bundle))?;
+    let mut layers = vec![registry.push_blob(BUNDLE_MEDIA_TYPE, content, Some(filename))?];
+    // the registry addresses blobs by digest, so the checksum file is not needed
+    let signature = integrity::sidecar(bundle, integrity::SIGNATURE_SUFFIX);
+    if signature.exists() {
+        let content = fs::read(&signature).context(format!("Failed to read {:?}", signature))?;
+        let title = format!("{}{}", filename, integrity::SIGNATURE_SUFFIX);
+        layers.push(registry.push_blob(SIGNATURE_MEDIA_TYPE, content, Some(&title))?);
+    }
+
+    let manifest = serde_json::to_vec(&Manifest {
+        schema_version: 2,
+        media_type: MANIFEST_MEDIA_TYPE.to_string(),
+        artifact_type: Some(ARTIFACT_TYPE.to_string()),
+        config,
+        layers,
+    })?;
+    let response = registry.send("push the manifest", |c| {
+        c.put(&manifest_url).header(CONTENT_TYPE, MANIFEST_MEDIA_TYPE).body(manifest.clone())
+    })?;
+    if !response.status().is_success() {
+        bail!("Failed to push the manifest of {}:{}: {}", reference, tag, response.text().unwrap_or_default());
+    }
+
+    log::info!("Upload successful");
+    Ok(())
+}
+
+fn pull(spec: &BundleRepoSpec, app_name: &str, version: &str, dest_dir: &Path) -> Result<PathBuf> {
+    let reference = Reference::parse(&spec.repo)?;
+    let registry = Registry::new(&reference, spec)?;
+    let filename = format!("{}.{}.tar.gz", app_name, version);
+    let tag = format!("{}{}", spec.tag_prefix.as_deref().unwrap_or(""), version);
+
+    let manifest_url = reference.url(&format!("manifests/{}", tag));
+    let response = registry.send("get the manifest", |c| c.get(&manifest_url).header(ACCEPT, MANIFEST_MEDIA_TYPE))?;
+    if response.status().as_u16() == 404 {
+        bail!("Bundle {} not found: {} has no tag {}", filename, reference, tag);
+    }
+    if !response.status().is_success() {
+        bail!("Failed to get the manifest of {}:{}: {}", reference, tag, response.status());
+    }
+    let manifest: Manifest = response.json().context(format!("Failed to parse the manifest of {}:{}", reference, tag))?;
+    let find = |title: &str| manifest.layers.iter().find(|layer| layer.title() == Some(title));
+    let layer = find(&filename).context(format!("{}:{} has no layer titled {}", reference, tag, filename))?;
+
+    fs::create_dir_all(dest_dir).context(format!("Failed to create directory {:?}", dest_dir))?;
+    let dest_path = dest_dir.join(&filename);
+    let signature = integrity::sidecar(&dest_path, integrity::SIGNATURE_SUFFIX);
+    match find(&format!("{}{}", filename, integrity::SIGNATURE_SUFFIX)) {
+        Some(signature_layer) => registry.fetch_blob(signature_layer, &signature)?,
+        None if signature.exists() => fs::remove_file(&signature).context(format!("Failed to remove stale {:?}", signature))?,
+        None => {}
+    }
+
+    if dest_path.exists() && format!("sha256:{}", integrity::sha256_hex(&dest_path)?) == layer.digest {
+        log::info!("Using cached bundle {:?}", dest_path);
+        return Ok(dest_path);
+    }
+    registry.fetch_blob(layer, &dest_path)?;
+    Ok(dest_path)
+}
+
+// Versions tagged in the repository, from the first page of tags
+fn list_tags(spec: &BundleRepoSpec) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct Tags {
+        #[serde(default)]
+        tags: Option<Vec<String>>,
+    }
+
+    let reference = Reference::parse(&spec.repo)?;
+    let registry = Registry::new(&reference, spec)?;
+    let response = registry.send("list tags", |c| c.get(reference.url("tags/list")))?;
+    if !response.status().is_success() {
+        bail!("Failed to list the tags of {}: {}", reference, response.status());
+    }
+    let tags: Tags = response.json().context(format!("Failed to parse the tags of {}", reference))?;
+    let prefix = spec.tag_prefix.as_deref().unwrap_or("");
+    Ok(tags.tags.unwrap_or_default().into_iter()
+        .filter_map(|tag| tag.strip_prefix(prefix).map(str::to_string))
+        .filter(|version| semver::Version::parse(version).is_ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_challenges_and_docker_logins_are_parsed() {
+        let reference = Reference::parse("oci://registry.example.com:5000/acme/shop").unwrap();
+        assert_eq!((reference.registry.as_str(), reference.repository.as_str()), ("registry.example.com:5000", "acme/shop"));
+        assert_eq!(reference.url("tags/list"), "https://registry.example.com:5000/v2/acme/shop/tags/list");
+        assert_eq!(Reference::parse("localhost:5000/shop").unwrap().url("tags/list"), "http://localhost:5000/v2/shop/tags/list");
+        for bad in ["acme/shop", "registry.example.com/", "registry.example.com/acme/shop:1.0", "registry.example.com/Acme/shop"] {
+            assert!(Reference::parse(bad).is_err(), "{}", bad);
+        }
+
+        let challenge = parse_challenge(r#"Bearer realm="https://auth.example.com/token",service="registry.example.com",scope="repository:acme/shop:pull,push""#).unwrap();
+        assert_eq!(challenge, Challenge {
+            realm: "https://auth.example.com/token".to_string(),
+            service: Some("registry.example.com".to_string()),
+            scope: Some("repository:acme/shop:pull,push".to_string()),
+        });
+        assert!(parse_challenge(r#"Basic realm="registry""#).is_none());
+
+        let config = r#"{ "auths": { "registry.example.com": { "auth": "Y2k6czNjcmV0" } } }"#;
+        assert_eq!(docker_auth(config, "registry.example.com"), Some(Credentials::Basic { username: "ci".to_string(), password: "s3cret".to_string() }));
+        assert_eq!(docker_auth(config, "ghcr.io"), None);
+    }
+
+    #[test]
+    fn manifests_are_oras_artifacts() {
+        let layer = Descriptor {
+            media_type: BUNDLE_MEDIA_TYPE.to_string(),
+            digest: "sha256:abc".to_string(),
+            size: 3,
+            annotations: BTreeMap::from([(TITLE_ANNOTATION.to_string(), "shop.1.4.0.tar.gz".to_string())]),
+        };
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: MANIFEST_MEDIA_TYPE.to_string(),
+            artifact_type: Some(ARTIFACT_TYPE.to_string()),
+            config: Descriptor { media_type: EMPTY_CONFIG_MEDIA_TYPE.to_string(), digest: "sha256:def".to_string(), size: 2, annotations: BTreeMap::new() },
+            layers: vec![layer.clone()],
+        };
+        let json: serde_json::Value = serde_json::to_value(&manifest).unwrap();
+        assert_eq!(json["schemaVersion"], 2);
+        assert_eq!(json["artifactType"], ARTIFACT_TYPE);
+        assert_eq!(json["layers"][0]["annotations"][TITLE_ANNOTATION], "shop.1.4.0.tar.gz");
+        assert!(json["config"].get("annotations").is_none());
+
+        let parsed: Manifest = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.layers[0].title(), Some("shop.1.4.0.tar.gz"));
+        assert_eq!(parsed.layers, vec![layer]);
+    }
+}
@@ -0,0 +1,145 @@
+use std::io::{self, IsTerminal, Read, Write};
+use std::time::{Duration, Instant};
+
+// Transfers smaller than this finish too fast to be worth reporting
+const MIN_REPORTED: u64 = 1024 * 1024;
+// How often the bar is redrawn, and a log line written without a terminal
+const REDRAW_EVERY: Duration = Duration::from_millis(200);
+const LOG_EVERY: Duration = Duration::from_secs(10);
+const BAR_WIDTH: usize = 24;
+
+/// Shows how far a transfer got: a bar redrawn on stderr when it is a
+/// terminal, else a log line every few seconds so CI logs stay readable.
+pub struct Progress {
+    label: String,
+    total: Option<u64>,
+    done: u64,
+    started: Instant,
+    last_report: Instant,
+    terminal: bool,
+    quiet: bool,
+    finished: bool,
+}
+
+impl Progress {
+    pub fn new(label: &str, total: Option<u64>) -> Progress {
+        let now = Instant::now();
+        Progress {
+            label: label.to_string(),
+            total,
+            done: 0,
+            started: now,
+            last_report: now,
+            // drawn where the logs go, so stdout stays clean for --format json
+            terminal: io::stderr().is_terminal(),
+            quiet: total.is_some_and(|total| total < MIN_REPORTED),
+            finished: false,
+        }
+    }
+
+    pub fn advance(&mut self, bytes: u64) {
+        self.done += bytes;
+        let every = if self.terminal { REDRAW_EVERY } else { LOG_EVERY };
+        if !self.quiet && self.last_report.elapsed() >= every {
+            self.last_report = Instant::now();
+            self.report();
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if !self.quiet && !self.finished {
+            self.finished = true;
+            self.report();
+            if self.terminal {
+                eprintln!();
+            }
+        }
+    }
+
+    fn report(&self) {
+        let line = status_line(&self.label, self.done, self.total, self.started.elapsed());
+        if self.terminal {
+            let bar = match self.total {
+                Some(total) if total > 0 => {
+                    let filled = (self.done.min(total) * BAR_WIDTH as u64 / total) as usize;
+                    format!("[{}{}] ", "#".repeat(filled), " ".repeat(BAR_WIDTH - filled))
+                }
+                _ => String::new(),
+            };
+            let mut stderr = io::stderr();
+            let _ = write!(stderr, "\r\x1b[2K{}{}", bar, line);
+            let _ = stderr.flush();
+        } else {
+            log::info!("{}", line);
+        }
+    }
+}
+
+/// A reader that advances `progress` by what is read through it.
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, progress: Progress) -> ProgressReader<R> {
+        ProgressReader { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 {
+            self.progress.finish();
+        } else {
+            self.progress.advance(read as u64);
+        }
+        Ok(read)
+    }
+}
+
+// `shop.1.4.0.tar.gz  12.0 MiB / 48.0 MiB (25%)  3.0 MiB/s`
+fn status_line(label: &str, done: u64, total: Option<u64>, elapsed: Duration) -> String {
+    let amount = match total {
+        Some(total) if total > 0 => format!("{} / {} ({}%)", bytes(done), bytes(total), done.min(total) * 100 / total),
+        _ => bytes(done),
+    };
+    let secs = elapsed.as_secs_f64();
+    let speed = if secs > 0.0 { bytes((done as f64 / secs) as u64) } else { bytes(0) };
+    format!("{}  {}  {}/s", label, amount, speed)
+}
+
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_lines_show_bytes_and_speed() {
+        let mib = 1024 * 1024;
+        assert_eq!(
+            status_line("shop.1.4.0.tar.gz", 12 * mib, Some(48 * mib), Duration::from_secs(4)),
+            "shop.1.4.0.tar.gz  12.0 MiB / 48.0 MiB (25%)  3.0 MiB/s"
+        );
+        assert_eq!(status_line("shop.1.4.0.tar.gz", 512, None, Duration::ZERO), "shop.1.4.0.tar.gz  512 B  0 B/s");
+
+        let mut reader = ProgressReader::new(&[7u8; 3000][..], Progress::new("sig", Some(3000)));
+        let mut copied = Vec::new();
+        reader.read_to_end(&mut copied).unwrap();
+        assert_eq!((copied.len() as u64, reader.progress.done), (3000, 3000));
+    }
+}
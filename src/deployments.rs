@@ -0,0 +1,334 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::Path;
+
+use crate::diagnostics;
+use crate::secrets;
+use crate::spec::{DeploymentEnvType, DeploymentEnvironmentSpec, DeploymentSecretSource, DeploymentSpec, ResourcesSpec};
+use crate::spec_loader;
+
+#[derive(Serialize)]
+struct DeploymentSummary {
+    name: String,
+    application: String,
+    // version requirement on the app, if the deployment pins one
+    version: Option<String>,
+    primary_host: String,
+    domains: Vec<String>,
+    env_type: &'static str,
+    // secret files the deployment references that don't exist; missing extra
+    // specs already fail loading the env spec
+    missing_files: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DeploymentDetails {
+    #[serde(flatten)]
+    summary: DeploymentSummary,
+    // the deployment it extends; the other fields are the merged result
+    extends: Option<String>,
+    services: Vec<ServiceDetails>,
+    defaults: ResourcesSpec,
+    secrets: Vec<SecretDetails>,
+    configs: Vec<String>,
+    // names only, values may be sensitive
+    environment: Vec<String>,
+    undockerized_environment: Vec<String>,
+    extra: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ServiceDetails {
+    name: String,
+    // false when the deployment leaves the service out
+    enabled: bool,
+    variant: Option<String>,
+    // image used instead of the bundle's
+    image: Option<String>,
+    host: Option<String>,
+    prefixes: Vec<String>,
+    // external:internal[/udp]
+    ports: Vec<String>,
+    // only when they differ from the deployment defaults
+    resources: Option<ResourcesSpec>,
+    // names of the env values set for this service only
+    environment: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SecretDetails {
+    name: String,
+    source: String,
+}
+
+/// Prints every deployment of the env spec in `path`. Needs no app bundle.
+pub fn list(path: Option<&str>, format: &str) -> Result<()> {
+    let env_spec = spec_loader::load_env_spec(Path::new(path.unwrap_or(".")))?;
+    let mut summaries: Vec<DeploymentSummary> = env_spec.deployments.iter()
+        .map(|deployment| summarize(&env_spec, deployment))
+        .collect();
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+    warn_missing_files(&summaries);
+
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(&summaries)?),
+        _ => print!("{}", list_table(&summaries)),
+    }
+    Ok(())
+}
+
+/// Prints what one deployment of the env spec in `path` configures: its
+/// services and the secrets, configs and env vars it provides.
+pub fn describe(deployment_name: &str, path: Option<&str>, format: &str) -> Result<()> {
+    let env_spec = spec_loader::load_env_spec(Path::new(path.unwrap_or(".")))?;
+    let deployment = env_spec.deployments.iter()
+        .find(|d| d.name == deployment_name)
+        .context(format!("Deployment {} not found in env spec", deployment_name))?;
+    let details = details(&env_spec, deployment);
+    warn_missing_files(std::slice::from_ref(&details.summary));
+
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(&details)?),
+        _ => print!("{}", describe_table(&details)),
+    }
+    Ok(())
+}
+
+fn summarize(env_spec: &DeploymentEnvironmentSpec, deployment: &DeploymentSpec) -> DeploymentSummary {
+    let secret_files = deployment.secrets.iter().filter_map(|s| match &s.source {
+        DeploymentSecretSource::FilePath(path) => Some(path),
+        _ => None,
+    });
+    let missing_files = secret_files
+        .filter(|file| !Path::new(file).exists())
+        .cloned()
+        .collect();
+    DeploymentSummary {
+        name: deployment.name.clone(),
+        application: deployment.application.name.clone(),
+        version: deployment.application.version.as_ref().map(|v| v.to_string()),
+        primary_host: deployment.primary_host.clone(),
+        domains: env_spec.ingress.hosts.iter()
+            .find(|h| h.name == deployment.primary_host)
+            .map(|h| h.domain_names.clone())
+            .unwrap_or_default(),
+        env_type: match &env_spec.env_type {
+            DeploymentEnvType::K8S => "k8s",
+            DeploymentEnvType::Docker(docker) if docker.swarm_mode => "docker (swarm)",
+            DeploymentEnvType::Docker(_) => "docker",
+            DeploymentEnvType::Local => "local",
+        },
+        missing_files,
+    }
+}
+
+fn details(env_spec: &DeploymentEnvironmentSpec, deployment: &DeploymentSpec) -> DeploymentDetails {
+    let mut services: Vec<ServiceDetails> = deployment.services.iter().flatten()
+        .map(|(name, service)| ServiceDetails {
+            name: name.clone(),
+            enabled: service.enabled,
+            variant: service.variant.clone(),
+            image: service.image.clone(),
+            host: service.host.clone(),
+            prefixes: service.prefixes.iter()
+                .map(|p| if p.strip { p.prefix.clone() } else { format!("{} (not stripped)", p.prefix) })
+                .collect(),
+            ports: service.ports.iter().map(|p| p.mapping()).collect(),
+            resources: (service.resources != deployment.defaults).then(|| service.resources.clone()),
+            environment: service.environment.iter().map(|v| v.name.clone()).collect(),
+        })
+        .collect();
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+
+    DeploymentDetails {
+        summary: summarize(env_spec, deployment),
+        extends: deployment.extends.clone(),
+        services,
+        defaults: deployment.defaults.clone(),
+        secrets: deployment.secrets.iter()
+            .map(|s| SecretDetails { name: s.secret_name.clone(), source: secrets::describe_source(&s.source) })
+            .collect(),
+        configs: deployment.configs.iter().map(|c| c.name.clone()).collect(),
+        environment: deployment.environment.iter().map(|v| v.name.clone()).collect(),
+        undockerized_environment: deployment.undockerized_environment.iter().map(|v| v.name.clone()).collect(),
+        extra: deployment.application.extra.clone(),
+    }
+}
+
+fn warn_missing_files(summaries: &[DeploymentSummary]) {
+    for summary in summaries {
+        for file in &summary.missing_files {
+            diagnostics::warn("file_not_found", format!("Deployment {} references {}, which does not exist", summary.name, file));
+        }
+    }
+}
+
+fn host(summary: &DeploymentSummary) -> String {
+    if summary.domains.is_empty() {
+        summary.primary_host.clone()
+    } else {
+        format!("{} ({})", summary.primary_host, summary.domains.join(", "))
+    }
+}
+
+fn list_table(summaries: &[DeploymentSummary]) -> String {
+    let rows: Vec<[String; 5]> = summaries.iter()
+        .map(|s| [
+            s.name.clone(),
+            s.application.clone(),
+            s.version.clone().unwrap_or_else(|| "*".to_string()),
+            host(s),
+            s.env_type.to_string(),
+        ])
+        .collect();
+    let width = |i: usize| rows.iter().map(|r| r[i].len()).max().unwrap_or(0);
+    let (w0, w1, w2, w3) = (width(0), width(1), width(2), width(3));
+
+    let mut out = String::new();
+    for (row, summary) in rows.iter().zip(summaries) {
+        let mut line = format!("{:w0$}  {:w1$}  {:w2$}  {:w3$}  {}", row[0], row[1], row[2], row[3], row[4]);
+        if !summary.missing_files.is_empty() {
+            line.push_str(&format!("  missing files: {}", summary.missing_files.join(", ")));
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+fn resources(resources: &ResourcesSpec) -> String {
+    format!(
+        "replicas {}, requests {}/{}, limits {}/{}",
+        resources.replicas, resources.requests.cpu, resources.requests.memory, resources.limits.cpu, resources.limits.memory
+    )
+}
+
+fn describe_table(details: &DeploymentDetails) -> String {
+    let summary = &details.summary;
+    let mut out = format!(
+        "{}: {} {} on {} ({})\n",
+        summary.name,
+        summary.application,
+        summary.version.as_deref().unwrap_or("*"),
+        host(summary),
+        summary.env_type
+    );
+    if let Some(parent) = &details.extends {
+        out.push_str(&format!("Extends: {} (settings below include the inherited ones)\n", parent));
+    }
+    out.push_str(&format!("Defaults: {}\n", resources(&details.defaults)));
+
+    if !details.services.is_empty() {
+        out.push_str("\nServices\n");
+        for service in &details.services {
+            if !service.enabled {
+                out.push_str(&format!("  {} (disabled)\n", service.name));
+                continue;
+            }
+            out.push_str(&format!("  {}\n", service.name));
+            let fields = [
+                ("variant", service.variant.clone()),
+                ("image override", service.image.clone()),
+                ("host", service.host.clone()),
+                ("prefixes", (!service.prefixes.is_empty()).then(|| service.prefixes.join(", "))),
+                ("ports", (!service.ports.is_empty()).then(|| service.ports.join(", "))),
+                ("resources", service.resources.as_ref().map(resources)),
+                ("env vars", (!service.environment.is_empty()).then(|| service.environment.join(", "))),
+            ];
+            for (field, value) in fields {
+                if let Some(value) = value {
+                    out.push_str(&format!("    {}: {}\n", field, value));
+                }
+            }
+        }
+    }
+
+    if !details.secrets.is_empty() {
+        out.push_str("\nSecrets\n");
+        let width = details.secrets.iter().map(|s| s.name.len()).max().unwrap_or(0);
+        for secret in &details.secrets {
+            out.push_str(&format!("  {:width$}  {}\n", secret.name, secret.source));
+        }
+    }
+    for (title, items) in [
+        ("Configs", &details.configs),
+        ("Env vars", &details.environment),
+        ("Undockerized env vars", &details.undockerized_environment),
+        ("Extra specs", &details.extra),
+        ("Missing files", &summary.missing_files),
+    ] {
+        if !items.is_empty() {
+            out.push_str(&format!("\n{}\n", title));
+            for item in items {
+                out.push_str(&format!("  {}\n", item));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn lists_deployments_without_a_bundle_and_flags_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("nginx")).unwrap();
+        fs::write(dir.path().join("localenv.yaml"), r#"
+gateway:
+  hosts:
+    web: shop.localhost
+deployments:
+  dev:
+    primary_host: web
+    application:
+      name: shop
+      version: ^1.2
+    configs:
+      nginx: nginx
+    environment:
+      - LOG_LEVEL=debug
+    services:
+      api:
+        variant: debug
+        prefix: /api
+        ports:
+          - 8080:80
+        replicas: 2
+      reporting:
+        enabled: false
+  demo:
+    primary_host: web
+    application:
+      name: shop
+    secrets:
+      token:
+        file: demo.token
+"#).unwrap();
+        let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+
+        let dev = env_spec.deployments.iter().find(|d| d.name == "dev").unwrap();
+        let summary = summarize(&env_spec, dev);
+        assert_eq!(summary.version.as_deref(), Some("^1.2"));
+        assert_eq!(summary.domains, vec!["shop.localhost"]);
+        assert_eq!(summary.env_type, "local");
+        assert!(summary.missing_files.is_empty());
+
+        let details = details(&env_spec, dev);
+        let api = &details.services[0];
+        assert_eq!((api.variant.as_deref(), api.prefixes.clone(), api.ports.clone()), (Some("debug"), vec!["/api".to_string()], vec!["8080:80".to_string()]));
+        assert_eq!(api.resources.as_ref().map(|r| r.replicas), Some(2));
+        assert_eq!((details.configs.clone(), details.environment.clone()), (vec!["nginx".to_string()], vec!["LOG_LEVEL".to_string()]));
+        assert!(api.enabled && !details.services[1].enabled);
+        assert!(describe_table(&details).contains("\n  reporting (disabled)\n"));
+
+        let demo = env_spec.deployments.iter().find(|d| d.name == "demo").unwrap();
+        let summary = summarize(&env_spec, demo);
+        assert_eq!(summary.missing_files.len(), 1);
+        assert!(summary.missing_files[0].ends_with("demo.token"));
+        let table = list_table(&[summary]);
+        assert!(table.starts_with("demo  shop  *  web (shop.localhost)  local  missing files: "), "{}", table);
+    }
+}
@@ -0,0 +1,107 @@
+use std::io::Write;
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter};
+use serde::Serialize;
+
+// Warnings reported during the current command, re-printed in the summary
+static WARNINGS: Mutex<Vec<Diagnostic>> = Mutex::new(Vec::new());
+
+// A warning or error as reported by `--format json`
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    // stable identifier, e.g. `config_not_found`
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Sets up logging for simpled's own output: progress at info, details at
+/// debug. `-q` leaves warnings and errors, `-v` adds debug; otherwise a
+/// `RUST_LOG` directive for `simpled` sets the level, and `RUST_LOG` still
+/// applies to other crates.
+pub fn init_logger(quiet: bool, verbose: bool) {
+    let mut builder = logger(quiet, verbose, std::env::var("RUST_LOG").ok().as_deref());
+    if let Ok(style) = std::env::var("RUST_LOG_STYLE") {
+        builder.parse_write_style(&style);
+    }
+    builder.init();
+}
+
+// Logger for `init_logger`, given the value of `RUST_LOG`
+fn logger(quiet: bool, verbose: bool, rust_log: Option<&str>) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+    if let Some(filters) = rust_log {
+        builder.parse_filters(filters);
+    }
+    let level = if quiet {
+        Some(LevelFilter::Warn)
+    } else if verbose {
+        Some(LevelFilter::Debug)
+    } else if rust_log.is_some_and(names_simpled) {
+        None
+    } else {
+        Some(LevelFilter::Info)
+    };
+    if let Some(level) = level {
+        builder.filter_module("simpled", level);
+    }
+    builder.format(|buf, record| match record.level() {
+        Level::Info => writeln!(buf, "{}", record.args()),
+        Level::Warn => writeln!(buf, "Warning: {}", record.args()),
+        Level::Error => writeln!(buf, "Error: {}", record.args()),
+        Level::Debug | Level::Trace => writeln!(buf, "[{}] {}", record.target(), record.args()),
+    });
+    builder
+}
+
+// Whether `RUST_LOG` has a directive for simpled or one of its modules
+fn names_simpled(rust_log: &str) -> bool {
+    let directives = rust_log.split('/').next().unwrap_or_default();
+    directives.split(',').any(|directive| {
+        let module = directive.split('=').next().unwrap_or_default().trim();
+        module == "simpled" || module.starts_with("simpled::")
+    })
+}
+
+/// Logs a warning and keeps it for the summary printed when the command ends.
+/// `code` identifies the kind of warning in machine-readable output.
+pub fn warn(code: &'static str, message: impl Into<String>) {
+    let message = message.into();
+    log::warn!("{}", message);
+    WARNINGS.lock().unwrap_or_else(|e| e.into_inner()).push(Diagnostic { code, message });
+}
+
+/// Warnings reported so far, oldest first.
+pub fn warnings() -> Vec<Diagnostic> {
+    WARNINGS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Like `warnings`, but also clears them.
+pub fn take_warnings() -> Vec<Diagnostic> {
+    std::mem::take(&mut *WARNINGS.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled(quiet: bool, verbose: bool, rust_log: Option<&str>, target: &str, level: Level) -> bool {
+        let logger = logger(quiet, verbose, rust_log).build();
+        logger.matches(&log::Record::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn rust_log_sets_the_level_unless_q_or_v_is_given() {
+        assert!(enabled(false, false, None, "simpled::run_local", Level::Info));
+        assert!(!enabled(false, false, None, "simpled::run_local", Level::Debug));
+        assert!(enabled(false, false, Some("simpled=debug"), "simpled::run_local", Level::Debug));
+        assert!(enabled(false, false, Some("hyper=info,simpled::run_local=trace"), "simpled::run_local", Level::Trace));
+        assert!(!enabled(true, false, Some("simpled=debug"), "simpled::run_local", Level::Info));
+        assert!(enabled(false, true, Some("simpled=warn"), "simpled::run_local", Level::Debug));
+
+        // a directive for other crates leaves simpled at info
+        assert!(enabled(false, false, Some("hyper=debug"), "simpled::run_local", Level::Info));
+        assert!(enabled(false, false, Some("hyper=debug"), "hyper::client", Level::Debug));
+        assert!(!enabled(false, false, None, "hyper::client", Level::Warn));
+    }
+}
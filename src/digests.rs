@@ -0,0 +1,213 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::error::Failure;
+use crate::generated::GeneratedFiles;
+use crate::resolved_spec::EnvironmentResolvedSpec;
+
+/// File in the output directory mapping every image tag to its digest.
+pub const LOCK_FILE: &str = "simpled.lock";
+
+/// How `prepare-deployment` pins images to digests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Pinning {
+    // keep the tags
+    #[default]
+    Off,
+    // query the registry and write the lockfile
+    Pin,
+    // take the digests from the lockfile, without network access
+    Locked,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Lockfile {
+    // image with its tag -> sha256 digest
+    pub images: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    pub fn load(output_dir: &Path) -> Result<Option<Lockfile>> {
+        let path = output_dir.join(LOCK_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path).context(format!("Failed to read {:?}", path))?;
+        let lock = serde_yaml::from_str(&content).context(format!("Failed to parse {:?}", path))?;
+        Ok(Some(lock))
+    }
+
+    /// Adds the lockfile to the generated files, so it is written, skipped or
+    /// refused together with them.
+    pub fn add_to(&self, files: &mut GeneratedFiles, output_dir: &Path) -> Result<()> {
+        files.add(output_dir.join(LOCK_FILE), serde_yaml::to_string(self)?);
+        Ok(())
+    }
+}
+
+// Registry lookups, replaced by a fixed table in tests
+trait DigestSource {
+    fn digest(&self, image: &str) -> Result<String>;
+}
+
+struct DockerDigestSource;
+
+impl DigestSource for DockerDigestSource {
+    fn digest(&self, image: &str) -> Result<String> {
+        let output = Command::new("docker")
+            .args(["buildx", "imagetools", "inspect", image, "--format", "{{.Manifest.Digest}}"])
+            .output()
+            .context("Failed to execute docker buildx imagetools inspect")?;
+        if !output.status.success() {
+            bail!("Failed to look up the digest of {}: {}", image, String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+/// Rewrites every service image to `repo@sha256:...`. With `Pin` the digests
+/// are looked up in the registry and checked against an existing lockfile;
+/// with `Locked` they are read from the lockfile. Returns the lockfile to
+/// write, `None` when nothing is pinned or the lockfile was only read.
+pub fn pin(resolved: &mut EnvironmentResolvedSpec, output_dir: &Path, pinning: Pinning) -> Result<Option<Lockfile>> {
+    pin_with(resolved, output_dir, pinning, &DockerDigestSource).context(Failure::Resolution)
+}
+
+fn pin_with(resolved: &mut EnvironmentResolvedSpec, output_dir: &Path, pinning: Pinning, source: &dyn DigestSource) -> Result<Option<Lockfile>> {
+    if pinning == Pinning::Off {
+        return Ok(None);
+    }
+    let existing = Lockfile::load(output_dir)?;
+    if pinning == Pinning::Locked && existing.is_none() {
+        bail!("--locked needs {} in {:?}; create it with --pin-digests", LOCK_FILE, output_dir);
+    }
+    let existing = existing.unwrap_or_default();
+
+    let mut lock = Lockfile::default();
+    for service in &mut resolved.current_deployment.services {
+        if service.image.contains('@') {
+            continue;
+        }
+        let locked = existing.images.get(&service.image);
+        let digest = match (pinning, locked) {
+            (Pinning::Locked, Some(digest)) => digest.clone(),
+            (Pinning::Locked, None) => bail!("{} of service {} is not in {}; update it with --pin-digests", service.image, service.full_name, LOCK_FILE),
+            _ => {
+                let digest = source.digest(&service.image)?;
+                if !digest.starts_with("sha256:") {
+                    bail!("Registry returned an invalid digest for {}: {:?}", service.image, digest);
+                }
+                if let Some(locked) = locked.filter(|locked| **locked != digest) {
+                    bail!(
+                        "{} now resolves to {}, but {} pins {}; the tag was moved. Remove its entry from the lockfile to accept the new image",
+                        service.image, digest, LOCK_FILE, locked
+                    );
+                }
+                digest
+            }
+        };
+        lock.images.insert(service.image.clone(), digest.clone());
+        service.image = format!("{}@{}", repository(&service.image), digest);
+    }
+    Ok((pinning == Pinning::Pin).then_some(lock))
+}
+
+// Image reference without its tag; a `:` before the last `/` is a registry port.
+fn repository(image: &str) -> &str {
+    let name_start = image.rfind('/').map_or(0, |i| i + 1);
+    match image[name_start..].rfind(':') {
+        Some(i) => &image[..name_start + i],
+        None => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generated::OnConflict;
+    use crate::{resolver, spec_loader};
+    use std::cell::RefCell;
+
+    struct FixedDigests {
+        digests: BTreeMap<&'static str, &'static str>,
+        queried: RefCell<Vec<String>>,
+    }
+
+    impl DigestSource for FixedDigests {
+        fn digest(&self, image: &str) -> Result<String> {
+            self.queried.borrow_mut().push(image.to_string());
+            Ok(self.digests[image].to_string())
+        }
+    }
+
+    #[test]
+    fn pins_images_and_reuses_the_lockfile() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("envspec.yaml"), r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+registry:
+  acme: reg.example.com:5000
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+"#).unwrap();
+        fs::write(dir.path().join("appspec.yaml"), "name: shop\nversion: 1.0.0\napp_services:\n  api:\n    image: acme/api\nextra_services:\n  db:\n    image: postgres:16\n").unwrap();
+        let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env_spec)).unwrap();
+        let resolve = || resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let images = |resolved: &EnvironmentResolvedSpec| -> Vec<String> {
+            resolved.current_deployment.services.iter().map(|s| s.image.clone()).collect()
+        };
+        let registry = |api: &'static str| FixedDigests {
+            digests: BTreeMap::from([("reg.example.com:5000/acme/api:1.0.0", api), ("postgres:16", "sha256:db")]),
+            queried: RefCell::new(Vec::new()),
+        };
+        let output_dir = dir.path().join("out");
+
+        let mut resolved = resolve();
+        let lock = pin_with(&mut resolved, &output_dir, Pinning::Pin, &registry("sha256:api")).unwrap().unwrap();
+        assert_eq!(images(&resolved), ["reg.example.com:5000/acme/api@sha256:api", "postgres@sha256:db"]);
+        let mut files = GeneratedFiles::default();
+        lock.add_to(&mut files, &output_dir).unwrap();
+        files.commit(OnConflict::Fail).unwrap();
+
+        let mut resolved = resolve();
+        let offline = registry("sha256:api");
+        assert!(pin_with(&mut resolved, &output_dir, Pinning::Locked, &offline).unwrap().is_none());
+        assert!(offline.queried.borrow().is_empty());
+        assert_eq!(images(&resolved), ["reg.example.com:5000/acme/api@sha256:api", "postgres@sha256:db"]);
+
+        let err = pin_with(&mut resolve(), &output_dir, Pinning::Pin, &registry("sha256:moved")).unwrap_err();
+        assert!(err.to_string().contains("the tag was moved"), "{}", err);
+    }
+
+    #[test]
+    fn lockfile_follows_on_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join(LOCK_FILE);
+        fs::write(&lock_path, "images: {}\n").unwrap();
+        let lock = Lockfile { images: BTreeMap::from([("acme/api:1.0.0".to_string(), "sha256:api".to_string())]) };
+        let files = || {
+            let mut files = GeneratedFiles::default();
+            lock.add_to(&mut files, dir.path()).unwrap();
+            files
+        };
+
+        assert!(files().commit(OnConflict::Fail).is_err());
+        files().commit(OnConflict::Skip).unwrap();
+        assert_eq!(fs::read_to_string(&lock_path).unwrap(), "images: {}\n");
+        files().commit(OnConflict::Overwrite).unwrap();
+        assert_eq!(Lockfile::load(dir.path()).unwrap().unwrap().images, lock.images);
+    }
+}
@@ -1,23 +1,44 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
 use anyhow::Context;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use crate::resolved_spec::{EnvironmentResolvedSpec, ServiceResolvedSpec};
-use crate::spec;
-use crate::spec::{EnvVariable, Healthcheck, SecretMount, ServiceCommand, ServiceType, ServiceVolumeType};
+use crate::{diagnostics, spec};
+use crate::generated::GeneratedFiles;
+use crate::spec::{EnvVariable, Healthcheck, SecretMount, ServiceBuild, ServiceCommand, ServiceType, ServiceVolumeType};
+
+/// Directory next to the compose file that holds the named volumes.
+pub const VOLUMES_DIR: &str = "volumes";
 
 #[derive(Serialize)]
 pub struct DockerCompose {
-    pub services: HashMap<String, DockerService>,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
-    pub networks: HashMap<String, DockerComposeNetwork>,
+    pub services: BTreeMap<String, DockerService>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub networks: BTreeMap<String, DockerComposeNetwork>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub configs: BTreeMap<String, DockerComposeConfig>,
+}
+
+// A file-based swarm config object. Docker distributes its content to whichever
+// node runs the task, unlike a bind mount which needs the file on every node.
+#[derive(Serialize)]
+pub struct DockerComposeConfig {
+    pub file: String,
+}
+
+#[derive(Serialize)]
+pub struct DockerServiceConfig {
+    pub source: String,
+    pub target: String,
 }
 
 #[derive(Serialize)]
 pub struct DockerService {
     pub image: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build: Option<ServiceBuild>,
     pub container_name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub entrypoint: Option<ServiceCommand>,
@@ -25,16 +46,28 @@ pub struct DockerService {
     pub command: Option<ServiceCommand>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub healthcheck: Option<Healthcheck>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub ports: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub expose: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub volumes: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub configs: Vec<DockerServiceConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub env_file: Vec<String>,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
-    pub environment: HashMap<String, String>,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
-    pub networks: HashMap<String, ServiceNetwork>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub environment: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub networks: BTreeMap<String, ServiceNetwork>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub deploy: Option<DeployConfig>,
 }
@@ -43,6 +76,23 @@ pub struct DockerService {
 pub struct DeployConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub restart_policy: Option<RestartPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<DeployResources>,
+}
+
+#[derive(Serialize)]
+pub struct DeployResources {
+    pub limits: DeployLimits,
+}
+
+#[derive(Serialize)]
+pub struct DeployLimits {
+    // bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<u64>,
+    // decimal cores
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -52,6 +102,7 @@ pub struct RestartPolicy {
 
 #[derive(Serialize)]
 pub struct DockerComposeNetwork {
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
     pub external: bool,
     pub name: String,
 }
@@ -61,28 +112,32 @@ pub struct ServiceNetwork {
     pub aliases: Vec<String>,
 }
 
-pub fn prepare_service(service: &ServiceResolvedSpec, spec: &EnvironmentResolvedSpec, output_dir: &Path) -> anyhow::Result<DockerService> {
+/// Builds the compose service of `service`. The `.env`, config and secret
+/// files it mounts are added to `files` under `output_dir`.
+pub fn prepare_service(service: &ServiceResolvedSpec, spec: &EnvironmentResolvedSpec, output_dir: &Path, files: &mut GeneratedFiles) -> anyhow::Result<DockerService> {
     let svc_dir = output_dir.join(service.full_name.clone());
-    fs::create_dir_all(&svc_dir).context("Failed to create service directory")?;
+    files.add_dir(&svc_dir);
 
-    // Generate .env files
-    let env_path = svc_dir.join(".env".to_string());
-    write_env_file(&env_path, &service.environment_variables)?;
+    // Generate .env files; values embedding a secret go to `environment`
+    let (sensitive, plain): (Vec<EnvVariable>, Vec<EnvVariable>) = service.environment_variables.iter().cloned().partition(|e| e.sensitive);
+    files.add(svc_dir.join(".env"), env_file_content(&plain));
 
     // A host-run service with a `working_dir` gets its `.env` written into that
     // directory by `write_working_dir`, so skip the in-tree `undockerized.env`.
     if spec.env_type == spec::DeploymentEnvType::Local && service.working_dir.is_none() {
-        let undoc_env_path = svc_dir.join("undockerized.env".to_string());
-        write_env_file(&undoc_env_path, &service.undockerized_environment_variables)?;
+        files.add(svc_dir.join("undockerized.env"), env_file_content(&service.undockerized_environment_variables));
     }
 
     let mut volumes = Vec::new();
-    let mut environment = HashMap::new();
+    let mut swarm_configs = Vec::new();
+    let mut environment: BTreeMap<String, String> = sensitive.into_iter().map(|e| (e.name, e.value)).collect();
+
+    let swarm_mode = matches!(&spec.env_type, spec::DeploymentEnvType::Docker(d) if d.swarm_mode);
 
     for volume in &service.volumes {
         match &volume.name {
             ServiceVolumeType::Named(name) => {
-                volumes.push(format!("./volumes/{}:{}",  name, volume.mount_path));
+                volumes.push(format!("./{}/{}:{}", VOLUMES_DIR, name, volume.mount_path));
             }
             ServiceVolumeType::Path(from_path) => {
                 volumes.push(format!("{}:{}",  from_path, volume.mount_path));
@@ -90,6 +145,19 @@ pub fn prepare_service(service: &ServiceResolvedSpec, spec: &EnvironmentResolved
         }
     }
 
+    // Dev volumes are bind-mounted by absolute path since the compose file
+    // lives in the output directory. Missing directories are created here, or
+    // docker would create them owned by root.
+    for dev_volume in &service.dev_volumes {
+        let host_path = std::path::absolute(&dev_volume.host_path)
+            .with_context(|| format!("Invalid dev volume path {:?} of service {}", dev_volume.host_path, service.full_name))?;
+        if !host_path.exists() {
+            diagnostics::warn("dev_volume_created", format!("dev volume {:?} of service {} does not exist, creating it", host_path, service.full_name));
+            fs::create_dir_all(&host_path).context(format!("Failed to create dev volume directory {:?}", host_path))?;
+        }
+        volumes.push(format!("{}:{}", host_path.to_string_lossy().replace('\\', "/"), dev_volume.mount_path));
+    }
+
     // Configs
     for config_option in &service.configs {
         if let Some(config_spec) = spec.current_deployment.configs.iter().find(|c| c.name == config_option.config_name) {
@@ -104,27 +172,37 @@ pub fn prepare_service(service: &ServiceResolvedSpec, spec: &EnvironmentResolved
                 false
             };
 
-            if is_file_mount {
-                if let Some(parent) = host_path.parent() {
-                    fs::create_dir_all(parent).context("Failed to create config parent directory")?;
+            if swarm_mode {
+                // Swarm: reference the top-level config objects written by the
+                // swarm generator instead of bind-mounting host files.
+                for file in &config_spec.files {
+                    let target = if is_file_mount {
+                        config_option.mount_path.clone()
+                    } else {
+                        format!("{}/{}", config_option.mount_path.trim_end_matches('/'), file.name)
+                    };
+                    swarm_configs.push(DockerServiceConfig {
+                        source: swarm_config_key(&config_spec.name, &file.name, &file.content),
+                        target,
+                    });
                 }
-                fs::write(&host_path, &config_spec.files[0].content).context("Failed to write config file")?;
+            } else if is_file_mount {
+                files.add(host_path, config_spec.files[0].content.clone());
 
                 // Use forward slashes for docker-compose
                 let rel_path_str = rel_path.replace("\\", "/");
                 volumes.push(format!("./{}/{}:{}", service.full_name, rel_path_str, config_option.mount_path));
             } else {
-                fs::create_dir_all(&host_path).context("Failed to create config directory")?;
+                files.add_dir(&host_path);
                 for file in &config_spec.files {
-                    let p = host_path.join(&file.name);
-                    fs::write(&p, &file.content).context("Failed to write config file inside dir")?;
+                    files.add(host_path.join(&file.name), file.content.clone());
                 }
 
                 let rel_path_str = rel_path.replace("\\", "/");
                 volumes.push(format!("./{}/{}:{}", service.full_name, rel_path_str, config_option.mount_path));
             }
         } else {
-            eprintln!("Warning: Config {} not found for service {}", config_option.config_name, service.full_name);
+            diagnostics::warn("config_not_found", format!("Config {} not found for service {}", config_option.config_name, service.full_name));
         }
     }
 
@@ -139,60 +217,87 @@ pub fn prepare_service(service: &ServiceResolvedSpec, spec: &EnvironmentResolved
 
                 SecretMount::FilePath(mount_path) => {
                     let rel_path = mount_path.trim_start_matches('/');
-                    let host_path = svc_dir.join(rel_path);
-
-                    if let Some(parent) = host_path.parent() {
-                        fs::create_dir_all(parent).context("Failed to create secret parent directory")?;
-                    }
-                    fs::write(&host_path, &secret_spec.value).context("Failed to write secret file")?;
+                    files.add(svc_dir.join(rel_path), secret_spec.value.clone());
 
                     let rel_path_str = rel_path.replace("\\", "/");
                     volumes.push(format!("./{}/{}:{}", service.full_name, rel_path_str, mount_path));
                 }
             }
         } else {
-            eprintln!("Warning: Secret {} not found for service {}", secret_option.name, service.full_name);
+            diagnostics::warn("secret_not_found", format!("Secret {} not found for service {}", secret_option.name, service.full_name));
         }
     }
 
     // Ports
-    let ports = service.ports.iter()
-        .map(|port| format!("{}:{}", port.external, port.internal))
-        .collect();
-
-    let deploy_date = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
-    environment.insert("DEPLOY_DATE".to_string(), deploy_date.to_string());
+    // Ports without a host port are only reachable from other containers
+    let (published, exposed): (Vec<_>, Vec<_>) = service.ports.iter().partition(|port| port.external.is_some());
+    let ports = published.iter().map(|port| port.mapping()).collect();
+    let expose = exposed.iter().map(|port| port.mapping()).collect();
 
     // Job services run to completion and must not be restarted by Swarm.
     // Swarm's default restart policy is `condition: any`, which would keep
     // re-running a job after it exits, so disable restarts explicitly.
-    let deploy = match service.service_type {
-        ServiceType::Job => Some(DeployConfig {
-            restart_policy: Some(RestartPolicy {
-                condition: "none".to_string(),
-            }),
+    let restart_policy = match service.service_type {
+        ServiceType::Job => Some(RestartPolicy {
+            condition: "none".to_string(),
         }),
         _ => None,
     };
+    // Swarm stacks get the limits the specs set, like `docker run` in
+    // deploy.sh; local environments run without them.
+    let limits = service.explicit_limits;
+    let resources = (swarm_mode && (limits.memory.is_some() || limits.cpu.is_some())).then(|| DeployResources {
+        limits: DeployLimits {
+            memory: limits.memory.map(|m| m.bytes()),
+            cpus: limits.cpu.map(|c| c.cores().to_string()),
+        },
+    });
+    let deploy = (restart_policy.is_some() || resources.is_some()).then_some(DeployConfig { restart_policy, resources });
+
+    // The compose file lives in the output directory, so the build context is
+    // made absolute to keep it relative to the appspec directory.
+    let build = service.build.as_ref()
+        .map(|build| -> anyhow::Result<ServiceBuild> {
+            let context = std::path::absolute(&build.context)
+                .with_context(|| format!("Invalid build context {:?} of service {}", build.context, service.full_name))?;
+            Ok(ServiceBuild { context: context.to_string_lossy().replace('\\', "/"), ..build.clone() })
+        })
+        .transpose()?;
 
     Ok(DockerService {
         image: service.image.clone(),
+        build,
         container_name: service.full_name.clone(),
         entrypoint: service.entrypoint.clone(),
         command: service.command.clone(),
         healthcheck: service.healthcheck.clone(),
+        working_dir: service.container_working_dir.clone(),
+        user: service.user.clone(),
+        profiles: service.profile.iter().cloned().collect(),
         ports,
+        expose,
         volumes,
+        configs: swarm_configs,
         env_file: vec![format!("./{}/.env", service.full_name)],
         environment,
-        networks: HashMap::new(),
+        networks: BTreeMap::new(),
+        restart: None,
         deploy,
     })
 }
 
+/// Key of the swarm config object holding one config file. Swarm configs are
+/// immutable, so the key embeds a hash of the content: changed content yields a
+/// new config object instead of a failing update of the existing one.
+pub fn swarm_config_key(config_name: &str, file_name: &str, content: &[u8]) -> String {
+    let hash = Sha256::digest(content);
+    let short: String = hash.iter().take(5).map(|b| format!("{:02x}", b)).collect();
+    let sanitized: String = file_name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+        .collect();
+    format!("{}-{}-{}", config_name, sanitized, short)
+}
+
 /// For a host-run (non-dockerized) local service that declares a `working_dir`,
 /// write the undockerized environment as a `.env` file into that directory and
 /// copy the service's secrets alongside it, so the service can be started by
@@ -205,38 +310,51 @@ pub fn write_working_dir(service: &ServiceResolvedSpec, spec: &EnvironmentResolv
     let dir = Path::new(working_dir);
     fs::create_dir_all(dir).context(format!("Failed to create working_dir {:?}", dir))?;
 
-    let mut env_vars = service.undockerized_environment_variables.clone();
-
     // Env-variable secrets are merged into `.env`; file secrets are written as
     // files relative to the working directory.
     for secret_option in &service.secrets {
         let Some(secret_spec) = spec.current_deployment.secrets.iter().find(|s| s.name == secret_option.name) else {
-            eprintln!("Warning: Secret {} not found for service {}", secret_option.name, service.full_name);
             continue;
         };
-        match &secret_option.mount {
-            SecretMount::EnvVariable(var_name) => {
-                env_vars.push(EnvVariable { name: var_name.clone(), value: secret_spec.value.clone() });
-            }
-            SecretMount::FilePath(mount_path) => {
-                let rel_path = mount_path.trim_start_matches('/');
-                let host_path = dir.join(rel_path);
-                if let Some(parent) = host_path.parent() {
-                    fs::create_dir_all(parent).context("Failed to create secret parent directory")?;
-                }
-                fs::write(&host_path, &secret_spec.value).context("Failed to write secret file")?;
+        if let SecretMount::FilePath(mount_path) = &secret_option.mount {
+            let rel_path = mount_path.trim_start_matches('/');
+            let host_path = dir.join(rel_path);
+            if let Some(parent) = host_path.parent() {
+                fs::create_dir_all(parent).context("Failed to create secret parent directory")?;
             }
+            fs::write(&host_path, &secret_spec.value).context("Failed to write secret file")?;
         }
     }
 
     let env_path = dir.join(".env");
-    write_env_file(&env_path, &env_vars)
+    write_env_file(&env_path, &undockerized_env(service, spec))
+}
+
+/// Environment of a service run outside docker: the undockerized variables
+/// plus the secrets the service mounts as environment variables.
+pub fn undockerized_env(service: &ServiceResolvedSpec, spec: &EnvironmentResolvedSpec) -> Vec<EnvVariable> {
+    let mut env_vars = service.undockerized_environment_variables.clone();
+
+    for secret_option in &service.secrets {
+        let Some(secret_spec) = spec.current_deployment.secrets.iter().find(|s| s.name == secret_option.name) else {
+            diagnostics::warn("secret_not_found", format!("Secret {} not found for service {}", secret_option.name, service.full_name));
+            continue;
+        };
+        if let SecretMount::EnvVariable(var_name) = &secret_option.mount {
+            env_vars.push(EnvVariable { name: var_name.clone(), value: secret_spec.value.clone(), sensitive: true });
+        }
+    }
+
+    env_vars
+}
+
+pub fn write_env_file(path: &Path, vars: &[EnvVariable]) -> anyhow::Result<()> {
+    fs::write(path, env_file_content(vars)).context(format!("Failed to write env file {:?}", path))
 }
 
-fn write_env_file(path: &Path, vars: &[EnvVariable]) -> anyhow::Result<()> {
-    let content = vars.iter()
+fn env_file_content(vars: &[EnvVariable]) -> String {
+    vars.iter()
         .map(|v| format!("{}={}", v.name, v.value))
         .collect::<Vec<_>>()
-        .join("\n");
-    fs::write(path, content).context(format!("Failed to write env file {:?}", path))
+        .join("\n")
 }
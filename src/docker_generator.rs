@@ -1,177 +1,230 @@
-use std::collections::HashMap;
-use crate::resolved_spec::{EnvironmentResolvedSpec, IngressResolvedSpec, LetsEncryptResolvedSpec};
-use crate::spec::{DockerIngressType, DockerSpecificSpec, SecretMount, ServiceVolumeType};
+use std::collections::BTreeMap;
+use crate::resolved_spec::{DefaultBackendResolvedSpec, EnvironmentResolvedSpec, IngressResolvedSpec, IngressRule, LetsEncryptResolvedSpec, ServiceResolvedSpec};
+use crate::spec::{CorsSpec, DockerIngressType, DockerSpecificSpec, ScriptFormat, SecretMount, ServiceVolumeType};
 use anyhow::{anyhow, Result};
-use std::fs::{self, File};
 use std::io::Write;
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
-#[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
-use crate::docker_compose::{prepare_service, DockerCompose, DockerComposeNetwork, ServiceNetwork};
+use std::path::{Path, PathBuf};
+use crate::generated::GeneratedFiles;
+use crate::docker_compose::{prepare_service, swarm_config_key, DockerCompose, DockerComposeConfig, DockerComposeNetwork, ServiceNetwork};
 
 const DOCKER_NETWORK: &str = "common_network";
 const NGINX_IMAGE: &str = "nginx:alpine";
 const TRAEFIK_IMAGE: &str = "traefik:v2.10";
 const TRAEFIK_RESOLVER: &str = "myresolver";
+const NGINX_PAGES_DIR: &str = "/etc/nginx/pages";
 
-pub fn generate(
+/// Renders the deployment files into memory; nothing is written to
+/// `output_dir` until the result is committed.
+pub fn render(
     resolved_spec: &EnvironmentResolvedSpec,
     docker_spec: &DockerSpecificSpec,
     output_dir: &Path,
-) -> Result<()> {
-    if !output_dir.exists() {
-        std::fs::create_dir_all(output_dir)?;
-    }
-    
+) -> Result<GeneratedFiles> {
+    let mut files = GeneratedFiles::default();
+    files.add_dir(output_dir);
     if docker_spec.swarm_mode {
-        generate_swarm(resolved_spec, docker_spec, output_dir)
+        generate_swarm(resolved_spec, docker_spec, output_dir, &mut files)?;
     } else {
-        generate_standalone(resolved_spec, docker_spec, output_dir)
+        generate_standalone(resolved_spec, docker_spec, output_dir, &mut files)?;
     }
+    Ok(files)
 }
 
 fn generate_standalone(
     resolved_spec: &EnvironmentResolvedSpec,
     docker_spec: &DockerSpecificSpec,
     output_dir: &Path,
+    files: &mut GeneratedFiles,
 ) -> Result<()> {
     let deployment = &resolved_spec.current_deployment;
-    let app_name = &deployment.application_name;
     
     // Create subdirs
     let configs_dir = output_dir.join("configs");
-    fs::create_dir_all(&configs_dir)?;
+    files.add_dir(&configs_dir);
     
     // 1. Configs
+    // Resolved config and secret names already carry the app name
     for config in &deployment.configs {
-         let dir_name = format!("{}-{}", app_name, config.name);
-         let cfg_dir = configs_dir.join(&dir_name);
-         fs::create_dir_all(&cfg_dir)?;
+         let cfg_dir = configs_dir.join(&config.name);
+         files.add_dir(&cfg_dir);
          for cfg_file in &config.files {
-             let path = cfg_dir.join(&cfg_file.name);
-             fs::write(&path, &cfg_file.content)?;
+             files.add(cfg_dir.join(&cfg_file.name), cfg_file.content.clone());
          }
     }
     
     // 2. Secrets
     let secrets_dir = output_dir.join("secrets");
-    fs::create_dir_all(&secrets_dir)?;
+    files.add_dir(&secrets_dir);
     for secret in &deployment.secrets {
-        let secret_name = format!("{}-{}", app_name, secret.name);
-        let path = secrets_dir.join(&secret_name);
-        fs::write(&path, &secret.value)?;
+        files.add(secrets_dir.join(&secret.name), secret.value.clone());
     }
 
     // 3. Envs
     let envs_dir = output_dir.join("envs");
-    fs::create_dir_all(&envs_dir)?;
-
-    // 4. Script
-    let mut deploy_sh = File::create(output_dir.join("deploy.sh"))?;
-    
-    #[cfg(unix)]
-    {
-        let mut perms = deploy_sh.metadata()?.permissions();
-        perms.set_mode(0o755);
-        deploy_sh.set_permissions(perms)?;
-    }
-
-    writeln!(deploy_sh, "#!/bin/bash")?;
-    writeln!(deploy_sh, "set -e")?;
-    
-    let network_name = DOCKER_NETWORK.to_string();
-    writeln!(deploy_sh, "docker network create {} || true", network_name)?;
-    
+    files.add_dir(&envs_dir);
     for service in &deployment.services {
-         writeln!(deploy_sh, "echo 'Starting {}...'", service.full_name)?;
-         writeln!(deploy_sh, "docker rm -f {} || true", service.full_name)?;
-         
-         // Create env file
-         let env_file_name = format!("{}.env", service.full_name);
-         let env_path = envs_dir.join(&env_file_name);
-         let mut env_file = File::create(&env_path)?;
-         
-         for env in &service.environment_variables {
+         // values embedding a secret go next to the secrets instead
+         let (sensitive, plain): (Vec<_>, Vec<_>) = service.environment_variables.iter().partition(|e| e.sensitive);
+         let mut env_file = Vec::new();
+         for env in plain {
              writeln!(env_file, "{}={}", env.name, env.value)?;
          }
-
-         write!(deploy_sh, "docker run -d --name {} --network {}", service.full_name, network_name)?;
-         
-         for port in &service.ports {
-             write!(deploy_sh, " -p {}:{}", port.external, port.internal)?;
-         }
-         
-         write!(deploy_sh, " --env-file $(pwd)/envs/{}", env_file_name)?;
-         
-         for secret in &service.secrets {
-              if let SecretMount::EnvVariable(var_name) = &secret.mount {
-                   write!(deploy_sh, " -e {}=$(cat ./secrets/{})", var_name, secret.name)?;
-              }
-         }
-         
-         for config in &service.configs {
-              let local_path = format!("$(pwd)/configs/{}", config.config_name);
-              write!(deploy_sh, " -v {}:{}", local_path, config.mount_path)?;
-         }
-         
-         for secret in &service.secrets {
-             if let SecretMount::FilePath(path) = &secret.mount {
-                  let local_path = format!("$(pwd)/secrets/{}", secret.name);
-                  write!(deploy_sh, " -v {}:{}", local_path, path)?;
-             }
-         }
-         
-         // Healthcheck: map docker-compose `healthcheck` onto `docker run` flags.
-         if let Some(hc) = &service.healthcheck {
-             if hc.is_disabled() {
-                 write!(deploy_sh, " --no-healthcheck")?;
-             } else {
-                 if let Some(cmd) = hc.health_cmd_string() {
-                     write!(deploy_sh, " --health-cmd '{}'", cmd.replace('\'', "'\\''"))?;
-                 }
-                 if let Some(v) = &hc.interval { write!(deploy_sh, " --health-interval {}", v)?; }
-                 if let Some(v) = &hc.timeout { write!(deploy_sh, " --health-timeout {}", v)?; }
-                 if let Some(v) = hc.retries { write!(deploy_sh, " --health-retries {}", v)?; }
-                 if let Some(v) = &hc.start_period { write!(deploy_sh, " --health-start-period {}", v)?; }
+         files.add(envs_dir.join(format!("{}.env", service.full_name)), env_file);
+         if !sensitive.is_empty() {
+             let mut secret_env_file = Vec::new();
+             for env in sensitive {
+                 writeln!(secret_env_file, "{}={}", env.name, env.value)?;
              }
+             files.add(secrets_dir.join(format!("{}.env", service.full_name)), secret_env_file);
          }
+    }
 
-         // `docker run --entrypoint` overrides only the executable, so the first
-         // entrypoint token goes there and any remaining entrypoint tokens are
-         // prepended to the container args after the image. The effective process
-         // is therefore entrypoint ++ command, matching docker-compose.
-         let mut trailing_args: Vec<String> = Vec::new();
-         if let Some(entrypoint) = &service.entrypoint {
-             let mut args = entrypoint.to_args();
-             if !args.is_empty() {
-                 write!(deploy_sh, " --entrypoint {}", args.remove(0))?;
-                 trailing_args.extend(args);
-             }
-         }
-         if let Some(command) = &service.command {
-             trailing_args.extend(command.to_args());
-         }
+    // 4. Ingress files
+    let shells = Shell::for_format(docker_spec.script_format);
+    let has_ingress = !resolved_spec.ingress.rules.is_empty();
+    if has_ingress {
+        match docker_spec.ingress_type {
+            DockerIngressType::Nginx => generate_nginx_standalone(resolved_spec, output_dir, &shells, files)?,
+            DockerIngressType::Traefik => generate_traefik_standalone(resolved_spec, output_dir, files)?,
+        }
+    }
 
-         write!(deploy_sh, " {}", service.image)?;
-         for arg in trailing_args {
-             write!(deploy_sh, " {}", arg)?;
-         }
-         writeln!(deploy_sh)?;
+    // 5. Scripts
+    let network_name = DOCKER_NETWORK.to_string();
+    let has_profiles = deployment.services.iter().any(|s| s.profile.is_some());
+
+    for &shell in &shells {
+        let mut deploy = Vec::new();
+        shell.write_header(&mut deploy, has_profiles)?;
+        writeln!(deploy, "{}", shell.ignore_failure(&format!("docker network create {}", network_name)))?;
+
+        for service in &deployment.services {
+            // Profiled services only start when their profile is enabled with
+            // `--with <profile>`, mirroring `docker compose --profile`.
+            if let Some(profile) = &service.profile {
+                writeln!(deploy, "{}", shell.begin_profile(profile))?;
+            }
+            writeln!(deploy, "{}", shell.echo(&format!("Starting {}...", service.full_name)))?;
+            writeln!(deploy, "{}", shell.ignore_failure(&format!("docker rm -f {}", service.full_name)))?;
+            write_service_run(&mut deploy, shell, service, &network_name)?;
+            if service.profile.is_some() {
+                writeln!(deploy, "{}", shell.end_block())?;
+            }
+        }
+
+        // Ingress Container
+        if has_ingress {
+            match docker_spec.ingress_type {
+                DockerIngressType::Nginx => write_nginx_container(&mut deploy, shell, resolved_spec, &network_name)?,
+                DockerIngressType::Traefik => write_traefik_container(&mut deploy, shell, resolved_spec, &network_name)?,
+            }
+        }
+
+        let mut undeploy = Vec::new();
+        shell.write_header(&mut undeploy, false)?;
+        for service in &deployment.services {
+            writeln!(undeploy, "{}", shell.echo(&format!("Removing {}...", service.full_name)))?;
+            writeln!(undeploy, "{}", shell.ignore_failure(&format!("docker rm -f {}", service.full_name)))?;
+        }
+        if has_ingress {
+            let ingress_container = match docker_spec.ingress_type {
+                DockerIngressType::Nginx => "nginx-ingress",
+                DockerIngressType::Traefik => "traefik-ingress",
+            };
+            writeln!(undeploy, "{}", shell.ignore_failure(&format!("docker rm -f {}", ingress_container)))?;
+        }
+
+        files.add_executable(shell.script_path(output_dir, "deploy"), deploy);
+        files.add_executable(shell.script_path(output_dir, "undeploy"), undeploy);
     }
     
-    // Ingress Container
-    if !resolved_spec.ingress.rules.is_empty() {
-        match docker_spec.ingress_type {
-            DockerIngressType::Nginx => generate_nginx_standalone(resolved_spec, output_dir, &mut deploy_sh, network_name)?,
+    Ok(())
+}
+
+fn write_service_run(
+    script: &mut Vec<u8>,
+    shell: Shell,
+    service: &ServiceResolvedSpec,
+    network_name: &str,
+) -> Result<()> {
+    write!(script, "docker run -d --name {} --network {}", service.full_name, network_name)?;
+
+    for port in service.ports.iter().filter(|p| p.external.is_some()) {
+        write!(script, " -p {}", port.mapping())?;
+    }
+
+    write!(script, " --env-file {}", shell.host_path(&format!("envs/{}.env", service.full_name)))?;
+    if service.environment_variables.iter().any(|e| e.sensitive) {
+        write!(script, " --env-file {}", shell.host_path(&format!("secrets/{}.env", service.full_name)))?;
+    }
+
+    if let Some(working_dir) = &service.container_working_dir {
+        write!(script, " --workdir {}", shell.quote(working_dir))?;
+    }
+    if let Some(user) = &service.user {
+        write!(script, " --user {}", shell.quote(user))?;
+    }
+
+    for secret in &service.secrets {
+        if let SecretMount::EnvVariable(var_name) = &secret.mount {
+            write!(script, " -e {}", shell.env_arg(var_name, &shell.read_file(&format!("./secrets/{}", secret.name))))?;
+        }
+    }
+
+    for config in &service.configs {
+        write!(script, " -v {}", shell.host_path(&format!("configs/{}:{}", config.config_name, config.mount_path)))?;
+    }
+
+    if let Some(memory) = service.explicit_limits.memory {
+        write!(script, " --memory {}", memory.bytes())?;
+    }
+    if let Some(cpu) = service.explicit_limits.cpu {
+        write!(script, " --cpus {}", cpu.cores())?;
+    }
+
+    for secret in &service.secrets {
+        if let SecretMount::FilePath(path) = &secret.mount {
+            write!(script, " -v {}", shell.host_path(&format!("secrets/{}:{}", secret.name, path)))?;
+        }
+    }
 
-            DockerIngressType::Traefik => {
-                generate_traefik_standalone(resolved_spec, output_dir, &mut deploy_sh, network_name)?;
+    // Healthcheck: map docker-compose `healthcheck` onto `docker run` flags.
+    if let Some(hc) = &service.healthcheck {
+        if hc.is_disabled() {
+            write!(script, " --no-healthcheck")?;
+        } else {
+            if let Some(cmd) = hc.health_cmd_string() {
+                write!(script, " --health-cmd {}", shell.quote(&cmd))?;
             }
+            if let Some(v) = &hc.interval { write!(script, " --health-interval {}", v)?; }
+            if let Some(v) = &hc.timeout { write!(script, " --health-timeout {}", v)?; }
+            if let Some(v) = hc.retries { write!(script, " --health-retries {}", v)?; }
+            if let Some(v) = &hc.start_period { write!(script, " --health-start-period {}", v)?; }
         }
+    }
 
+    // `docker run --entrypoint` overrides only the executable, so the first
+    // entrypoint token goes there and any remaining entrypoint tokens are
+    // prepended to the container args after the image. The effective process
+    // is therefore entrypoint ++ command, matching docker-compose.
+    let mut trailing_args: Vec<String> = Vec::new();
+    if let Some(entrypoint) = &service.entrypoint {
+        let mut args = entrypoint.to_args();
+        if !args.is_empty() {
+            write!(script, " --entrypoint {}", args.remove(0))?;
+            trailing_args.extend(args);
+        }
     }
-    
+    if let Some(command) = &service.command {
+        trailing_args.extend(command.to_args());
+    }
+
+    write!(script, " {}", service.image)?;
+    for arg in trailing_args {
+        write!(script, " {}", arg)?;
+    }
+    writeln!(script)?;
+    shell.write_exit_check(script)?;
     Ok(())
 }
 
@@ -179,78 +232,97 @@ fn generate_swarm(
     resolved_spec: &EnvironmentResolvedSpec,
     docker_spec: &DockerSpecificSpec,
     output_dir: &Path,
+    files: &mut GeneratedFiles,
 ) -> Result<()> {
     let deployment = &resolved_spec.current_deployment;
     let app_name = &deployment.application_name;
     
     // Application folder
     let app_dir = output_dir.join(&deployment.name);
-    fs::create_dir_all(&app_dir)?;
+    files.add_dir(&app_dir);
 
     for volume in &deployment.volumes {
-        let volume_sub_dir = app_dir.clone().join("volumes").join(&volume);
-        fs::create_dir_all(volume_sub_dir)?;
+        files.add_dir(app_dir.join("volumes").join(volume));
     }
 
 
     let network_name = DOCKER_NETWORK.to_string();
 
-    let mut services_map = HashMap::new();
+    let mut services_map = BTreeMap::new();
+    // `docker stack deploy` ignores compose profiles, so profiled services go
+    // to a stack file of their own that deploy.sh adds with `--with <profile>`
+    let mut profile_services: BTreeMap<String, BTreeMap<String, _>> = BTreeMap::new();
 
     for service in &deployment.services {
-        let mut docker_service = prepare_service(service, resolved_spec, &app_dir)?;
+        let mut docker_service = prepare_service(service, resolved_spec, &app_dir, files)?;
+        docker_service.profiles.clear();
 
-        let mut networks = HashMap::new();
+        let mut networks = BTreeMap::new();
         networks.insert("default".to_string(), ServiceNetwork {
             aliases: vec![service.full_name.clone()],
         });
 
         docker_service.networks = networks;
+        // set by deploy.sh, so that every deploy restarts the tasks while the
+        // stack file itself stays the same
+        docker_service.environment.insert("DEPLOY_DATE".to_string(), "${DEPLOY_DATE}".to_string());
 
-        services_map.insert(service.full_name.clone(), docker_service);
+        match &service.profile {
+            Some(profile) => profile_services.entry(profile.clone()).or_default().insert(service.full_name.clone(), docker_service),
+            None => services_map.insert(service.full_name.clone(), docker_service),
+        };
     }
 
-    let mut networks = HashMap::new();
+    let mut networks = BTreeMap::new();
 
     networks.insert("default".to_string(), DockerComposeNetwork {
         external: true,
         name: network_name.clone(),
     });
 
+    // Config files become swarm config objects so they reach every node. The
+    // files are written once per config; services reference them by key.
+    let mut configs = BTreeMap::new();
+    for config in &deployment.configs {
+        let cfg_dir = app_dir.join("configs").join(&config.name);
+        for file in &config.files {
+            files.add(cfg_dir.join(&file.name), file.content.clone());
+            configs.insert(
+                swarm_config_key(&config.name, &file.name, &file.content),
+                DockerComposeConfig { file: format!("./configs/{}/{}", config.name, file.name) },
+            );
+        }
+    }
+    let mut config_names: Vec<String> = configs.keys()
+        .map(|key| format!("{}_{}", deployment.name, key))
+        .collect();
+    config_names.sort();
+
     let compose = DockerCompose {
         services: services_map,
         networks,
+        configs,
     };
 
-    let compose_path = &app_dir.join("docker-compose.yaml");
     let yaml = serde_yaml::to_string(&compose)?;
-    fs::write(&compose_path, yaml)?;
+    files.add(app_dir.join("docker-compose.yaml"), yaml);
+    let profiles: Vec<String> = profile_services.keys().cloned().collect();
+    for (profile, services) in profile_services {
+        let compose = DockerCompose { services, networks: BTreeMap::new(), configs: BTreeMap::new() };
+        files.add(app_dir.join(format!("docker-compose.{}.yaml", profile)), serde_yaml::to_string(&compose)?);
+    }
 
 
 
     // 4. Ingress Stack
     let ingress_dir = output_dir.join("ingress");
-    fs::create_dir_all(&ingress_dir)?;
+    files.add_dir(&ingress_dir);
     
     match docker_spec.ingress_type {
-        DockerIngressType::Nginx => generate_nginx_swarm(resolved_spec, &ingress_dir, network_name.clone())?,
-        DockerIngressType::Traefik => generate_traefik_swarm(resolved_spec, &ingress_dir, network_name.clone())?,
-    }
-
-    // 5. Deploy Script
-    let mut deploy_sh = File::create(output_dir.join("deploy.sh"))?;
-    
-    #[cfg(unix)]
-    {
-        let mut perms = deploy_sh.metadata()?.permissions();
-        perms.set_mode(0o755);
-        deploy_sh.set_permissions(perms)?;
+        DockerIngressType::Nginx => generate_nginx_swarm(resolved_spec, &ingress_dir, network_name.clone(), files)?,
+        DockerIngressType::Traefik => generate_traefik_swarm(resolved_spec, &ingress_dir, network_name.clone(), files)?,
     }
 
-    writeln!(deploy_sh, "#!/bin/bash")?;
-    writeln!(deploy_sh, "set -e")?;
-    writeln!(deploy_sh, "docker network create --driver overlay --attachable {} || true", network_name)?;
-
     // Bind-mounted volume directories are not created automatically on the node
     // during the first deployment, which makes `docker stack deploy` fail. Collect
     // every host directory the stack binds and ensure it exists (mkdir -p is a
@@ -277,94 +349,170 @@ fn generate_swarm(
     volume_dirs.sort();
     volume_dirs.dedup();
 
-    if !volume_dirs.is_empty() {
-        writeln!(deploy_sh, "echo 'Ensuring volume directories exist...'")?;
-        for dir in &volume_dirs {
-            writeln!(deploy_sh, "mkdir -p \"{}\"", dir)?;
+    // 5. Deploy Scripts
+    for shell in Shell::for_format(docker_spec.script_format) {
+        let mut deploy = Vec::new();
+        shell.write_header(&mut deploy, !profiles.is_empty())?;
+        writeln!(deploy, "{}", shell.ignore_failure(&format!("docker network create --driver overlay --attachable {}", network_name)))?;
+
+        if !volume_dirs.is_empty() {
+            writeln!(deploy, "{}", shell.echo("Ensuring volume directories exist..."))?;
+            for dir in &volume_dirs {
+                writeln!(deploy, "{}", shell.mkdir(dir))?;
+            }
+        }
+
+        writeln!(deploy, "{}", shell.export("DEPLOY_DATE", shell.unix_time()))?;
+        writeln!(deploy, "docker stack deploy -c ingress/docker-compose.yaml ingress --detach=false")?;
+        shell.write_exit_check(&mut deploy)?;
+        if profiles.is_empty() {
+            writeln!(deploy, "docker stack deploy -c {}/docker-compose.yaml {} --with-registry-auth", deployment.name, deployment.name)?;
+        } else {
+            // --prune removes the services of profiles left out this time
+            writeln!(deploy, "{}", shell.set_stack_files(&format!("{}/docker-compose.yaml", deployment.name)))?;
+            for profile in &profiles {
+                writeln!(deploy, "{}", shell.begin_profile(profile))?;
+                writeln!(deploy, "{}", shell.add_stack_file(&format!("{}/docker-compose.{}.yaml", deployment.name, profile)))?;
+                writeln!(deploy, "{}", shell.end_block())?;
+            }
+            writeln!(deploy, "docker stack deploy {} {} --with-registry-auth --prune", shell.stack_files(), deployment.name)?;
+        }
+        shell.write_exit_check(&mut deploy)?;
+
+        // After a successful deploy, reclaim disk space by removing images that are no
+        // longer used by any container/service (e.g. the previous versions replaced by
+        // this rollout). The exit checks above guarantee this only runs when the
+        // deploy succeeded.
+        //
+        // `docker stack deploy` returns before the rollout has converged, so the new
+        // tasks may still be pulling/starting their images. Wait 3 minutes to give the
+        // rollout time to settle before pruning, otherwise we could remove an image a
+        // task still depends on.
+        writeln!(deploy, "{}", shell.echo("Waiting for rollout to settle before pruning..."))?;
+        writeln!(deploy, "{}", shell.sleep(180))?;
+        writeln!(deploy, "{}", shell.echo("Pruning unused images..."))?;
+        writeln!(deploy, "docker image prune -af")?;
+
+        // Config objects are immutable and versioned by content hash, so every
+        // changed file leaves the previous version behind. Remove the stack's
+        // configs that the current deployment no longer references; the stack
+        // label keeps other stacks sharing the name prefix out of it.
+        writeln!(deploy, "{}", shell.echo("Pruning old configs..."))?;
+        match shell {
+            Shell::Bash => {
+                writeln!(deploy, "CURRENT_CONFIGS=\" {} \"", config_names.join(" "))?;
+                writeln!(deploy, "for cfg in $(docker config ls --filter label=com.docker.stack.namespace={} --format '{{{{.Name}}}}'); do", deployment.name)?;
+                writeln!(deploy, "  case \"$CURRENT_CONFIGS\" in")?;
+                writeln!(deploy, "    *\" $cfg \"*) ;;")?;
+                writeln!(deploy, "    *) docker config rm \"$cfg\" || true ;;")?;
+                writeln!(deploy, "  esac")?;
+                writeln!(deploy, "done")?;
+            }
+            Shell::PowerShell => {
+                let quoted: Vec<String> = config_names.iter().map(|n| shell.quote(n)).collect();
+                writeln!(deploy, "$currentConfigs = @({})", quoted.join(", "))?;
+                writeln!(deploy, "foreach ($cfg in (docker config ls --filter label=com.docker.stack.namespace={} --format '{{{{.Name}}}}')) {{", deployment.name)?;
+                writeln!(deploy, "    if ($currentConfigs -notcontains $cfg) {{ docker config rm $cfg 2>$null }}")?;
+                writeln!(deploy, "}}")?;
+            }
         }
-    }
 
-    writeln!(deploy_sh, "docker stack deploy -c ingress/docker-compose.yaml ingress --detach=false")?;
-    writeln!(deploy_sh, "docker stack deploy -c {}/docker-compose.yaml {} --with-registry-auth", deployment.name, deployment.name)?;
+        let mut undeploy = Vec::new();
+        shell.write_header(&mut undeploy, false)?;
+        writeln!(undeploy, "{}", shell.echo(&format!("Removing stack {}...", deployment.name)))?;
+        writeln!(undeploy, "docker stack rm {}", deployment.name)?;
+        shell.write_exit_check(&mut undeploy)?;
+        writeln!(undeploy, "{}", shell.ignore_failure("docker stack rm ingress"))?;
 
-    // After a successful deploy, reclaim disk space by removing images that are no
-    // longer used by any container/service (e.g. the previous versions replaced by
-    // this rollout). `set -e` above guarantees this only runs when the deploy
-    // succeeded.
-    //
-    // `docker stack deploy` returns before the rollout has converged, so the new
-    // tasks may still be pulling/starting their images. Wait 3 minutes to give the
-    // rollout time to settle before pruning, otherwise we could remove an image a
-    // task still depends on.
-    writeln!(deploy_sh, "echo 'Waiting for rollout to settle before pruning...'")?;
-    writeln!(deploy_sh, "sleep 180")?;
-    writeln!(deploy_sh, "echo 'Pruning unused images...'")?;
-    writeln!(deploy_sh, "docker image prune -af")?;
+        files.add_executable(shell.script_path(output_dir, "deploy"), deploy);
+        files.add_executable(shell.script_path(output_dir, "undeploy"), undeploy);
+    }
 
     Ok(())
 }
 
-fn generate_nginx_standalone(resolved_spec: &EnvironmentResolvedSpec, output_dir: &Path, deploy_sh: &mut File, network_name: String) -> Result<()> {
-    if !resolved_spec.ingress.rules.is_empty() {
-        let nginx_dir = output_dir.join("nginx");
-        fs::create_dir_all(&nginx_dir)?;
-        generate_nginx_config(&resolved_spec.ingress, &nginx_dir.join("default.conf"))?;
+fn generate_nginx_standalone(resolved_spec: &EnvironmentResolvedSpec, output_dir: &Path, shells: &[Shell], files: &mut GeneratedFiles) -> Result<()> {
+    files.add(output_dir.join("nginx").join("default.conf"), generate_nginx_config(&resolved_spec.ingress)?);
+    for (name, page) in nginx_pages(&resolved_spec.ingress) {
+        files.add(output_dir.join("nginx").join("pages").join(name), page.as_bytes().to_vec());
     }
 
-    writeln!(deploy_sh, "echo 'Starting Nginx ingress...'")?;
-    writeln!(deploy_sh, "docker rm -f nginx-ingress || true")?;
-    write!(deploy_sh, "docker run -d --name nginx-ingress --network {}", network_name)?;
-    write!(deploy_sh, " -p 80:80")?;
+    if resolved_spec.ingress.tls.is_some() {
+        files.add_dir(output_dir.join("certs"));
+    }
+
+    if let Some(tls) = &resolved_spec.ingress.tls {
+        if let Some(le) = &tls.letsencrypt {
+            files.add_dir(output_dir.join("letsencrypt"));
+            for &shell in shells {
+                let (continuation, restart) = match shell {
+                    Shell::Bash => (" \\", "   && docker restart nginx-ingress"),
+                    Shell::PowerShell => (" `", "if ($LASTEXITCODE -eq 0) { docker restart nginx-ingress }"),
+                };
+                let mut certbot = Vec::new();
+                writeln!(certbot, "docker run -it --rm --name certbot{}", continuation)?;
+                writeln!(certbot, "  -v {}{}", shell.host_path("letsencrypt:/var/www/letsencrypt"), continuation)?;
+                writeln!(certbot, "  -v {}{}", shell.host_path("certs:/etc/nginx/certs"), continuation)?;
+                writeln!(certbot, "  certbot/certbot certonly --webroot --webroot-path=/var/www/letsencrypt{}", continuation)?;
+                write!(certbot, "  --email {} --agree-tos --no-eff-email", le.email)?;
+                for domain in resolved_spec.ingress.tls_domains() {
+                    write!(certbot, "{}\n   -d {}", continuation, domain)?;
+                }
+                match shell {
+                    Shell::Bash => writeln!(certbot, "{}\n{}", continuation, restart)?,
+                    Shell::PowerShell => writeln!(certbot, "\n{}", restart)?,
+                }
+                files.add_executable(shell.script_path(output_dir, "certbot"), certbot);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_nginx_container(deploy: &mut Vec<u8>, shell: Shell, resolved_spec: &EnvironmentResolvedSpec, network_name: &str) -> Result<()> {
+    writeln!(deploy, "{}", shell.echo("Starting Nginx ingress..."))?;
+    writeln!(deploy, "{}", shell.ignore_failure("docker rm -f nginx-ingress"))?;
+    write!(deploy, "docker run -d --name nginx-ingress --network {}", network_name)?;
+    write!(deploy, " -p 80:80")?;
 
     let has_tls = resolved_spec.ingress.tls.is_some();
     if has_tls {
-        write!(deploy_sh, " -p 443:443")?;
+        write!(deploy, " -p 443:443")?;
     }
 
-    write!(deploy_sh, " -v $(pwd)/nginx/default.conf:/etc/nginx/conf.d/default.conf")?;
+    write!(deploy, " -v {}", shell.host_path("nginx/default.conf:/etc/nginx/conf.d/default.conf"))?;
+    if !nginx_pages(&resolved_spec.ingress).is_empty() {
+        write!(deploy, " -v {}", shell.host_path(&format!("nginx/pages:{}", NGINX_PAGES_DIR)))?;
+    }
 
     if has_tls {
-        fs::create_dir_all(output_dir.join("certs"))?;
-        write!(deploy_sh, " -v $(pwd)/certs:/etc/nginx/certs")?;
+        write!(deploy, " -v {}", shell.host_path("certs:/etc/nginx/certs"))?;
     }
 
     if let Some(tls) = &resolved_spec.ingress.tls {
         if tls.letsencrypt.is_some() {
-            write!(deploy_sh, " -v $(pwd)/letsencrypt:/var/www/letsencrypt")?;
-            fs::create_dir_all(output_dir.join("letsencrypt"))?;
+            write!(deploy, " -v {}", shell.host_path("letsencrypt:/var/www/letsencrypt"))?;
         }
     }
 
-    write!(deploy_sh, " -e DEPLOY_DATE=$(date +%s)")?;
-    writeln!(deploy_sh, " {}", NGINX_IMAGE)?;
-
-    if let Some(tls) = &resolved_spec.ingress.tls {
-        if let Some(le) = &tls.letsencrypt {
-            let mut certbot_sh = File::create(output_dir.join("certbot.sh"))?;
-            writeln!(certbot_sh, "docker run -it --rm --name certbot \\")?;
-            writeln!(certbot_sh, "  -v $(pwd)/letsencrypt:/var/www/letsencrypt \\")?;
-            writeln!(certbot_sh, "  -v $(pwd)/certs:/etc/nginx/certs \\")?;
-            writeln!(certbot_sh, "  certbot/certbot certonly --webroot --webroot-path=/var/www/letsencrypt \\")?;
-            writeln!(certbot_sh, "  --email {} --agree-tos --no-eff-email \\", le.email)?;
-            for domain in &resolved_spec.ingress.domains {
-                writeln!(certbot_sh, "   -d {} \\", domain)?;
-            }
-            writeln!(certbot_sh, "   && docker restart nginx-ingress")?;
-        }
-    }
+    write!(deploy, " -e {}", shell.env_arg("DEPLOY_DATE", shell.unix_time()))?;
+    writeln!(deploy, " {}", NGINX_IMAGE)?;
+    shell.write_exit_check(deploy)?;
     Ok(())
 }
 
-fn generate_nginx_swarm(resolved_spec: &EnvironmentResolvedSpec, ingress_dir: &Path, network_name: String) -> Result<()> {
-    if resolved_spec.ingress.rules.is_empty() {
+fn generate_nginx_swarm(resolved_spec: &EnvironmentResolvedSpec, ingress_dir: &Path, network_name: String, files: &mut GeneratedFiles) -> Result<()> {
+    if resolved_spec.ingress.rules.is_empty() && resolved_spec.ingress.redirects.is_empty() {
         return Ok(());
     }
 
-    let nginx_conf_dir = ingress_dir.join("nginx");
-    fs::create_dir_all(&nginx_conf_dir)?;
-    generate_nginx_config(&resolved_spec.ingress, &nginx_conf_dir.join("default.conf"))?;
+    files.add(ingress_dir.join("nginx").join("default.conf"), generate_nginx_config(&resolved_spec.ingress)?);
+    let pages = nginx_pages(&resolved_spec.ingress);
+    for (name, page) in &pages {
+        files.add(ingress_dir.join("nginx").join("pages").join(name), page.as_bytes().to_vec());
+    }
     
-    let mut stack = File::create(ingress_dir.join("docker-compose.yaml"))?;
+    let mut stack = Vec::new();
     writeln!(stack, "version: '3.8'")?;
     writeln!(stack, "services:")?;
     writeln!(stack, "  nginx:")?;
@@ -376,6 +524,9 @@ fn generate_nginx_swarm(resolved_spec: &EnvironmentResolvedSpec, ingress_dir: &P
     }
     writeln!(stack, "    volumes:")?;
     writeln!(stack, "      - ./nginx/default.conf:/etc/nginx/conf.d/default.conf")?;
+    if !pages.is_empty() {
+        writeln!(stack, "      - ./nginx/pages:{}", NGINX_PAGES_DIR)?;
+    }
     
     if resolved_spec.ingress.tls.is_some() {
          // We assume certs are placed in output_dir/certs -> so from ingress/docker-compose.yaml, it is ../certs
@@ -391,22 +542,26 @@ fn generate_nginx_swarm(resolved_spec: &EnvironmentResolvedSpec, ingress_dir: &P
     }
     
     write_swarm_compose_network(&mut stack, &network_name)?;
+    files.add(ingress_dir.join("docker-compose.yaml"), stack);
 
     Ok(())
 }
 
-fn generate_nginx_config(ingress: &IngressResolvedSpec, path: &Path) -> Result<()> {
-    let mut file = File::create(path)?;
-    
-    let has_tls = ingress.tls.is_some();
+fn generate_nginx_config(ingress: &IngressResolvedSpec) -> Result<Vec<u8>> {
+    let mut file = Vec::new();
     
-    for rule in &ingress.rules {
+    write_nginx_cors_maps(&mut file, ingress)?;
+
+    for (i, rule) in ingress.rules.iter().enumerate() {
+        // hosts that opted out of TLS get the plain http block alone
+        let has_tls = ingress.domain_tls(&rule.domain_name);
+
         writeln!(file, "server {{")?;
         writeln!(file, "    listen 80;")?;
         writeln!(file, "    server_name {};", rule.domain_name)?;
         
         if let Some(tls) = &ingress.tls {
-            if tls.letsencrypt.is_some() {
+            if has_tls && tls.letsencrypt.is_some() {
                 writeln!(file, "    location /.well-known/acme-challenge/ {{")?;
                 writeln!(file, "        root /var/www/letsencrypt;")?;
                 writeln!(file, "    }}")?;
@@ -425,21 +580,53 @@ fn generate_nginx_config(ingress: &IngressResolvedSpec, path: &Path) -> Result<(
             writeln!(file, "    ssl_certificate /etc/nginx/certs/live/{}/fullchain.pem;", rule.domain_name)?;
             writeln!(file, "    ssl_certificate_key /etc/nginx/certs/live/{}/privkey.pem;", rule.domain_name)?;
             
-            generate_locations(&mut file, rule)?;
+            generate_locations(&mut file, i, rule)?;
             
             writeln!(file, "}}")?;
             
         } else {
-            generate_locations(&mut file, rule)?;
+            generate_locations(&mut file, i, rule)?;
+            writeln!(file, "}}")?;
+        }
+    }
+
+    for redirect in &ingress.redirects {
+        let has_tls = ingress.domain_tls(&redirect.from);
+        let location = if redirect.preserve_path {
+            format!("{}$request_uri", redirect.target)
+        } else {
+            format!("{}/", redirect.target)
+        };
+
+        writeln!(file, "server {{")?;
+        writeln!(file, "    listen 80;")?;
+        writeln!(file, "    server_name {};", redirect.from)?;
+        if has_tls && ingress.tls.as_ref().is_some_and(|tls| tls.letsencrypt.is_some()) {
+            writeln!(file, "    location /.well-known/acme-challenge/ {{")?;
+            writeln!(file, "        root /var/www/letsencrypt;")?;
+            writeln!(file, "    }}")?;
+        }
+        writeln!(file, "    location / {{")?;
+        writeln!(file, "        return 301 {};", location)?;
+        writeln!(file, "    }}")?;
+        writeln!(file, "}}")?;
+
+        if has_tls {
+            writeln!(file, "server {{")?;
+            writeln!(file, "    listen 443 ssl;")?;
+            writeln!(file, "    server_name {};", redirect.from)?;
+            writeln!(file, "    ssl_certificate /etc/nginx/certs/live/{}/fullchain.pem;", redirect.from)?;
+            writeln!(file, "    ssl_certificate_key /etc/nginx/certs/live/{}/privkey.pem;", redirect.from)?;
+            writeln!(file, "    return 301 {};", location)?;
             writeln!(file, "}}")?;
         }
     }
     
-    Ok(())
+    Ok(file)
 }
 
-fn generate_locations(file: &mut File, rule: &crate::resolved_spec::IngressRule) -> Result<()> {
-    for svc in &rule.services {
+fn generate_locations(file: &mut Vec<u8>, rule_index: usize, rule: &IngressRule) -> Result<()> {
+    for (j, svc) in rule.routed_services().enumerate() {
         let prefix = &svc.prefix;
         let location_path = if prefix.ends_with('/') {
             prefix.clone()
@@ -448,11 +635,14 @@ fn generate_locations(file: &mut File, rule: &crate::resolved_spec::IngressRule)
         };
         
         writeln!(file, "    location {} {{", location_path)?;
+        if let Some(cors) = &svc.cors {
+            write_nginx_cors(file, cors, &nginx_cors_origin_var(rule_index, j))?;
+        }
         
         if svc.strip_prefix {
-            writeln!(file, "        proxy_pass http://{}:{}/;", svc.service_name, svc.port)?;
+            writeln!(file, "        proxy_pass http://{}:{}/;", svc.service_name, svc.container_port)?;
         } else {
-            writeln!(file, "        proxy_pass http://{}:{};", svc.service_name, svc.port)?;
+            writeln!(file, "        proxy_pass http://{}:{};", svc.service_name, svc.container_port)?;
         }
         
         writeln!(file, "        proxy_set_header Host $host;")?;
@@ -461,48 +651,142 @@ fn generate_locations(file: &mut File, rule: &crate::resolved_spec::IngressRule)
         writeln!(file, "        proxy_set_header X-Forwarded-Proto $scheme;")?;
         writeln!(file, "    }}")?;
     }
+
+    if let Some(DefaultBackendResolvedSpec::Page(_)) = rule.fallback() {
+        writeln!(file, "    error_page 404 503 /__simpled/page.html;")?;
+        writeln!(file, "    location = /__simpled/page.html {{")?;
+        writeln!(file, "        internal;")?;
+        writeln!(file, "        default_type text/html;")?;
+        writeln!(file, "        alias {}/{};", NGINX_PAGES_DIR, nginx_page_file(&rule.domain_name))?;
+        writeln!(file, "    }}")?;
+        writeln!(file, "    location / {{")?;
+        writeln!(file, "        return {};", if rule.maintenance { 503 } else { 404 })?;
+        writeln!(file, "    }}")?;
+    }
+    Ok(())
+}
+
+// Pages of the domains whose default backend is a page, by file name under
+// `nginx/pages`, which is mounted at NGINX_PAGES_DIR
+fn nginx_pages(ingress: &IngressResolvedSpec) -> Vec<(String, &String)> {
+    ingress.rules.iter()
+        .filter_map(|rule| match rule.fallback() {
+            Some(DefaultBackendResolvedSpec::Page(page)) => Some((nginx_page_file(&rule.domain_name), page)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn nginx_page_file(domain: &str) -> String {
+    format!("{}.html", domain.replace(':', "_"))
+}
+
+// Variable holding the allowed origin of a location's CORS, set by a `map`
+fn nginx_cors_origin_var(rule_index: usize, service_index: usize) -> String {
+    format!("$cors_origin_{}_{}", rule_index, service_index)
+}
+
+/// `map` blocks echoing a request's origin back when a location allows it;
+/// they must sit outside the server blocks.
+fn write_nginx_cors_maps(file: &mut Vec<u8>, ingress: &IngressResolvedSpec) -> Result<()> {
+    for (i, rule) in ingress.rules.iter().enumerate() {
+        for (j, svc) in rule.routed_services().enumerate() {
+            let Some(cors) = svc.cors.as_ref().filter(|c| !c.any_origin()) else {
+                continue;
+            };
+            let origins: Vec<String> = cors.origins.iter().map(|o| o.replace('.', "\\.")).collect();
+            writeln!(file, "map $http_origin {} {{", nginx_cors_origin_var(i, j))?;
+            writeln!(file, "    default \"\";")?;
+            writeln!(file, "    \"~^({})$\" $http_origin;", origins.join("|"))?;
+            writeln!(file, "}}")?;
+        }
+    }
     Ok(())
 }
 
-fn generate_traefik_standalone(resolved_spec: &EnvironmentResolvedSpec, output_dir: &Path, deploy_sh: &mut File, network_name: String) -> Result<()> {
+/// CORS headers for a location: preflight requests are answered by nginx
+/// itself, other responses get the origin headers added. An empty origin
+/// variable leaves the header out.
+fn write_nginx_cors(file: &mut Vec<u8>, cors: &CorsSpec, origin_var: &str) -> Result<()> {
+    let origin = if cors.any_origin() { "*" } else { origin_var };
+    let origin_headers = |file: &mut Vec<u8>, indent: &str| -> Result<()> {
+        writeln!(file, "{}add_header Access-Control-Allow-Origin {} always;", indent, origin)?;
+        if cors.credentials {
+            writeln!(file, "{}add_header Access-Control-Allow-Credentials true always;", indent)?;
+        }
+        if !cors.any_origin() {
+            writeln!(file, "{}add_header Vary Origin always;", indent)?;
+        }
+        Ok(())
+    };
+    writeln!(file, "        if ($request_method = OPTIONS) {{")?;
+    origin_headers(file, "            ")?;
+    writeln!(file, "            add_header Access-Control-Allow-Methods \"{}\" always;", cors.methods.join(", "))?;
+    writeln!(file, "            add_header Access-Control-Allow-Headers \"{}\" always;", cors.headers.join(", "))?;
+    writeln!(file, "            add_header Access-Control-Max-Age {} always;", cors.max_age)?;
+    writeln!(file, "            return 204;")?;
+    writeln!(file, "        }}")?;
+    origin_headers(file, "        ")?;
+    Ok(())
+}
+
+fn generate_traefik_standalone(resolved_spec: &EnvironmentResolvedSpec, output_dir: &Path, files: &mut GeneratedFiles) -> Result<()> {
     let traefik_dir = output_dir.join("traefik");
-    fs::create_dir_all(&traefik_dir)?;
 
     let has_tls = resolved_spec.ingress.tls.is_some();
     let letsencrypt = resolved_spec.ingress.tls.as_ref().and_then(|t| t.letsencrypt.as_ref());
 
-    let mut static_conf = File::create(traefik_dir.join("traefik.yml"))?;
-    write_traefik_static_config(&mut static_conf, has_tls, letsencrypt)?;
+    let mut static_conf = Vec::new();
+    write_traefik_static_config(&mut static_conf, has_tls, resolved_spec.ingress.plain_http_domains.is_empty(), letsencrypt)?;
+    files.add(traefik_dir.join("traefik.yml"), static_conf);
+
+    files.add(traefik_dir.join("dynamic_conf.yml"), generate_traefik_dynamic_config(&resolved_spec.ingress)?);
+
+    if letsencrypt.is_some() {
+        files.add_dir(output_dir.join("letsencrypt"));
+    }
+
+    Ok(())
+}
 
-    generate_traefik_dynamic_config(&resolved_spec.ingress, &traefik_dir.join("dynamic_conf.yml"))?;
+fn write_traefik_container(deploy: &mut Vec<u8>, shell: Shell, resolved_spec: &EnvironmentResolvedSpec, network_name: &str) -> Result<()> {
+    let has_tls = resolved_spec.ingress.tls.is_some();
+    let letsencrypt = resolved_spec.ingress.tls.as_ref().and_then(|t| t.letsencrypt.as_ref());
 
-    writeln!(deploy_sh, "echo 'Starting Traefik ingress...'")?;
-    writeln!(deploy_sh, "docker rm -f traefik-ingress || true")?;
+    writeln!(deploy, "{}", shell.echo("Starting Traefik ingress..."))?;
+    writeln!(deploy, "{}", shell.ignore_failure("docker rm -f traefik-ingress"))?;
     
-    write!(deploy_sh, "docker run -d --name traefik-ingress --network {}", network_name)?;
-    write!(deploy_sh, " -p 80:80")?;
+    write!(deploy, "docker run -d --name traefik-ingress --network {}", network_name)?;
+    write!(deploy, " -p 80:80")?;
     if has_tls {
-        write!(deploy_sh, " -p 443:443")?;
+        write!(deploy, " -p 443:443")?;
     }
 
-    write!(deploy_sh, " -v $(pwd)/traefik/traefik.yml:/etc/traefik/traefik.yml")?;
-    write!(deploy_sh, " -v $(pwd)/traefik/dynamic_conf.yml:/etc/traefik/dynamic_conf.yml")?;
+    write!(deploy, " -v {}", shell.host_path("traefik/traefik.yml:/etc/traefik/traefik.yml"))?;
+    write!(deploy, " -v {}", shell.host_path("traefik/dynamic_conf.yml:/etc/traefik/dynamic_conf.yml"))?;
 
     if letsencrypt.is_some() {
-        let le_dir = output_dir.join("letsencrypt");
-        fs::create_dir_all(&le_dir)?;
-        write!(deploy_sh, " -v $(pwd)/letsencrypt:/letsencrypt")?;
+        write!(deploy, " -v {}", shell.host_path("letsencrypt:/letsencrypt"))?;
+    }
+    // the DNS provider's token for the dnsChallenge, next to the scripts
+    // unless absolute, wherever they are run from
+    if let Some(dns01) = letsencrypt.and_then(|le| le.dns01.as_ref()) {
+        if Path::new(&dns01.credentials).is_absolute() {
+            write!(deploy, " --env-file {}", dns01.credentials)?;
+        } else {
+            write!(deploy, " --env-file {}", shell.script_relative(&dns01.credentials))?;
+        }
     }
 
-    write!(deploy_sh, " -e DEPLOY_DATE=$(date +%s)")?;
-    writeln!(deploy_sh, " {}", TRAEFIK_IMAGE)?;
+    write!(deploy, " -e {}", shell.env_arg("DEPLOY_DATE", shell.unix_time()))?;
+    writeln!(deploy, " {}", TRAEFIK_IMAGE)?;
+    shell.write_exit_check(deploy)?;
 
     Ok(())
 }
 
-fn generate_traefik_swarm(resolved_spec: &EnvironmentResolvedSpec, ingress_dir: &Path, network_name: String) -> Result<()> {
+fn generate_traefik_swarm(resolved_spec: &EnvironmentResolvedSpec, ingress_dir: &Path, network_name: String, files: &mut GeneratedFiles) -> Result<()> {
     let traefik_dir = ingress_dir.join("traefik");
-    fs::create_dir_all(&traefik_dir)?;
 
     // Reuse generate logic for config, but write to new dir
     let has_tls = resolved_spec.ingress.tls.is_some();
@@ -512,12 +796,13 @@ fn generate_traefik_swarm(resolved_spec: &EnvironmentResolvedSpec, ingress_dir:
         return Err(anyhow!("Currently swarm ingress only supports Let's Encrypt, specify a letsencrypt block in ingress.tls"));
     }
 
-    let mut static_conf = File::create(traefik_dir.join("traefik.yml"))?;
-    write_traefik_static_config(&mut static_conf, has_tls, letsencrypt)?;
+    let mut static_conf = Vec::new();
+    write_traefik_static_config(&mut static_conf, has_tls, resolved_spec.ingress.plain_http_domains.is_empty(), letsencrypt)?;
+    files.add(traefik_dir.join("traefik.yml"), static_conf);
 
-    generate_traefik_dynamic_config(&resolved_spec.ingress, &traefik_dir.join("dynamic_conf.yml"))?;
+    files.add(traefik_dir.join("dynamic_conf.yml"), generate_traefik_dynamic_config(&resolved_spec.ingress)?);
 
-    let mut stack = File::create(ingress_dir.join("docker-compose.yaml"))?;
+    let mut stack = Vec::new();
     writeln!(stack, "version: '3.8'")?;
     writeln!(stack, "services:")?;
     writeln!(stack, "  traefik:")?;
@@ -535,24 +820,40 @@ fn generate_traefik_swarm(resolved_spec: &EnvironmentResolvedSpec, ingress_dir:
     if letsencrypt.is_some() {
         writeln!(stack, "      - ../letsencrypt:/letsencrypt")?;
         // Make sure dir exists
-        fs::create_dir_all(ingress_dir.parent().unwrap().join("letsencrypt"))?;
+        files.add_dir(ingress_dir.parent().unwrap().join("letsencrypt"));
+    }
+
+    // the compose file sits one level below the deploy scripts
+    if let Some(dns01) = letsencrypt.and_then(|le| le.dns01.as_ref()) {
+        writeln!(stack, "    env_file:")?;
+        if Path::new(&dns01.credentials).is_absolute() {
+            writeln!(stack, "      - {}", dns01.credentials)?;
+        } else {
+            writeln!(stack, "      - ../{}", dns01.credentials)?;
+        }
     }
 
     write_swarm_compose_network(&mut stack, &network_name)?;
+    files.add(ingress_dir.join("docker-compose.yaml"), stack);
 
     Ok(())
 }
 
-fn write_traefik_static_config(file: &mut File, has_tls: bool, letsencrypt: Option<&LetsEncryptResolvedSpec>) -> Result<()> {
+/// With `redirect_all` the web entrypoint sends every request to https;
+/// otherwise the dynamic config redirects the TLS routers one by one, so
+/// hosts that opted out of TLS stay on plain http.
+fn write_traefik_static_config(file: &mut Vec<u8>, has_tls: bool, redirect_all: bool, letsencrypt: Option<&LetsEncryptResolvedSpec>) -> Result<()> {
     writeln!(file, "entryPoints:")?;
     writeln!(file, "  web:")?;
     writeln!(file, "    address: \":80\"")?;
     if has_tls {
-        writeln!(file, "    http:")?;
-        writeln!(file, "      redirections:")?;
-        writeln!(file, "        entryPoint:")?;
-        writeln!(file, "          to: websecure")?;
-        writeln!(file, "          scheme: https")?;
+        if redirect_all {
+            writeln!(file, "    http:")?;
+            writeln!(file, "      redirections:")?;
+            writeln!(file, "        entryPoint:")?;
+            writeln!(file, "          to: websecure")?;
+            writeln!(file, "          scheme: https")?;
+        }
         writeln!(file, "  websecure:")?;
         writeln!(file, "    address: \":443\"")?;
     }
@@ -566,19 +867,23 @@ fn write_traefik_static_config(file: &mut File, has_tls: bool, letsencrypt: Opti
         writeln!(file, "    acme:")?;
         writeln!(file, "      email: \"{}\"", le.email)?;
         writeln!(file, "      storage: \"/letsencrypt/acme.json\"")?;
-        writeln!(file, "      httpChallenge:")?;
-        writeln!(file, "        entryPoint: web")?;
+        match &le.dns01 {
+            Some(dns01) => {
+                writeln!(file, "      dnsChallenge:")?;
+                writeln!(file, "        provider: {}", dns01.provider.traefik().0)?;
+            }
+            None => {
+                writeln!(file, "      httpChallenge:")?;
+                writeln!(file, "        entryPoint: web")?;
+            }
+        }
     }
     Ok(())
 }
 
-fn write_swarm_compose_network(stack: &mut File, network_name: &str) -> Result<()> {
-    let deploy_date = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
+fn write_swarm_compose_network(stack: &mut Vec<u8>, network_name: &str) -> Result<()> {
     writeln!(stack, "    environment:")?;
-    writeln!(stack, "      - DEPLOY_DATE={}", deploy_date)?;
+    writeln!(stack, "      - DEPLOY_DATE=${{DEPLOY_DATE}}")?;
     writeln!(stack, "    networks:")?;
     writeln!(stack, "      default:")?;
     writeln!(stack, "networks:")?;
@@ -588,17 +893,36 @@ fn write_swarm_compose_network(stack: &mut File, network_name: &str) -> Result<(
     Ok(())
 }
 
-fn generate_traefik_dynamic_config(ingress: &IngressResolvedSpec, path: &Path) -> Result<()> {
-    let mut file = File::create(path)?;
-    let has_tls = ingress.tls.is_some();
+// Router, middleware and service name part for a domain; a wildcard's `*`
+// would start a YAML alias
+fn traefik_name(domain: &str) -> String {
+    domain.replace('.', "-").replace('*', "wildcard")
+}
+
+fn generate_traefik_dynamic_config(ingress: &IngressResolvedSpec) -> Result<Vec<u8>> {
+    let mut file = Vec::new();
     let use_le = ingress.tls.as_ref().map(|t| t.letsencrypt.is_some()).unwrap_or(false);
+    // with hosts on plain http the web entrypoint can't redirect everything,
+    // so each TLS router gets a companion router on web that redirects
+    let redirect_per_router = ingress.tls.is_some() && !ingress.plain_http_domains.is_empty();
 
     writeln!(file, "http:")?;
     
     let mut middlewares_written = false;
+    if redirect_per_router {
+        writeln!(file, "  middlewares:")?;
+        middlewares_written = true;
+        writeln!(file, "    redirect-to-https:")?;
+        writeln!(file, "      redirectScheme:")?;
+        writeln!(file, "        scheme: https")?;
+        writeln!(file, "        permanent: true")?;
+    }
      for rule in ingress.rules.iter() {
-         let router_name_base = rule.domain_name.replace(".", "-");
-         for (j, svc) in rule.services.iter().enumerate() {
+         if let Some(DefaultBackendResolvedSpec::Page(_)) = rule.fallback() {
+             return Err(anyhow!("Domain {}: Traefik cannot serve a static page, which a page default_backend and maintenance without a default_backend need; point default_backend at a service or use the nginx gateway", rule.domain_name));
+         }
+         let router_name_base = traefik_name(&rule.domain_name);
+         for (j, svc) in rule.routed_services().enumerate() {
              if svc.strip_prefix && svc.prefix != "/" {
                  if !middlewares_written {
                      writeln!(file, "  middlewares:")?;
@@ -609,16 +933,52 @@ fn generate_traefik_dynamic_config(ingress: &IngressResolvedSpec, path: &Path) -
                  writeln!(file, "        prefixes:")?;
                  writeln!(file, "          - \"{}\"", svc.prefix)?;
              }
+             if let Some(cors) = &svc.cors {
+                 if !middlewares_written {
+                     writeln!(file, "  middlewares:")?;
+                     middlewares_written = true;
+                 }
+                 writeln!(file, "    cors-{}-{}:", router_name_base, j)?;
+                 writeln!(file, "      headers:")?;
+                 writeln!(file, "        accessControlAllowOriginList:")?;
+                 for origin in &cors.origins {
+                     writeln!(file, "          - \"{}\"", origin)?;
+                 }
+                 writeln!(file, "        accessControlAllowMethods:")?;
+                 for method in &cors.methods {
+                     writeln!(file, "          - \"{}\"", method)?;
+                 }
+                 writeln!(file, "        accessControlAllowHeaders:")?;
+                 for header in &cors.headers {
+                     writeln!(file, "          - \"{}\"", header)?;
+                 }
+                 writeln!(file, "        accessControlAllowCredentials: {}", cors.credentials)?;
+                 writeln!(file, "        accessControlMaxAge: {}", cors.max_age)?;
+                 writeln!(file, "        addVaryHeader: true")?;
+             }
          }
     }
+    for redirect in &ingress.redirects {
+        if !middlewares_written {
+            writeln!(file, "  middlewares:")?;
+            middlewares_written = true;
+        }
+        let replacement = if redirect.preserve_path { "${1}" } else { "/" };
+        writeln!(file, "    redirect-{}:", redirect.from.replace(['.', ':'], "-"))?;
+        writeln!(file, "      redirectRegex:")?;
+        writeln!(file, "        regex: \"^https?://{}(.*)\"", redirect.from.replace('.', "\\\\."))?;
+        writeln!(file, "        replacement: \"{}{}\"", redirect.target, replacement)?;
+        writeln!(file, "        permanent: true")?;
+    }
     
     writeln!(file, "  routers:")?;
     for (i, rule) in ingress.rules.iter().enumerate() {
-        let router_name_base = rule.domain_name.replace(".", "-");
+        let router_name_base = traefik_name(&rule.domain_name);
+        let has_tls = ingress.domain_tls(&rule.domain_name);
         
-        for (j, svc) in rule.services.iter().enumerate() {
+        // a service default backend gets a Host-only rule, which Traefik tries last
+        for (j, svc) in rule.routed_services().enumerate() {
              let router_name = format!("{}-{}-{}", router_name_base, i, j);
-             writeln!(file, "    {}:", router_name)?;
              
              let path_rule = if svc.prefix == "/" {
                  String::new()
@@ -626,7 +986,22 @@ fn generate_traefik_dynamic_config(ingress: &IngressResolvedSpec, path: &Path) -
                  format!(" && PathPrefix(`{}`)", svc.prefix)
              };
              
+             if has_tls && redirect_per_router {
+                 writeln!(file, "    {}-http:", router_name)?;
+                 writeln!(file, "      rule: \"Host(`{}`){}\"", rule.domain_name, path_rule)?;
+                 writeln!(file, "      service: service-{}-{}", router_name_base, j)?;
+                 writeln!(file, "      entryPoints:")?;
+                 writeln!(file, "        - web")?;
+                 writeln!(file, "      middlewares:")?;
+                 writeln!(file, "        - redirect-to-https")?;
+             }
+
+             writeln!(file, "    {}:", router_name)?;
              writeln!(file, "      rule: \"Host(`{}`){}\"", rule.domain_name, path_rule)?;
+             if ingress.allow_nested_prefixes {
+                 // Traefik tries higher priorities first
+                 writeln!(file, "      priority: {}", svc.prefix.trim_end_matches('/').len() + 1)?;
+             }
              writeln!(file, "      service: service-{}-{}", router_name_base, j)?;
              
              if has_tls {
@@ -635,29 +1010,1130 @@ fn generate_traefik_dynamic_config(ingress: &IngressResolvedSpec, path: &Path) -
                  writeln!(file, "      tls:")?;
                  if use_le {
                      writeln!(file, "        certResolver: {}", TRAEFIK_RESOLVER)?;
+                     // the resolver can't tell the certificate's names from a wildcard rule
+                     if rule.domain_name.starts_with("*.") {
+                         writeln!(file, "        domains:")?;
+                         writeln!(file, "          - main: \"{}\"", rule.domain_name)?;
+                     }
                  }
              } else {
                  writeln!(file, "      entryPoints:")?;
                  writeln!(file, "        - web")?;
              }
              
+             let mut middlewares = Vec::new();
+             if svc.cors.is_some() {
+                 middlewares.push(format!("cors-{}-{}", router_name_base, j));
+             }
              if svc.strip_prefix && svc.prefix != "/" {
+                 middlewares.push(format!("strip-{}-{}", router_name_base, j));
+             }
+             if !middlewares.is_empty() {
                   writeln!(file, "      middlewares:")?;
-                  writeln!(file, "        - strip-{}-{}", router_name_base, j)?;
+                  for middleware in middlewares {
+                      writeln!(file, "        - {}", middleware)?;
+                  }
              }
         }
     }
+    // Redirects answer on both entrypoints and never reach a service
+    for redirect in &ingress.redirects {
+        let name = format!("redirect-{}", redirect.from.replace(['.', ':'], "-"));
+        let mut entry_points = vec![("web", false)];
+        if ingress.domain_tls(&redirect.from) {
+            entry_points.push(("websecure", true));
+        }
+        for (entry_point, tls) in entry_points {
+            writeln!(file, "    {}{}:", name, if tls { "-secure" } else { "" })?;
+            writeln!(file, "      rule: \"Host(`{}`)\"", redirect.from)?;
+            writeln!(file, "      service: noop@internal")?;
+            writeln!(file, "      entryPoints:")?;
+            writeln!(file, "        - {}", entry_point)?;
+            if tls {
+                writeln!(file, "      tls:")?;
+                if use_le {
+                    writeln!(file, "        certResolver: {}", TRAEFIK_RESOLVER)?;
+                }
+            }
+            writeln!(file, "      middlewares:")?;
+            writeln!(file, "        - {}", name)?;
+        }
+    }
     
     writeln!(file, "  services:")?;
     for rule in ingress.rules.iter() {
-        let router_name_base = rule.domain_name.replace(".", "-");
-        for (j, svc) in rule.services.iter().enumerate() {
+        let router_name_base = traefik_name(&rule.domain_name);
+        for (j, svc) in rule.routed_services().enumerate() {
              writeln!(file, "    service-{}-{}:", router_name_base, j)?;
              writeln!(file, "      loadBalancer:")?;
              writeln!(file, "        servers:")?;
-             writeln!(file, "          - url: \"http://{}_{}:{}/\"", svc.deployment_name,  svc.service_name, svc.port)?;
+             writeln!(file, "          - url: \"http://{}_{}:{}/\"", svc.deployment_name,  svc.service_name, svc.container_port)?;
         }
     }
     
-    Ok(())
+    Ok(file)
+}
+
+/// Shell dialect a generated script is written in. Bash scripts rely on
+/// `set -e`; PowerShell does not stop on native command failures, so every
+/// command that must succeed is followed by an explicit exit code check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shell {
+    Bash,
+    PowerShell,
+}
+
+impl Shell {
+    fn for_format(format: ScriptFormat) -> Vec<Shell> {
+        match format {
+            ScriptFormat::Bash => vec![Shell::Bash],
+            ScriptFormat::PowerShell => vec![Shell::PowerShell],
+            ScriptFormat::Both => vec![Shell::Bash, Shell::PowerShell],
+        }
+    }
+
+    /// Path of the `<name>.sh` or `<name>.ps1` script in `dir`.
+    fn script_path(self, dir: &Path, name: &str) -> PathBuf {
+        let extension = match self {
+            Shell::Bash => "sh",
+            Shell::PowerShell => "ps1",
+        };
+        dir.join(format!("{}.{}", name, extension))
+    }
+
+    /// Writes the script preamble. With `with_profiles` the script accepts
+    /// repeated `--with <profile>` (bash) or `-With <profile>` (PowerShell).
+    fn write_header(self, script: &mut Vec<u8>, with_profiles: bool) -> Result<()> {
+        match self {
+            Shell::Bash => {
+                writeln!(script, "#!/bin/bash")?;
+                writeln!(script, "set -e")?;
+                if with_profiles {
+                    writeln!(script, "PROFILES=\" \"")?;
+                    writeln!(script, "while [ $# -gt 0 ]; do")?;
+                    writeln!(script, "  case \"$1\" in")?;
+                    writeln!(script, "    --with) PROFILES=\"$PROFILES$2 \"; shift 2 ;;")?;
+                    writeln!(script, "    *) echo \"Unknown argument: $1\"; exit 1 ;;")?;
+                    writeln!(script, "  esac")?;
+                    writeln!(script, "done")?;
+                }
+            }
+            Shell::PowerShell => {
+                if with_profiles {
+                    writeln!(script, "param([string[]]$With = @())")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_exit_check(self, script: &mut Vec<u8>) -> Result<()> {
+        if self == Shell::PowerShell {
+            writeln!(script, "if ($LASTEXITCODE -ne 0) {{ exit $LASTEXITCODE }}")?;
+        }
+        Ok(())
+    }
+
+    fn begin_profile(self, profile: &str) -> String {
+        match self {
+            Shell::Bash => format!("if [[ \"$PROFILES\" == *\" {} \"* ]]; then", profile),
+            Shell::PowerShell => format!("if ($With -contains {}) {{", self.quote(profile)),
+        }
+    }
+
+    fn set_stack_files(self, file: &str) -> String {
+        match self {
+            Shell::Bash => format!("STACK_FILES=\"-c {}\"", file),
+            Shell::PowerShell => format!("$StackFiles = @('-c', '{}')", file),
+        }
+    }
+
+    fn add_stack_file(self, file: &str) -> String {
+        match self {
+            Shell::Bash => format!("STACK_FILES=\"$STACK_FILES -c {}\"", file),
+            Shell::PowerShell => format!("$StackFiles += @('-c', '{}')", file),
+        }
+    }
+
+    // The `-c <file>` arguments collected by `set_stack_files` and `add_stack_file`
+    fn stack_files(self) -> &'static str {
+        match self {
+            Shell::Bash => "$STACK_FILES",
+            Shell::PowerShell => "@StackFiles",
+        }
+    }
+
+    fn end_block(self) -> &'static str {
+        match self {
+            Shell::Bash => "fi",
+            Shell::PowerShell => "}",
+        }
+    }
+
+    fn ignore_failure(self, command: &str) -> String {
+        match self {
+            Shell::Bash => format!("{} || true", command),
+            Shell::PowerShell => format!("{} 2>$null", command),
+        }
+    }
+
+    fn echo(self, message: &str) -> String {
+        match self {
+            Shell::Bash => format!("echo {}", self.quote(message)),
+            Shell::PowerShell => format!("Write-Host {}", self.quote(message)),
+        }
+    }
+
+    fn mkdir(self, dir: &str) -> String {
+        match self {
+            Shell::Bash => format!("mkdir -p \"{}\"", dir),
+            Shell::PowerShell => format!("New-Item -ItemType Directory -Force -Path \"{}\" | Out-Null", dir),
+        }
+    }
+
+    fn sleep(self, seconds: u32) -> String {
+        match self {
+            Shell::Bash => format!("sleep {}", seconds),
+            Shell::PowerShell => format!("Start-Sleep -Seconds {}", seconds),
+        }
+    }
+
+    /// Single-quotes `value` so it reaches the command verbatim.
+    fn quote(self, value: &str) -> String {
+        match self {
+            Shell::Bash => format!("'{}'", value.replace('\'', "'\\''")),
+            Shell::PowerShell => format!("'{}'", value.replace('\'', "''")),
+        }
+    }
+
+    /// Path relative to the script directory, resolved against the current
+    /// directory. Separators stay forward slashes, which docker accepts on
+    /// Windows as well.
+    fn host_path(self, relative: &str) -> String {
+        match self {
+            Shell::Bash => format!("$(pwd)/{}", relative),
+            Shell::PowerShell => format!("\"${{PWD}}/{}\"", relative),
+        }
+    }
+
+    // `relative` to the directory of the running script
+    fn script_relative(self, relative: &str) -> String {
+        match self {
+            Shell::Bash => format!("\"$(dirname \"$0\")/{}\"", relative),
+            Shell::PowerShell => format!("\"$PSScriptRoot/{}\"", relative),
+        }
+    }
+
+    fn read_file(self, path: &str) -> String {
+        match self {
+            Shell::Bash => format!("$(cat {})", path),
+            Shell::PowerShell => format!("$(Get-Content -Raw {})", path),
+        }
+    }
+
+    fn unix_time(self) -> &'static str {
+        match self {
+            Shell::Bash => "$(date +%s)",
+            Shell::PowerShell => "$([DateTimeOffset]::UtcNow.ToUnixTimeSeconds())",
+        }
+    }
+
+    /// Sets env variable `name` for the commands that follow, where `value`
+    /// may contain command substitutions.
+    fn export(self, name: &str, value: &str) -> String {
+        match self {
+            Shell::Bash => format!("export {}={}", name, value),
+            Shell::PowerShell => format!("$env:{} = \"{}\"", name, value),
+        }
+    }
+
+    /// `NAME=value` argument for `docker run -e`, where `value` may contain
+    /// command substitutions.
+    fn env_arg(self, name: &str, value: &str) -> String {
+        match self {
+            Shell::Bash => format!("{}={}", name, value),
+            Shell::PowerShell => format!("\"{}={}\"", name, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generated::OnConflict, resolver, spec::DeploymentEnvType, spec_loader, test_support};
+    use std::fs;
+
+    // Extra env spec for a swarm deployment; swarm ingress needs Let's Encrypt
+    const SWARM: &str = "swarm_mode: true\ngateway:\n  tls:\n    letsencrypt:\n      email: ops@example.com\n";
+
+    #[test]
+    fn renders_without_touching_the_output_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("envspec.yaml"), test_support::env_spec("docker", "")).unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec("app_services:\n  api:\n    type: public\n    image: acme/api\n    export:\n      host: web\n      prefix: /\n")).unwrap();
+        let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env_spec)).unwrap();
+        let resolved = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let DeploymentEnvType::Docker(docker_spec) = &env_spec.env_type else { panic!("expected docker env") };
+
+        let output_dir = dir.path().join("docker-deploy");
+        let files = render(&resolved, docker_spec, &output_dir).unwrap();
+        assert!(!output_dir.exists());
+        let deploy = files.get(output_dir.join("deploy.sh")).unwrap();
+        assert!(deploy.contains("docker run -d --name api --network common_network"), "{}", deploy);
+        assert!(files.files.iter().any(|f| f.path == output_dir.join("deploy.sh") && f.executable));
+        assert!(files.get(output_dir.join("envs/api.env")).is_some());
+    }
+
+    #[test]
+    fn regenerating_unchanged_specs_is_not_a_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("nginx")).unwrap();
+        fs::write(dir.path().join("nginx/site.conf"), "listen 80;").unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec("configs:\n  nginx: [site.conf]\napp_services:\n  web:\n    type: public\n    image: acme/web\n    configs:\n      - nginx: /etc/nginx/conf.d\n")).unwrap();
+
+        for (env_type, extra) in [("docker", SWARM), ("k8s", "")] {
+            fs::write(dir.path().join("envspec.yaml"), test_support::env_spec(env_type, &format!("{}deployments:\n  prod:\n    configs:\n      nginx: nginx\n", extra))).unwrap();
+            let output_dir = dir.path().join(env_type);
+            let render_once = || {
+                let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+                let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env_spec)).unwrap();
+                let resolved = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+                match &env_spec.env_type {
+                    DeploymentEnvType::Docker(docker_spec) => render(&resolved, docker_spec, &output_dir).unwrap(),
+                    _ => crate::k8s_generator::render(&resolved, &output_dir).unwrap(),
+                }
+            };
+
+            render_once().commit(OnConflict::Fail).unwrap();
+            render_once().commit(OnConflict::Fail).unwrap_or_else(|e| panic!("{}: {:#}", env_type, e));
+
+            // a hand edit still is one
+            let edited = render_once().files[0].path.clone();
+            fs::write(&edited, "edited").unwrap();
+            assert!(render_once().commit(OnConflict::Fail).is_err(), "{}", env_type);
+        }
+    }
+
+    #[test]
+    fn powershell_scripts_mirror_the_bash_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("envspec.yaml"), test_support::env_spec("docker", r#"
+script_format: both
+gateway:
+  type: nginx
+deployments:
+  prod:
+    secrets:
+      db_password: hunter2
+"#)).unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+secrets:
+  - db_password
+app_services:
+  api:
+    type: public
+    image: acme/api
+    secrets:
+      - db_password:
+          variable: DB_PASSWORD
+    export:
+      host: web
+      prefix: /
+  worker:
+    image: acme/worker
+    profile: jobs
+"#)).unwrap();
+        let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env_spec)).unwrap();
+        let resolved = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let DeploymentEnvType::Docker(docker_spec) = &env_spec.env_type else { panic!("expected docker env") };
+        let output_dir = dir.path().join("docker-deploy");
+        let files = render(&resolved, docker_spec, &output_dir).unwrap();
+        assert!(files.files.iter().any(|f| f.path == output_dir.join("deploy.ps1") && f.executable));
+
+        let deploy = files.get(output_dir.join("deploy.ps1")).unwrap();
+        assert!(deploy.starts_with("param([string[]]$With = @())\ndocker network create common_network 2>$null\n"), "{}", deploy);
+        assert!(deploy.contains("docker run -d --name api --network common_network --env-file \"${PWD}/envs/api.env\" \
+            -e \"DB_PASSWORD=$(Get-Content -Raw ./secrets/shop-db_password)\" reg.example.com/acme/api:1.0.0\n\
+            if ($LASTEXITCODE -ne 0) { exit $LASTEXITCODE }\n"), "{}", deploy);
+        assert!(deploy.contains("if ($With -contains 'jobs') {\nWrite-Host 'Starting worker...'\ndocker rm -f worker 2>$null\n"), "{}", deploy);
+        assert!(deploy.contains("-v \"${PWD}/nginx/default.conf:/etc/nginx/conf.d/default.conf\""), "{}", deploy);
+        assert!(!deploy.contains("$(pwd)") && !deploy.contains("|| true"), "{}", deploy);
+
+        assert_eq!(files.get(output_dir.join("undeploy.ps1")).unwrap(), "Write-Host 'Removing api...'\ndocker rm -f api 2>$null\n\
+            Write-Host 'Removing worker...'\ndocker rm -f worker 2>$null\ndocker rm -f nginx-ingress 2>$null\n");
+        assert_eq!(files.get(output_dir.join("undeploy.sh")).unwrap(), "#!/bin/bash\nset -e\necho 'Removing api...'\ndocker rm -f api || true\n\
+            echo 'Removing worker...'\ndocker rm -f worker || true\ndocker rm -f nginx-ingress || true\n");
+    }
+
+    #[test]
+    fn docker_applies_only_the_limits_a_spec_sets() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+app_services:
+  api:
+    image: acme/api
+    resources:
+      limits:
+        cpu: 1500m
+  worker:
+    image: acme/worker
+"#)).unwrap();
+        let env_spec = |extra: &str| test_support::env_spec("docker", &format!(r#"
+{extra}
+deployments:
+  prod:
+    services:
+      api:
+        resources:
+          requests:
+            memory: 256Mi
+          limits:
+            memory: 512Mi
+"#));
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec("")).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let DeploymentEnvType::Docker(docker_spec) = &env.env_type else { unreachable!() };
+        let output_dir = dir.path().join("out");
+        let files = render(&resolved, docker_spec, &output_dir).unwrap();
+        let deploy = files.get(output_dir.join("deploy.sh")).unwrap();
+        assert!(deploy.contains("--name api --network common_network --env-file $(pwd)/envs/api.env --memory 536870912 --cpus 1.5 "), "{}", deploy);
+        // the built-in defaults are Kubernetes only
+        let worker = deploy.lines().find(|l| l.contains("--name worker")).unwrap();
+        assert!(!worker.contains("--memory") && !worker.contains("--cpus"), "{}", worker);
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec(SWARM)).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let DeploymentEnvType::Docker(docker_spec) = &env.env_type else { unreachable!() };
+        let files = render(&resolved, docker_spec, &output_dir).unwrap();
+        let compose = files.get(output_dir.join("prod/docker-compose.yaml")).unwrap();
+        assert!(compose.contains("    deploy:\n      resources:\n        limits:\n          memory: 536870912\n          cpus: '1.5'\n"), "{}", compose);
+        assert_eq!(compose.matches("deploy:").count(), 1, "{}", compose);
+    }
+
+    #[test]
+    fn gateways_route_to_the_port_the_service_listens_on() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec("app_services:\n  api:\n    type: public\n    image: acme/api\n")).unwrap();
+        let deployment = r#"
+deployments:
+  prod:
+    services:
+      api:
+        prefix: /api
+        ports:
+          - "8081:3000"
+"#;
+
+        let cases = [
+            ("docker", "gateway:\n  type: nginx", "proxy_pass http://api:3000"),
+            ("docker", SWARM, "url: \"http://prod_api:3000/\""),
+            ("k8s", "", "number: 8081"),
+        ];
+        for (env_type, extra, expected) in cases {
+            fs::write(dir.path().join("envspec.yaml"), test_support::env_spec(env_type, &format!("{}\n{}", extra, deployment))).unwrap();
+            let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+            let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env_spec)).unwrap();
+            let resolved = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+            let target = &resolved.ingress.rules[0].services[0];
+            assert_eq!((target.port, target.container_port), (8081, 3000));
+
+            let output_dir = dir.path().join("out");
+            let files = match &env_spec.env_type {
+                DeploymentEnvType::Docker(docker_spec) => render(&resolved, docker_spec, &output_dir).unwrap(),
+                _ => crate::k8s_generator::render(&resolved, &output_dir).unwrap(),
+            };
+            let rendered: Vec<String> = files.files.iter().map(|f| String::from_utf8_lossy(&f.content).to_string()).collect();
+            assert!(rendered.iter().any(|f| f.contains(expected)), "{}: {}", env_type, rendered.join("\n---\n"));
+            if env_type == "k8s" {
+                let service = files.get(output_dir.join("service-api.yaml")).unwrap();
+                assert!(service.contains("- port: 8081\n    targetPort: 3000"), "{}", service);
+            }
+        }
+    }
+
+    #[test]
+    fn nested_prefixes_are_rejected_unless_allowed_and_then_ordered() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+app_services:
+  web:
+    type: public
+    image: acme/web
+    export:
+      prefix: /
+  api:
+    type: public
+    image: acme/api
+    export:
+      prefix: /api
+  v1:
+    type: public
+    image: acme/v1
+    export:
+      prefix: /api/v1
+"#)).unwrap();
+        let env_spec = |allow: bool| test_support::env_spec("docker", &format!("gateway:\n  allow_nested_prefixes: {}\n", allow));
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec(false)).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let report = crate::validator::validate(&env, &app_spec, "prod").unwrap_err().root_cause().to_string();
+        for expected in ["Prefix / of service web contains prefix /api of service api", "Prefix / of service web contains prefix /api/v1 of service v1", "Prefix /api of service api contains prefix /api/v1 of service v1"] {
+            assert!(report.contains(expected), "{}", report);
+        }
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec(true)).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        crate::validator::validate(&env, &app_spec, "prod").unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let prefixes: Vec<&str> = resolved.ingress.rules[0].services.iter().map(|s| s.prefix.as_str()).collect();
+        assert_eq!(prefixes, ["/api/v1", "/api", "/"]);
+
+        let traefik = String::from_utf8(generate_traefik_dynamic_config(&resolved.ingress).unwrap()).unwrap();
+        let priorities: Vec<&str> = traefik.lines().filter(|l| l.contains("priority:")).map(str::trim).collect();
+        assert_eq!(priorities, ["priority: 8", "priority: 5", "priority: 1"], "{}", traefik);
+    }
+
+    #[test]
+    fn hosts_can_opt_out_of_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+environment:
+  relative:
+    - BASE_URL=/
+app_services:
+  api:
+    type: public
+    image: acme/api
+    environment:
+      - BASE_URL
+  admin:
+    type: public
+    image: acme/admin
+    environment:
+      - BASE_URL
+"#)).unwrap();
+        let env_spec = |env_type: &str, gateway: &str| test_support::env_spec(env_type, &format!(r#"
+gateway:
+  {gateway}
+  hosts:
+    admin:
+      domains: [admin.internal]
+      tls: false
+  tls:
+    letsencrypt:
+      email: ops@example.com
+deployments:
+  prod:
+    services:
+      api:
+        prefix: /
+      admin:
+        host: admin
+        prefix: /
+"#));
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec("docker", "type: nginx")).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        assert_eq!(resolved.ingress.plain_http_domains, ["admin.internal"]);
+        let base_url = |name: &str| resolved.current_deployment.services.iter().find(|s| s.full_name == name).unwrap()
+            .environment_variables.iter().find(|v| v.name == "BASE_URL").unwrap().value.clone();
+        assert_eq!(base_url("api"), "https://example.com/");
+        assert_eq!(base_url("admin"), "http://admin.internal/");
+
+        let nginx = String::from_utf8(generate_nginx_config(&resolved.ingress).unwrap()).unwrap();
+        let (admin, public) = nginx.split_once("server_name example.com;").unwrap();
+        assert!(admin.contains("server_name admin.internal;") && !admin.contains("443") && !admin.contains("return 301"), "{}", nginx);
+        assert!(public.contains("listen 443 ssl;") && public.contains("return 301"), "{}", nginx);
+
+        let traefik = String::from_utf8(generate_traefik_dynamic_config(&resolved.ingress).unwrap()).unwrap();
+        let admin_router = traefik.split("    admin-internal-0-0:").nth(1).unwrap().split("    example-com").next().unwrap();
+        assert!(admin_router.contains("- web") && !admin_router.contains("tls:"), "{}", traefik);
+        assert!(traefik.contains("example-com-1-0-http:") && traefik.contains("- redirect-to-https"), "{}", traefik);
+        let mut static_conf = Vec::new();
+        write_traefik_static_config(&mut static_conf, true, resolved.ingress.plain_http_domains.is_empty(), None).unwrap();
+        assert!(!String::from_utf8(static_conf).unwrap().contains("redirections:"));
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec("k8s", "name: gateway")).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let output_dir = dir.path().join("out");
+        let files = crate::k8s_generator::render(&resolved, &output_dir).unwrap();
+        let ingress = files.get(output_dir.join("ingress.yaml")).unwrap();
+        assert!(ingress.contains("  - hosts:\n    - example.com\n    secretName"), "{}", ingress);
+        assert!(ingress.contains("- host: admin.internal"), "{}", ingress);
+    }
+
+    #[test]
+    fn binary_config_files_land_in_configmap_binary_data() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("geoip")).unwrap();
+        fs::write(dir.path().join("geoip/countries.mmdb"), [0x00u8, 0xff, 0xfe, 0x10]).unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+configs:
+  geoip: [countries.mmdb]
+app_services:
+  web:
+    image: acme/web
+    configs:
+      - geoip: /usr/share/geoip
+"#)).unwrap();
+        fs::write(dir.path().join("envspec.yaml"), test_support::env_spec("k8s", "deployments:\n  prod:\n    configs:\n      geoip: geoip\n")).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let output_dir = dir.path().join("out");
+        let files = crate::k8s_generator::render(&resolved, &output_dir).unwrap();
+        let configmap = files.get(output_dir.join("configmap-shop-geoip.yaml")).unwrap();
+        assert!(configmap.contains("binaryData:\n  countries.mmdb: AP/+EA==\n"), "{}", configmap);
+    }
+
+    #[test]
+    fn restarts_are_triggered_by_the_deploy_or_by_changed_configs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("nginx")).unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+configs:
+  nginx: [site.conf]
+app_services:
+  web:
+    type: public
+    image: acme/web
+    configs:
+      - nginx: /etc/nginx/conf.d
+  api:
+    type: public
+    image: acme/api
+"#)).unwrap();
+        let render_with = |env_type: &str, extra: &str, site: &str| {
+            fs::write(dir.path().join("nginx/site.conf"), site).unwrap();
+            fs::write(dir.path().join("envspec.yaml"), test_support::env_spec(env_type, &format!("{}deployments:\n  prod:\n    configs:\n      nginx: nginx\n    services:\n      api:\n        prefix: /api\n", extra))).unwrap();
+            let env = spec_loader::load_env_spec(dir.path()).unwrap();
+            let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+            let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+            let output_dir = dir.path().join("out");
+            match &env.env_type {
+                DeploymentEnvType::Docker(docker_spec) => render(&resolved, docker_spec, &output_dir).unwrap(),
+                _ => crate::k8s_generator::render(&resolved, &output_dir).unwrap(),
+            }
+        };
+        let out = dir.path().join("out");
+
+        // swarm takes the time from deploy.sh, so the stack files stay the same
+        let files = render_with("docker", SWARM, "listen 80;");
+        assert!(files.get(out.join("prod/docker-compose.yaml")).unwrap().contains("DEPLOY_DATE: ${DEPLOY_DATE}"));
+        assert!(files.get(out.join("ingress/docker-compose.yaml")).unwrap().contains("- DEPLOY_DATE=${DEPLOY_DATE}"));
+        let deploy = files.get(out.join("deploy.sh")).unwrap();
+        assert!(deploy.contains("export DEPLOY_DATE=$(date +%s)\ndocker stack deploy -c ingress/docker-compose.yaml"), "{}", deploy);
+
+        // k8s pods restart when the configs they mount change
+        let checksum = |files: &GeneratedFiles, service: &str| {
+            let manifest = files.get(out.join(format!("deployment-{}.yaml", service))).unwrap().to_string();
+            manifest.lines().find_map(|l| l.strip_prefix("        simpled/config-checksum: ").map(str::to_string))
+        };
+        let files = render_with("k8s", "", "listen 80;");
+        let web = checksum(&files, "web").unwrap();
+        assert!(!files.get(out.join("deployment-web.yaml")).unwrap().contains("DEPLOY_DATE"));
+        assert_eq!(checksum(&files, "api"), None);
+        assert_eq!(checksum(&render_with("k8s", "", "listen 80;"), "web").unwrap(), web);
+        assert_ne!(checksum(&render_with("k8s", "", "listen 8080;"), "web").unwrap(), web);
+    }
+
+    #[test]
+    fn wildcard_domains_get_certificates_over_dns01() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec("app_services:\n  api:\n    type: public\n    image: acme/api\n")).unwrap();
+        let env_spec = |env_type: &str, gateway: &str, credentials: &str| test_support::env_spec(env_type, &format!(r#"
+gateway:
+  {gateway}
+  hosts:
+    web: ~
+    tenants: "*.apps.example.com"
+  tls:
+    letsencrypt:
+      email: ops@example.com
+      dns01:
+        provider: cloudflare
+        credentials: {credentials}
+deployments:
+  prod:
+    primary_host: tenants
+    services:
+      api:
+        prefix: /
+"#));
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec("docker", "type: traefik", "cloudflare.env")).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let traefik = String::from_utf8(generate_traefik_dynamic_config(&resolved.ingress).unwrap()).unwrap();
+        assert!(traefik.contains("    wildcard-apps-example-com-0-0:\n      rule: \"Host(`*.apps.example.com`)\""), "{}", traefik);
+        assert!(traefik.contains("        domains:\n          - main: \"*.apps.example.com\""), "{}", traefik);
+        let mut static_conf = Vec::new();
+        let letsencrypt = resolved.ingress.tls.as_ref().and_then(|t| t.letsencrypt.as_ref());
+        write_traefik_static_config(&mut static_conf, true, true, letsencrypt).unwrap();
+        let static_conf = String::from_utf8(static_conf).unwrap();
+        assert!(static_conf.contains("      dnsChallenge:\n        provider: cloudflare") && !static_conf.contains("httpChallenge"), "{}", static_conf);
+        let mut deploy = Vec::new();
+        write_traefik_container(&mut deploy, Shell::Bash, &resolved, "shop").unwrap();
+        assert!(String::from_utf8(deploy).unwrap().contains(r#" --env-file "$(dirname "$0")/cloudflare.env" -e "#));
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec("k8s", "name: gateway", "cloudflare-token")).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let output_dir = dir.path().join("out");
+        let files = crate::k8s_generator::render(&resolved, &output_dir).unwrap();
+        let issuer = files.get(output_dir.join("cluster-issuer.yaml")).unwrap();
+        assert!(issuer.contains("    - dns01:\n        cloudflare:\n          apiTokenSecretRef:\n            name: cloudflare-token\n            key: api-token"), "{}", issuer);
+        let ingress = files.get(output_dir.join("ingress.yaml")).unwrap();
+        assert!(ingress.contains("  - host: \"*.apps.example.com\"") && ingress.contains("    - \"*.apps.example.com\""), "{}", ingress);
+    }
+
+    #[test]
+    fn redirect_domains_answer_with_a_permanent_redirect() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec("app_services:\n  api:\n    type: public\n    image: acme/api\n")).unwrap();
+        let env_spec = |env_type: &str, gateway: &str| test_support::env_spec(env_type, &format!(r#"
+gateway:
+  {gateway}
+  redirects:
+    www.example.com: example.com
+    old.example.org:
+      to: example.com
+      preserve_path: false
+  tls:
+    letsencrypt:
+      email: ops@example.com
+deployments:
+  prod:
+    services:
+      api:
+        prefix: /
+"#));
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec("docker", "type: nginx")).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        assert_eq!(resolved.ingress.domains, ["example.com", "old.example.org", "www.example.com"]);
+
+        let nginx = String::from_utf8(generate_nginx_config(&resolved.ingress).unwrap()).unwrap();
+        assert!(nginx.contains("server_name www.example.com;\n    ssl_certificate /etc/nginx/certs/live/www.example.com/fullchain.pem;"), "{}", nginx);
+        assert!(nginx.contains("return 301 https://example.com$request_uri;"), "{}", nginx);
+        assert!(nginx.contains("return 301 https://example.com/;"), "{}", nginx);
+
+        let traefik = String::from_utf8(generate_traefik_dynamic_config(&resolved.ingress).unwrap()).unwrap();
+        assert!(traefik.contains("regex: \"^https?://www\\\\.example\\\\.com(.*)\"\n        replacement: \"https://example.com${1}\""), "{}", traefik);
+        assert!(traefik.contains("redirect-old-example-org-secure:\n      rule: \"Host(`old.example.org`)\"\n      service: noop@internal"), "{}", traefik);
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec("k8s", "name: gateway")).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let output_dir = dir.path().join("out");
+        let files = crate::k8s_generator::render(&resolved, &output_dir).unwrap();
+        let redirects = files.get(output_dir.join("ingress-redirects.yaml")).unwrap();
+        assert!(redirects.contains("name: gateway-redirect-www-example-com\n  annotations:\n    nginx.ingress.kubernetes.io/permanent-redirect: https://example.com$request_uri"), "{}", redirects);
+        assert!(files.get(output_dir.join("ingress.yaml")).unwrap().contains("    - www.example.com\n"));
+    }
+
+    #[test]
+    fn cors_headers_are_added_on_the_prefixes_that_ask_for_them() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec("app_services:\n  api:\n    type: public\n    image: acme/api\n  web:\n    type: public\n    image: acme/web\n")).unwrap();
+        let env_spec = |env_type: &str, gateway: &str, origins: &str| test_support::env_spec(env_type, &format!(r#"
+gateway:
+  {gateway}
+deployments:
+  prod:
+    services:
+      web:
+        prefix: /
+      api:
+        prefix: /api
+        cors:
+          origins: [{origins}]
+          methods: [GET, POST]
+          credentials: true
+"#));
+        let load = |env_type: &str, gateway: &str| {
+            fs::write(dir.path().join("envspec.yaml"), env_spec(env_type, gateway, "https://app.example.com")).unwrap();
+            let env = spec_loader::load_env_spec(dir.path()).unwrap();
+            let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+            resolver::resolve(&env, &app_spec, "prod").unwrap()
+        };
+
+        let resolved = load("docker", "type: nginx");
+        let nginx = String::from_utf8(generate_nginx_config(&resolved.ingress).unwrap()).unwrap();
+        assert!(nginx.starts_with("map $http_origin $cors_origin_0_0 {\n    default \"\";\n    \"~^(https://app\\.example\\.com)$\" $http_origin;\n}"), "{}", nginx);
+        let api = nginx.split("location /api/ {").nth(1).unwrap().split("location / {").next().unwrap();
+        assert!(api.contains("if ($request_method = OPTIONS) {\n            add_header Access-Control-Allow-Origin $cors_origin_0_0 always;"), "{}", nginx);
+        assert!(api.contains("add_header Access-Control-Allow-Methods \"GET, POST\" always;") && api.contains("return 204;"), "{}", nginx);
+        assert!(!nginx.split("location / {").nth(1).unwrap().contains("Access-Control"), "{}", nginx);
+
+        let traefik = String::from_utf8(generate_traefik_dynamic_config(&resolved.ingress).unwrap()).unwrap();
+        assert!(traefik.contains("cors-example-com-0:\n      headers:\n        accessControlAllowOriginList:\n          - \"https://app.example.com\""), "{}", traefik);
+        assert!(traefik.contains("      middlewares:\n        - cors-example-com-0\n        - strip-example-com-0"), "{}", traefik);
+
+        let resolved = load("k8s", "name: gateway");
+        let output_dir = dir.path().join("out");
+        let files = crate::k8s_generator::render(&resolved, &output_dir).unwrap();
+        let main = files.get(output_dir.join("ingress.yaml")).unwrap();
+        assert!(!main.contains("/api") && !main.contains("enable-cors"), "{}", main);
+        let cors = files.get(output_dir.join("ingress-cors.yaml")).unwrap();
+        assert!(cors.contains("name: gateway-cors-1") && cors.contains("cors-allow-origin: \"https://app.example.com\"") && cors.contains("- path: /api(/|$)(.*)"), "{}", cors);
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec("docker", "type: nginx", "\"*\"")).unwrap();
+        let err = format!("{:#}", spec_loader::load_env_spec(dir.path()).unwrap_err());
+        assert!(err.contains("cannot be combined with credentials: true"), "{}", err);
+    }
+
+    #[test]
+    fn default_backends_answer_unmatched_requests_and_maintenance() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec("app_services:\n  api:\n    type: public\n    image: acme/api\n  web:\n    type: public\n    image: acme/web\n")).unwrap();
+        let env_spec = |maintenance: bool| test_support::env_spec("docker", &format!(r#"
+gateway:
+  type: nginx
+  hosts:
+    web:
+      domains: [example.com]
+      default_backend:
+        page: "<h1>Nothing here</h1>"
+    shop:
+      domains: [shop.example.com]
+      default_backend:
+        service: web
+deployments:
+  prod:
+    maintenance: {maintenance}
+    services:
+      api:
+        prefix: /api
+      web:
+        host: shop
+        prefix: /web
+"#));
+        let load = |maintenance: bool| {
+            fs::write(dir.path().join("envspec.yaml"), env_spec(maintenance)).unwrap();
+            let env = spec_loader::load_env_spec(dir.path()).unwrap();
+            let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+            resolver::resolve(&env, &app_spec, "prod").unwrap()
+        };
+
+        let resolved = load(false);
+        let nginx = String::from_utf8(generate_nginx_config(&resolved.ingress).unwrap()).unwrap();
+        let (shop, web) = nginx.split_once("server_name example.com;").unwrap();
+        assert!(web.contains("location /api/ {") && web.contains("error_page 404 503 /__simpled/page.html;"), "{}", nginx);
+        assert!(web.contains("alias /etc/nginx/pages/example.com.html;") && web.contains("location / {\n        return 404;"), "{}", nginx);
+        assert!(shop.contains("location /web/ {") && shop.contains("location / {\n        proxy_pass http://web:80;"), "{}", nginx);
+        let output_dir = dir.path().join("out");
+        let DeploymentEnvType::Docker(docker_spec) = &spec_loader::load_env_spec(dir.path()).unwrap().env_type else { panic!("expected docker env") };
+        let files = render(&resolved, docker_spec, &output_dir).unwrap();
+        assert_eq!(files.get(output_dir.join("nginx/pages/example.com.html")).unwrap(), "<h1>Nothing here</h1>");
+        assert!(files.get(output_dir.join("deploy.sh")).unwrap().contains("nginx/pages:/etc/nginx/pages"));
+
+        let mut traefik_ingress = resolved.ingress.clone();
+        traefik_ingress.rules.retain(|r| r.domain_name == "shop.example.com");
+        let traefik = String::from_utf8(generate_traefik_dynamic_config(&traefik_ingress).unwrap()).unwrap();
+        assert!(traefik.contains("shop-example-com-0-1:\n      rule: \"Host(`shop.example.com`)\"\n      service: service-shop-example-com-1"), "{}", traefik);
+        let err = generate_traefik_dynamic_config(&resolved.ingress).unwrap_err().to_string();
+        assert!(err.contains("Traefik cannot serve a static page"), "{}", err);
+
+        let resolved = load(true);
+        assert!(resolved.ingress.rules.iter().all(|r| r.maintenance));
+        let nginx = String::from_utf8(generate_nginx_config(&resolved.ingress).unwrap()).unwrap();
+        assert!(!nginx.contains("location /api/") && !nginx.contains("location /web/"), "{}", nginx);
+        assert!(nginx.contains("location / {\n        return 503;"), "{}", nginx);
+        assert!(nginx.contains("server_name shop.example.com;\n    location / {\n        proxy_pass http://web:80;"), "{}", nginx);
+    }
+
+    #[test]
+    fn env_values_embedding_secrets_stay_out_of_plain_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+environment:
+  internal:
+    - DB_URL=postgres://app:${secret:db_password}@db/app
+    - REPLICA_URL="${DB_URL}?replica=1"
+    - LOG_LEVEL=debug
+secrets:
+  - db_password
+app_services:
+  api:
+    image: acme/api
+    environment:
+      - $all
+      - AUTH=basic ${secret:db_password}
+"#)).unwrap();
+        let secrets = "deployments:\n  prod:\n    secrets:\n      db_password: hunter2\n";
+
+        let cases = [
+            ("docker", "", "envs/api.env", "secrets/api.env", "hunter2"),
+            ("docker", SWARM, "prod/api/.env", "prod/docker-compose.yaml", "AUTH: basic hunter2"),
+            ("k8s", "", "deployment-api.yaml", "secret-api-env.yaml", "DB_URL: cG9zdGdyZXM6Ly9hcHA6aHVudGVyMkBkYi9hcHA="),
+        ];
+        for (env_type, extra, plain_file, secret_file, expected) in cases {
+            fs::write(dir.path().join("envspec.yaml"), test_support::env_spec(env_type, &format!("{}{}", extra, secrets))).unwrap();
+            let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+            let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env_spec)).unwrap();
+            let resolved = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+            let sensitive: Vec<&str> = resolved.current_deployment.services[0].environment_variables.iter()
+                .filter(|e| e.sensitive)
+                .map(|e| e.name.as_str())
+                .collect();
+            assert_eq!(sensitive, ["DB_URL", "REPLICA_URL", "AUTH"]);
+
+            let output_dir = dir.path().join("out");
+            let files = match &env_spec.env_type {
+                DeploymentEnvType::Docker(docker_spec) => render(&resolved, docker_spec, &output_dir).unwrap(),
+                _ => crate::k8s_generator::render(&resolved, &output_dir).unwrap(),
+            };
+            let plain = files.get(output_dir.join(plain_file)).unwrap();
+            assert!(!plain.contains("hunter2") && plain.contains("debug"), "{}: {}", env_type, plain);
+            let secret = files.get(output_dir.join(secret_file)).unwrap();
+            assert!(secret.contains(expected), "{}: {}", env_type, secret);
+        }
+    }
+
+    // Generated files by path
+    fn rendered(files: GeneratedFiles) -> Vec<(PathBuf, String)> {
+        let mut rendered: Vec<(PathBuf, String)> = files.files.into_iter()
+            .map(|f| (f.path, String::from_utf8(f.content).unwrap()))
+            .collect();
+        rendered.sort();
+        rendered
+    }
+
+    #[test]
+    fn swarm_configs_are_versioned_objects_pruned_by_stack_label() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("nginx")).unwrap();
+        fs::write(dir.path().join("nginx/site.conf"), "listen 80;").unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+configs:
+  nginx: [site.conf]
+app_services:
+  web:
+    image: acme/web
+    configs:
+      - nginx: /etc/nginx/conf.d
+"#)).unwrap();
+        let extra = format!("{}script_format: both\ndeployments:\n  prod:\n    configs:\n      nginx: nginx\n", SWARM);
+        fs::write(dir.path().join("envspec.yaml"), test_support::env_spec("docker", &extra)).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let DeploymentEnvType::Docker(docker_spec) = &env.env_type else { unreachable!() };
+        let output_dir = dir.path().join("out");
+        let files = render(&resolved, docker_spec, &output_dir).unwrap();
+
+        let key = swarm_config_key("shop-nginx", "site.conf", b"listen 80;");
+        assert_eq!(files.get(output_dir.join("prod/configs/shop-nginx/site.conf")), Some("listen 80;"));
+        let compose = files.get(output_dir.join("prod/docker-compose.yaml")).unwrap();
+        assert!(compose.contains(&format!("configs:\n  {}:\n    file: ./configs/shop-nginx/site.conf\n", key)), "{}", compose);
+        assert!(compose.contains(&format!("- source: {}\n      target: /etc/nginx/conf.d/site.conf\n", key)), "{}", compose);
+
+        let deploy = files.get(output_dir.join("deploy.sh")).unwrap();
+        assert!(deploy.contains(&format!("CURRENT_CONFIGS=\" prod_{} \"\n", key)), "{}", deploy);
+        assert!(deploy.contains("docker config ls --filter label=com.docker.stack.namespace=prod --format '{{.Name}}'"), "{}", deploy);
+        assert!(!deploy.contains("grep"), "{}", deploy);
+        let ps1 = files.get(output_dir.join("deploy.ps1")).unwrap();
+        assert!(ps1.contains(&format!("$currentConfigs = @('prod_{}')\n", key)), "{}", ps1);
+        assert!(ps1.contains("docker config ls --filter label=com.docker.stack.namespace=prod --format '{{.Name}}'"), "{}", ps1);
+        assert!(ps1.contains("if ($currentConfigs -notcontains $cfg) { docker config rm $cfg 2>$null }"), "{}", ps1);
+    }
+
+    #[test]
+    fn swarm_deploys_profiled_services_only_with_their_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+app_services:
+  web:
+    type: public
+    image: acme/web
+extra_services:
+  adminer:
+    image: adminer:4
+    profile: debug
+"#)).unwrap();
+        let extra = format!("{}script_format: both\ndeployments:\n  prod:\n    services:\n      web:\n        prefix: /\n", SWARM);
+        fs::write(dir.path().join("envspec.yaml"), test_support::env_spec("docker", &extra)).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let DeploymentEnvType::Docker(docker_spec) = &env.env_type else { unreachable!() };
+        let output_dir = dir.path().join("out");
+        let files = render(&resolved, docker_spec, &output_dir).unwrap();
+
+        let compose = files.get(output_dir.join("prod/docker-compose.yaml")).unwrap();
+        assert!(compose.contains("  web:\n") && !compose.contains("adminer"), "{}", compose);
+        let debug = files.get(output_dir.join("prod/docker-compose.debug.yaml")).unwrap();
+        assert!(debug.starts_with("services:\n  adminer:\n    image: adminer:4\n") && !debug.contains("profiles"), "{}", debug);
+
+        let deploy = files.get(output_dir.join("deploy.sh")).unwrap();
+        assert!(deploy.contains("    --with) PROFILES=\"$PROFILES$2 \"; shift 2 ;;\n"), "{}", deploy);
+        assert!(deploy.contains(concat!(
+            "STACK_FILES=\"-c prod/docker-compose.yaml\"\n",
+            "if [[ \"$PROFILES\" == *\" debug \"* ]]; then\n",
+            "STACK_FILES=\"$STACK_FILES -c prod/docker-compose.debug.yaml\"\n",
+            "fi\n",
+            "docker stack deploy $STACK_FILES prod --with-registry-auth --prune\n",
+        )), "{}", deploy);
+        let ps1 = files.get(output_dir.join("deploy.ps1")).unwrap();
+        assert!(ps1.starts_with("param([string[]]$With = @())\n"), "{}", ps1);
+        assert!(ps1.contains(concat!(
+            "$StackFiles = @('-c', 'prod/docker-compose.yaml')\n",
+            "if ($With -contains 'debug') {\n",
+            "$StackFiles += @('-c', 'prod/docker-compose.debug.yaml')\n",
+            "}\n",
+            "docker stack deploy @StackFiles prod --with-registry-auth --prune\n",
+        )), "{}", ps1);
+    }
+
+    #[test]
+    fn config_directories_keep_their_subdirectories() {
+        let dir = tempfile::tempdir().unwrap();
+        let dashboards = dir.path().join("grafana/provisioning/dashboards");
+        fs::create_dir_all(&dashboards).unwrap();
+        fs::write(dir.path().join("grafana/grafana.ini"), "ini").unwrap();
+        fs::write(dashboards.join("app.json"), "{}").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path().join("grafana"), dashboards.join("loop")).unwrap();
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+configs:
+  grafana:
+    - provisioning/dashboards/app.json
+app_services:
+  grafana:
+    type: internal
+    image: acme/grafana
+    configs:
+      - grafana: /etc/grafana
+"#)).unwrap();
+        let env_spec = |env_type: &str| test_support::env_spec(env_type, "deployments:\n  prod:\n    configs:\n      grafana: grafana\n");
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec("docker")).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env)).unwrap();
+        crate::validator::validate(&env, &app_spec, "prod").unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let names: Vec<&str> = resolved.current_deployment.configs[0].files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["grafana.ini", "provisioning/dashboards/app.json"]);
+        let DeploymentEnvType::Docker(docker_spec) = &env.env_type else { unreachable!() };
+        let output_dir = dir.path().join("out");
+        let files = render(&resolved, docker_spec, &output_dir).unwrap();
+        assert_eq!(files.get(output_dir.join("configs/shop-grafana/provisioning/dashboards/app.json")), Some("{}"));
+
+        fs::write(dir.path().join("envspec.yaml"), env_spec("k8s")).unwrap();
+        let env = spec_loader::load_env_spec(dir.path()).unwrap();
+        let resolved = resolver::resolve(&env, &app_spec, "prod").unwrap();
+        let files = crate::k8s_generator::render(&resolved, &output_dir).unwrap();
+        let configmap = files.get(output_dir.join("configmap-shop-grafana.yaml")).unwrap();
+        assert!(configmap.contains("  provisioning__dashboards__app.json: e30="), "{}", configmap);
+        let deployment = files.get(output_dir.join("deployment-grafana.yaml")).unwrap();
+        assert!(deployment.contains("          items:\n          - key: grafana.ini\n            path: grafana.ini\n          - key: provisioning__dashboards__app.json\n            path: provisioning/dashboards/app.json"), "{}", deployment);
+    }
+
+    #[test]
+    fn renders_identical_output_on_every_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_spec = |env_type: &str, extra: &str| test_support::env_spec(env_type, &format!(r#"
+{extra}
+gateway:
+  hosts:
+    admin: admin.example.com
+  tls:
+    letsencrypt:
+      email: ops@example.com
+deployments:
+  prod:
+    environment:
+      - ZONE=eu
+      - API_KEY=key
+      - LOG_LEVEL=info
+    configs:
+      nginx: conf
+      app: conf
+    services:
+      web:
+        prefix: /
+      api:
+        prefix: /api
+      admin:
+        host: admin
+        prefix: /
+"#));
+        fs::create_dir(dir.path().join("conf")).unwrap();
+        for name in ["b.conf", "a.conf", "c.conf"] {
+            fs::write(dir.path().join("conf").join(name), name).unwrap();
+        }
+        fs::write(dir.path().join("appspec.yaml"), test_support::app_spec(r#"
+environment:
+  external:
+    - ZONE
+    - API_KEY
+    - LOG_LEVEL
+configs:
+  nginx:
+    - a.conf
+  app:
+    - b.conf
+app_services:
+  web:
+    type: public
+    image: acme/web
+    environment:
+      - $all
+  api:
+    type: public
+    image: acme/api
+    configs:
+      - app: /etc/app
+    environment:
+      - $all
+  admin:
+    type: public
+    image: acme/admin
+extra_services:
+  worker:
+    type: internal
+    image: acme/worker
+  cache:
+    type: internal
+    image: acme/cache
+"#)).unwrap();
+
+        for (env_type, extra) in [("docker", "swarm_mode: true"), ("docker", ""), ("k8s", "")] {
+            fs::write(dir.path().join("envspec.yaml"), env_spec(env_type, extra)).unwrap();
+            let render_once = || {
+                // fresh specs, so every map is built and iterated anew
+                let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+                let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env_spec)).unwrap();
+                let resolved = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+                let output_dir = dir.path().join("out");
+                match &env_spec.env_type {
+                    DeploymentEnvType::Docker(docker_spec) => rendered(render(&resolved, docker_spec, &output_dir).unwrap()),
+                    _ => rendered(crate::k8s_generator::render(&resolved, &output_dir).unwrap()),
+                }
+            };
+            let first = render_once();
+            for _ in 0..5 {
+                assert_eq!(render_once(), first, "{} {}", env_type, extra);
+            }
+        }
+    }
 }
@@ -1,9 +1,18 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use anyhow::{Context, Result, anyhow};
+use crate::diagnostics;
 use crate::spec::EnvVariable;
 
+// `--allow-missing-env`: an unset `${env:NAME}` becomes an empty value
+static ALLOW_MISSING_ENV: AtomicBool = AtomicBool::new(false);
+
+pub fn set_allow_missing_env(allow: bool) {
+    ALLOW_MISSING_ENV.store(allow, Ordering::Relaxed);
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct EnvDescriptor {
     pub name: String,
@@ -54,14 +63,54 @@ pub fn parse_env_string(input: &str) -> Result<EnvDescriptor> {
     }
 }
 
+/// Parses a deployment's `NAME=value` entry. `${env:NAME}` in the value is
+/// replaced with the variable of the simpled process.
 pub fn parse_env_variable(input: &str) -> Result<EnvVariable> {
     let desc = parse_env_string(input)?;
+    let value = desc.default.ok_or_else(|| anyhow!("No value for env variable: {}", input))?;
     Ok(EnvVariable {
         name: desc.name,
-        value: desc.default.ok_or_else(|| anyhow!("No value for env variable: {}", input))?,
+        value: expand_process_env(&value, |name| std::env::var(name).ok())?,
+        sensitive: false,
     })
 }
 
+// Other `${...}` references, and `$${env:NAME}`, are left to the resolver.
+fn expand_process_env(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut result = String::new();
+    let mut last_end = 0;
+
+    while let Some(start) = value[last_end..].find("${env:") {
+        let absolute_start = last_end + start;
+        let escaped = absolute_start > 0 && value.as_bytes()[absolute_start - 1] == b'$';
+        let Some(end_offset) = value[absolute_start..].find('}') else {
+            return Err(anyhow!("Invalid variable reference: {}", value));
+        };
+        let absolute_end = absolute_start + end_offset;
+        result.push_str(&value[last_end..absolute_start]);
+
+        if escaped {
+            result.push_str(&value[absolute_start..=absolute_end]);
+        } else {
+            let name = &value[absolute_start + "${env:".len()..absolute_end];
+            match lookup(name) {
+                Some(v) => result.push_str(&v),
+                None if ALLOW_MISSING_ENV.load(Ordering::Relaxed) => {
+                    diagnostics::warn("env_not_set", format!("Environment variable {} is not set, using an empty value", name));
+                }
+                None => return Err(anyhow!(
+                    "Environment variable {} is not set (referenced by ${{env:{}}}, use --allow-missing-env to leave it empty)",
+                    name, name
+                )),
+            }
+        }
+        last_end = absolute_end + 1;
+    }
+
+    result.push_str(&value[last_end..]);
+    Ok(result)
+}
+
 pub fn load_env_file<P: AsRef<Path>>(path: P) -> Result<Vec<EnvVariable>> {
     let path_ref = path.as_ref();
     let file = File::open(path_ref).context(format!("Failed to open .env file: {:?}", path_ref))?;
@@ -135,12 +184,23 @@ mod tests {
 
         let vars = load_env_file(file.path())?;
         assert_eq!(vars.len(), 2);
-        assert_eq!(vars[0], EnvVariable { name: "FOO".to_string(), value: "bar".to_string() });
-        assert_eq!(vars[1], EnvVariable { name: "BAZ".to_string(), value: "qux".to_string() });
+        assert_eq!(vars[0], EnvVariable { name: "FOO".to_string(), value: "bar".to_string(), sensitive: false });
+        assert_eq!(vars[1], EnvVariable { name: "BAZ".to_string(), value: "qux".to_string(), sensitive: false });
 
         Ok(())
     }
 
+    #[test]
+    fn test_expand_process_env() {
+        let lookup = |name: &str| (name == "GIT_SHA").then(|| "abc123".to_string());
+        assert_eq!(expand_process_env("v-${env:GIT_SHA}-${LOCAL}", lookup).unwrap(), "v-abc123-${LOCAL}");
+        assert_eq!(expand_process_env("$${env:GIT_SHA}", lookup).unwrap(), "$${env:GIT_SHA}");
+
+        let err = expand_process_env("${env:BUILD_NUMBER}", lookup).unwrap_err();
+        assert!(err.to_string().contains("BUILD_NUMBER is not set"), "{}", err);
+        assert!(expand_process_env("${env:GIT_SHA", lookup).is_err());
+    }
+
     #[test]
     fn test_load_env_file_error() -> Result<()> {
         let mut file = NamedTempFile::new()?;
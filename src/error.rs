@@ -0,0 +1,152 @@
+use std::fmt;
+
+/// Kind of failure, attached as anyhow context where a spec is loaded,
+/// validated, resolved or generated, or a bundle is transferred. Decides the
+/// exit code and the `--format json` error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Load,
+    Validation,
+    Resolution,
+    Generation,
+    Download,
+    Upload,
+}
+
+impl Failure {
+    /// The failure kind of an error, if any part of it was given one.
+    pub fn of(error: &anyhow::Error) -> Option<Failure> {
+        error.downcast_ref::<Failure>().copied()
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Failure::Load => "load_failed",
+            Failure::Validation => "validation_failed",
+            Failure::Resolution => "resolution_failed",
+            Failure::Generation => "generation_failed",
+            Failure::Download => "download_failed",
+            Failure::Upload => "upload_failed",
+        }
+    }
+
+    // 2: the specs are wrong, 3: a value they need is missing,
+    // 4: writing the output failed, 5: the bundle repo could not be reached
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Failure::Load | Failure::Validation => 2,
+            Failure::Resolution => 3,
+            Failure::Generation => 4,
+            Failure::Download | Failure::Upload => 5,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Load => "Failed to load specs",
+            Failure::Validation => "Validation failed",
+            Failure::Resolution => "Resolution failed",
+            Failure::Generation => "Generation failed",
+            Failure::Download => "Bundle download failed",
+            Failure::Upload => "Bundle upload failed",
+        })
+    }
+}
+
+/// A file the specs reference that does not exist. The specs themselves are
+/// fine, a value they need is missing, so it is a resolution failure even when
+/// found while the specs are loaded.
+#[derive(Debug)]
+pub struct MissingFile(pub String);
+
+impl fmt::Display for MissingFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for MissingFile {}
+
+/// Tags an error of loading specs: `Resolution` for a missing referenced
+/// file, `Load` otherwise.
+pub fn loading(error: anyhow::Error) -> anyhow::Error {
+    let failure = if error.downcast_ref::<MissingFile>().is_some() { Failure::Resolution } else { Failure::Load };
+    error.context(failure)
+}
+
+/// Exit code of a failed command: the one of its failure kind, 1 otherwise.
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    Failure::of(error).map_or(1, Failure::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{resolver, spec_loader};
+    use anyhow::Context;
+    use std::fs;
+
+    #[test]
+    fn malformed_yaml_and_missing_secret_exit_differently() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("envspec.yaml"), "type: docker\ngateway: [unclosed\n").unwrap();
+        let err = spec_loader::load_env_spec(dir.path()).unwrap_err();
+        assert_eq!(exit_code(&err), 2, "{:#}", err);
+
+        fs::write(dir.path().join("envspec.yaml"), r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+registry:
+  shop: reg.example.com
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    secrets:
+      db_password:
+        env: SIMPLED_TEST_UNSET_DB_PASSWORD
+"#).unwrap();
+        fs::write(dir.path().join("appspec.yaml"), "name: shop\nversion: 1.0.0\nsecrets:\n  - db_password\n").unwrap();
+        let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_spec = spec_loader::load_app_spec(dir.path(), Some(&env_spec)).unwrap();
+        let err = resolver::resolve(&env_spec, &app_spec, "prod").context("Deploying prod").unwrap_err();
+        assert_eq!(exit_code(&err), 3, "{:#}", err);
+        assert_eq!(exit_code(&anyhow::anyhow!("other")), 1);
+    }
+
+    #[test]
+    fn missing_referenced_files_are_resolution_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_spec = |entries: &str| format!(r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+{}"#, entries);
+        for entries in [
+            "    configs:\n      app: ./missing.yaml\n",
+            "    environment:\n      - DB_URL:\n          file: ./db_url.txt\n",
+            "    secrets_folder: ./secrets\n    secrets:\n      db_password:\n",
+        ] {
+            fs::write(dir.path().join("envspec.yaml"), env_spec(entries)).unwrap();
+            let err = spec_loader::load_env_spec(dir.path()).unwrap_err();
+            assert_eq!(exit_code(&err), 3, "{:#}", err);
+        }
+        fs::write(dir.path().join("envspec.yaml"), env_spec("    unknown_field: true\n")).unwrap();
+        assert_eq!(exit_code(&spec_loader::load_env_spec(dir.path()).unwrap_err()), 2);
+    }
+}
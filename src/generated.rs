@@ -0,0 +1,158 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use crate::diagnostics;
+
+/// What to do with an existing output file whose content differs from the
+/// generated one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnConflict {
+    #[default]
+    Overwrite,
+    // keep the existing file and report it
+    Skip,
+    // write nothing if any file would change
+    Fail,
+}
+
+impl OnConflict {
+    pub fn parse(value: &str) -> Result<OnConflict> {
+        match value {
+            "overwrite" => Ok(OnConflict::Overwrite),
+            "skip" => Ok(OnConflict::Skip),
+            "fail" => Ok(OnConflict::Fail),
+            other => bail!("Unknown --on-conflict value: {} (expected overwrite, skip or fail)", other),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GeneratedFile {
+    pub path: PathBuf,
+    pub content: Vec<u8>,
+    // scripts are made executable on unix
+    pub executable: bool,
+}
+
+/// Output of a generator, rendered in memory and written by `commit` once
+/// everything rendered successfully.
+#[derive(Debug, Default)]
+pub struct GeneratedFiles {
+    pub files: Vec<GeneratedFile>,
+    // directories created even when no file is written into them
+    pub dirs: Vec<PathBuf>,
+}
+
+impl GeneratedFiles {
+    pub fn add(&mut self, path: impl Into<PathBuf>, content: impl Into<Vec<u8>>) {
+        self.files.push(GeneratedFile { path: path.into(), content: content.into(), executable: false });
+    }
+
+    pub fn add_executable(&mut self, path: impl Into<PathBuf>, content: impl Into<Vec<u8>>) {
+        self.files.push(GeneratedFile { path: path.into(), content: content.into(), executable: true });
+    }
+
+    pub fn add_dir(&mut self, path: impl Into<PathBuf>) {
+        self.dirs.push(path.into());
+    }
+
+    /// Content of the file generated at `path`, if any.
+    #[cfg(test)]
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&str> {
+        self.files.iter()
+            .find(|f| f.path == path.as_ref())
+            .map(|f| std::str::from_utf8(&f.content).unwrap_or_default())
+    }
+
+    /// Existing files whose content differs from the generated one.
+    pub fn conflicts(&self) -> Vec<&Path> {
+        self.files.iter()
+            .filter(|f| fs::read(&f.path).is_ok_and(|existing| existing != f.content))
+            .map(|f| f.path.as_path())
+            .collect()
+    }
+
+    /// Writes the files to disk, creating directories as needed. Differing
+    /// existing files are handled as `on_conflict` says; `Fail` checks all of
+    /// them before writing anything.
+    pub fn commit(self, on_conflict: OnConflict) -> Result<()> {
+        let skipped: Vec<PathBuf> = match on_conflict {
+            OnConflict::Overwrite => Vec::new(),
+            OnConflict::Skip => self.conflicts().into_iter().map(Path::to_path_buf).collect(),
+            OnConflict::Fail => {
+                let conflicts = self.conflicts();
+                if !conflicts.is_empty() {
+                    let list: Vec<String> = conflicts.iter().map(|p| format!("  {}", p.display())).collect();
+                    bail!(
+                        "{} existing file(s) differ from the generated ones, nothing was written (use --on-conflict overwrite or skip):\n{}",
+                        conflicts.len(),
+                        list.join("\n")
+                    );
+                }
+                Vec::new()
+            }
+        };
+
+        for dir in &self.dirs {
+            fs::create_dir_all(dir).context(format!("Failed to create directory {:?}", dir))?;
+        }
+        for file in &self.files {
+            if skipped.contains(&file.path) {
+                diagnostics::warn("file_skipped", format!("{} differs from the generated file, left unchanged", file.path.display()));
+                continue;
+            }
+            write_file(file).context(format!("Failed to write {:?}", file.path))?;
+        }
+        Ok(())
+    }
+}
+
+fn write_file(file: &GeneratedFile) -> Result<()> {
+    if let Some(parent) = file.path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&file.path, &file.content)?;
+
+    #[cfg(unix)]
+    if file.executable {
+        let mut perms = fs::metadata(&file.path)?.permissions();
+        perms.set_mode(0o755);
+        fs::set_permissions(&file.path, perms)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(dir: &Path) -> GeneratedFiles {
+        let mut files = GeneratedFiles::default();
+        files.add(dir.join("a.yaml"), "a: generated\n");
+        files.add(dir.join("sub/b.yaml"), "b: generated\n");
+        files
+    }
+
+    #[test]
+    fn differing_files_are_overwritten_skipped_or_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.yaml"), "a: hand-edited\n").unwrap();
+
+        let err = files(dir.path()).commit(OnConflict::Fail).unwrap_err();
+        assert!(format!("{:#}", err).contains("a.yaml"), "{:#}", err);
+        assert!(!dir.path().join("sub/b.yaml").exists());
+
+        files(dir.path()).commit(OnConflict::Skip).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("a.yaml")).unwrap(), "a: hand-edited\n");
+        assert_eq!(fs::read_to_string(dir.path().join("sub/b.yaml")).unwrap(), "b: generated\n");
+
+        files(dir.path()).commit(OnConflict::Overwrite).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("a.yaml")).unwrap(), "a: generated\n");
+
+        // identical content is not a conflict
+        files(dir.path()).commit(OnConflict::Fail).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::{Result, bail};
+use std::fmt;
+
+/// Docker image reference: `[registry/]namespace/name[:tag][@digest]`,
+/// following the rules of the distribution reference grammar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    // registry host with optional port, when the first component names one
+    pub registry: Option<String>,
+    // path components after the registry, e.g. `acme/api`
+    pub path: String,
+    pub tag: Option<String>,
+    // `algorithm:hex`, e.g. `sha256:...`
+    pub digest: Option<String>,
+}
+
+impl ImageRef {
+    pub fn parse(input: &str) -> Result<ImageRef> {
+        if input.is_empty() {
+            bail!("Image reference is empty");
+        }
+        let (rest, digest) = match input.split_once('@') {
+            Some((rest, digest)) => {
+                check_digest(input, digest)?;
+                (rest, Some(digest.to_string()))
+            }
+            None => (input, None),
+        };
+
+        // a `:` after the last `/` starts the tag; before it, it is a registry port
+        let name_start = rest.rfind('/').map_or(0, |i| i + 1);
+        let (name, tag) = match rest[name_start..].find(':') {
+            Some(i) => {
+                let tag = &rest[name_start + i + 1..];
+                check_tag(input, tag)?;
+                (&rest[..name_start + i], Some(tag.to_string()))
+            }
+            None => (rest, None),
+        };
+
+        let (registry, path) = match name.split_once('/') {
+            Some((first, path)) if first.contains(['.', ':']) || first == "localhost" => {
+                check_registry(input, first)?;
+                (Some(first.to_string()), path)
+            }
+            _ => (None, name),
+        };
+        for component in path.split('/') {
+            check_path_component(input, component)?;
+        }
+
+        Ok(ImageRef { registry, path: path.to_string(), tag, digest })
+    }
+
+    /// The reference without its tag and digest.
+    pub fn repository(&self) -> String {
+        match &self.registry {
+            Some(registry) => format!("{}/{}", registry, self.path),
+            None => self.path.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.repository())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+// Lowercase alphanumerics separated by `.`, `_`, `__` or any number of `-`
+fn check_path_component(input: &str, component: &str) -> Result<()> {
+    if component.is_empty() {
+        bail!("Invalid image reference {:?}: empty name component", input);
+    }
+    if let Some(c) = component.chars().find(|c| !matches!(c, 'a'..='z' | '0'..='9' | '.' | '_' | '-')) {
+        bail!("Invalid image reference {:?}: {:?} is not allowed in an image name, use lowercase letters, digits, '.', '_' and '-'", input, c);
+    }
+    let separators_ok = component.split(|c: char| c.is_ascii_alphanumeric())
+        .all(|sep| sep.is_empty() || sep == "." || sep == "_" || sep == "__" || sep.bytes().all(|b| b == b'-'));
+    let ends_ok = component.starts_with(|c: char| c.is_ascii_alphanumeric()) && component.ends_with(|c: char| c.is_ascii_alphanumeric());
+    if !separators_ok || !ends_ok {
+        bail!("Invalid image reference {:?}: name component {:?} must start and end with a letter or digit and use single separators", input, component);
+    }
+    Ok(())
+}
+
+fn check_registry(input: &str, registry: &str) -> Result<()> {
+    let (host, port) = match registry.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (registry, None),
+    };
+    let host_ok = host.split('.').all(|label| {
+        !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            && !label.starts_with('-') && !label.ends_with('-')
+    });
+    let port_ok = port.is_none_or(|port| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()));
+    if !host_ok || !port_ok {
+        bail!("Invalid image reference {:?}: {:?} is not a valid registry host", input, registry);
+    }
+    Ok(())
+}
+
+// Up to 128 of letters, digits, `_`, `.` and `-`, not starting with `.` or `-`
+fn check_tag(input: &str, tag: &str) -> Result<()> {
+    if tag.is_empty() {
+        bail!("Invalid image reference {:?}: empty tag after ':'", input);
+    }
+    let valid = tag.len() <= 128
+        && tag.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'-'))
+        && !tag.starts_with(['.', '-']);
+    if !valid {
+        bail!("Invalid image reference {:?}: tag {:?} may only use letters, digits, '_', '.' and '-' (at most 128, not starting with '.' or '-')", input, tag);
+    }
+    Ok(())
+}
+
+fn check_digest(input: &str, digest: &str) -> Result<()> {
+    let valid = match digest.split_once(':') {
+        Some((algorithm, hex)) => !algorithm.is_empty()
+            && algorithm.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'+' | b'.' | b'_' | b'-'))
+            && hex.len() >= 32
+            && hex.bytes().all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase()),
+        None => false,
+    };
+    if !valid {
+        bail!("Invalid image reference {:?}: digest {:?} must look like sha256:<64 hex digits>", input, digest);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_rejects_image_references() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let pinned = format!("postgres:16@{}", digest);
+        for (input, registry, path, tag, digest) in [
+            ("acme/api", None, "acme/api", None, None),
+            ("redis:7-alpine", None, "redis", Some("7-alpine"), None),
+            ("reg.example.com:5000/acme/api-v2:1.2.3", Some("reg.example.com:5000"), "acme/api-v2", Some("1.2.3"), None),
+            ("localhost/my_org/web__app", Some("localhost"), "my_org/web__app", None, None),
+            (pinned.as_str(), None, "postgres", Some("16"), Some(digest.as_str())),
+        ] {
+            let image = ImageRef::parse(input).unwrap();
+            assert_eq!((image.registry.as_deref(), image.path.as_str(), image.tag.as_deref(), image.digest.as_deref()), (registry, path, tag, digest), "{}", input);
+            assert_eq!(image.to_string(), input);
+        }
+
+        assert_eq!(ImageRef::parse("MyOrg/api").unwrap_err().to_string(),
+            "Invalid image reference \"MyOrg/api\": 'M' is not allowed in an image name, use lowercase letters, digits, '.', '_' and '-'");
+        assert_eq!(ImageRef::parse("acme/api:").unwrap_err().to_string(), "Invalid image reference \"acme/api:\": empty tag after ':'");
+        for input in ["acme/api :1.2", "acme//api", "acme/-api", "acme/a..b", "acme/api:-x", "acme/api@sha256:xyz", "acme/api@md5", "reg.example.com:port/api", ""] {
+            assert!(ImageRef::parse(input).is_err(), "{}", input);
+        }
+    }
+}
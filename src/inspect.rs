@@ -0,0 +1,269 @@
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use crate::resolved_spec::{DefaultBackendResolvedSpec, EnvironmentResolvedSpec, MASK, ServiceResolvedSpec};
+use crate::spec::{EnvVariable, SecretMount, ServiceType, ServiceVolumeType};
+
+/// Prints the resolved spec of a deployment as `table`, `json` or `yaml`,
+/// optionally limited to one service. Secret values, including env variables
+/// they were substituted into, are masked unless `show_secrets` is set.
+pub fn print(spec: &mut EnvironmentResolvedSpec, service: Option<&str>, format: &str, show_secrets: bool) -> Result<()> {
+    if let Some(name) = service {
+        let deployment = &mut spec.current_deployment;
+        if let Some(skipped) = deployment.skipped_services.iter().find(|s| s.name == name) {
+            bail!("Service {} is skipped in deployment {}: {}", name, deployment.name, skipped.reason);
+        }
+        if !deployment.services.iter().any(|s| s.full_name == name) {
+            let available: Vec<&str> = deployment.services.iter().map(|s| s.full_name.as_str()).collect();
+            bail!("Service {} not found in deployment {}. Available services: {}", name, deployment.name, available.join(", "));
+        }
+        deployment.services.retain(|s| s.full_name == name);
+        deployment.skipped_services.clear();
+        for rule in &mut spec.ingress.rules {
+            rule.services.retain(|s| s.service_name == name);
+        }
+        spec.ingress.rules.retain(|r| !r.services.is_empty());
+    }
+    if !show_secrets {
+        mask_env_secrets(spec);
+    }
+
+    let output = match format {
+        "table" => table(spec, show_secrets)?,
+        "json" => serde_json::to_string_pretty(&Inspected::new(spec, show_secrets))? + "\n",
+        "yaml" => serde_yaml::to_string(&Inspected::new(spec, show_secrets))?,
+        other => bail!("Unknown format: {} (expected table, json or yaml)", other),
+    };
+    print!("{}", output);
+    Ok(())
+}
+
+/// Writes the resolved spec to `path` as JSON, in the shape printed by
+/// `inspect --format json`, for tools that consume a deployment without
+/// resolving it themselves. Secret values are masked unless
+/// `include_secret_values` is set.
+pub fn emit(spec: &EnvironmentResolvedSpec, path: &Path, include_secret_values: bool) -> Result<()> {
+    let mut spec = spec.clone();
+    if !include_secret_values {
+        mask_env_secrets(&mut spec);
+    }
+    let json = serde_json::to_string_pretty(&Inspected::new(&spec, include_secret_values))? + "\n";
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).context(format!("Failed to create directory {:?}", parent))?;
+    }
+    fs::write(path, json).context(format!("Failed to write resolved spec to {:?}", path))
+}
+
+// The resolved spec as printed by json/yaml; secret values are listed
+// separately because SecretResolvedSpec always serializes masked.
+#[derive(Serialize)]
+struct Inspected<'a> {
+    #[serde(flatten)]
+    spec: &'a EnvironmentResolvedSpec,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_values: Option<BTreeMap<&'a str, &'a str>>,
+}
+
+impl<'a> Inspected<'a> {
+    fn new(spec: &'a EnvironmentResolvedSpec, show_secrets: bool) -> Self {
+        let secret_values = show_secrets.then(|| {
+            spec.current_deployment.secrets.iter().map(|s| (s.name.as_str(), s.value.as_str())).collect()
+        });
+        Inspected { spec, secret_values }
+    }
+}
+
+// Env values that embed a secret (e.g. through `$secret(name)`) are masked
+// as a whole, the rest of such a value may be just as sensitive.
+pub fn mask_env_secrets(spec: &mut EnvironmentResolvedSpec) {
+    let secrets: Vec<&str> = spec.current_deployment.secrets.iter()
+        .map(|s| s.value.as_str())
+        .filter(|v| !v.is_empty())
+        .collect();
+    let mask = |vars: &mut Vec<EnvVariable>| {
+        for var in vars.iter_mut().filter(|v| v.sensitive || secrets.iter().any(|secret| v.value.contains(secret))) {
+            var.value = MASK.to_string();
+        }
+    };
+    for service in &mut spec.current_deployment.services {
+        mask(&mut service.environment_variables);
+        mask(&mut service.undockerized_environment_variables);
+    }
+}
+
+fn table(spec: &EnvironmentResolvedSpec, show_secrets: bool) -> Result<String> {
+    let deployment = &spec.current_deployment;
+    let mut out = String::new();
+    writeln!(out, "Deployment {} (app {})", deployment.name, deployment.application_name)?;
+
+    for service in &deployment.services {
+        writeln!(out)?;
+        service_table(&mut out, service)?;
+    }
+
+    if !deployment.skipped_services.is_empty() {
+        writeln!(out, "\nSkipped services")?;
+        for skipped in &deployment.skipped_services {
+            writeln!(out, "  {}: {}", skipped.name, skipped.reason)?;
+        }
+    }
+
+    if show_secrets && !deployment.secrets.is_empty() {
+        writeln!(out, "\nSecrets")?;
+        for secret in &deployment.secrets {
+            writeln!(out, "  {} = {}", secret.name, secret.value)?;
+        }
+    }
+
+    if !spec.ingress.rules.is_empty() {
+        writeln!(out, "\nGateway {}", spec.ingress.name)?;
+        for rule in &spec.ingress.rules {
+            if rule.maintenance {
+                writeln!(out, "  {} is in maintenance", rule.domain_name)?;
+            }
+            for target in rule.active_services() {
+                let strip = if target.strip_prefix { " (prefix stripped)" } else { "" };
+                writeln!(out, "  {}{} -> {}:{}{}", rule.domain_name, target.prefix, target.service_name, target.port, strip)?;
+            }
+            match rule.fallback() {
+                Some(DefaultBackendResolvedSpec::Service(target)) => writeln!(out, "  {} (default) -> {}:{}", rule.domain_name, target.service_name, target.port)?,
+                Some(DefaultBackendResolvedSpec::Page(_)) => writeln!(out, "  {} (default) -> static page", rule.domain_name)?,
+                None => {}
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn service_table(out: &mut String, service: &ServiceResolvedSpec) -> Result<()> {
+    let service_type = match service.service_type {
+        ServiceType::Public => "public",
+        ServiceType::Internal => "internal",
+        ServiceType::Job => "job",
+    };
+    writeln!(out, "Service {} ({})", service.full_name, service_type)?;
+    let overridden = if service.image_override { " (overridden by the deployment)" } else { "" };
+    writeln!(out, "  image: {}{}", service.image, overridden)?;
+    if let Some(profile) = &service.profile {
+        writeln!(out, "  profile: {}", profile)?;
+    }
+
+    if !service.environment_variables.is_empty() {
+        writeln!(out, "  env:")?;
+        for var in &service.environment_variables {
+            writeln!(out, "    {}={}", var.name, var.value)?;
+        }
+    }
+    if !service.configs.is_empty() {
+        writeln!(out, "  configs:")?;
+        for config in &service.configs {
+            writeln!(out, "    {} -> {}", config.config_name, config.mount_path)?;
+        }
+    }
+    if !service.secrets.is_empty() {
+        writeln!(out, "  secrets:")?;
+        for secret in &service.secrets {
+            match &secret.mount {
+                SecretMount::FilePath(path) => writeln!(out, "    {} -> {}", secret.name, path)?,
+                SecretMount::EnvVariable(var) => writeln!(out, "    {} -> env {}", secret.name, var)?,
+            }
+        }
+    }
+    if !service.ports.is_empty() {
+        let ports: Vec<String> = service.ports.iter().map(|p| p.mapping()).collect();
+        writeln!(out, "  ports: {}", ports.join(", "))?;
+    }
+    if !service.volumes.is_empty() {
+        writeln!(out, "  volumes:")?;
+        for volume in &service.volumes {
+            let source = match &volume.name {
+                ServiceVolumeType::Named(name) | ServiceVolumeType::Path(name) => name,
+            };
+            writeln!(out, "    {} -> {}", source, volume.mount_path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{resolver, spec_yaml, transform};
+
+    fn resolved() -> EnvironmentResolvedSpec {
+        let env_yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_str(r#"
+type: local
+gateway:
+  hosts:
+    web: localhost:8080
+deployments:
+  dev:
+    primary_host: web
+    application:
+      name: shop
+    environment:
+      - DB_URL=postgres://app:$secret(db_password)@db/app
+      - LOG_LEVEL=debug
+    secrets:
+      db_password: hunter2
+    services:
+      api:
+        host: web
+        prefix: /api
+        ports:
+          - "8081:80"
+"#).unwrap();
+        let app_yaml: spec_yaml::AppSpecYaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+environment:
+  external:
+    - DB_URL
+    - LOG_LEVEL
+secrets:
+  - db_password
+app_services:
+  api:
+    type: public
+    image: shop/api
+    environment:
+      - DB_URL
+      - LOG_LEVEL
+"#).unwrap();
+
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = transform::convert_env_spec(env_yaml, root.path()).unwrap();
+        let app_spec = transform::convert_app_spec(app_yaml, Some(&env_spec)).unwrap();
+        resolver::resolve(&env_spec, &app_spec, "dev").unwrap()
+    }
+
+    #[test]
+    fn secrets_are_masked_unless_requested() {
+        let mut spec = resolved();
+        mask_env_secrets(&mut spec);
+        let json = serde_json::to_string(&Inspected::new(&spec, false)).unwrap();
+        assert!(!json.contains("hunter2"), "{}", json);
+        assert!(json.contains("LOG_LEVEL") && json.contains("debug"));
+        assert!(!table(&spec, false).unwrap().contains("hunter2"));
+
+        let spec = resolved();
+        let json = serde_json::to_string(&Inspected::new(&spec, true)).unwrap();
+        assert!(json.contains(r#""secret_values":{"shop-db_password":"hunter2"}"#), "{}", json);
+    }
+
+    #[test]
+    fn emitted_spec_matches_the_golden_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out/resolved.json");
+        emit(&resolved(), &path, false).unwrap();
+        let emitted = fs::read_to_string(&path).unwrap();
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/resolved.json"), &emitted).unwrap();
+        }
+        assert_eq!(emitted, include_str!("../tests/golden/resolved.json"));
+    }
+}
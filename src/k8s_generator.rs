@@ -1,43 +1,51 @@
-use crate::resolved_spec::{EnvironmentResolvedSpec, LetsEncryptResolvedSpec};
-use crate::spec::{parse_duration_secs, Healthcheck, SecretMount};
-use anyhow::Result;
-use std::fs::File;
+use crate::resolved_spec::{DefaultBackendResolvedSpec, DeploymentResolvedSpec, EnvironmentResolvedSpec, ServiceResolvedSpec, IngressToServiceRule, LetsEncryptResolvedSpec};
+use crate::generated::GeneratedFiles;
+use crate::spec::{parse_duration_secs, CorsSpec, Dns01Provider, parse_numeric_user, Healthcheck, PortProtocol, SecretMount};
+use anyhow::{Result, anyhow};
 use std::io::Write;
 use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
 use base64::{Engine as _, engine::general_purpose};
 
 const LETSENCRYPT_ISSUER: &str = "letsencrypt-prod";
+// Pod template annotation that changes with the configs and secrets a
+// service uses, so a rollout restarts its pods exactly when they change
+const CONFIG_CHECKSUM_ANNOTATION: &str = "simpled/config-checksum";
 
-pub fn generate(
+/// Renders the manifests into memory; nothing is written to `output_dir`
+/// until the result is committed.
+pub fn render(
     resolved_spec: &EnvironmentResolvedSpec,
     output_dir: &Path,
-) -> Result<()> {
-    if !output_dir.exists() {
-        std::fs::create_dir_all(output_dir)?;
-    }
+) -> Result<GeneratedFiles> {
+    let mut files = GeneratedFiles::default();
+    files.add_dir(output_dir);
 
     let deployment = &resolved_spec.current_deployment;
 
     // 1. ConfigMaps
     for config in &deployment.configs {
         let file_name = output_dir.join(format!("configmap-{}.yaml", config.name));
-        let mut file = File::create(file_name)?;
+        let mut file = Vec::new();
         writeln!(file, "apiVersion: v1")?;
         writeln!(file, "kind: ConfigMap")?;
         writeln!(file, "metadata:")?;
         writeln!(file, "  name: {}", config.name)?;
         writeln!(file, "binaryData:")?;
-        for cfg_file in &config.files {
+        for (i, cfg_file) in config.files.iter().enumerate() {
+             if let Some(other) = config.files[..i].iter().find(|f| configmap_key(&f.name) == configmap_key(&cfg_file.name)) {
+                 return Err(anyhow!("Config {}: files {} and {} would share the ConfigMap key {}", config.name, other.name, cfg_file.name, configmap_key(&cfg_file.name)));
+             }
              let encoded = general_purpose::STANDARD.encode(&cfg_file.content);
-             writeln!(file, "  {}: {}", cfg_file.name, encoded)?;
+             writeln!(file, "  {}: {}", configmap_key(&cfg_file.name), encoded)?;
         }
+        files.add(file_name, file);
     }
 
     // 2. Secrets
     for secret in &deployment.secrets {
         let file_name = output_dir.join(format!("secret-{}.yaml", secret.name));
-        let mut file = File::create(file_name)?;
+        let mut file = Vec::new();
         writeln!(file, "apiVersion: v1")?;
         writeln!(file, "kind: Secret")?;
         writeln!(file, "metadata:")?;
@@ -46,12 +54,32 @@ pub fn generate(
         writeln!(file, "data:")?;
         let encoded = general_purpose::STANDARD.encode(&secret.value);
         writeln!(file, "  {}: {}", "value", encoded)?; 
+        files.add(file_name, file);
+    }
+
+    // Env vars embedding a secret, one Secret per service
+    for service in &deployment.services {
+        let sensitive: Vec<_> = service.environment_variables.iter().filter(|e| e.sensitive).collect();
+        if sensitive.is_empty() {
+            continue;
+        }
+        let mut file = Vec::new();
+        writeln!(file, "apiVersion: v1")?;
+        writeln!(file, "kind: Secret")?;
+        writeln!(file, "metadata:")?;
+        writeln!(file, "  name: {}", env_secret_name(&service.full_name))?;
+        writeln!(file, "type: Opaque")?;
+        writeln!(file, "data:")?;
+        for env in sensitive {
+            writeln!(file, "  {}: {}", env.name, general_purpose::STANDARD.encode(&env.value))?;
+        }
+        files.add(output_dir.join(format!("secret-{}.yaml", env_secret_name(&service.full_name))), file);
     }
 
     // 3. Deployments & Services
     for service in &deployment.services {
         let file_name = output_dir.join(format!("deployment-{}.yaml", service.full_name));
-        let mut file = File::create(file_name)?;
+        let mut file = Vec::new();
         
         // Deployment
         writeln!(file, "apiVersion: apps/v1")?;
@@ -59,7 +87,7 @@ pub fn generate(
         writeln!(file, "metadata:")?;
         writeln!(file, "  name: {}", service.full_name)?;
         writeln!(file, "spec:")?;
-        writeln!(file, "  replicas: {}", deployment.defaults.replicas)?; 
+        writeln!(file, "  replicas: {}", service.resources.replicas)?;
         writeln!(file, "  selector:")?;
         writeln!(file, "    matchLabels:")?;
         writeln!(file, "      app: {}", service.full_name)?;
@@ -67,6 +95,10 @@ pub fn generate(
         writeln!(file, "    metadata:")?;
         writeln!(file, "      labels:")?;
         writeln!(file, "        app: {}", service.full_name)?;
+        if let Some(checksum) = config_checksum(service, deployment) {
+            writeln!(file, "      annotations:")?;
+            writeln!(file, "        {}: \"{}\"", CONFIG_CHECKSUM_ANNOTATION, checksum)?;
+        }
         writeln!(file, "    spec:")?;
         writeln!(file, "      containers:")?;
         writeln!(file, "      - name: {}", service.full_name)?;
@@ -86,6 +118,26 @@ pub fn generate(
                 writeln!(file, "        - \"{}\"", arg)?;
             }
         }
+        if !service.ports.is_empty() {
+            writeln!(file, "        ports:")?;
+            for port in &service.ports {
+                writeln!(file, "        - containerPort: {}", port.internal)?;
+                if port.protocol == PortProtocol::Udp {
+                    writeln!(file, "          protocol: UDP")?;
+                }
+            }
+        }
+        if let Some(working_dir) = &service.container_working_dir {
+            writeln!(file, "        workingDir: \"{}\"", working_dir)?;
+        }
+        // The validator only lets numeric `uid[:gid]` users through for k8s.
+        if let Some((uid, gid)) = service.user.as_deref().and_then(parse_numeric_user) {
+            writeln!(file, "        securityContext:")?;
+            writeln!(file, "          runAsUser: {}", uid)?;
+            if let Some(gid) = gid {
+                writeln!(file, "          runAsGroup: {}", gid)?;
+            }
+        }
         // docker-compose `healthcheck` maps to liveness/readiness probes.
         if let Some(hc) = &service.healthcheck {
             if let Some(argv) = hc.probe_argv() {
@@ -95,22 +147,25 @@ pub fn generate(
         }
         writeln!(file, "        resources:")?;
         writeln!(file, "          requests:")?;
-        writeln!(file, "            memory: {}", deployment.defaults.requests.memory)?;
-        writeln!(file, "            cpu: {}", deployment.defaults.requests.cpu)?;
+        writeln!(file, "            memory: {}", service.resources.requests.memory)?;
+        writeln!(file, "            cpu: {}", service.resources.requests.cpu)?;
         writeln!(file, "          limits:")?;
-        writeln!(file, "            memory: {}", deployment.defaults.limits.memory)?;
-        writeln!(file, "            cpu: {}", deployment.defaults.limits.cpu)?;
+        writeln!(file, "            memory: {}", service.resources.limits.memory)?;
+        writeln!(file, "            cpu: {}", service.resources.limits.cpu)?;
 
-        let deploy_date = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-        writeln!(file, "        env:")?;
-        writeln!(file, "        - name: DEPLOY_DATE")?;
-        writeln!(file, "          value: \"{}\"", deploy_date)?;
+        if !service.environment_variables.is_empty() || service.secrets.iter().any(|s| matches!(s.mount, SecretMount::EnvVariable(_))) {
+            writeln!(file, "        env:")?;
+        }
         for env in &service.environment_variables {
             writeln!(file, "        - name: {}", env.name)?;
-            writeln!(file, "          value: \"{}\"", env.value)?;
+            if env.sensitive {
+                writeln!(file, "          valueFrom:")?;
+                writeln!(file, "            secretKeyRef:")?;
+                writeln!(file, "              name: {}", env_secret_name(&service.full_name))?;
+                writeln!(file, "              key: {}", env.name)?;
+            } else {
+                writeln!(file, "          value: \"{}\"", env.value)?;
+            }
         }
         for secret in &service.secrets {
              if let SecretMount::EnvVariable(var_name) = &secret.mount {
@@ -148,6 +203,15 @@ pub fn generate(
                  writeln!(file, "      - name: config-{}", config.config_name)?;
                  writeln!(file, "        configMap:")?;
                  writeln!(file, "          name: {}", config.config_name)?;
+                 // files from subdirectories are projected back to their paths
+                 let config_files = deployment.configs.iter().find(|c| c.name == config.config_name).map(|c| c.files.as_slice()).unwrap_or_default();
+                 if config_files.iter().any(|f| f.name.contains('/')) {
+                     writeln!(file, "          items:")?;
+                     for cfg_file in config_files {
+                         writeln!(file, "          - key: {}", configmap_key(&cfg_file.name))?;
+                         writeln!(file, "            path: {}", cfg_file.name)?;
+                     }
+                 }
             }
             for secret in &service.secrets {
                  if !matches!(secret.mount, SecretMount::EnvVariable(_)) {
@@ -160,7 +224,7 @@ pub fn generate(
         
         // Service
         let svc_file_name = output_dir.join(format!("service-{}.yaml", service.full_name));
-        let mut svc_file = File::create(svc_file_name)?;
+        let mut svc_file = Vec::new();
         writeln!(svc_file, "apiVersion: v1")?;
         writeln!(svc_file, "kind: Service")?;
         writeln!(svc_file, "metadata:")?;
@@ -170,22 +234,33 @@ pub fn generate(
         writeln!(svc_file, "    app: {}", service.full_name)?;
         writeln!(svc_file, "  ports:")?;
         for port in &service.ports {
-            writeln!(svc_file, "  - port: {}", port.external)?;
+            writeln!(svc_file, "  - port: {}", port.service_port())?;
             writeln!(svc_file, "    targetPort: {}", port.internal)?;
+            if port.protocol == PortProtocol::Udp {
+                writeln!(svc_file, "    protocol: UDP")?;
+            }
         }
+        files.add(file_name, file);
+        files.add(svc_file_name, svc_file);
     }
 
     // 4. Ingress
-    generate_ingress(resolved_spec, output_dir)?;
+    files.add(output_dir.join("ingress.yaml"), generate_ingress(resolved_spec)?);
+    if let Some(cors) = generate_cors_ingresses(resolved_spec)? {
+        files.add(output_dir.join("ingress-cors.yaml"), cors);
+    }
+    if !resolved_spec.ingress.redirects.is_empty() {
+        files.add(output_dir.join("ingress-redirects.yaml"), generate_redirect_ingresses(resolved_spec)?);
+    }
 
     // 5. ClusterIssuer (if needed)
     if let Some(tls) = &resolved_spec.ingress.tls {
         if let Some(le) = &tls.letsencrypt {
-             generate_cluster_issuer(output_dir, le)?;
+             files.add(output_dir.join("cluster-issuer.yaml"), generate_cluster_issuer(le)?);
         }
     }
 
-    Ok(())
+    Ok(files)
 }
 
 /// Write a Kubernetes exec probe (`livenessProbe`/`readinessProbe`) built from a
@@ -193,7 +268,37 @@ pub fn generate(
 /// `retries` maps to `failureThreshold` and `start_period` to
 /// `initialDelaySeconds`. Fields with no compose counterpart are left to the
 /// Kubernetes defaults.
-fn write_probe(file: &mut File, name: &str, argv: &[String], hc: &Healthcheck) -> Result<()> {
+// Secret holding the env vars of a service that embed a secret
+// Checksum of the config files, secrets and secret env values `service`
+// reads; `None` when it reads none.
+fn config_checksum(service: &ServiceResolvedSpec, deployment: &DeploymentResolvedSpec) -> Option<String> {
+    let mut hasher = Sha256::new();
+    let mut used = false;
+    for config in deployment.configs.iter().filter(|c| service.configs.iter().any(|sc| sc.config_name == c.name)) {
+        for file in &config.files {
+            hasher.update(format!("config {} {} {}\n", config.name, file.name, file.content.len()));
+            hasher.update(&file.content);
+            used = true;
+        }
+    }
+    for secret in deployment.secrets.iter().filter(|s| service.secrets.iter().any(|ss| ss.name == s.name)) {
+        hasher.update(format!("secret {} {}\n", secret.name, secret.value.len()));
+        hasher.update(&secret.value);
+        used = true;
+    }
+    for env in service.environment_variables.iter().filter(|e| e.sensitive) {
+        hasher.update(format!("env {} {}\n", env.name, env.value.len()));
+        hasher.update(&env.value);
+        used = true;
+    }
+    used.then(|| hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn env_secret_name(service_name: &str) -> String {
+    format!("{}-env", service_name)
+}
+
+fn write_probe(file: &mut Vec<u8>, name: &str, argv: &[String], hc: &Healthcheck) -> Result<()> {
     writeln!(file, "        {}:", name)?;
     writeln!(file, "          exec:")?;
     writeln!(file, "            command:")?;
@@ -215,53 +320,108 @@ fn write_probe(file: &mut File, name: &str, argv: &[String], hc: &Healthcheck) -
     Ok(())
 }
 
-fn generate_ingress(
-    resolved_spec: &EnvironmentResolvedSpec,
-    output_dir: &Path
-) -> Result<()> {
-    let file_name = output_dir.join("ingress.yaml");
-    let mut file = File::create(file_name)?;
-    
+fn generate_ingress(resolved_spec: &EnvironmentResolvedSpec) -> Result<Vec<u8>> {
+    let mut file = Vec::new();
+    let (plain, _) = ingress_paths_by_cors(resolved_spec)?;
+    let domains: Vec<&String> = resolved_spec.ingress.tls_domains().collect();
+    write_ingress(&mut file, resolved_spec, &resolved_spec.ingress.name, None, &domains, &plain, true)?;
+    Ok(file)
+}
+
+/// Ingresses for the paths with CORS, one per distinct configuration, as the
+/// ingress-nginx CORS annotations apply to a whole Ingress. `None` when no
+/// path has CORS.
+fn generate_cors_ingresses(resolved_spec: &EnvironmentResolvedSpec) -> Result<Option<Vec<u8>>> {
+    let (_, groups) = ingress_paths_by_cors(resolved_spec)?;
+    if groups.is_empty() {
+        return Ok(None);
+    }
+    let mut file = Vec::new();
+    for (i, (cors, paths)) in groups.iter().enumerate() {
+        if i > 0 {
+            writeln!(file, "---")?;
+        }
+        let name = format!("{}-cors-{}", resolved_spec.ingress.name, i + 1);
+        let domains: Vec<&String> = paths.iter().map(|(domain, _)| *domain).filter(|d| resolved_spec.ingress.domain_tls(d)).collect();
+        write_ingress(&mut file, resolved_spec, &name, Some(cors), &domains, paths, false)?;
+    }
+    Ok(Some(file))
+}
+
+type DomainPaths<'a> = Vec<(&'a String, Vec<&'a IngressToServiceRule>)>;
+
+// Paths of the gateway rules without CORS, and those with it grouped by configuration
+fn ingress_paths_by_cors(resolved_spec: &EnvironmentResolvedSpec) -> Result<(DomainPaths<'_>, Vec<(&CorsSpec, DomainPaths<'_>)>)> {
+    fn add<'a>(paths: &mut DomainPaths<'a>, domain: &'a String, svc: &'a IngressToServiceRule) {
+        match paths.iter_mut().find(|(d, _)| *d == domain) {
+            Some((_, services)) => services.push(svc),
+            None => paths.push((domain, vec![svc])),
+        }
+    }
+    let mut plain: DomainPaths = Vec::new();
+    let mut groups: Vec<(&CorsSpec, DomainPaths)> = Vec::new();
+    for rule in &resolved_spec.ingress.rules {
+        if let Some(DefaultBackendResolvedSpec::Page(_)) = rule.fallback() {
+            return Err(anyhow!("Domain {}: a Kubernetes Ingress cannot serve a static page, which a page default_backend and maintenance without a default_backend need; point default_backend at a service", rule.domain_name));
+        }
+        for svc in rule.routed_services() {
+            match &svc.cors {
+                None => add(&mut plain, &rule.domain_name, svc),
+                Some(cors) => {
+                    let index = match groups.iter().position(|(c, _)| *c == cors) {
+                        Some(index) => index,
+                        None => {
+                            groups.push((cors, Vec::new()));
+                            groups.len() - 1
+                        }
+                    };
+                    add(&mut groups[index].1, &rule.domain_name, svc);
+                }
+            }
+        }
+    }
+    Ok((plain, groups))
+}
+
+// `main` is the gateway's own Ingress, which requests the certificates
+fn write_ingress(file: &mut Vec<u8>, resolved_spec: &EnvironmentResolvedSpec, name: &str, cors: Option<&CorsSpec>, tls_domains: &[&String], paths: &DomainPaths, main: bool) -> Result<()> {
     writeln!(file, "apiVersion: networking.k8s.io/v1")?;
     writeln!(file, "kind: Ingress")?;
     writeln!(file, "metadata:")?;
-    writeln!(file, "  name: {}", resolved_spec.ingress.name)?;
+    writeln!(file, "  name: {}", name)?;
     writeln!(file, "  annotations:")?;
     // Annotations for strip-prefix and cert-manager
     if let Some(tls) = &resolved_spec.ingress.tls {
-        if tls.letsencrypt.is_some() {
+        if main && tls.letsencrypt.is_some() {
             writeln!(file, "    cert-manager.io/cluster-issuer: {}", LETSENCRYPT_ISSUER)?;
         }
     }
     // Check if any rule needs strip-prefix
-    let needs_strip_prefix = resolved_spec.ingress.rules.iter().any(|r| r.services.iter().any(|s| s.strip_prefix));
+    let needs_strip_prefix = paths.iter().any(|(_, services)| services.iter().any(|s| s.strip_prefix));
     if needs_strip_prefix {
         writeln!(file, "    nginx.ingress.kubernetes.io/rewrite-target: /$2")?;
     }
+    if let Some(cors) = cors {
+        writeln!(file, "    nginx.ingress.kubernetes.io/enable-cors: \"true\"")?;
+        writeln!(file, "    nginx.ingress.kubernetes.io/cors-allow-origin: \"{}\"", cors.origins.join(", "))?;
+        writeln!(file, "    nginx.ingress.kubernetes.io/cors-allow-methods: \"{}\"", cors.methods.join(", "))?;
+        writeln!(file, "    nginx.ingress.kubernetes.io/cors-allow-headers: \"{}\"", cors.headers.join(", "))?;
+        writeln!(file, "    nginx.ingress.kubernetes.io/cors-allow-credentials: \"{}\"", cors.credentials)?;
+        writeln!(file, "    nginx.ingress.kubernetes.io/cors-max-age: \"{}\"", cors.max_age)?;
+    }
 
     writeln!(file, "spec:")?;
     writeln!(file, "  ingressClassName: nginx")?;
-    if let Some(tls) = &resolved_spec.ingress.tls {
-        writeln!(file, "  tls:")?;
-        writeln!(file, "  - hosts:")?;
-        for domain in &resolved_spec.ingress.domains {
-            writeln!(file, "    - {}", domain)?;
-        }
-        if let Some(secret) = &tls.secret {
-             writeln!(file, "    secretName: {}", secret)?;
-        } else if tls.letsencrypt.is_some() {
-             writeln!(file, "    secretName: {}--tls", resolved_spec.ingress.name)?;
-        }
-    }
+    write_ingress_tls(file, resolved_spec, tls_domains)?;
     
     writeln!(file, "  rules:")?;
 
-    for rule in &resolved_spec.ingress.rules {
-        writeln!(file, "  - host: {}", rule.domain_name)?;
+    for (domain, services) in paths {
+        writeln!(file, "  - host: {}", yaml_host(domain))?;
         writeln!(file, "    http:")?;
         writeln!(file, "      paths:")?;
         
-        for svc_rule in &rule.services {
+        for svc_rule in services {
              let path = if svc_rule.strip_prefix {
                  let trimmed = svc_rule.prefix.trim_end_matches('/');
                  format!("{}(/|$)(.*)", trimmed)
@@ -283,9 +443,86 @@ fn generate_ingress(
     Ok(())
 }
 
-fn generate_cluster_issuer(output_dir: &Path, le_spec: &LetsEncryptResolvedSpec) -> Result<()> {
-    let file_name = output_dir.join("cluster-issuer.yaml");
-    let mut file = File::create(file_name)?;
+// The certificate's secret, shared by every Ingress of the gateway
+fn write_ingress_tls(file: &mut Vec<u8>, resolved_spec: &EnvironmentResolvedSpec, domains: &[&String]) -> Result<()> {
+    let Some(tls) = resolved_spec.ingress.tls.as_ref().filter(|_| !domains.is_empty()) else {
+        return Ok(());
+    };
+    writeln!(file, "  tls:")?;
+    writeln!(file, "  - hosts:")?;
+    for domain in domains {
+        writeln!(file, "    - {}", yaml_host(domain))?;
+    }
+    if let Some(secret) = &tls.secret {
+         writeln!(file, "    secretName: {}", secret)?;
+    } else if tls.letsencrypt.is_some() {
+         writeln!(file, "    secretName: {}--tls", resolved_spec.ingress.name)?;
+    }
+    Ok(())
+}
+
+/// One Ingress per gateway redirect, as the ingress-nginx redirect annotation
+/// applies to a whole Ingress. Their rules need a backend, which the redirect
+/// never reaches, so they point at the first routed service. Certificates
+/// come from the main Ingress, whose TLS hosts include the redirect sources.
+fn generate_redirect_ingresses(resolved_spec: &EnvironmentResolvedSpec) -> Result<Vec<u8>> {
+    let ingress = &resolved_spec.ingress;
+    let backend = ingress.rules.iter().flat_map(|r| &r.services).next()
+        .ok_or_else(|| anyhow!("Gateway redirects need at least one service routed by the gateway for the Kubernetes Ingress to point at"))?;
+    let mut file = Vec::new();
+
+    for (i, redirect) in ingress.redirects.iter().enumerate() {
+        if i > 0 {
+            writeln!(file, "---")?;
+        }
+        let location = if redirect.preserve_path {
+            format!("{}$request_uri", redirect.target)
+        } else {
+            format!("{}/", redirect.target)
+        };
+        writeln!(file, "apiVersion: networking.k8s.io/v1")?;
+        writeln!(file, "kind: Ingress")?;
+        writeln!(file, "metadata:")?;
+        writeln!(file, "  name: {}-redirect-{}", ingress.name, redirect.from.replace(['.', ':'], "-"))?;
+        writeln!(file, "  annotations:")?;
+        writeln!(file, "    nginx.ingress.kubernetes.io/permanent-redirect: {}", location)?;
+        writeln!(file, "spec:")?;
+        writeln!(file, "  ingressClassName: nginx")?;
+        let domains: Vec<&String> = std::iter::once(&redirect.from).filter(|d| ingress.domain_tls(d)).collect();
+        write_ingress_tls(&mut file, resolved_spec, &domains)?;
+        writeln!(file, "  rules:")?;
+        writeln!(file, "  - host: {}", redirect.from)?;
+        writeln!(file, "    http:")?;
+        writeln!(file, "      paths:")?;
+        writeln!(file, "      - path: /")?;
+        writeln!(file, "        pathType: Prefix")?;
+        writeln!(file, "        backend:")?;
+        writeln!(file, "          service:")?;
+        writeln!(file, "            name: {}", backend.service_name)?;
+        writeln!(file, "            port:")?;
+        writeln!(file, "              number: {}", backend.port)?;
+    }
+
+    Ok(file)
+}
+
+// ConfigMap keys can't contain `/`, so a file from a config subdirectory is
+// keyed by its path with `__` between the parts
+fn configmap_key(name: &str) -> String {
+    name.replace('/', "__")
+}
+
+// A wildcard's leading `*` would start a YAML alias
+fn yaml_host(domain: &str) -> String {
+    if domain.starts_with('*') {
+        format!("\"{}\"", domain)
+    } else {
+        domain.to_string()
+    }
+}
+
+fn generate_cluster_issuer(le_spec: &LetsEncryptResolvedSpec) -> Result<Vec<u8>> {
+    let mut file = Vec::new();
     
     writeln!(file, "apiVersion: cert-manager.io/v1")?;
     writeln!(file, "kind: ClusterIssuer")?;
@@ -298,9 +535,25 @@ fn generate_cluster_issuer(output_dir: &Path, le_spec: &LetsEncryptResolvedSpec)
     writeln!(file, "    privateKeySecretRef:")?;
     writeln!(file, "      name: {}", LETSENCRYPT_ISSUER)?;
     writeln!(file, "    solvers:")?;
-    writeln!(file, "    - http01:")?;
-    writeln!(file, "        ingress:")?;
-    writeln!(file, "          class: nginx")?;
+    match &le_spec.dns01 {
+        // cert-manager reads the token from a Secret in its own namespace
+        Some(dns01) => {
+            let (provider, secret_ref, key) = match dns01.provider {
+                Dns01Provider::Cloudflare => ("cloudflare", "apiTokenSecretRef", "api-token"),
+                Dns01Provider::DigitalOcean => ("digitalocean", "tokenSecretRef", "access-token"),
+            };
+            writeln!(file, "    - dns01:")?;
+            writeln!(file, "        {}:", provider)?;
+            writeln!(file, "          {}:", secret_ref)?;
+            writeln!(file, "            name: {}", dns01.credentials)?;
+            writeln!(file, "            key: {}", key)?;
+        }
+        None => {
+            writeln!(file, "    - http01:")?;
+            writeln!(file, "        ingress:")?;
+            writeln!(file, "          class: nginx")?;
+        }
+    }
     
-    Ok(())
+    Ok(file)
 }
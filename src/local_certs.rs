@@ -0,0 +1,171 @@
+use crate::run_local::OUTPUT_DIR;
+use crate::secrets;
+use anyhow::{Context, Result};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair, KeyUsagePurpose};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use time::OffsetDateTime;
+
+pub const CERTS_DIR: &str = "certs";
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca-key.pem";
+const CA_NAME: &str = "simpled local development CA";
+// Directory of the CA under the user data directory
+const CA_DIR: &str = "local-ca";
+
+const CA_VALIDITY_DAYS: i64 = 3650;
+const CERT_VALIDITY_DAYS: i64 = 365;
+// Cached files are regenerated a while before they expire.
+const RENEW_MARGIN_DAYS: i64 = 30;
+
+// Certificate and key of one local domain, signed by the local CA
+pub struct DomainCert {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Path of the local CA certificate. Developers trust it once per machine;
+/// every domain certificate is signed by it.
+pub fn ca_cert_path() -> Result<PathBuf> {
+    Ok(ca_dir()?.join(CA_CERT_FILE))
+}
+
+/// Returns a certificate for `host` from `local_env/certs/`, creating the
+/// local CA and the certificate when they are missing or about to expire.
+pub fn ensure_domain_cert(host: &str) -> Result<DomainCert> {
+    remove_project_ca(&certs_dir());
+    ensure_domain_cert_in(&ca_dir()?, &certs_dir(), host)
+}
+
+// `ensure_domain_cert` with the CA kept in `ca_dir` and the domain
+// certificates in `dir`
+fn ensure_domain_cert_in(ca_dir: &Path, dir: &Path, host: &str) -> Result<DomainCert> {
+    fs::create_dir_all(dir).context("Failed to create local_env/certs directory")?;
+    fs::create_dir_all(ca_dir).context(format!("Failed to create the local CA directory {:?}", ca_dir))?;
+
+    let ca_key_path = ca_dir.join(CA_KEY_FILE);
+    let ca_cert_path = ca_dir.join(CA_CERT_FILE);
+    if !is_fresh(&ca_cert_path, CA_VALIDITY_DAYS) || !ca_key_path.exists() {
+        let ca_key = KeyPair::generate()?;
+        let ca = ca_params()?.self_signed(&ca_key)?;
+        secrets::write_private(&ca_key_path, &ca_key.serialize_pem())?;
+        fs::write(&ca_cert_path, ca.pem())?;
+        log::info!("Generated local CA certificate {:?}; trust it once to avoid browser warnings", ca_cert_path);
+    }
+
+    let file_stem = host.replace(['*', ':'], "_");
+    let cert = DomainCert {
+        cert_path: dir.join(format!("{}.pem", file_stem)),
+        key_path: dir.join(format!("{}-key.pem", file_stem)),
+    };
+
+    // A certificate older than the CA was signed by a previous CA.
+    let signed_by_current_ca = modified(&cert.cert_path) >= modified(&ca_cert_path);
+    if is_fresh(&cert.cert_path, CERT_VALIDITY_DAYS) && cert.key_path.exists() && signed_by_current_ca {
+        return Ok(cert);
+    }
+
+    let ca_key = KeyPair::from_pem(&fs::read_to_string(&ca_key_path)?)
+        .context("Failed to read local CA key")?;
+    // Re-creating the CA from the same key and subject yields an issuer that
+    // verifies against the stored CA certificate.
+    let ca: Certificate = ca_params()?.self_signed(&ca_key)?;
+
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![host.to_string()])?;
+    params.distinguished_name.push(DnType::CommonName, host);
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::days(1);
+    params.not_after = now + time::Duration::days(CERT_VALIDITY_DAYS);
+    let signed = params.signed_by(&key, &ca, &ca_key)?;
+
+    secrets::write_private(&cert.key_path, &key.serialize_pem())?;
+    fs::write(&cert.cert_path, signed.pem())?;
+    Ok(cert)
+}
+
+fn certs_dir() -> PathBuf {
+    Path::new(OUTPUT_DIR).join(CERTS_DIR)
+}
+
+// The CA lives in the user's data directory rather than in the project, so
+// its key is never committed or shared along with the project's files.
+fn ca_dir() -> Result<PathBuf> {
+    #[cfg(windows)]
+    let data_dir = env::var_os("LOCALAPPDATA").map(PathBuf::from);
+    #[cfg(not(windows))]
+    let data_dir = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")));
+    let data_dir = data_dir.context("Failed to locate the user data directory for the local CA")?;
+    Ok(data_dir.join("simpled").join(CA_DIR))
+}
+
+// Earlier versions kept the CA in `local_env/certs/`; its key must not stay
+// in the project.
+fn remove_project_ca(dir: &Path) {
+    for file in [CA_KEY_FILE, CA_CERT_FILE] {
+        let path = dir.join(file);
+        if path.exists() && fs::remove_file(&path).is_ok() {
+            log::info!("Removed {:?}; the local CA now lives in the user data directory", path);
+        }
+    }
+}
+
+fn ca_params() -> Result<CertificateParams> {
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, CA_NAME);
+    params.distinguished_name.push(DnType::OrganizationName, "simpled");
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::days(1);
+    params.not_after = now + time::Duration::days(CA_VALIDITY_DAYS);
+    Ok(params)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Whether a cached file generated with `validity_days` is still usable.
+/// The generation time is taken from the file's modification time.
+fn is_fresh(path: &Path, validity_days: i64) -> bool {
+    let usable_days = (validity_days - RENEW_MARGIN_DAYS) as u64;
+    modified(path)
+        .and_then(|time| time.elapsed().ok())
+        .is_some_and(|age| age < Duration::from_secs(usable_days * 24 * 60 * 60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_ca_key_stays_out_of_the_project_and_private() {
+        let ca_dir = tempfile::tempdir().unwrap();
+        let project = tempfile::tempdir().unwrap();
+        let certs = project.path().join("certs");
+        fs::create_dir(&certs).unwrap();
+        fs::write(certs.join(CA_KEY_FILE), "old key").unwrap();
+        fs::write(certs.join(CA_CERT_FILE), "old cert").unwrap();
+
+        remove_project_ca(&certs);
+        let cert = ensure_domain_cert_in(ca_dir.path(), &certs, "shop.test").unwrap();
+
+        let mut files: Vec<String> = fs::read_dir(&certs).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        files.sort();
+        assert_eq!(files, ["shop.test-key.pem", "shop.test.pem"]);
+        assert!(ca_dir.path().join(CA_KEY_FILE).exists() && ca_dir.path().join(CA_CERT_FILE).exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            for key in [ca_dir.path().join(CA_KEY_FILE), cert.key_path] {
+                assert_eq!(fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600, "{:?}", key);
+            }
+        }
+    }
+}
@@ -1,8 +1,29 @@
 use crate::resolved_spec::*;
-use anyhow::{Result};
-use std::thread;
-use std::process;
-use axum::{Router};
+use crate::spec::IngressSpec;
+use crate::local_certs::{self, DomainCert};
+use crate::run_local::OUTPUT_DIR;
+use crate::diagnostics;
+use anyhow::{Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::process::{self, Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use axum::Router;
+use axum::body::{Body, HttpBody};
+use axum::Json;
+use axum::extract::{OriginalUri, Request};
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::get;
 use axum_reverse_proxy::ReverseProxy;
 
 /// A proxy path is treated by `axum-reverse-proxy` as a root fallback when it is
@@ -12,6 +33,290 @@ fn is_root_prefix(prefix: &str) -> bool {
     prefix.is_empty() || prefix == "/"
 }
 
+/// Default time a proxied request waits for a service that refuses connections.
+pub const DEFAULT_UPSTREAM_WAIT_SECS: u64 = 30;
+
+/// How the local ingress listens and talks to services.
+#[derive(Debug, Clone)]
+pub struct IngressOptions {
+    // address the listeners bind to
+    pub bind: IpAddr,
+    // how long to retry a service that refuses connections; zero disables retries
+    pub upstream_wait: Duration,
+}
+
+impl Default for IngressOptions {
+    fn default() -> Self {
+        Self {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            upstream_wait: Duration::from_secs(DEFAULT_UPSTREAM_WAIT_SECS),
+        }
+    }
+}
+
+/// Path on every gateway domain that returns the resolved gateway spec as JSON.
+const ROUTES_PATH: &str = "/__simpled/routes";
+
+pub const PID_FILE: &str = "ingress.pid";
+pub const LOG_FILE: &str = "ingress.log";
+
+/// Domains the local ingress serves for the given deployment.
+fn served_domains<'a>(spec: &'a IngressResolvedSpec, current_deployment: &'a str) -> impl Iterator<Item = &'a String> {
+    spec.domains.iter()
+        .filter(move |domain| spec.rules.iter().any(|rule| {
+            &rule.domain_name == *domain
+                && rule.services.iter().any(|svc| svc.deployment_name == current_deployment)
+        }))
+}
+
+fn is_self_signed(spec: &IngressResolvedSpec) -> bool {
+    spec.tls.as_ref().is_some_and(|tls| tls.self_signed)
+}
+
+/// Whether the local ingress serves `domain` over https; hosts that opted out
+/// of TLS stay on plain http.
+fn serves_https(spec: &IngressResolvedSpec, domain: &str) -> bool {
+    is_self_signed(spec) && spec.domain_tls(domain)
+}
+
+/// Ports the local ingress listens on for all of the gateway's domains, from
+/// the env spec; `listen_ports` gives those of one resolved deployment.
+pub fn gateway_ports(ingress: &IngressSpec) -> Vec<u16> {
+    let self_signed = ingress.tls.as_ref().is_some_and(|tls| tls.self_signed);
+    let mut ports = Vec::new();
+    for host in &ingress.hosts {
+        let https = self_signed && host.tls;
+        for domain in &host.domain_names {
+            let port = domain_port(domain, https);
+            ports.push(port);
+            if https {
+                ports.push(http_sibling_port(port));
+            }
+        }
+    }
+    ports
+}
+
+/// Port the ingress listens on for a domain; a domain can be "hostname" or "hostname:port".
+fn domain_port(domain: &str, https: bool) -> u16 {
+    domain.rsplit_once(':')
+        .and_then(|(_, port)| port.parse::<u16>().ok())
+        .unwrap_or(if https { 443 } else { 80 })
+}
+
+/// With https on a domain's port, plain http is served on the next port
+/// (or on 80 next to the default 443).
+fn http_sibling_port(https_port: u16) -> u16 {
+    if https_port == 443 { 80 } else { https_port + 1 }
+}
+
+/// Unprivileged port used when a domain's privileged port cannot be bound.
+fn fallback_port(port: u16) -> u16 {
+    match port {
+        80 => 8080,
+        443 => 8443,
+        port => 8000 + port,
+    }
+}
+
+/// Rewrites gateway domains whose privileged port (e.g. the default 80/443)
+/// cannot be bound without root to an explicit high port, e.g.
+/// `myapp.localhost` to `myapp.localhost:8080`. This runs before resolution,
+/// so relative env variables and printed URLs carry the port actually served.
+pub fn apply_port_fallbacks(ingress: &mut IngressSpec, bind: IpAddr) {
+    for (port, domain, replacement) in rewrite_denied_ports(ingress, |port| is_permission_denied(bind, port)) {
+        diagnostics::warn("port_fallback", format!(
+            "binding port {} needs elevated privileges; serving {} as {} instead",
+            port, domain, replacement
+        ));
+    }
+}
+
+// Moves every privileged domain port for which `denied` holds to its fallback
+// port; returns (port, old domain, new domain) for each rewrite.
+fn rewrite_denied_ports(ingress: &mut IngressSpec, denied: impl Fn(u16) -> bool) -> Vec<(u16, String, String)> {
+    let self_signed = ingress.tls.as_ref().is_some_and(|tls| tls.self_signed);
+    let mut rewrites = Vec::new();
+    for host in &mut ingress.hosts {
+        let https = self_signed && host.tls;
+        for domain in &mut host.domain_names {
+            let port = domain_port(domain, https);
+            if port >= 1024 || !denied(port) {
+                continue;
+            }
+            let hostname = domain.rsplit_once(':').map_or(domain.as_str(), |(hostname, _)| hostname);
+            let replacement = format!("{}:{}", hostname, fallback_port(port));
+            rewrites.push((port, std::mem::replace(domain, replacement.clone()), replacement));
+        }
+    }
+    rewrites
+}
+
+fn is_permission_denied(bind: IpAddr, port: u16) -> bool {
+    matches!(TcpListener::bind((bind, port)), Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied)
+}
+
+#[cfg(windows)]
+const HOSTS_FILE: &str = r"C:\Windows\System32\drivers\etc\hosts";
+#[cfg(not(windows))]
+const HOSTS_FILE: &str = "/etc/hosts";
+
+/// Whether a gateway hostname needs a hosts-file entry to reach this machine.
+/// `localhost` and `*.localhost` always loop back, IP addresses need nothing,
+/// wildcards can't be listed in a hosts file, and other names are fine once
+/// they resolve to a loopback address.
+fn needs_hosts_entry(hostname: &str) -> bool {
+    if hostname == "localhost" || hostname.ends_with(".localhost") || hostname.starts_with("*.") || hostname.parse::<IpAddr>().is_ok() {
+        return false;
+    }
+    match (hostname, 0).to_socket_addrs() {
+        Ok(mut addrs) => !addrs.any(|addr| addr.ip().is_loopback()),
+        Err(_) => true,
+    }
+}
+
+/// Checks that every gateway domain of the deployment resolves to this
+/// machine. Missing hosts-file lines are printed, or appended to the hosts
+/// file when `add` is set.
+pub fn check_hosts(spec: &IngressResolvedSpec, current_deployment: &str, add: bool) -> Result<()> {
+    let mut hostnames: Vec<&str> = served_domains(spec, current_deployment)
+        .map(|domain| domain.rsplit_once(':').map_or(domain.as_str(), |(hostname, _)| hostname))
+        .filter(|hostname| needs_hosts_entry(hostname))
+        .collect();
+    hostnames.sort();
+    hostnames.dedup();
+    if hostnames.is_empty() {
+        return Ok(());
+    }
+
+    let lines: Vec<String> = hostnames.iter().map(|hostname| format!("127.0.0.1 {}", hostname)).collect();
+    if !add {
+        diagnostics::warn("hosts_entry_missing", format!(
+            "these gateway domains do not resolve to this machine; add to {}:\n  {}\nor rerun with --add-hosts (needs permission to write {})",
+            HOSTS_FILE, lines.join("\n  "), HOSTS_FILE
+        ));
+        return Ok(());
+    }
+
+    append_lines(Path::new(HOSTS_FILE), &lines).with_context(|| {
+        format!("Failed to update {}; rerun with administrator privileges or add the lines manually", HOSTS_FILE)
+    })?;
+    for line in &lines {
+        log::info!("Added '{}' to {}", line, HOSTS_FILE);
+    }
+    Ok(())
+}
+
+// Appends `lines` to the file at `path`, after a newline if its last line has
+// none; the existing content is never rewritten.
+fn append_lines(path: &Path, lines: &[String]) -> Result<()> {
+    let existing = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut appended = String::new();
+    if existing.last().is_some_and(|&b| b != b'\n') {
+        appended.push('\n');
+    }
+    for line in lines {
+        appended.push_str(line);
+        appended.push('\n');
+    }
+    let mut file = fs::OpenOptions::new().append(true).open(path)?;
+    file.write_all(appended.as_bytes())?;
+    Ok(())
+}
+
+/// URLs the local ingress serves for the given deployment, one per domain.
+pub fn urls(spec: &IngressResolvedSpec, current_deployment: &str) -> Vec<String> {
+    served_domains(spec, current_deployment)
+        .map(|domain| format!("{}://{}", if serves_https(spec, domain) { "https" } else { "http" }, domain))
+        .collect()
+}
+
+/// Ports the local ingress listens on for the given deployment.
+pub fn listen_ports(spec: &IngressResolvedSpec, current_deployment: &str) -> Vec<u16> {
+    let mut ports = Vec::new();
+    for domain in served_domains(spec, current_deployment) {
+        let https = serves_https(spec, domain);
+        let port = domain_port(domain, https);
+        ports.push(port);
+        if https {
+            ports.push(http_sibling_port(port));
+        }
+    }
+    ports.sort();
+    ports.dedup();
+    ports
+}
+
+/// Starts the local ingress in a background `simpled` process that outlives
+/// this one, for `local run --detach`. Its pid is recorded in `local_env/` so
+/// `stop_detached` can terminate it later; output goes to `local_env/ingress.log`.
+pub fn spawn_detached(path: Option<&str>, deployment: &str, only: &[String], options: &IngressOptions) -> Result<()> {
+    let output_dir = Path::new(OUTPUT_DIR);
+    fs::create_dir_all(output_dir).context("Failed to create local_env directory")?;
+
+    let exe = std::env::current_exe().context("Failed to locate the simpled executable")?;
+    let log = File::create(output_dir.join(LOG_FILE)).context("Failed to create ingress log file")?;
+
+    let mut command = Command::new(exe);
+    command.args(["local", "ingress", "--deployment", deployment]);
+    for service in only {
+        command.args(["--only", service]);
+    }
+    command.args(["--bind", &options.bind.to_string()]);
+    command.args(["--upstream-wait", &options.upstream_wait.as_secs().to_string()]);
+    if let Some(path) = path {
+        command.args(["--path", path]);
+    }
+    if log::log_enabled!(log::Level::Debug) {
+        command.arg("--verbose");
+    }
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()
+        .context("Failed to start background local ingress")?;
+
+    fs::write(output_dir.join(PID_FILE), child.id().to_string())?;
+    Ok(())
+}
+
+/// Terminates the background ingress started by `spawn_detached`, if any.
+pub fn stop_detached() -> Result<()> {
+    let pid_path = Path::new(OUTPUT_DIR).join(PID_FILE);
+    let Ok(pid) = fs::read_to_string(&pid_path) else {
+        return Ok(());
+    };
+    let pid = pid.trim();
+
+    // The process may already be gone; a failed kill just means there is
+    // nothing left to stop.
+    #[cfg(unix)]
+    let status = Command::new("kill").arg(pid).stderr(Stdio::null()).status();
+    #[cfg(windows)]
+    let status = Command::new("taskkill").args(["/PID", pid, "/F"]).stderr(Stdio::null()).status();
+
+    if matches!(status, Ok(s) if s.success()) {
+        log::info!("Stopped background local ingress (pid {})", pid);
+    }
+    fs::remove_file(&pid_path)?;
+    Ok(())
+}
+
+/// Running local ingress. Dropping the handle leaves the listeners running;
+/// `stop` shuts them down and waits until their ports are released.
+pub struct IngressHandle {
+    shutdown: tokio::sync::watch::Sender<bool>,
+    thread: JoinHandle<()>,
+}
+
+impl IngressHandle {
+    pub fn stop(self) {
+        let _ = self.shutdown.send(true);
+        let _ = self.thread.join();
+    }
+}
+
 /// Starts the local ingress for a single, specific deployment.
 ///
 /// Unlike k8s/docker, a local run only brings up the currently selected
@@ -19,43 +324,64 @@ fn is_root_prefix(prefix: &str) -> bool {
 /// services. The resolved ingress spec contains rules for every deployment, so
 /// we filter by `current_deployment` here.
 ///
+/// Requests to a service that refuses connections are retried for up to
+/// `options.upstream_wait` before answering 502; zero disables retries and the startup
+/// readiness poll.
+///
 /// Any failure setting up the ingress aborts the whole process: an unreachable
 /// ingress means the local deployment is unusable, so there is no point letting
 /// the services keep running.
-pub fn run(spec: IngressResolvedSpec, current_deployment: &str) -> Result<()> {
+pub fn run(spec: IngressResolvedSpec, current_deployment: &str, options: &IngressOptions) -> Result<IngressHandle> {
+    let IngressOptions { bind, upstream_wait } = *options;
+    let self_signed = is_self_signed(&spec);
+    let mut certs = HashMap::new();
+    if self_signed {
+        for domain in served_domains(&spec, current_deployment).filter(|d| spec.domain_tls(d)) {
+            let host = domain.rsplit_once(':').map_or(domain.as_str(), |(host, _)| host);
+            certs.insert(domain.clone(), local_certs::ensure_domain_cert(host)?);
+        }
+        log::info!("Local ingress serves https; trust {:?} to avoid certificate warnings", local_certs::ca_cert_path()?);
+    }
+
     let current_deployment = current_deployment.to_string();
+    let (shutdown, shutdown_rx) = watch::channel(false);
 
-    thread::spawn(move || {
+    let thread = thread::spawn(move || {
         // Create a new tokio runtime for the ingress server
         let rt = match tokio::runtime::Runtime::new() {
             Ok(rt) => rt,
             Err(e) => {
-                eprintln!("Failed to create tokio runtime for local ingress: {}", e);
+                log::error!("Failed to create tokio runtime for local ingress: {}", e);
                 process::exit(1);
             }
         };
 
         rt.block_on(async move {
             let mut handles = vec![];
+            let mut upstreams = vec![];
 
             for domain in &spec.domains {
-                // domain can be "hostname" or "hostname:port"
-                let (_host, port_str) = if let Some((h, p)) = domain.rsplit_once(':') {
-                    (h, p)
-                } else {
-                    (domain.as_str(), "80")
-                };
-
-                let port = port_str.parse::<u16>().unwrap_or(80);
+                let port = domain_port(domain, serves_https(&spec, domain));
 
                 let mut app = Router::new();
+                let mut listing = vec![];
                 let mut rules_found = false;
                 let mut root_fallback_set = false;
+                let mut page_fallback = None;
 
                 for rule in &spec.rules {
                     // Match rules for this domain
                     if &rule.domain_name == domain {
-                        for svc in &rule.services {
+                        // a page default backend answers what the services
+                        // leave, or everything in maintenance
+                        if let Some(DefaultBackendResolvedSpec::Page(page)) = rule.fallback() {
+                            if rule.services.iter().any(|svc| svc.deployment_name == current_deployment) {
+                                rules_found = true;
+                                page_fallback = Some(default_page(page, rule.maintenance));
+                            }
+                        }
+                        // a service default backend is routed on `/`
+                        for svc in rule.routed_services() {
                             // Only route to services of the deployment being run locally.
                             if svc.deployment_name != current_deployment {
                                 continue;
@@ -63,7 +389,7 @@ pub fn run(spec: IngressResolvedSpec, current_deployment: &str) -> Result<()> {
 
                             if is_root_prefix(&svc.prefix) {
                                 if root_fallback_set {
-                                    eprintln!(
+                                    log::error!(
                                         "Local ingress misconfiguration on {}: multiple services map to the root path '/' for deployment '{}'",
                                         domain, current_deployment
                                     );
@@ -88,33 +414,71 @@ pub fn run(spec: IngressResolvedSpec, current_deployment: &str) -> Result<()> {
 
                             let path = svc.prefix.clone();
 
-                            let proxy = ReverseProxy::new(&path, &target);
+                            let proxy = Arc::new(ReverseProxy::new(&path, &target));
+                            let send = move |req: Request| {
+                                let proxy = proxy.clone();
+                                async move {
+                                    match proxy.proxy_request(req).await {
+                                        Ok(response) => response,
+                                        Err(never) => match never {},
+                                    }
+                                }
+                            };
+                            upstreams.push((svc.service_name.clone(), svc.port));
+                            listing.push(ListedRoute {
+                                prefix: path.clone(),
+                                service: svc.service_name.clone(),
+                                port: svc.port,
+                            });
+                            let route = Arc::new(Route {
+                                service: svc.service_name.clone(),
+                                prefix: path.clone(),
+                                target,
+                                port: svc.port,
+                                upstream_wait,
+                            });
 
-                            app = app.merge(proxy);
+                            app = mount(app, &path, move |req: Request| forward(route.clone(), send.clone(), req));
                         }
                     }
                 }
 
+                let routes_json = serde_json::to_value(&spec).unwrap_or_default();
+                app = app.route(ROUTES_PATH, get(move || async move { Json(routes_json) }));
+                // A root rule already catches every path; the listing only
+                // answers requests that no rule matched.
+                if let Some(page) = page_fallback {
+                    app = app.fallback(move || async move { page });
+                } else if !root_fallback_set {
+                    let listing = Arc::new(RouteListing {
+                        domain: domain.clone(),
+                        domains: served_domains(&spec, &current_deployment).cloned().collect(),
+                        routes: listing,
+                    });
+                    app = app.fallback(move |headers: HeaderMap, uri: Uri| async move {
+                        not_found_page(&listing, &headers, uri.path())
+                    });
+                }
+
                 if rules_found {
-                    let bind_addr = format!("0.0.0.0:{}", port);
-                    match tokio::net::TcpListener::bind(&bind_addr).await {
-                        Ok(listener) => {
-                            println!("Local ingress listening on {}", bind_addr);
-                            handles.push(tokio::spawn(async move {
-                                if let Err(e) = axum::serve(listener, app).await {
-                                    eprintln!("Error serving ingress on {}: {}", bind_addr, e);
-                                    process::exit(1);
-                                }
-                            }));
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to bind local ingress on {}: {}", bind_addr, e);
-                            process::exit(1);
+                    match certs.get(domain) {
+                        Some(cert) => {
+                            handles.push(serve_https((bind, port).into(), app.clone(), cert, shutdown_rx.clone()).await);
+                            handles.push(serve_http((bind, http_sibling_port(port)).into(), app, shutdown_rx.clone()).await);
                         }
+                        None => handles.push(serve_http((bind, port).into(), app, shutdown_rx.clone()).await),
                     }
                 }
             }
 
+            if !upstream_wait.is_zero() {
+                upstreams.sort();
+                upstreams.dedup();
+                for (service, port) in upstreams {
+                    tokio::spawn(wait_for_upstream(service, port, upstream_wait));
+                }
+            }
+
             // Wait for all listeners
             for handle in handles {
                 let _ = handle.await;
@@ -122,5 +486,419 @@ pub fn run(spec: IngressResolvedSpec, current_deployment: &str) -> Result<()> {
         });
     });
 
-    Ok(())
+    Ok(IngressHandle { shutdown, thread })
+}
+
+/// Where a proxied route leads, kept for retries, access logging and error pages.
+// Largest request body kept in memory to retry a request
+const MAX_REPLAYED_BODY: usize = 4 * 1024 * 1024;
+
+struct Route {
+    service: String,
+    prefix: String,
+    target: String,
+    port: u16,
+    // how long to keep retrying while the service refuses connections
+    upstream_wait: Duration,
+}
+
+/// Proxies a request through `send`, logs it and replaces the proxy's bare
+/// "failed to connect" reply with a page naming the service that is not reachable.
+// Same shape as `Router::from(proxy)`, but requests go through `handler`
+// for retries and logging. Keeping it on the proxy itself means unmatched
+// paths are not attributed to this route.
+fn mount<H, T>(app: Router, prefix: &str, handler: H) -> Router
+where
+    H: axum::handler::Handler<T, ()>,
+    T: 'static,
+{
+    let proxy = Router::new().fallback(handler);
+    if is_root_prefix(prefix) {
+        app.merge(proxy)
+    } else {
+        app.merge(Router::new().nest(prefix, proxy))
+    }
+}
+
+// Inside a nested route the prefix is already stripped from `req.uri()`.
+fn request_path(req: &Request) -> String {
+    req.extensions().get::<OriginalUri>()
+        .map_or(req.uri().path(), |uri| uri.path())
+        .to_string()
+}
+
+async fn forward<F, Fut>(route: Arc<Route>, send: F, req: Request) -> Response
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let method = req.method().clone();
+    let host = req.headers().get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+    let path = request_path(&req);
+    let started = Instant::now();
+
+    let mut response = send_with_retry(&route, send, req).await;
+
+    if is_connect_failure(&response) {
+        log::error!(
+            "{} {}{}: service '{}' is not reachable at localhost:{}",
+            method, host, path, route.service, route.port
+        );
+        response = bad_gateway_page(&route);
+    }
+
+    log::debug!(
+        "{} {}{} prefix={} upstream={} status={} {}ms",
+        method, host, path, route.prefix, route.target,
+        response.status().as_u16(), started.elapsed().as_millis()
+    );
+    response
+}
+
+/// Sends a request, retrying with a short backoff while the service refuses
+/// connections (e.g. its container is still starting) for up to the route's
+/// `upstream_wait`. The body is buffered so it can be replayed.
+async fn send_with_retry<F, Fut>(route: &Route, send: F, req: Request) -> Response
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    // Upgrade requests carry the client connection in their extensions and
+    // cannot be replayed. Bodies are buffered for a replay only when their
+    // size is known and small; larger or streamed ones are sent once.
+    let replayable = req.body().size_hint().exact().is_some_and(|len| len <= MAX_REPLAYED_BODY as u64);
+    if route.upstream_wait.is_zero() || !replayable || req.headers().contains_key(header::UPGRADE) {
+        return send(req).await;
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_REPLAYED_BODY).await {
+        Ok(body) => body,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Failed to read request body: {}", e)).into_response(),
+    };
+
+    let deadline = Instant::now() + route.upstream_wait;
+    let mut delay = Duration::from_millis(100);
+    loop {
+        let mut attempt = Request::new(Body::from(body.clone()));
+        *attempt.method_mut() = parts.method.clone();
+        *attempt.uri_mut() = parts.uri.clone();
+        *attempt.version_mut() = parts.version;
+        *attempt.headers_mut() = parts.headers.clone();
+
+        let response = send(attempt).await;
+        if !is_connect_failure(&response) || Instant::now() + delay > deadline {
+            return response;
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(Duration::from_secs(1));
+    }
+}
+
+/// The proxy answers connection failures itself with a header-less 502;
+/// a 502 coming from the service always carries at least its own headers.
+fn is_connect_failure(response: &Response) -> bool {
+    response.status() == StatusCode::BAD_GATEWAY && response.headers().is_empty()
+}
+
+/// Polls a routed service's port at startup so the console shows which
+/// services the gateway is still waiting for.
+async fn wait_for_upstream(service: String, port: u16, max_wait: Duration) {
+    let reachable = || tokio::net::TcpStream::connect(("localhost", port));
+    if reachable().await.is_ok() {
+        return;
+    }
+
+    log::info!("Waiting for {} on :{}", service, port);
+    let deadline = Instant::now() + max_wait;
+    while Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        if reachable().await.is_ok() {
+            log::info!("{} is up on :{}", service, port);
+            return;
+        }
+    }
+    diagnostics::warn("service_unreachable", format!("{} is still not reachable on :{} after {}s", service, port, max_wait.as_secs()));
+}
+
+/// Routes of one gateway domain, shown when a request matches none of them.
+#[derive(Serialize)]
+struct RouteListing {
+    domain: String,
+    domains: Vec<String>,
+    routes: Vec<ListedRoute>,
+}
+
+#[derive(Serialize)]
+struct ListedRoute {
+    prefix: String,
+    service: String,
+    port: u16,
+}
+
+// Default backend page, as the docker gateways serve it
+fn default_page(page: &str, maintenance: bool) -> (StatusCode, Html<String>) {
+    let status = if maintenance { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::NOT_FOUND };
+    (status, Html(page.to_string()))
+}
+
+/// 404 listing the routes of the requested domain, as JSON for clients that
+/// ask for it and as HTML otherwise.
+fn not_found_page(listing: &RouteListing, headers: &HeaderMap, path: &str) -> Response {
+    let wants_json = headers.get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    if wants_json {
+        let body = serde_json::json!({ "error": "no route matches", "path": path, "gateway": listing });
+        return (StatusCode::NOT_FOUND, Json(body)).into_response();
+    }
+    (StatusCode::NOT_FOUND, Html(route_listing_html(listing, path))).into_response()
+}
+
+fn route_listing_html(listing: &RouteListing, path: &str) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><title>404 - simpled local gateway</title></head><body>\n\
+         <h1>No route matches {}</h1>\n<p>Routes on {}:</p>\n<table>\n\
+         <tr><th>Prefix</th><th>Service</th><th>Port</th></tr>\n",
+        escape_html(path), escape_html(&listing.domain)
+    );
+    for route in &listing.routes {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&route.prefix), escape_html(&route.service), route.port
+        ));
+    }
+    html.push_str("</table>\n<p>Gateway domains: ");
+    html.push_str(&listing.domains.iter().map(|d| escape_html(d)).collect::<Vec<_>>().join(", "));
+    html.push_str(&format!("</p>\n<p>All routes as JSON: <a href=\"{0}\">{0}</a></p>\n</body></html>\n", ROUTES_PATH));
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn bad_gateway_page(route: &Route) -> Response {
+    let body = format!(
+        "502 Bad Gateway\n\n\
+         The local ingress could not connect to service '{}' on localhost:{}.\n\
+         Check that it is running with `simpled local status`.\n",
+        route.service, route.port
+    );
+    (StatusCode::BAD_GATEWAY, body).into_response()
+}
+
+/// Resolves once `IngressHandle::stop` is called. A dropped handle closes
+/// the channel without a stop request, in which case the listeners keep running.
+async fn stop_requested(mut shutdown_rx: watch::Receiver<bool>) {
+    if shutdown_rx.wait_for(|stop| *stop).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+async fn serve_http(bind_addr: SocketAddr, app: Router, shutdown_rx: watch::Receiver<bool>) -> tokio::task::JoinHandle<()> {
+    match tokio::net::TcpListener::bind(&bind_addr).await {
+        Ok(listener) => {
+            log::info!("Local ingress listening on {}", bind_addr);
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app).with_graceful_shutdown(stop_requested(shutdown_rx)).await {
+                    log::error!("Error serving ingress on {}: {}", bind_addr, e);
+                    process::exit(1);
+                }
+            })
+        }
+        Err(e) => {
+            log::error!("Failed to bind local ingress on {}: {}", bind_addr, e);
+            process::exit(1);
+        }
+    }
+}
+
+async fn serve_https(addr: SocketAddr, app: Router, cert: &DomainCert, shutdown_rx: watch::Receiver<bool>) -> tokio::task::JoinHandle<()> {
+    // Both rustls crypto backends are compiled in, so one has to be chosen
+    // explicitly; a second install attempt just reports the existing one.
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+    let config = match RustlsConfig::from_pem_file(&cert.cert_path, &cert.key_path).await {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Failed to load local ingress certificate {:?}: {}", cert.cert_path, e);
+            process::exit(1);
+        }
+    };
+
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        stop_requested(shutdown_rx).await;
+        shutdown_handle.graceful_shutdown(Some(Duration::from_secs(5)));
+    });
+
+    log::info!("Local ingress listening on {} (https)", addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum_server::bind_rustls(addr, config).handle(handle).serve(app.into_make_service()).await {
+            log::error!("Error serving ingress on {}: {}", addr, e);
+            process::exit(1);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{HostSpec, IngressTlsSpec};
+
+    #[test]
+    fn loopback_names_need_no_hosts_entry() {
+        assert!(!needs_hosts_entry("localhost"));
+        assert!(!needs_hosts_entry("shop.localhost"));
+        assert!(!needs_hosts_entry("127.0.0.1"));
+        assert!(!needs_hosts_entry("*.shop.test"));
+    }
+
+    #[test]
+    fn hosts_lines_are_appended_to_the_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let hosts = dir.path().join("hosts");
+        // not UTF-8, and without a final newline
+        fs::write(&hosts, b"127.0.0.1 localhost\n# caf\xe9").unwrap();
+
+        append_lines(&hosts, &["127.0.0.1 shop.test".to_string(), "127.0.0.1 admin.shop.test".to_string()]).unwrap();
+        assert_eq!(fs::read(&hosts).unwrap(), b"127.0.0.1 localhost\n# caf\xe9\n127.0.0.1 shop.test\n127.0.0.1 admin.shop.test\n");
+
+        assert!(append_lines(&dir.path().join("missing"), &["127.0.0.1 shop.test".to_string()]).is_err());
+        assert!(!dir.path().join("missing").exists());
+    }
+
+    #[test]
+    fn privileged_ports_fall_back_to_high_ports() {
+        assert_eq!(fallback_port(80), 8080);
+        assert_eq!(fallback_port(443), 8443);
+        assert_eq!(fallback_port(22), 8022);
+    }
+
+    #[test]
+    fn denied_domain_ports_are_rewritten() {
+        let host = |name: &str, domains: &[&str], tls: bool| HostSpec {
+            name: name.to_string(),
+            domain_names: domains.iter().map(|d| d.to_string()).collect(),
+            tls,
+            default_backend: None,
+        };
+        let mut ingress = IngressSpec {
+            name: "gateway".to_string(),
+            hosts: vec![
+                host("web", &["myapp.localhost", "x.localhost:8081", "y.localhost:81"], true),
+                host("plain", &["plain.localhost"], false),
+            ],
+            tls: Some(IngressTlsSpec { secret: None, letsencrypt: None, self_signed: true }),
+            allow_nested_prefixes: false,
+            redirects: vec![],
+        };
+
+        let rewrites = rewrite_denied_ports(&mut ingress, |port| port != 81);
+        assert_eq!(ingress.hosts[0].domain_names, ["myapp.localhost:8443", "x.localhost:8081", "y.localhost:81"]);
+        assert_eq!(ingress.hosts[1].domain_names, ["plain.localhost:8080"]);
+        assert_eq!(rewrites, [
+            (443, "myapp.localhost".to_string(), "myapp.localhost:8443".to_string()),
+            (80, "plain.localhost".to_string(), "plain.localhost:8080".to_string()),
+        ]);
+
+        assert!(rewrite_denied_ports(&mut ingress, |port| port != 81).is_empty());
+    }
+
+    #[test]
+    fn route_listing_escapes_request_path() {
+        let listing = RouteListing {
+            domain: "localhost:8080".to_string(),
+            domains: vec!["localhost:8080".to_string()],
+            routes: vec![ListedRoute { prefix: "/api".to_string(), service: "api".to_string(), port: 8081 }],
+        };
+        let html = route_listing_html(&listing, "/<script>");
+
+        assert!(html.contains("No route matches /&lt;script&gt;"));
+        assert!(html.contains("<td>/api</td><td>api</td><td>8081</td>"));
+    }
+
+    // A body of unknown length, like a chunked upload
+    struct Streamed(Option<axum::body::Bytes>);
+
+    impl HttpBody for Streamed {
+        type Data = axum::body::Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(mut self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+            std::task::Poll::Ready(self.0.take().map(|data| Ok(http_body::Frame::data(data))))
+        }
+    }
+
+    #[test]
+    fn refused_requests_are_retried_with_small_bodies_only() {
+        let route = Route { service: "api".to_string(), prefix: "/".to_string(), target: "http://localhost:8081".to_string(), port: 8081, upstream_wait: Duration::from_secs(5) };
+        // the service refuses the first two connections, as the proxy reports them
+        let forward = |body: Body| {
+            let attempts = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let send = |req: Request| {
+                let attempts = attempts.clone();
+                async move {
+                    let body = axum::body::to_bytes(req.into_body(), usize::MAX).await.unwrap();
+                    let mut attempts = attempts.lock().unwrap();
+                    attempts.push(body.len());
+                    if attempts.len() <= 2 { StatusCode::BAD_GATEWAY.into_response() } else { StatusCode::OK.into_response() }
+                }
+            };
+            let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+            let status = rt.block_on(send_with_retry(&route, send, Request::post("/upload").body(body).unwrap())).status();
+            let attempts = attempts.lock().unwrap().clone();
+            (status, attempts)
+        };
+
+        assert_eq!(forward(Body::from("payload")), (StatusCode::OK, vec![7, 7, 7]));
+        assert_eq!(forward(Body::from(vec![0u8; MAX_REPLAYED_BODY + 1])), (StatusCode::BAD_GATEWAY, vec![MAX_REPLAYED_BODY + 1]));
+        assert_eq!(forward(Body::new(Streamed(Some("chunk".into())))), (StatusCode::BAD_GATEWAY, vec![5]));
+    }
+
+    #[test]
+    fn only_stop_shuts_the_listeners_down() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        let resolves = |shutdown_rx| rt.block_on(async { tokio::time::timeout(Duration::from_millis(50), stop_requested(shutdown_rx)).await.is_ok() });
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        drop(shutdown_tx);
+        assert!(!resolves(shutdown_rx));
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        shutdown_tx.send(true).unwrap();
+        assert!(resolves(shutdown_rx));
+    }
+
+    #[test]
+    fn only_matched_requests_reach_a_route() {
+        use tower::ServiceExt;
+
+        let app = mount(Router::new(), "/api", |req: Request| async move { request_path(&req) });
+        let app = app.route(ROUTES_PATH, get(|| async { "routes" }));
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let get = |path: &str| rt.block_on(async {
+            let response = app.clone().oneshot(Request::get(path).body(Body::empty()).unwrap()).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        });
+
+        assert_eq!(get("/api/users/1"), "/api/users/1");
+        assert_eq!(get("/api"), "/api");
+        assert_eq!(get("/apiary"), "");
+        assert_eq!(get("/other"), "");
+        assert_eq!(get(ROUTES_PATH), "routes");
+    }
+
+    #[test]
+    fn only_bare_502s_are_connect_failures() {
+        assert!(is_connect_failure(&StatusCode::BAD_GATEWAY.into_response()));
+        assert!(!is_connect_failure(&(StatusCode::BAD_GATEWAY, "upstream error").into_response()));
+        assert!(!is_connect_failure(&StatusCode::SERVICE_UNAVAILABLE.into_response()));
+    }
 }
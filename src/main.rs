@@ -1,6 +1,9 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use anyhow::{Context, Result, bail, anyhow};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::net::IpAddr;
+use std::process::ExitCode;
+use std::time::Duration;
 
 mod spec;
 mod spec_yaml;
@@ -11,13 +14,27 @@ mod resolved_spec;
 mod resolver;
 mod k8s_generator;
 mod docker_generator;
+mod generated;
 mod run_local;
 mod local_ingress;
+mod local_certs;
 mod spec_loader;
 mod app_bundle;
+mod secrets;
+mod inspect;
+mod deployments;
+mod diagnostics;
+mod report;
+mod error;
+mod schema;
 mod bundle_repo;
 mod docker_compose;
 mod updater;
+mod digests;
+mod quantities;
+mod image_ref;
+#[cfg(test)]
+mod test_support;
 
 #[derive(Parser)]
 #[command(name = "simpled")]
@@ -25,6 +42,50 @@ mod updater;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Print debug output, e.g. a log line for every request through the local ingress
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Print only warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Fail the command if it reported any warning
+    #[arg(long, global = true)]
+    deny_warnings: bool,
+
+    /// Run as if simpled was started in this directory
+    #[arg(short = 'C', long, global = true, value_name = "DIR")]
+    chdir: Option<PathBuf>,
+
+    /// Appspec file to use instead of appspec.yaml in the current directory
+    #[arg(long, global = true, value_name = "PATH")]
+    appspec: Option<PathBuf>,
+
+    /// Env spec file (or its directory) to use instead of looking for envspec.yaml
+    #[arg(long, global = true, value_name = "PATH")]
+    envspec: Option<PathBuf>,
+
+    /// Use an empty value, with a warning, for a `${env:NAME}` deployment value whose variable is not set
+    #[arg(long, global = true)]
+    allow_missing_env: bool,
+
+    /// Warn about, instead of rejecting, env variable names docker and Kubernetes do not accept
+    #[arg(long, global = true)]
+    lenient_names: bool,
+
+    /// Warn about, instead of rejecting, unknown fields and duplicate keys in spec files
+    #[arg(long, global = true)]
+    lenient: bool,
+
+    /// State file keeping the values of generated secrets, instead of .simpled/secrets.<deployment>.json next to the env spec
+    #[arg(long, global = true, value_name = "FILE")]
+    secrets_state: Option<PathBuf>,
+
+    /// Generate a new value for this generated secret (repeatable)
+    #[arg(long, global = true, value_name = "NAME")]
+    rotate_secret: Vec<String>,
 }
 
 #[derive(Subcommand)]
@@ -39,6 +100,11 @@ enum Commands {
         #[command(subcommand)]
         command: SecretsCommands,
     },
+    /// Env spec related commands
+    Env {
+        #[command(subcommand)]
+        command: EnvCommands,
+    },
     /// Prepare deployment (e.g. generate k8s manifests)
     PrepareDeployment {
         deployment_name: String,
@@ -52,11 +118,56 @@ enum Commands {
         #[arg(long)]
         download_bundle_from: Option<String>,
 
+        /// GitHub owner/repo of the bundle releases, over the env spec's bundle_repo
         #[arg(long)]
         github_repo: Option<String>,
 
+        /// Release tag prefix, over the env spec's bundle_repo
         #[arg(long)]
         github_tag_prefix: Option<String>,
+
+        /// `json` prints a single result object on stdout
+        #[arg(long, value_parser = ["text", "json"], default_value = "text")]
+        format: String,
+
+        /// What to do with existing output files that differ from the generated ones
+        #[arg(long, value_parser = ["overwrite", "skip", "fail"], default_value = "overwrite")]
+        on_conflict: String,
+
+        /// Look up the digest of every image and deploy `image@sha256:...`, recorded in simpled.lock
+        #[arg(long, conflicts_with = "locked")]
+        pin_digests: bool,
+
+        /// Deploy the digests recorded in simpled.lock without querying the registry
+        #[arg(long)]
+        locked: bool,
+
+        /// Also write the resolved spec as JSON to this file
+        #[arg(long, value_name = "PATH")]
+        emit_resolved: Option<String>,
+
+        /// Write secret values into the --emit-resolved file instead of masking them
+        #[arg(long, requires = "emit_resolved")]
+        include_secret_values: bool,
+    },
+
+    /// Print the fully resolved spec of a deployment
+    Inspect {
+        deployment_name: String,
+
+        #[arg(long, alias = "app-bundle")]
+        bundle: String,
+
+        /// Show only this service
+        #[arg(long)]
+        service: Option<String>,
+
+        #[arg(long, value_parser = ["table", "json", "yaml"], default_value = "table")]
+        format: String,
+
+        /// Print secret values instead of masking them
+        #[arg(long)]
+        show_secrets: bool,
     },
 
     /// Used for local development and tests
@@ -71,6 +182,17 @@ enum Commands {
         #[arg(long)]
         check: bool,
     },
+
+    /// Print a shell completion script, e.g. `simpled completions bash > /etc/bash_completion.d/simpled`
+    Completions {
+        shell: clap_complete::Shell,
+    },
+
+    /// Print the JSON Schema of appspec.yaml or envspec.yaml for editor validation
+    Schema {
+        #[arg(value_parser = ["appspec", "envspec"])]
+        kind: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -79,12 +201,119 @@ enum LocalCommands {
         #[arg(short, long)]
         exclude: Option<Vec<String>>,
 
+        /// Run only these services (repeatable)
+        #[arg(long, conflicts_with = "exclude")]
+        only: Vec<String>,
+
         #[arg(long)]
         path: Option<String>,
 
         /// Deployment to run. Required when the env spec defines more than one.
         #[arg(long)]
         deployment: Option<String>,
+
+        /// Also start services in this compose profile (repeatable)
+        #[arg(long)]
+        profile: Vec<String>,
+
+        /// Start services in the background and return once they are up
+        #[arg(short, long)]
+        detach: bool,
+
+        /// Reload the environment when the specs, env files or configs change
+        #[arg(long, conflicts_with = "detach")]
+        watch: bool,
+
+        /// Run this service natively instead of in docker
+        #[arg(long)]
+        undockerized: Option<String>,
+
+        /// Command that starts the undockerized service with its environment
+        #[arg(long, requires = "undockerized", conflicts_with_all = ["detach", "watch"])]
+        exec: Option<String>,
+
+        /// Leave the containers running when simpled exits or is interrupted
+        #[arg(long, conflicts_with = "detach")]
+        keep_running: bool,
+
+        /// Start even if published ports are already in use
+        #[arg(long)]
+        ignore_port_conflicts: bool,
+
+        /// Seconds the gateway retries a service that refuses connections (0 disables)
+        #[arg(long, value_name = "SECONDS", default_value_t = local_ingress::DEFAULT_UPSTREAM_WAIT_SECS)]
+        upstream_wait: u64,
+
+        /// Address the gateway listens on, e.g. 127.0.0.1 to keep it off the network
+        #[arg(long, default_value = "0.0.0.0")]
+        bind: IpAddr,
+
+        /// Add gateway domains that do not resolve to this machine to the hosts file
+        #[arg(long)]
+        add_hosts: bool,
+
+        /// Rebuild images of services that declare a `build` section
+        #[arg(long)]
+        build: bool,
+
+        /// Start without running job services first
+        #[arg(long)]
+        skip_jobs: bool,
+
+        /// Move files of services that no longer exist out of local_env
+        #[arg(long)]
+        clean: bool,
+
+        /// With --detach: wait until all services are ready, then print their gateway URLs as JSON
+        #[arg(long, requires = "detach")]
+        wait_healthy: bool,
+
+        /// Seconds --wait-healthy waits before failing
+        #[arg(long, value_name = "SECONDS", default_value_t = 120, requires = "wait_healthy")]
+        timeout: u64,
+
+        /// Override a deployment environment variable, KEY=VALUE (repeatable)
+        #[arg(long = "env", value_name = "KEY=VALUE")]
+        env: Vec<String>,
+
+        /// Override deployment environment variables from a .env file; --env wins over it
+        #[arg(long, value_name = "PATH")]
+        env_file: Option<String>,
+
+        /// Also write the resolved spec as JSON to this file
+        #[arg(long, value_name = "PATH")]
+        emit_resolved: Option<String>,
+
+        /// Write secret values into the --emit-resolved file instead of masking them
+        #[arg(long, requires = "emit_resolved")]
+        include_secret_values: bool,
+    },
+    /// Stop the local environment started with `local run`
+    Down,
+    /// Show container logs of the local environment
+    Logs {
+        /// Service to show logs for (default: all services)
+        service: Option<String>,
+
+        /// Keep streaming new log output
+        #[arg(short, long)]
+        follow: bool,
+
+        #[arg(long)]
+        path: Option<String>,
+
+        /// Deployment the service belongs to. Required when the env spec defines more than one.
+        #[arg(long)]
+        deployment: Option<String>,
+    },
+    /// Show state, ports and URLs of the local environment's services
+    Status {
+        #[arg(long)]
+        path: Option<String>,
+
+        /// Deployment to show. Required when the env spec defines more than one.
+        #[arg(long)]
+        deployment: Option<String>,
     },
     /// Run the gateway and only extra services (no app services)
     OnlyExtra {
@@ -104,94 +333,325 @@ enum LocalCommands {
         #[arg(long)]
         deployment: Option<String>,
     },
+    /// Run only the local gateway; used by `local run --detach` as a background process
+    #[command(hide = true)]
+    Ingress {
+        #[arg(long)]
+        path: Option<String>,
+
+        #[arg(long)]
+        deployment: Option<String>,
+
+        #[arg(long, default_value_t = local_ingress::DEFAULT_UPSTREAM_WAIT_SECS)]
+        upstream_wait: u64,
+
+        #[arg(long, default_value = "0.0.0.0")]
+        bind: IpAddr,
+
+        #[arg(long)]
+        only: Vec<String>,
+    },
 }
 
 #[derive(Subcommand)]
 enum AppBundleCommands {
-    Verify,
-    Version,
+    Verify {
+        /// `json` prints a single result object on stdout
+        #[arg(long, value_parser = ["text", "json"], default_value = "text")]
+        format: String,
+    },
+    /// Print the app version, or change it in the appspec
+    Version {
+        /// Increment the version
+        #[arg(long, value_parser = ["patch", "minor", "major"], conflicts_with = "set")]
+        bump: Option<String>,
+
+        /// Set an explicit version, e.g. 1.4.0
+        #[arg(long)]
+        set: Option<String>,
+    },
     Create {
         #[arg(short, long)]
         registry: Option<String>,
         #[arg(long)]
         push_images: bool,
-        #[arg(long)]
+        /// Upload the bundle to a GitHub release, gh:owner/repo, or an OCI registry, oci://registry/path
+        #[arg(long, value_name = "gh:OWNER/REPO|oci://REGISTRY/PATH", conflicts_with = "upload_bundle_to")]
         upload: Option<String>,
         #[arg(long)]
         upload_bundle_to: Option<String>,
         #[arg(long)]
         github_repo: Option<String>,
-        #[arg(long)]
+        /// Prefix of the release tag, e.g. v for v1.4.0
+        #[arg(long, alias = "tag-prefix")]
         github_tag_prefix: Option<String>,
+        /// ed25519 private key (PKCS#8 PEM) to sign the bundle with
+        #[arg(long, value_name = "PATH")]
+        sign_key: Option<String>,
+    },
+    /// Show the contents of a tar.gz app bundle without unpacking it
+    Inspect {
+        /// Bundle path, or app version downloaded from the env spec's bundle_repo
+        #[arg(value_name = "PATH|VERSION")]
+        bundle: String,
+
+        #[arg(long, value_parser = ["table", "json"], default_value = "table")]
+        format: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum EnvCommands {
+    /// Validate the env spec, and with --with-bundle every deployment against the app
+    Verify {
+        /// Directory of the env spec (default: current dir)
+        path: Option<String>,
+
+        /// App bundle (path, or version downloaded from bundle_repo) to validate the deployments against
+        #[arg(long, value_name = "PATH|VERSION")]
+        with_bundle: Option<String>,
+
+        /// Check only this deployment
+        #[arg(long)]
+        deployment: Option<String>,
+    },
+    /// List the deployments of the env spec
+    List {
+        /// Directory of the env spec (default: current dir)
+        path: Option<String>,
+
+        #[arg(long, value_parser = ["table", "json"], default_value = "table")]
+        format: String,
+    },
+    /// Show the services, secrets, configs and env vars a deployment configures
+    Describe {
+        /// Deployment to describe
+        deployment: String,
+        /// Directory of the env spec (default: current dir)
+        path: Option<String>,
+
+        #[arg(long, value_parser = ["table", "json"], default_value = "table")]
+        format: String,
     },
 }
 
 #[derive(Subcommand)]
 enum SecretsCommands {
+    /// Set values of a deployment's `env:` secrets
     Set {
+        /// Deployment the secrets belong to
         env_name: String,
+        /// Directory of the env spec (default: current dir)
         path: Option<String>,
-        #[arg(short = 'f', long)]
+        /// key=value, key=@file to read the value from a file, or key to be prompted (repeatable)
+        #[arg(short = 'f', long, value_name = "KEY[=VALUE]")]
         file: Vec<String>,
     },
+    /// List the secrets of a deployment and whether their values are available
+    List {
+        /// Deployment the secrets belong to
+        env_name: String,
+        /// Directory of the env spec (default: current dir)
+        path: Option<String>,
+    },
+    /// Fail if any secret of a deployment has no value available
+    Verify {
+        /// Deployment the secrets belong to
+        env_name: String,
+        /// Directory of the env spec (default: current dir)
+        path: Option<String>,
+    },
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    diagnostics::init_logger(cli.quiet, cli.verbose);
+    spec_loader::set_spec_paths(cli.appspec.as_deref(), cli.envspec.as_deref());
+    env_loader::set_allow_missing_env(cli.allow_missing_env);
+    transform::set_lenient_names(cli.lenient_names);
+    spec_loader::set_lenient(cli.lenient);
+    secrets::set_generated_options(cli.secrets_state.as_deref(), &cli.rotate_secret);
+
+    let result = run(&cli);
+    let warnings = diagnostics::take_warnings();
+    if !warnings.is_empty() && !cli.quiet {
+        eprintln!("\n{} warning(s):", warnings.len());
+        for warning in &warnings {
+            eprintln!("  {}", warning.message);
+        }
+    }
+    let result = result.and_then(|()| {
+        if cli.deny_warnings && !warnings.is_empty() {
+            bail!("{} warning(s) reported and --deny-warnings is set", warnings.len());
+        }
+        Ok(())
+    });
 
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    if let Some(dir) = &cli.chdir {
+        std::env::set_current_dir(dir).context(format!("Failed to change to directory {:?}", dir))?;
+    }
     match &cli.command {
         Commands::AppBundle { command } => match command {
-            AppBundleCommands::Verify => {
-                verify_command()?;
+            AppBundleCommands::Verify { format } => {
+                let mut report = report::Report::default();
+                let result = verify_command(&mut report);
+                report.finish(result, format, cli.deny_warnings)?;
+            }
+            AppBundleCommands::Version { bump, set } => {
+                app_bundle::version(bump.as_deref(), set.as_deref())?;
             }
-            AppBundleCommands::Version => {
-                version_command()?;
+            AppBundleCommands::Create { registry, push_images, upload, upload_bundle_to, github_repo, github_tag_prefix, sign_key } => {
+                app_bundle::create_app_bundle(registry, *push_images, upload, upload_bundle_to, github_repo, github_tag_prefix, sign_key)?;
             }
-            AppBundleCommands::Create { registry, push_images, upload, upload_bundle_to, github_repo, github_tag_prefix } => {
-                app_bundle::create_app_bundle(registry, *push_images, upload, upload_bundle_to, github_repo, github_tag_prefix)?;
+            AppBundleCommands::Inspect { bundle, format } => {
+                app_bundle::inspect(bundle, format)?;
             }
         },
         Commands::Secrets { command } => match command {
             SecretsCommands::Set { env_name, path, file } => {
-                println!("Set secrets for {}, path={:?}, args={:?}", env_name, path, file);
+                secrets::set(env_name, path.as_deref(), file)?;
+            }
+            SecretsCommands::List { env_name, path } => {
+                secrets::list(env_name, path.as_deref())?;
+            }
+            SecretsCommands::Verify { env_name, path } => {
+                secrets::verify(env_name, path.as_deref())?;
             }
         },
-        Commands::PrepareDeployment { deployment_name, bundle, version, download_bundle_from, github_repo, github_tag_prefix } => {
-            prepare_deployment_command(deployment_name, bundle, version, download_bundle_from, github_repo, github_tag_prefix)?;
+        Commands::Env { command } => match command {
+            EnvCommands::Verify { path, with_bundle, deployment } => {
+                env_verify_command(path.as_deref(), with_bundle.as_deref(), deployment.as_deref())?;
+            }
+            EnvCommands::List { path, format } => {
+                deployments::list(path.as_deref(), format)?;
+            }
+            EnvCommands::Describe { deployment, path, format } => {
+                deployments::describe(deployment, path.as_deref(), format)?;
+            }
+        },
+        Commands::PrepareDeployment { deployment_name, bundle, version, download_bundle_from, github_repo, github_tag_prefix, format, on_conflict, pin_digests, locked, emit_resolved, include_secret_values } => {
+            let mut report = report::Report { deployment: Some(deployment_name.clone()), ..Default::default() };
+            let pinning = match (pin_digests, locked) {
+                (true, _) => digests::Pinning::Pin,
+                (_, true) => digests::Pinning::Locked,
+                _ => digests::Pinning::Off,
+            };
+            let options = PrepareOptions {
+                bundle: bundle.as_deref(),
+                version: version.as_deref(),
+                download_bundle_from: download_bundle_from.as_deref(),
+                github_repo: github_repo.as_deref(),
+                github_tag_prefix: github_tag_prefix.as_deref(),
+                on_conflict: generated::OnConflict::parse(on_conflict)?,
+                pinning,
+                emit_resolved: emit_resolved.as_deref(),
+                include_secret_values: *include_secret_values,
+            };
+            let result = prepare_deployment_command(deployment_name, &options, &mut report);
+            report.finish(result, format, cli.deny_warnings)?;
         },
+        Commands::Inspect { deployment_name, bundle, service, format, show_secrets } => {
+            inspect_command(deployment_name, bundle, service.as_deref(), format, *show_secrets)?;
+        }
         Commands::Local { command } => {
             local(&command)?;
         }
         Commands::Update { check } => {
             updater::check_and_update(*check)?;
         }
+        Commands::Completions { shell } => {
+            clap_complete::generate(*shell, &mut Cli::command(), "simpled", &mut std::io::stdout());
+        }
+        Commands::Schema { kind } => {
+            println!("{}", serde_json::to_string_pretty(&schema::generate(kind)?)?);
+        }
     }
     Ok(())
 }
 
-fn verify_command() -> Result<()> {
-    let app_spec = spec_loader::load_app_spec(Path::new("."), None)?;
-    println!("Successfully validated appspec: {} v{}", app_spec.name, app_spec.version);
+fn verify_command(report: &mut report::Report) -> Result<()> {
+    let app_spec = spec_loader::load_project_app_spec(None)?;
+    report.app_name = Some(app_spec.name.clone());
+    report.version = Some(app_spec.version.to_string());
+    log::info!("Successfully validated appspec: {} v{}", app_spec.name, app_spec.version);
     Ok(())
 }
 
-fn version_command() -> Result<()> {
-    let app_spec = spec_loader::load_app_spec(Path::new("."), None)?;
-    println!("{}", app_spec.version);
+fn env_verify_command(path: Option<&str>, with_bundle: Option<&str>, deployment_name: Option<&str>) -> Result<()> {
+    let root = Path::new(path.unwrap_or("."));
+    let env_spec = spec_loader::load_env_spec(root)?;
+    let validator::EnvSpecReport { mut errors, warnings } = validator::check_env_spec(&env_spec);
+
+    let mut deployments: Vec<&spec::DeploymentSpec> = env_spec.deployments.iter()
+        .filter(|d| deployment_name.is_none_or(|name| d.name == name))
+        .collect();
+    if let (Some(name), true) = (deployment_name, deployments.is_empty()) {
+        bail!("Deployment {} not found in env spec", name);
+    }
+    deployments.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if let Some(bundle) = with_bundle {
+        let bundle_path = bundle_repo::locate(bundle, &env_spec, bundle_repo::app_of(deployments.iter().copied())?)?;
+        let app_spec = spec_loader::load_app_spec(&bundle_path, Some(&env_spec))?;
+        for deployment in &deployments {
+            match validator::validate(&env_spec, &app_spec, &deployment.name) {
+                Ok(()) => log::info!("Deployment {}: ok", deployment.name),
+                Err(e) => {
+                    log::info!("Deployment {}: failed", deployment.name);
+                    errors.push(format!("Deployment {}: {:#}", deployment.name, e));
+                }
+            }
+        }
+    }
+
+    for warning in &warnings {
+        diagnostics::warn("env_spec", warning.as_str());
+    }
+    for error in &errors {
+        log::error!("{}", error);
+    }
+    if !errors.is_empty() {
+        bail!("Env spec has {} error(s) and {} warning(s)", errors.len(), warnings.len());
+    }
+    log::info!("Env spec is valid ({} warning(s))", warnings.len());
     Ok(())
 }
 
-fn prepare_deployment_command(
-    deployment_name: &str, 
-    bundle: &Option<String>, 
-    version: &Option<String>,
-    download_bundle_from: &Option<String>,
-    github_repo: &Option<String>,
-    github_tag_prefix: &Option<String>
-) -> Result<()> {
+fn inspect_command(deployment_name: &str, bundle: &str, service: Option<&str>, format: &str, show_secrets: bool) -> Result<()> {
+    let env_spec = spec_loader::load_env_spec(Path::new("."))?;
+    let app_spec = spec_loader::load_app_spec(Path::new(bundle), Some(&env_spec))?;
+    validator::validate(&env_spec, &app_spec, deployment_name)?;
+    let mut resolved_spec = resolver::resolve(&env_spec, &app_spec, deployment_name)?;
+    inspect::print(&mut resolved_spec, service, format, show_secrets)
+}
+
+// Command-line options of `prepare-deployment`
+struct PrepareOptions<'a> {
+    // --app-bundle; --version and --download-bundle-from select one instead
+    bundle: Option<&'a str>,
+    version: Option<&'a str>,
+    download_bundle_from: Option<&'a str>,
+    github_repo: Option<&'a str>,
+    github_tag_prefix: Option<&'a str>,
+    on_conflict: generated::OnConflict,
+    pinning: digests::Pinning,
+    emit_resolved: Option<&'a str>,
+    include_secret_values: bool,
+}
+
+fn prepare_deployment_command(deployment_name: &str, options: &PrepareOptions, report: &mut report::Report) -> Result<()> {
     // 1. Load specs
-    let env_spec = spec_loader::load_env_spec(Path::new("."), Some(deployment_name))?;
+    let env_spec = spec_loader::load_env_spec(Path::new("."))?;
     
     // Find deployment to get app name
     let deployment = env_spec.deployments.iter()
@@ -200,49 +660,82 @@ fn prepare_deployment_command(
         
     let app_name = &deployment.application.name;
 
-    let bundle_path_str = if let Some(source) = download_bundle_from {
+    // --github-repo and --github-tag-prefix override the env spec's bundle_repo
+    let repo = bundle_repo::with_overrides(env_spec.bundle_repo.as_ref(), options.github_repo, options.github_tag_prefix);
+
+    let bundle_path = if let Some(source) = options.download_bundle_from {
         if source == "github-release" {
-            let ver = version.as_ref().context("--app-version is required when downloading from github-release")?;
-            let repo = github_repo.as_ref().context("--github-repo is required when downloading from github-release")?;
+            let ver = options.version.context("--app-version is required when downloading from github-release")?;
+            let repo = repo.as_ref()
+                .filter(|repo| repo.provider == spec::BundleRepoProvider::Github)
+                .context("--github-repo is required when downloading from github-release")?;
             
-            bundle_repo::gh_release::download(repo, ver, app_name, github_tag_prefix.as_deref())?
+            bundle_repo::gh_release::download(repo, app_name, ver, Path::new("."))?
         } else {
             bail!("Unknown download source: {}. Only 'github-release' is supported.", source);
         }
-    } else {
-        if version.is_some() {
-             bail!("Deploying by version without download is not implemented yet. Use --app-bundle to specify file.");
+    } else if let Some(ver) = options.version {
+        let repo = repo.as_ref()
+            .context("Deploying by --version requires a 'bundle_repo' section in the env spec or --github-repo. Use --app-bundle to specify file.")?;
+        let requested = semver::Version::parse(ver).context(format!("Invalid app version: {}", ver))?;
+        if let Some(req) = &deployment.application.version {
+            if !req.matches(&requested) {
+                bail!("App version {} does not satisfy deployment requirement {}", requested, req);
+            }
         }
-        bundle.as_ref().context("Either --app-bundle or --download-bundle-from must be specified")?.clone()
+        bundle_repo::download(repo, app_name, ver, &env_spec.root)?
+    } else {
+        PathBuf::from(options.bundle.context("Either --app-bundle, --version or --download-bundle-from must be specified")?)
     };
+    if let Some(public_key) = repo.as_ref().and_then(|r| r.public_key.as_ref()) {
+        bundle_repo::integrity::verify_signature(&bundle_path, public_key).context(error::Failure::Load)?;
+        log::info!("Signature of {:?} verified", bundle_path);
+    }
 
-    let bundle_path = Path::new(&bundle_path_str);
-
-    let app_spec = spec_loader::load_app_spec(bundle_path, Some(&env_spec))?;
+    let app_spec = spec_loader::load_app_spec(&bundle_path, Some(&env_spec))?;
+    report.app_name = Some(app_spec.name.clone());
+    report.version = Some(app_spec.version.to_string());
 
     // 2. Validate
-    validator::validate(&env_spec, &app_spec, deployment_name).context("Validation failed")?;
+    validator::validate(&env_spec, &app_spec, deployment_name)?;
 
-    println!("Validation passed for deployment {}", deployment_name);
+    log::info!("Validation passed for deployment {}", deployment_name);
 
     // 3. Resolve
-    let resolved_spec = resolver::resolve(&env_spec, &app_spec, deployment_name).context("Resolution failed")?;
+    let mut resolved_spec = resolver::resolve(&env_spec, &app_spec, deployment_name)?;
 
-    // 4. Generate
-    match env_spec.env_type {
-        spec::DeploymentEnvType::K8S => {
-            let output_dir = Path::new("manifests");
-            k8s_generator::generate(&resolved_spec, output_dir).context("Generation failed")?;
-            println!("Manifests generated in {:?}", output_dir);
-        },
-        spec::DeploymentEnvType::Docker(ref docker_spec) => {
-            let output_dir = Path::new("docker-deploy");
-            docker_generator::generate(&resolved_spec, docker_spec, output_dir).context("Generation failed")?;
-            println!("Docker deployment script generated in {:?}", output_dir);
-        },
+    let output_dir = Path::new(match env_spec.env_type {
+        spec::DeploymentEnvType::K8S => "manifests",
+        spec::DeploymentEnvType::Docker(_) => "docker-deploy",
         spec::DeploymentEnvType::Local => {
              bail!("prepare deployment doesn't support local deployments, use 'simpled local run' instead");
         }
+    });
+    for service in resolved_spec.current_deployment.services.iter().filter(|s| s.image_override) {
+        report.image_overrides.insert(service.full_name.clone(), service.image.clone());
+    }
+    let lock = digests::pin(&mut resolved_spec, output_dir, options.pinning)?;
+    if let Some(path) = options.emit_resolved {
+        inspect::emit(&resolved_spec, Path::new(path), options.include_secret_values)?;
+    }
+
+    // 4. Generate
+    let before = report::snapshot(output_dir)?;
+    let mut files = match env_spec.env_type {
+        spec::DeploymentEnvType::Docker(ref docker_spec) => docker_generator::render(&resolved_spec, docker_spec, output_dir),
+        _ => k8s_generator::render(&resolved_spec, output_dir),
+    }.context(error::Failure::Generation)?;
+    if let Some(lock) = lock {
+        lock.add_to(&mut files, output_dir).context(error::Failure::Generation)?;
+    }
+    files.commit(options.on_conflict).context(error::Failure::Generation)?;
+    match env_spec.env_type {
+        spec::DeploymentEnvType::Docker(_) => log::info!("Docker deployment script generated in {:?}", output_dir),
+        _ => log::info!("Manifests generated in {:?}", output_dir),
+    }
+    report.generated_files = report::generated_files(output_dir, &before)?;
+    for (service, image) in &report.image_overrides {
+        log::info!("Service {} runs override image {}", service, image);
     }
 
     Ok(())
@@ -280,34 +773,160 @@ fn select_deployment<'a>(
     }
 }
 
-fn local(command: &LocalCommands) -> Result<()> {
-    let (root, deployment_name) = match command {
-        LocalCommands::Run { path, deployment, .. }
-        | LocalCommands::OnlyExtra { path, deployment }
-        | LocalCommands::GenerateConfig { path, deployment } => (
-            path.as_ref()
-                .map(|p| Path::new(p))
-                .unwrap_or(Path::new(".")),
-            deployment,
-        ),
-    };
+/// Loads, validates and resolves the specs for a local command.
+fn resolve_local(
+    root: &Path,
+    deployment_name: Option<&str>,
+    options: &run_local::RunOptions,
+) -> Result<(spec::DeploymentEnvironmentSpec, resolved_spec::EnvironmentResolvedSpec)> {
+    let mut env_spec = spec_loader::load_env_spec(root)?;
+    local_ingress::apply_port_fallbacks(&mut env_spec.ingress, options.ingress.bind);
+    let app_spec = spec_loader::load_project_app_spec(Some(&env_spec))?;
+
+    let selected = select_deployment(&env_spec, deployment_name)?.name.clone();
+    if !options.env.is_empty() {
+        let deployment = env_spec.deployments.iter_mut()
+            .find(|d| d.name == selected)
+            .expect("selected deployment exists");
+        apply_env_overrides(deployment, &app_spec.environment, &options.env);
+    }
+    let deployment = select_deployment(&env_spec, Some(&selected))?;
+
+    validator::validate(&env_spec, &app_spec, &deployment.name)?;
+
+    log::info!("Validation passed for deployment {}", &deployment.name);
 
-    let exclude = match command {
-        LocalCommands::Run { exclude, .. } => exclude.clone().unwrap_or_default(),
-        _ => Vec::new(),
+    let resolved_spec = resolver::resolve(&env_spec, &app_spec, &deployment.name)?;
+    if log::log_enabled!(log::Level::Debug) {
+        // values embedding a secret are masked, as `inspect` does
+        let mut masked = resolved_spec.clone();
+        inspect::mask_env_secrets(&mut masked);
+        for service in &masked.current_deployment.services {
+            for var in &service.environment_variables {
+                log::debug!("{}: {}={}", service.full_name, var.name, var.value);
+            }
+        }
+    }
+    Ok((env_spec, resolved_spec))
+}
+
+/// Parses `--env-file` and `--env` overrides, in the order they take effect.
+fn env_overrides(env: &[String], env_file: Option<&str>) -> Result<Vec<spec::EnvVariable>> {
+    let mut overrides = match env_file {
+        Some(path) => env_loader::load_env_file(path)?,
+        None => Vec::new(),
     };
+    for value in env {
+        overrides.push(env_loader::parse_env_variable(value).context(format!("Invalid --env value: {}", value))?);
+    }
+    Ok(overrides)
+}
 
-    let env_spec = spec_loader::load_env_spec(root, deployment_name.as_deref())?;
-    let app_spec = spec_loader::load_app_spec_from_dir(Path::new("."), Some(&env_spec))?;
+/// Applies `--env`/`--env-file` overrides to the deployment environment, so
+/// they go through the same resolution as values from the env spec. The
+/// undockerized environment is overridden too where it sets the same variable.
+fn apply_env_overrides(deployment: &mut spec::DeploymentSpec, app_env: &spec::AppEnvironment, overrides: &[spec::EnvVariable]) {
+    for var in overrides {
+        let declared = app_env.external.iter().any(|v| v.name == var.name)
+            || app_env.optional.iter().any(|v| v.name == var.name)
+            || app_env.relative.iter().any(|v| v.name == var.name)
+            || app_env.internal.iter().any(|v| v.name == var.name);
+        if !declared {
+            diagnostics::warn("env_override_not_declared", format!("--env {} is not declared in the appspec environment", var.name));
+        }
 
-    let deployment = select_deployment(&env_spec, deployment_name.as_deref())?;
+        match deployment.environment.iter_mut().find(|v| v.name == var.name) {
+            Some(existing) => existing.value = var.value.clone(),
+            None => deployment.environment.push(var.clone()),
+        }
+        if let Some(existing) = deployment.undockerized_environment.iter_mut().find(|v| v.name == var.name) {
+            existing.value = var.value.clone();
+        }
+    }
+}
 
-    validator::validate(&env_spec, &app_spec, &deployment.name).context("Validation failed")?;
+/// Files and directories `local run --watch` reacts to: the spec directories
+/// (spec files and the env files next to them), config directories and extra
+/// service files.
+fn local_watch_paths(env_spec: &spec::DeploymentEnvironmentSpec, deployment: &spec::DeploymentSpec) -> Vec<PathBuf> {
+    let app_dir = spec_loader::project_app_spec_path().ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from("."));
+    let mut paths = vec![env_spec.root.clone(), app_dir];
+    for config in &deployment.configs {
+        for file in &config.files {
+            let (spec::ConfigFileSpec::PathRef(file) | spec::ConfigFileSpec::DirFile { path: file, .. }) = file else { continue };
+            if let Some(dir) = Path::new(file).parent() {
+                paths.push(dir.to_path_buf());
+            }
+        }
+    }
+    for extra in &deployment.application.extra {
+        paths.push(PathBuf::from(extra));
+    }
+    paths.sort();
+    paths.dedup();
+    paths
+}
 
-    println!("Validation passed for deployment {}", &deployment.name);
+fn local(command: &LocalCommands) -> Result<()> {
+    // Down works on the generated compose file alone, without loading specs.
+    if let LocalCommands::Down = command {
+        local_ingress::stop_detached()?;
+        return run_local::down();
+    }
 
-    // 3. Resolve
-    let resolved_spec = resolver::resolve(&env_spec, &app_spec, &deployment.name).context("Resolution failed")?;
+    let (path, deployment_name) = match command {
+        LocalCommands::Run { path, deployment, .. }
+        | LocalCommands::OnlyExtra { path, deployment }
+        | LocalCommands::GenerateConfig { path, deployment }
+        | LocalCommands::Logs { path, deployment, .. }
+        | LocalCommands::Status { path, deployment }
+        | LocalCommands::Ingress { path, deployment, .. } => (path, deployment),
+        LocalCommands::Down => unreachable!(),
+    };
+    let root = path.as_ref()
+        .map(Path::new)
+        .unwrap_or(Path::new("."));
+
+    let mut run_options = match command {
+        LocalCommands::Run { exclude, only, profile, detach, undockerized, exec, keep_running, ignore_port_conflicts, upstream_wait, bind, add_hosts, build, skip_jobs, clean, env, env_file, .. } => run_local::RunOptions {
+            exclude: exclude.clone().unwrap_or_default(),
+            only: only.clone(),
+            profiles: profile.clone(),
+            detach: *detach,
+            undockerized: undockerized.clone(),
+            exec: exec.clone(),
+            keep_running: *keep_running,
+            ignore_port_conflicts: *ignore_port_conflicts,
+            add_hosts: *add_hosts,
+            build: *build,
+            skip_jobs: *skip_jobs,
+            clean: *clean,
+            env: env_overrides(env, env_file.as_deref())?,
+            deployment_services: Vec::new(),
+            ingress: local_ingress::IngressOptions {
+                bind: *bind,
+                upstream_wait: Duration::from_secs(*upstream_wait),
+            },
+        },
+        LocalCommands::Ingress { upstream_wait, bind, only, .. } => run_local::RunOptions {
+            only: only.clone(),
+            ingress: local_ingress::IngressOptions {
+                bind: *bind,
+                upstream_wait: Duration::from_secs(*upstream_wait),
+            },
+            ..Default::default()
+        },
+        _ => run_local::RunOptions::default(),
+    };
+
+    let (env_spec, mut resolved_spec) = resolve_local(root, deployment_name.as_deref(), &run_options)?;
+    run_options.deployment_services = resolved_spec.current_deployment.services.iter().map(|s| s.full_name.clone()).collect();
+    run_local::retain_only(&mut resolved_spec, &run_options.only)?;
+    if let LocalCommands::Run { emit_resolved: Some(path), include_secret_values, .. } = command {
+        inspect::emit(&resolved_spec, Path::new(path), *include_secret_values)?;
+    }
 
     // 4. Generate
     match env_spec.env_type {
@@ -320,21 +939,69 @@ fn local(command: &LocalCommands) -> Result<()> {
         spec::DeploymentEnvType::Local => {
             match command {
                 LocalCommands::GenerateConfig { .. } => {
-                    println!("Regenerating local configuration");
+                    log::info!("Regenerating local configuration");
                     run_local::generate_config(&resolved_spec)?;
                 },
+                LocalCommands::Logs { service, follow, .. } => {
+                    run_local::logs(&resolved_spec, service.as_deref(), *follow)?;
+                },
+                LocalCommands::Status { .. } => {
+                    run_local::status(&resolved_spec)?;
+                },
+                LocalCommands::Ingress { .. } => {
+                    local_ingress::run(resolved_spec.ingress.clone(), &resolved_spec.current_deployment.name, &run_options.ingress)?;
+                    loop {
+                        std::thread::park();
+                    }
+                },
+                LocalCommands::Run { wait_healthy, timeout, .. } if run_options.detach => {
+                    // A previous detached run may still hold the gateway ports.
+                    local_ingress::stop_detached()?;
+                    run_local::check_ports(&resolved_spec, &run_options, false)?;
+                    local_ingress::check_hosts(&resolved_spec.ingress, &resolved_spec.current_deployment.name, run_options.add_hosts)?;
+                    log::info!("Running local deployment in the background");
+                    run_local::run(&resolved_spec, &run_options)?;
+                    local_ingress::spawn_detached(path.as_deref(), &resolved_spec.current_deployment.name, &run_options.only, &run_options.ingress)?;
+                    for url in local_ingress::urls(&resolved_spec.ingress, &resolved_spec.current_deployment.name) {
+                        log::info!("Available at {}", url);
+                    }
+                    log::info!("Stop with `simpled local down`");
+                    if *wait_healthy {
+                        run_local::wait_healthy(&resolved_spec, &run_options, Duration::from_secs(*timeout))?;
+                    }
+                },
                 _ => {
-                    local_ingress::run(resolved_spec.ingress.clone(), &resolved_spec.current_deployment.name)?;
+                    local_ingress::stop_detached()?;
+                    let only_extra = matches!(command, LocalCommands::OnlyExtra { .. });
+                    run_local::check_ports(&resolved_spec, &run_options, only_extra)?;
+                    local_ingress::check_hosts(&resolved_spec.ingress, &resolved_spec.current_deployment.name, run_options.add_hosts)?;
+                    let ingress = local_ingress::run(resolved_spec.ingress.clone(), &resolved_spec.current_deployment.name, &run_options.ingress)?;
                     match command {
+                        LocalCommands::Run { watch: true, .. } => {
+                            let deployment = select_deployment(&env_spec, Some(&resolved_spec.current_deployment.name))?;
+                            let watch_paths = local_watch_paths(&env_spec, deployment);
+                            log::info!("Running local deployment in watch mode");
+                            run_local::watch(resolved_spec, &run_options, ingress, &watch_paths, || {
+                                let (_, mut resolved) = resolve_local(root, deployment_name.as_deref(), &run_options)?;
+                                run_local::retain_only(&mut resolved, &run_options.only)?;
+                                Ok(resolved)
+                            })?;
+                        },
                         LocalCommands::Run { .. } => {
-                            println!("Running local deployment");
-                            run_local::run(&resolved_spec, &exclude)?;
+                            log::info!("Running local deployment");
+                            run_local::run(&resolved_spec, &run_options)?;
+                            ingress.stop();
                         },
                         LocalCommands::OnlyExtra { .. } => {
-                            println!("Running gateway and extra services only");
+                            log::info!("Running gateway and extra services only");
                             run_local::run_only_extra(&resolved_spec)?;
+                            ingress.stop();
                         },
-                        LocalCommands::GenerateConfig { .. } => unreachable!(),
+                        _ => unreachable!(),
+                    }
+                    if run_local::interrupted() {
+                        // Conventional exit status of a process stopped by SIGINT.
+                        std::process::exit(130);
                     }
                 },
             }
@@ -343,3 +1010,72 @@ fn local(command: &LocalCommands) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_paths_are_global_options() {
+        let cli = Cli::try_parse_from(["simpled", "prepare-deployment", "prod", "--appspec", "app/appspec.yml", "--envspec", "ops/envspec.yaml"]).unwrap();
+        assert_eq!(cli.appspec.as_deref(), Some(Path::new("app/appspec.yml")));
+        assert_eq!(cli.envspec.as_deref(), Some(Path::new("ops/envspec.yaml")));
+        assert!(matches!(cli.command, Commands::PrepareDeployment { ref deployment_name, .. } if deployment_name == "prod"));
+
+        let cli = Cli::try_parse_from(["simpled", "--envspec", "ops", "prepare-deployment", "prod"]).unwrap();
+        assert_eq!((cli.appspec, cli.envspec), (None, Some(PathBuf::from("ops"))));
+        assert!(Cli::try_parse_from(["simpled", "prepare-deployment", "prod", "--appspec"]).is_err());
+    }
+
+    #[test]
+    fn env_flags_are_applied_after_the_env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file = dir.path().join("ci.env");
+        std::fs::write(&env_file, "LOG_LEVEL=info\nDB_HOST=db\n").unwrap();
+        let env_file = env_file.to_str().unwrap();
+
+        let values = |vars: Vec<spec::EnvVariable>| vars.into_iter().map(|v| format!("{}={}", v.name, v.value)).collect::<Vec<_>>();
+        assert_eq!(values(env_overrides(&["LOG_LEVEL=warn".to_string()], Some(env_file)).unwrap()), ["LOG_LEVEL=info", "DB_HOST=db", "LOG_LEVEL=warn"]);
+        assert!(env_overrides(&["NO_VALUE".to_string()], None).is_err());
+        assert!(env_overrides(&[], Some(dir.path().join("missing.env").to_str().unwrap())).is_err());
+    }
+
+    #[test]
+    fn env_overrides_replace_append_and_warn_when_undeclared() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("envspec.yaml"), r#"
+type: local
+gateway:
+  hosts:
+    web: localhost
+deployments:
+  dev:
+    primary_host: web
+    application:
+      name: shop
+    environment:
+      - LOG_LEVEL=info
+    undockerized_environment:
+      - LOG_LEVEL=debug
+      - DB_HOST=localhost
+"#).unwrap();
+        let mut env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+        let app_env = spec::AppEnvironment {
+            external: vec![spec::ExternalEnvVariable { name: "LOG_LEVEL".to_string(), default: None }],
+            optional: vec![spec::OptionalEnvVariable { name: "FEATURE_FLAGS".to_string() }],
+            relative: vec![],
+            internal: vec![],
+        };
+        let overrides = env_overrides(&["LOG_LEVEL=warn".to_string(), "FEATURE_FLAGS=beta".to_string(), "TYPO_VAR=1".to_string()], None).unwrap();
+        apply_env_overrides(&mut env_spec.deployments[0], &app_env, &overrides);
+
+        let values = |vars: &[spec::EnvVariable]| vars.iter().map(|v| format!("{}={}", v.name, v.value)).collect::<Vec<_>>();
+        let deployment = &env_spec.deployments[0];
+        assert_eq!(values(&deployment.environment), vec!["LOG_LEVEL=warn", "FEATURE_FLAGS=beta", "TYPO_VAR=1"]);
+        // undockerized values are replaced, never added
+        assert_eq!(values(&deployment.undockerized_environment), vec!["LOG_LEVEL=warn", "DB_HOST=localhost"]);
+        let warnings: Vec<String> = diagnostics::warnings().into_iter().map(|w| w.message).collect();
+        assert!(warnings.contains(&"--env TYPO_VAR is not declared in the appspec environment".to_string()), "{:?}", warnings);
+        assert!(!warnings.iter().any(|w| w.contains("--env LOG_LEVEL") || w.contains("--env FEATURE_FLAGS")), "{:?}", warnings);
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::{Result, anyhow, bail};
+use serde::{Serialize, Serializer};
+use std::fmt;
+
+const KI: u64 = 1024;
+const MI: u64 = KI * 1024;
+const GI: u64 = MI * 1024;
+
+// Suffixes in the order they are tried when writing a quantity back out:
+// binary first, then decimal, so `128Mi` stays `128Mi`
+const MEMORY_SUFFIXES: [(&str, u64); 6] = [("Gi", GI), ("Mi", MI), ("Ki", KI), ("G", 1_000_000_000), ("M", 1_000_000), ("k", 1_000)];
+
+/// Amount of memory in bytes, written like a Kubernetes quantity: a number
+/// with an optional `Ki`, `Mi`, `Gi`, `K`, `M` or `G` suffix, e.g. `128Mi`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Memory(u64);
+
+impl Memory {
+    pub const fn from_bytes(bytes: u64) -> Memory {
+        Memory(bytes)
+    }
+
+    pub fn parse(input: &str) -> Result<Memory> {
+        let invalid = || anyhow!("Invalid memory quantity {:?}: expected a number with an optional Ki, Mi, Gi, K, M or G suffix, e.g. 128Mi", input);
+        let split = input.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(input.len());
+        let (number, suffix) = input.split_at(split);
+        let multiplier = match suffix {
+            "" => 1,
+            "K" => 1_000,
+            _ => MEMORY_SUFFIXES.iter().find(|(s, _)| *s == suffix).map(|(_, m)| *m).ok_or_else(invalid)?,
+        };
+        let value = parse_decimal(number).ok_or_else(invalid)? * multiplier as f64;
+        if value.fract() != 0.0 {
+            bail!("Invalid memory quantity {:?}: not a whole number of bytes", input);
+        }
+        Ok(Memory(value as u64))
+    }
+
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match MEMORY_SUFFIXES.iter().find(|(_, m)| self.0 != 0 && self.0.is_multiple_of(*m)) {
+            Some((suffix, multiplier)) => write!(f, "{}{}", self.0 / multiplier, suffix),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl Serialize for Memory {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// CPU in millicores, written as millicores (`500m`) or decimal cores
+/// (`0.5`, `2`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu(u64);
+
+impl Cpu {
+    pub const fn from_millicores(millicores: u64) -> Cpu {
+        Cpu(millicores)
+    }
+
+    pub fn parse(input: &str) -> Result<Cpu> {
+        let invalid = || anyhow!("Invalid cpu quantity {:?}: expected millicores like 500m or cores like 0.5", input);
+        let millicores = match input.strip_suffix('m') {
+            Some(number) if number.bytes().all(|b| b.is_ascii_digit()) => number.parse::<u64>().ok().ok_or_else(invalid)? as f64,
+            Some(_) => return Err(invalid()),
+            None => parse_decimal(input).ok_or_else(invalid)? * 1000.0,
+        };
+        if millicores.fract() != 0.0 {
+            bail!("Invalid cpu quantity {:?}: finer than 1m", input);
+        }
+        Ok(Cpu(millicores as u64))
+    }
+
+    pub fn millicores(self) -> u64 {
+        self.0
+    }
+
+    // Decimal cores, the unit of `docker run --cpus`
+    pub fn cores(self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+}
+
+impl fmt::Display for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 != 0 && self.0.is_multiple_of(1000) {
+            write!(f, "{}", self.0 / 1000)
+        } else {
+            write!(f, "{}m", self.0)
+        }
+    }
+}
+
+impl Serialize for Cpu {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// A non-negative decimal of digits with at most one `.`; rejects signs,
+// exponents and `,` that `f64::from_str` or a locale would accept
+fn parse_decimal(number: &str) -> Option<f64> {
+    let digits = number.replacen('.', "", 1);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) || number.starts_with('.') || number.ends_with('.') {
+        return None;
+    }
+    number.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_normalizes_quantities() {
+        for (input, bytes, normalized) in [("128Mi", 128 * MI, "128Mi"), ("1.5Gi", 1536 * MI, "1536Mi"), ("512K", 512_000, "500Ki"), ("3k", 3_000, "3k"), ("1G", 1_000_000_000, "1G"), ("1048576", MI, "1Mi")] {
+            let memory = Memory::parse(input).unwrap();
+            assert_eq!((memory.bytes(), memory.to_string().as_str()), (bytes, normalized), "{}", input);
+        }
+        for (input, millicores, normalized) in [("500m", 500, "500m"), ("0.5", 500, "500m"), ("2", 2000, "2"), ("1.25", 1250, "1250m")] {
+            let cpu = Cpu::parse(input).unwrap();
+            assert_eq!((cpu.millicores(), cpu.to_string().as_str()), (millicores, normalized), "{}", input);
+        }
+
+        for input in ["128mb", "Mi", "-1Gi", "1e3", "1..5G", ""] {
+            assert!(Memory::parse(input).is_err(), "{}", input);
+        }
+        for input in ["0,5", "m", "1.5m", "0.0001", "half"] {
+            assert!(Cpu::parse(input).is_err(), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::diagnostics::{self, Diagnostic};
+use crate::error::Failure;
+
+/// Result of `app-bundle verify` / `prepare-deployment` as printed by
+/// `--format json`.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deployment: Option<String>,
+    // relative to the current directory, sorted
+    pub generated_files: Vec<String>,
+    // service -> image the deployment runs instead of the bundle's
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub image_overrides: BTreeMap<String, String>,
+    pub warnings: Vec<Diagnostic>,
+    pub errors: Vec<Diagnostic>,
+}
+
+impl Report {
+    /// Completes the report with the command's outcome and prints it on
+    /// stdout for `json`. The command's error is passed through so the exit
+    /// code stays non-zero.
+    pub fn finish(mut self, result: Result<()>, format: &str, deny_warnings: bool) -> Result<()> {
+        self.complete(&result, deny_warnings);
+        if format == "json" {
+            println!("{}", serde_json::to_string_pretty(&self)?);
+        }
+        result
+    }
+
+    fn complete(&mut self, result: &Result<()>, deny_warnings: bool) {
+        self.warnings = diagnostics::warnings();
+        if let Err(e) = result {
+            let code = Failure::of(e).map_or("error", Failure::code);
+            self.errors.push(Diagnostic { code, message: format!("{:#}", e) });
+        }
+        if deny_warnings && !self.warnings.is_empty() && self.errors.is_empty() {
+            self.errors.push(Diagnostic {
+                code: "warnings_denied",
+                message: format!("{} warning(s) reported and --deny-warnings is set", self.warnings.len()),
+            });
+        }
+        self.ok = self.errors.is_empty();
+    }
+}
+
+/// Modification times of the files under `dir`, taken before a generator
+/// runs so `generated_files` can tell its output from older files there.
+pub fn snapshot(dir: &Path) -> Result<HashMap<PathBuf, SystemTime>> {
+    let mut files = HashMap::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let metadata = fs::metadata(&path)?;
+            if metadata.is_dir() {
+                pending.push(path);
+            } else {
+                files.insert(path, metadata.modified()?);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Files under `dir` that are new or were rewritten since `before`, as
+/// sorted paths relative to the current directory. Generators don't clear
+/// their output directory, so untouched older files are left out.
+pub fn generated_files(dir: &Path, before: &HashMap<PathBuf, SystemTime>) -> Result<Vec<String>> {
+    let mut files: Vec<String> = snapshot(dir)?.into_iter()
+        .filter(|(path, modified)| before.get(path) != Some(modified))
+        .map(|(path, _)| path.to_string_lossy().replace('\\', "/"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, anyhow};
+
+    #[test]
+    fn failures_are_reported_with_their_stage() {
+        let mut report = Report::default();
+        report.complete(&Ok(()), false);
+        assert!(report.ok && report.errors.is_empty());
+
+        let mut report = Report::default();
+        let result = Err(anyhow!("Service api references undefined env var DB_URL")).context(Failure::Validation);
+        report.complete(&result, false);
+        assert!(!report.ok);
+        assert_eq!(report.errors[0].code, "validation_failed");
+        assert_eq!(report.errors[0].message, "Validation failed: Service api references undefined env var DB_URL");
+
+        let mut report = Report::default();
+        report.complete(&Err(anyhow!("Deployment prod not found")), false);
+        assert_eq!(report.errors[0].code, "error");
+    }
+
+    #[test]
+    fn only_files_of_this_run_are_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("stale.yaml"), "").unwrap();
+        let before = snapshot(dir.path()).unwrap();
+        fs::create_dir(dir.path().join("configs")).unwrap();
+        fs::write(dir.path().join("configs/app.conf"), "").unwrap();
+        fs::write(dir.path().join("deploy.sh"), "").unwrap();
+
+        let files = generated_files(dir.path(), &before).unwrap();
+        let root = dir.path().to_string_lossy().replace('\\', "/");
+        assert_eq!(files, vec![format!("{}/configs/app.conf", root), format!("{}/deploy.sh", root)]);
+    }
+}
@@ -1,48 +1,127 @@
-use crate::spec::{DeploymentEnvType, EnvVariable, Healthcheck, ResourcesSpec, ServiceCommand, ServiceConfigOption, ServicePort, ServiceSecret, ServiceType, ServiceVolume};
+use serde::Serialize;
+use crate::spec::{CorsSpec, DeploymentEnvType, Dns01Spec, DevVolume, EnvVariable, Healthcheck, LimitHints, ResourcesSpec, ServiceBuild, ServiceCommand, ServiceConfigOption, ServicePort, ServiceSecret, ServiceType, ServiceVolume};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct EnvironmentResolvedSpec {
     pub env_type: DeploymentEnvType,
     pub ingress: IngressResolvedSpec,
     pub current_deployment: DeploymentResolvedSpec,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct IngressResolvedSpec {
     pub name: String,
     pub tls: Option<IngressTlsResolvedSpec>,
     pub domains: Vec<String>,
+    // domains of hosts that opted out of TLS, served over plain http
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub plain_http_domains: Vec<String>,
+    // rules of a domain are ordered from the longest prefix to the shortest
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub allow_nested_prefixes: bool,
     pub rules: Vec<IngressRule>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub redirects: Vec<RedirectResolvedSpec>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RedirectResolvedSpec {
+    // one of the ingress domains
+    pub from: String,
+    // scheme and domain redirected to, e.g. "https://example.com"
+    pub target: String,
+    pub preserve_path: bool,
+}
+
+impl IngressResolvedSpec {
+    /// Whether the gateway serves `domain` over TLS.
+    pub fn domain_tls(&self, domain: &str) -> bool {
+        self.tls.is_some() && !self.plain_http_domains.iter().any(|d| d == domain)
+    }
+
+    /// Domains the gateway serves over TLS.
+    pub fn tls_domains(&self) -> impl Iterator<Item = &String> {
+        self.domains.iter().filter(|d| self.domain_tls(d))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct IngressTlsResolvedSpec {
     pub secret: Option<String>,
     pub letsencrypt: Option<LetsEncryptResolvedSpec>,
+    // local only: the local ingress serves https with a generated certificate
+    pub self_signed: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct LetsEncryptResolvedSpec {
     pub server: String,
     pub email: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns01: Option<Dns01Spec>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct IngressRule {
     pub domain_name: String,
     pub services: Vec<IngressToServiceRule>,
+    // answers the requests no service prefix matches, and every request in
+    // maintenance
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_backend: Option<DefaultBackendResolvedSpec>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub maintenance: bool,
+}
+
+impl IngressRule {
+    /// Service routes the gateway serves; none while the domain is in maintenance.
+    pub fn active_services(&self) -> &[IngressToServiceRule] {
+        if self.maintenance { &[] } else { &self.services }
+    }
+
+    /// The default backend, unless a root prefix already catches every request.
+    pub fn fallback(&self) -> Option<&DefaultBackendResolvedSpec> {
+        if self.active_services().iter().any(|s| s.prefix.trim_end_matches('/').is_empty()) {
+            None
+        } else {
+            self.default_backend.as_ref()
+        }
+    }
+
+    /// Service routes the gateway serves, then a service default backend on `/`.
+    pub fn routed_services(&self) -> impl Iterator<Item = &IngressToServiceRule> {
+        let fallback = match self.fallback() {
+            Some(DefaultBackendResolvedSpec::Service(svc)) => Some(svc),
+            _ => None,
+        };
+        self.active_services().iter().chain(fallback)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DefaultBackendResolvedSpec {
+    // routed on `/`, without stripping it
+    Service(IngressToServiceRule),
+    // inline HTML, answered with 404, or 503 in maintenance
+    Page(String),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct IngressToServiceRule {
     pub service_name: String,
     pub deployment_name: String,
+    // the Service port on k8s, the published port for the local gateway
     pub port: u16,
+    // the port the container listens on, which docker gateways proxy to
+    pub container_port: u16,
     pub prefix: String,
     pub strip_prefix: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cors: Option<CorsSpec>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ServiceResolvedSpec {
     pub service_type: ServiceType,
     pub is_app_service: bool,
@@ -52,6 +131,9 @@ pub struct ServiceResolvedSpec {
 
     // resolved image with a full name, including registry and version
     pub image: String,
+    // the deployment replaced the appspec's image
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub image_override: bool,
 
     pub service_host: String,
     // Resolution rules for environment_variables:
@@ -83,18 +165,44 @@ pub struct ServiceResolvedSpec {
     // Container health probe, same as docker-compose `healthcheck`.
     pub healthcheck: Option<Healthcheck>,
 
+    // Working directory inside the container, same as docker-compose
+    // `working_dir`. Unrelated to `working_dir` below, which is on the host.
+    pub container_working_dir: Option<String>,
+
+    // User the container runs as, `user` or `user:group`.
+    pub user: Option<String>,
+
+    // Compose profile the service belongs to. Profiled services are emitted with
+    // `profiles:` in compose and gated behind `--with <profile>` in deploy.sh.
+    pub profile: Option<String>,
+
     // local-only: working directory of a host-run (non-dockerized) service.
     // When set, undockerized env is written there as `.env` and secrets copied alongside.
     pub working_dir: Option<String>,
+
+    // local-only: build the image from source instead of pulling it
+    pub build: Option<ServiceBuild>,
+
+    // local-only: host directories bind-mounted for live reload
+    pub dev_volumes: Vec<DevVolume>,
+
+    // replicas, requests and limits, merged from the deployment and the appspec
+    pub resources: ResourcesSpec,
+    // the limits a spec sets, without the built-in defaults; docker
+    // deployments apply only these
+    #[serde(skip)]
+    pub explicit_limits: LimitHints,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SecretResolvedSpec {
     pub name: String,
+    // never serialized in clear text, see inspect::Inspected::secret_values
+    #[serde(serialize_with = "masked")]
     pub value: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DeploymentResolvedSpec {
     pub name: String,
     pub application_name: String,
@@ -102,17 +210,37 @@ pub struct DeploymentResolvedSpec {
     pub secrets: Vec<SecretResolvedSpec>,
     pub defaults: ResourcesSpec,
     pub services: Vec<ServiceResolvedSpec>,
+    // app services the deployment leaves out, with the reason
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped_services: Vec<SkippedServiceResolvedSpec>,
     pub volumes: Vec<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedServiceResolvedSpec {
+    pub name: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct ConfigResolvedSpec {
     pub name: String,
     pub files: Vec<ConfigResolvedFile>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ConfigResolvedFile {
     pub name: String,
+    #[serde(rename = "size", serialize_with = "byte_len")]
     pub content: Vec<u8>,
 }
+
+pub const MASK: &str = "***";
+
+fn masked<S: serde::Serializer>(_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(MASK)
+}
+
+fn byte_len<S: serde::Serializer>(content: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(content.len() as u64)
+}
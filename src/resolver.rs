@@ -1,46 +1,99 @@
 use crate::spec::*;
 use crate::spec::EnvVariable;
 use crate::resolved_spec::*;
+use crate::{diagnostics, secrets, spec_loader};
+use crate::error::Failure;
+use crate::image_ref::ImageRef;
 use anyhow::{Result, anyhow, Context};
 use std::collections::{HashSet, HashMap};
 use std::fs;
 use std::path::Path;
 use std::env;
 
+// Served in maintenance on hosts without a default backend of their own
+const MAINTENANCE_PAGE: &str = "<!DOCTYPE html>\n<html>\n<head><title>Down for maintenance</title></head>\n<body>\n<h1>Down for maintenance</h1>\n<p>We'll be back shortly.</p>\n</body>\n</html>\n";
+
 pub fn resolve(
     env_spec: &DeploymentEnvironmentSpec,
     app_spec: &AppSpec,
     deployment_name: &str
+) -> Result<EnvironmentResolvedSpec> {
+    resolve_deployment(env_spec, app_spec, deployment_name)
+        .context(format!("deployment \"{}\"", deployment_name))
+        .context(Failure::Resolution)
+}
+
+fn resolve_deployment(
+    env_spec: &DeploymentEnvironmentSpec,
+    app_spec: &AppSpec,
+    deployment_name: &str
 ) -> Result<EnvironmentResolvedSpec> {
     let deployment = env_spec.deployments.iter()
         .find(|d| d.name == deployment_name)
         .ok_or_else(|| anyhow!("Deployment {} not found", deployment_name))?;
 
-    // 1. Resolve Configs
+    // 1. Resolve Configs: the deployment's files, preceded by the ones the
+    // appspec gives inline or the app ships
+    let mut config_specs: Vec<ConfigSpec> = deployment.configs.clone();
+    for app_config in &app_spec.configs {
+        let inline: Vec<ConfigFileSpec> = app_config.files.iter().filter(|f| !matches!(f, ConfigFileSpec::PathRef(_))).cloned().collect();
+        if inline.is_empty() {
+            continue;
+        }
+        match config_specs.iter_mut().find(|c| c.name == app_config.name) {
+            Some(config) => {
+                config.files.splice(0..0, inline);
+            }
+            None => config_specs.push(ConfigSpec { name: app_config.name.clone(), files: inline, template: false }),
+        }
+    }
     let mut resolved_configs = Vec::new();
-    for config_spec in &deployment.configs {
-        let mut resolved_files = Vec::new();
-        for file_path in &config_spec.files {
+    for config_spec in &config_specs {
+        let mut resolved_files: Vec<ConfigResolvedFile> = Vec::new();
+        for file in &config_spec.files {
+            let file_path = match file {
+                ConfigFileSpec::PathRef(path) => path,
+                ConfigFileSpec::DirFile { name, path } => {
+                    let content = fs::read(path).context(format!("Failed to read config file {:?}", path))?;
+                    resolved_files.retain(|f| &f.name != name);
+                    resolved_files.push(ConfigResolvedFile { name: name.clone(), content });
+                    continue;
+                }
+                ConfigFileSpec::Inline { name, content } => {
+                    resolved_files.retain(|f| &f.name != name);
+                    resolved_files.push(ConfigResolvedFile { name: name.clone(), content: content.clone().into_bytes() });
+                    continue;
+                }
+                ConfigFileSpec::Bundled { name, content } => {
+                    resolved_files.retain(|f| &f.name != name);
+                    resolved_files.push(ConfigResolvedFile { name: name.clone(), content: content.clone() });
+                    continue;
+                }
+            };
             let path = Path::new(file_path);
             if !path.exists() {
-                 return Err(anyhow!("Config file not found: {:?}", file_path));
+                 return Err(anyhow!("Config file not found: {:?} (config {} of deployment {})", file_path, config_spec.name, deployment.name));
             }
             if path.is_dir() {
-                for entry in fs::read_dir(path)? {
-                    let entry = entry?;
-                    let path = entry.path();
-                    if path.is_file() {
-                         let content = fs::read(&path).context(format!("Failed to read config file {:?}", path))?;
-                         let name = path.file_name().unwrap().to_string_lossy().to_string();
-                         resolved_files.push(ConfigResolvedFile { name, content });
-                    }
+                for (name, path) in spec_loader::config_dir_files(path)? {
+                    let content = fs::read(&path).context(format!("Failed to read config file {:?}", path))?;
+                    resolved_files.retain(|f| f.name != name);
+                    resolved_files.push(ConfigResolvedFile { name, content });
                 }
             } else {
                 let content = fs::read(path).context(format!("Failed to read config file {:?}", path))?;
                 let name = path.file_name().unwrap().to_string_lossy().to_string();
+                resolved_files.retain(|f| f.name != name);
                 resolved_files.push(ConfigResolvedFile { name, content });
             }
         }
+        if let Some(limit) = env_spec.max_config_file_size {
+            if let Some(file) = resolved_files.iter().find(|f| f.content.len() as u64 > limit.bytes()) {
+                return Err(anyhow!("Config file {} of config {} is {} bytes, over the {} max_config_file_size; \
+                    mount large data files from a volume instead, or raise max_config_file_size in the env spec",
+                    file.name, config_spec.name, file.content.len(), limit));
+            }
+        }
         resolved_configs.push(ConfigResolvedSpec {
             name: format!("{}-{}", app_spec.name, config_spec.name),
             files: resolved_files,
@@ -52,23 +105,12 @@ pub fn resolve(
     // Keyed by the secret's original (unprefixed) name so deployment env values
     // can reference them via `$secret(name)`.
     let mut secret_values: HashMap<String, String> = HashMap::new();
-    for secret_spec in &deployment.secrets {
-        let value = match &secret_spec.source {
-            DeploymentSecretSource::EnvVariable(var_name) => {
-                env::var(var_name).context(format!("Secret environment variable {} not set", var_name))?
-            }
-            DeploymentSecretSource::FilePath(path_str) => {
-                let path = Path::new(path_str);
-                if !path.exists() {
-                    return Err(anyhow!("Secret file not found: {:?}", path_str));
-                }
-                fs::read_to_string(path).context(format!("Failed to read secret file {:?}", path_str))?
-            }
-            DeploymentSecretSource::Embedded(value) => value.clone(),
-        };
+    secrets::check_rotated(deployment)?;
+    for (secret_spec, value) in resolve_secret_values(deployment) {
+        let value = value?;
         secret_values.insert(secret_spec.secret_name.clone(), value.clone());
         resolved_secrets.push(SecretResolvedSpec {
-            name: format!("{}-{}", app_spec.name, secret_spec.secret_name),
+            name: secrets::k8s_secret_name(&app_spec.name, &secret_spec.secret_name),
             value,
         });
     }
@@ -83,57 +125,72 @@ pub fn resolve(
     let mut resolved_services = Vec::new();
     let mut public_host_prefix_combinations = HashSet::new();
 
-    let primary_host = &deployment.primary_host;
-
-    for app_service in app_spec.all_services() {
+    // variables of services that may not run here need no value
+    let profile_only_env_vars = app_spec.env_vars_used_only_by(|s| s.profile.is_some() || !deployment.service_enabled(s));
+
+    // SERVICE_<NAME>_HOST / _URL of every service and the `secret:<name>`
+    // values, for references in the app's variables
+    let secret_vars: Vec<EnvVariable> = secret_values.iter()
+        .map(|(name, value)| EnvVariable { name: format!("secret:{}", name), value: value.clone(), sensitive: true })
+        .collect();
+    let service_vars = [service_url_vars(app_spec, deployment, &env_spec.env_type, false), secret_vars.clone()].concat();
+    let undockerized_service_vars = [service_url_vars(app_spec, deployment, &env_spec.env_type, true), secret_vars].concat();
+
+    // Configs flagged `template` in the appspec or the deployment; each
+    // service mounting one renders it, (config, service, rendered files)
+    let templated: HashSet<String> = app_spec.configs.iter().chain(&deployment.configs)
+        .filter(|c| c.template)
+        .map(|c| format!("{}-{}", app_spec.name, c.name))
+        .collect();
+    let mut rendered_configs: Vec<(String, String, Vec<ConfigResolvedFile>)> = Vec::new();
+
+    // Errors are reported with the service they came from
+    let mut resolve_service = |app_service: &ServiceSpec| -> Result<ServiceResolvedSpec> {
         let deployment_service_opt = deployment.services.as_ref().and_then(|s| s.get(&app_service.name));
 
-        let defaults = &deployment.defaults;
-
-        let empty_prefixes = Vec::new();
-        let (variant_name,  prefixes, _resources) =
-            if let Some(ds) = deployment_service_opt {
-             (
-                 ds.variant.as_deref().unwrap_or("default"),
-                 &ds.prefixes,
-                 &ds.resources
-             )
-        } else {
-             ("default",  &empty_prefixes, defaults)
-        };
-
+        let variant_name = deployment_service_opt
+            .and_then(|ds| ds.variant.as_deref())
+            .unwrap_or("default");
+        let (host_name, prefixes) = deployment.service_route(app_service);
 
-
-
-        let mut host_name = primary_host.clone();
-        if let Some(deployment_service) = deployment_service_opt {
-            host_name = deployment_service.host.clone().unwrap_or(primary_host.clone());
-        }
-
-        let mut host_domain_name: &String;
-
-        let host = env_spec.ingress.hosts.iter()
-            .find(|host_spec| &(host_spec.name) == &host_name);
-
-        match host.and_then(|h| h.domain_names.first()) {
-            Some(host) => {
-                host_domain_name = host;
-            },
-            None =>return Err(anyhow!("Host {} not found in ingress spec", host_name)),
-        }
+        // advertised in relative variables and SERVICE_HOST
+        let host_domain_name = *route_domains(env_spec, deployment, &host_name)?.first()
+            .ok_or_else(|| anyhow!("Host {} has no domain left for deployment {}, all of them are another deployment's public_domain", host_name, deployment.name))?;
 
         let is_app_service = app_service.is_app_service;
 
         // Resolve Image
-        let mut raw_image = match &app_service.image {
-            ImageSpec::Exact(img) => img.clone(),
-            ImageSpec::Variants(variants) => variants.iter()
+        let image_override = deployment_service_opt.and_then(|ds| ds.image.clone());
+        let mut raw_image = match (&image_override, &app_service.image) {
+            (Some(image), _) => image.clone(),
+            (None, Some(ImageSpec::Exact(img))) => img.clone(),
+            (None, Some(ImageSpec::Variants(variants))) => variants.iter()
                 .find(|v| v.variant_name == variant_name)
                 .map(|v| v.image.clone())
                 .ok_or_else(|| anyhow!("Image variant '{}' not found for service '{}'", variant_name, app_service.name))?,
+            // Built from source: compose tags the build with this name.
+            (None, None) if env_spec.env_type == DeploymentEnvType::Local => format!("{}-{}", app_spec.name, app_service.name),
+            (None, None) => return Err(anyhow!("Service '{}' declares no image; 'build' is only used in local environments", app_service.name)),
         };
 
-        if is_app_service {
+        if is_app_service && image_override.is_some() {
+             // an override is used as given; only an untagged one gets the version
+             let parsed = ImageRef::parse(&raw_image)?;
+             if parsed.tag.is_none() && parsed.digest.is_none() {
+                  let tag = if env_spec.env_type == DeploymentEnvType::Local { "latest".to_string() } else { app_spec.version.to_string() };
+                  raw_image = format!("{}:{}", raw_image, tag);
+             }
+        } else if is_app_service {
+             // a tag in the appspec only names the locally built image
+             if app_service.image.is_some() {
+                  let parsed = ImageRef::parse(&raw_image)?;
+                  if let Some(tag) = &parsed.tag {
+                       diagnostics::warn("app_image_tag", format!(
+                           "Service {}: ignoring tag '{}' of image {}, app service images are tagged with the app version",
+                           app_service.name, tag, raw_image));
+                       raw_image = parsed.repository();
+                  }
+             }
              if let DeploymentEnvType::Local = env_spec.env_type {
                   raw_image = format!("{}:latest", raw_image);
              } else {
@@ -145,15 +202,15 @@ pub fn resolve(
             return Err(anyhow!("Registry mapping is required for non-local deployments"));
         }
 
-        let image = if is_app_service {
-            resolve_app_service_image(env_spec, raw_image)?
+        let image = if env_spec.mirrors(app_service) {
+            resolve_service_image(env_spec, raw_image)?
         } else {
             raw_image
         };
 
         // Check Public Service uniqueness
         if let ServiceType::Public = app_service.service_type {
-            for prefix in prefixes {
+            for prefix in &prefixes {
                  let key = (host_name.to_string(), prefix.prefix.clone());
                  if !public_host_prefix_combinations.insert(key) {
                      return Err(anyhow!("Duplicate host+prefix combination for public service {}: {}{}",
@@ -162,25 +219,53 @@ pub fn resolve(
             }
         }
 
+        // The service's own deployment values, over the deployment's; they
+        // replace internal and relative variables too, and names the app does
+        // not declare are added for this service alone
+        let service_values = match deployment_service_opt {
+            Some(ds) => substitute_secret_refs(&ds.environment, &secret_values)?,
+            None => Vec::new(),
+        };
+        for var in &service_values {
+            if app_spec.environment.internal.iter().any(|v| v.name == var.name) || app_spec.environment.relative.iter().any(|v| v.name == var.name) {
+                diagnostics::warn("service_env_overrides_app", format!(
+                    "Deployment {} sets {} for service {}, replacing the app's own value for it", deployment.name, var.name, app_service.name));
+            }
+        }
+        let mut service_environment = deployment_environment.clone();
+        for var in &service_values {
+            add_unique_var(&mut service_environment, var.clone());
+        }
+
         // Resolve Environment Variables
-        let use_tls = env_spec.ingress.tls.is_some();
-        let environment_variables = resolve_app_env_vars(app_spec, &deployment_environment, Some(host_domain_name), use_tls)?;
-        let final_service_env_vars = filter_service_env_vars(app_service, &environment_variables)?;
+        let use_tls = env_spec.ingress.tls.is_some()
+            && env_spec.ingress.hosts.iter().any(|h| h.name == host_name && h.tls);
+        let environment_variables = resolve_app_env_vars(app_spec, &service_vars, &service_environment, &service_values, Some(host_domain_name), use_tls, &profile_only_env_vars)?;
+        // the selected variant's entries come last and win over the service's
+        let env_options = deployment.service_env_options(app_service);
+        let final_service_env_vars = filter_service_env_vars(app_service, &env_options, &environment_variables, &service_vars)?;
 
         // Resolve Undockerized Environment Variables
         let mut undockerized_values = deployment_environment.clone();
-        for override_var in &deployment_undockerized_environment {
+        for override_var in deployment_undockerized_environment.iter().chain(&service_values) {
             add_unique_var(&mut undockerized_values, override_var.clone());
         }
-        let undockerized_variables = resolve_app_env_vars(app_spec, &undockerized_values, Some(host_domain_name), use_tls)?;
-        let final_undockerized_service_env_vars = filter_service_env_vars(app_service, &undockerized_variables)?;
+        let undockerized_variables = resolve_app_env_vars(app_spec, &undockerized_service_vars, &undockerized_values, &service_values, Some(host_domain_name), use_tls, &profile_only_env_vars)?;
+        let final_undockerized_service_env_vars = filter_service_env_vars(app_service, &env_options, &undockerized_variables, &undockerized_service_vars)?;
 
         // Resolve Configs
         let mut service_configs = Vec::new();
         for sc_opt in &app_service.configs {
              let config_name = format!("{}-{}", app_spec.name, sc_opt.config_name);
-             if !resolved_configs.iter().any(|c| c.name == config_name) {
+             let Some(config) = resolved_configs.iter().find(|c| c.name == config_name) else {
                   return Err(anyhow!("Service {} references undefined config {}", app_service.name, config_name));
+             };
+             if templated.contains(&config_name) {
+                  let vars = with_service_vars(&service_vars, &environment_variables);
+                  let files = config.files.iter()
+                      .map(|file| render_config_file(file, &vars).context(format!("config {}", sc_opt.config_name)))
+                      .collect::<Result<Vec<_>>>()?;
+                  rendered_configs.push((config_name.clone(), app_service.name.clone(), files));
              }
              service_configs.push(ServiceConfigOption {
                  config_name,
@@ -191,7 +276,7 @@ pub fn resolve(
         // Resolve Secrets
         let mut service_secrets = Vec::new();
         for sec in &app_service.secrets {
-             let secret_name = format!("{}-{}", app_spec.name, sec.name);
+             let secret_name = secrets::k8s_secret_name(&app_spec.name, &sec.name);
              if !resolved_secrets.iter().any(|s| s.name == secret_name) {
                   return Err(anyhow!("Service {} references undefined secret {}", app_service.name, secret_name));
              }
@@ -201,11 +286,12 @@ pub fn resolve(
              });
         }
 
-        resolved_services.push(ServiceResolvedSpec {
+        Ok(ServiceResolvedSpec {
             full_name: format!("{}", app_service.name),
             service_type: app_service.service_type.clone(),
             is_app_service,
             image,
+            image_override: image_override.is_some(),
             service_host: host_domain_name.clone(),
             environment_variables: final_service_env_vars,
             undockerized_environment_variables: final_undockerized_service_env_vars,
@@ -215,11 +301,32 @@ pub fn resolve(
             command: app_service.command.clone(),
             entrypoint: app_service.entrypoint.clone(),
             healthcheck: app_service.healthcheck.clone(),
-            ports: deployment_service_opt.map(|s|
-                s.ports.clone()
-            ).unwrap_or(app_service.ports.clone()),
+            container_working_dir: app_service.working_dir.clone(),
+            user: app_service.user.clone(),
+            profile: app_service.profile.clone(),
+            ports: deployment.service_ports(app_service),
+            resources: deployment.service_resources(app_service),
+            explicit_limits: deployment.service_explicit_limits(app_service),
             working_dir: deployment_service_opt.and_then(|s| s.working_dir.clone()),
-        });
+            build: if env_spec.env_type == DeploymentEnvType::Local { app_service.build.clone() } else { None },
+            dev_volumes: if env_spec.env_type == DeploymentEnvType::Local { app_service.dev_volumes.clone() } else { vec![] },
+        })
+    };
+    for app_service in app_spec.all_services().filter(|s| deployment.service_enabled(s)) {
+        let resolved_service = resolve_service(app_service).context(format!("service \"{}\"", app_service.name))?;
+        resolved_services.push(resolved_service);
+    }
+
+    // A config is one set of files, so every service mounting a template
+    // must render it the same
+    for config in &mut resolved_configs {
+        let mut renders = rendered_configs.iter().filter(|(name, _, _)| *name == config.name);
+        let Some((_, first_service, files)) = renders.next() else { continue };
+        if let Some((_, service, _)) = renders.find(|(_, _, other)| other.iter().map(|f| &f.content).ne(files.iter().map(|f| &f.content))) {
+            return Err(anyhow!("Config template {} renders differently for services {} and {}, which see different variable values",
+                config.name, first_service, service));
+        }
+        config.files = files.clone();
     }
 
     let current_deployment = DeploymentResolvedSpec {
@@ -229,65 +336,119 @@ pub fn resolve(
         secrets: resolved_secrets,
         defaults: deployment.defaults.clone(),
         services: resolved_services,
+        skipped_services: app_spec.all_services()
+            .filter_map(|s| deployment.service_skip_reason(s).map(|reason| SkippedServiceResolvedSpec { name: s.name.clone(), reason }))
+            .collect(),
         volumes: app_spec.volumes.clone(),
     };
 
-    // Validate that every Public service configured in the current deployment has at least one ingress rule.
-    // A Public service with no prefixes is likely a configuration mistake.
-    if let Some(dep_services) = &deployment.services {
-        for app_service in app_spec.all_services() {
-            if let ServiceType::Public = app_service.service_type {
-                if let Some(ds) = dep_services.get(&app_service.name) {
-                    if ds.prefixes.is_empty() {
-                        return Err(anyhow!(
-                            "Public service '{}' in deployment '{}' has no prefixes configured and will not be reachable via ingress.",
-                            app_service.name,
-                            deployment.name
-                        ));
-                    }
-                }
+    // Routes of every deployment sharing the gateway. The current deployment's
+    // come from its services' effective routing, which includes the appspec's
+    // `export:` defaults; the appspecs of the other deployments are not loaded,
+    // so theirs come from their `services:` entries only.
+    let mut routes: Vec<ServiceRoute> = Vec::new();
+    for dep in &env_spec.deployments {
+        if dep.name == deployment.name {
+            let mut services: Vec<&ServiceSpec> = app_spec.all_services().filter(|s| dep.service_enabled(s)).collect();
+            services.sort_by(|a, b| a.name.cmp(&b.name));
+            for app_service in services {
+                let (host, prefixes) = dep.service_route(app_service);
+                routes.push(ServiceRoute {
+                    deployment_name: &dep.name,
+                    service_name: &app_service.name,
+                    domains: route_domains(env_spec, dep, &host)?,
+                    host,
+                    prefixes,
+                    ports: dep.service_ports(app_service),
+                });
+            }
+        } else {
+            for (service_name, ds) in dep.services.iter().flatten().filter(|(_, ds)| ds.enabled) {
+                let host = ds.host.clone().unwrap_or(dep.primary_host.clone());
+                routes.push(ServiceRoute {
+                    deployment_name: &dep.name,
+                    service_name,
+                    domains: route_domains(env_spec, dep, &host)?,
+                    host,
+                    prefixes: ds.prefixes.clone(),
+                    ports: ds.ports.clone(),
+                });
             }
         }
     }
 
+    // The gateways only proxy HTTP, so services whose ports are all UDP get no
+    // ingress rule.
+    let udp_only = |ports: &[ServicePort]| !ports.is_empty() && ports.iter().all(|p| p.protocol == PortProtocol::Udp);
+    for route in &routes {
+        if route.deployment_name == &deployment.name && udp_only(&route.ports) && !route.prefixes.is_empty() {
+            diagnostics::warn("udp_not_routed", format!(
+                "Service {} only has UDP ports; the gateway proxies HTTP, so its prefixes are not routed",
+                route.service_name
+            ));
+        }
+    }
+
     let mut ingress_rules = Vec::new();
     for host_spec in &env_spec.ingress.hosts {
         for domain in &host_spec.domain_names {
             let mut service_rules = Vec::new();
 
-            for dep in &env_spec.deployments {
-                let dep_primary_host = &dep.primary_host;
-                for (service_name, ds) in dep.services.clone().unwrap_or_default() {
-                        let h = ds.host.clone().unwrap_or(dep_primary_host.clone());
-                        if &h == &host_spec.name {
-                            let full_name = format!("{}", service_name);
-                            // Determine port
-                            let port = if let Some(_) = ds.ports.iter().find(|p| p.external == 80) {
-                                80
-                            } else if let Some(p) = ds.ports.first() {
-                                p.external
-                            } else {
-                                80 // Default
-                            };
-
-                            for prefix in &ds.prefixes {
-                                service_rules.push(IngressToServiceRule {
-                                    service_name: full_name.clone(),
-                                    deployment_name: dep.name.clone(),
-                                    port,
-                                    prefix: prefix.prefix.clone(),
-                                    strip_prefix: prefix.strip,
-                                });
-                            }
+            for route in &routes {
+                if route.host == host_spec.name && route.domains.contains(&domain) && !udp_only(&route.ports) {
+                    let (port, container_port) = routed_port(&route.ports);
+
+                    for prefix in &route.prefixes {
+                        service_rules.push(IngressToServiceRule {
+                            service_name: route.service_name.clone(),
+                            deployment_name: route.deployment_name.clone(),
+                            port,
+                            container_port,
+                            prefix: prefix.prefix.clone(),
+                            strip_prefix: prefix.strip,
+                            cors: prefix.cors.clone(),
+                        });
                     }
-
                 }
             }
 
-            if !service_rules.is_empty() {
+            // Nested prefixes are matched most specific first, whatever the
+            // order the gateway evaluates its rules in
+            if env_spec.ingress.allow_nested_prefixes {
+                service_rules.sort_by_key(|r| std::cmp::Reverse(r.prefix.trim_end_matches('/').len()));
+            }
+
+            // A deployment in maintenance takes down every domain it is routed on
+            let maintenance = routes.iter()
+                .filter(|r| r.domains.contains(&domain))
+                .any(|r| env_spec.deployments.iter().any(|d| &d.name == r.deployment_name && d.maintenance));
+            let default_backend = match &host_spec.default_backend {
+                None if maintenance => Some(DefaultBackendResolvedSpec::Page(MAINTENANCE_PAGE.to_string())),
+                None => None,
+                Some(DefaultBackendSpec::Page(page)) => Some(DefaultBackendResolvedSpec::Page(page.clone())),
+                Some(DefaultBackendSpec::Service(name)) => {
+                    let route = routes.iter()
+                        .find(|r| r.service_name == name && r.domains.contains(&domain))
+                        .ok_or_else(|| anyhow!("default_backend of host {} is service {}, which no deployment routed on {} runs", host_spec.name, name, domain))?;
+                    let (port, container_port) = routed_port(&route.ports);
+                    Some(DefaultBackendResolvedSpec::Service(IngressToServiceRule {
+                        service_name: route.service_name.clone(),
+                        deployment_name: route.deployment_name.clone(),
+                        port,
+                        container_port,
+                        prefix: "/".to_string(),
+                        strip_prefix: false,
+                        cors: None,
+                    }))
+                }
+            };
+
+            if !service_rules.is_empty() || default_backend.is_some() {
                 ingress_rules.push(IngressRule {
                     domain_name: domain.clone(),
                     services: service_rules,
+                    default_backend,
+                    maintenance,
                 });
             }
         }
@@ -298,6 +459,7 @@ pub fn resolve(
              Some(LetsEncryptResolvedSpec {
                  server: le.server.clone().unwrap_or("https://acme-v02.api.letsencrypt.org/directory".to_string()),
                  email: le.email.clone(),
+                 dns01: le.dns01.clone(),
              })
         } else {
              None
@@ -305,15 +467,34 @@ pub fn resolve(
         Some(IngressTlsResolvedSpec {
             secret: tls_spec.secret.clone(),
             letsencrypt: le_resolved,
+            self_signed: tls_spec.self_signed,
         })
     } else {
         None
     };
 
+    // Redirect sources are served, and get certificates, like host domains
+    let plain_http_domains: Vec<String> = env_spec.ingress.hosts.iter().filter(|h| !h.tls).flat_map(|h| h.domain_names.clone()).collect();
+    let redirects = env_spec.ingress.redirects.iter()
+        .map(|r| {
+            let https = tls.is_some() && !plain_http_domains.contains(&r.to);
+            RedirectResolvedSpec {
+                from: r.from.clone(),
+                target: format!("{}://{}", if https { "https" } else { "http" }, r.to),
+                preserve_path: r.preserve_path,
+            }
+        })
+        .collect();
+
     let ingress_resolved = IngressResolvedSpec {
         name: env_spec.ingress.name.clone(),
-        domains: env_spec.ingress.hosts.iter().flat_map(|h| h.domain_names.clone()).collect(),
+        domains: env_spec.ingress.hosts.iter().flat_map(|h| h.domain_names.clone())
+            .chain(env_spec.ingress.redirects.iter().map(|r| r.from.clone()))
+            .collect(),
+        plain_http_domains,
+        allow_nested_prefixes: env_spec.ingress.allow_nested_prefixes,
         rules: ingress_rules,
+        redirects,
         tls,
     };
 
@@ -324,28 +505,152 @@ pub fn resolve(
     })
 }
 
-fn resolve_app_service_image(env_spec: &DeploymentEnvironmentSpec, raw_image: String) -> Result<String> {
-    let image = if let Some((namespace, _rest)) = raw_image.split_once('/') {
-        if let Some(registry_host) = env_spec.registry.get(namespace) {
-            let registry_host = registry_host.strip_suffix('/').unwrap_or(registry_host);
-            format!("{}/{}", registry_host, raw_image)
-        } else {
-            if env_spec.env_type == DeploymentEnvType::Local {
-                raw_image
-            } else {
-                let available: Vec<_> = env_spec.registry.keys().collect();
-                return Err(anyhow!("Docker registry host for namespace '{}' not found in environment spec. Available namespaces: {:?}", namespace, available));
+// Port the gateway routes a service on: the one published on 80, else the
+// first TCP port; the service port and the port the container listens on
+fn routed_port(ports: &[ServicePort]) -> (u16, u16) {
+    let mut tcp_ports = ports.iter().filter(|p| p.protocol == PortProtocol::Tcp);
+    let routed_port = ports.iter()
+        .find(|p| p.external == Some(80) && p.protocol == PortProtocol::Tcp)
+        .or_else(|| tcp_ports.next());
+    match routed_port {
+        Some(p) => (p.service_port(), p.internal),
+        None => (80, 80), // Default
+    }
+}
+
+// A service the gateway may route to, in any deployment of the environment.
+struct ServiceRoute<'a> {
+    deployment_name: &'a String,
+    service_name: &'a String,
+    // gateway host alias
+    host: String,
+    // domains of the host the service is routed on
+    domains: Vec<&'a String>,
+    prefixes: Vec<Prefix>,
+    ports: Vec<ServicePort>,
+}
+
+/// Domains of gateway host `host_name` that carry the routes of `deployment`;
+/// it is advertised on the first one. A deployment's `public_domain` is used
+/// alone for its primary host, and no other deployment is routed on it.
+fn route_domains<'a>(env_spec: &'a DeploymentEnvironmentSpec, deployment: &DeploymentSpec, host_name: &str) -> Result<Vec<&'a String>> {
+    let host = env_spec.ingress.hosts.iter()
+        .find(|h| h.name == host_name)
+        .ok_or_else(|| anyhow!("Host {} not found in ingress spec", host_name))?;
+
+    if host_name == deployment.primary_host {
+        if let Some(domain) = &deployment.public_domain {
+            return host.domain_names.iter()
+                .find(|d| *d == domain)
+                .map(|d| vec![d])
+                .ok_or_else(|| anyhow!("public_domain {} of deployment {} is not a domain of host {}", domain, deployment.name, host_name));
+        }
+    }
+    let claimed: Vec<&String> = env_spec.deployments.iter()
+        .filter(|d| d.primary_host == host_name)
+        .filter_map(|d| d.public_domain.as_ref())
+        .collect();
+    Ok(host.domain_names.iter().filter(|d| !claimed.contains(d)).collect())
+}
+
+/// Reads every secret of the deployment from its source. Failures are kept per
+/// secret, so callers can report all missing secrets instead of the first one.
+pub fn resolve_secret_values(deployment: &DeploymentSpec) -> Vec<(&DeploymentSecretSpec, Result<String>)> {
+    deployment.secrets.iter()
+        .map(|secret_spec| (secret_spec, resolve_secret_value(secret_spec)))
+        .collect()
+}
+
+/// Like `resolve_secret_values`, without generating missing `generate:`
+/// values or rotating any: those are always available, so the state file is
+/// left untouched.
+pub fn check_secret_values(deployment: &DeploymentSpec) -> Vec<(&DeploymentSecretSpec, Result<()>)> {
+    deployment.secrets.iter()
+        .map(|secret_spec| {
+            let available = match secret_spec.source {
+                DeploymentSecretSource::Generated { .. } => Ok(()),
+                _ => resolve_secret_value(secret_spec).map(|_| ()),
+            };
+            (secret_spec, available)
+        })
+        .collect()
+}
+
+fn resolve_secret_value(secret_spec: &DeploymentSecretSpec) -> Result<String> {
+    let value = match &secret_spec.source {
+        DeploymentSecretSource::EnvVariable(var_name) => {
+            env::var(var_name).context(format!("Secret environment variable {} not set", var_name))?
+        }
+        DeploymentSecretSource::FilePath(path_str) => {
+            let path = Path::new(path_str);
+            if !path.exists() {
+                return Err(anyhow!("Secret file not found: {:?}", path_str));
             }
+            fs::read_to_string(path).context(format!("Failed to read secret file {:?}", path_str))?
+        }
+        DeploymentSecretSource::Embedded(value) => value.clone(),
+        DeploymentSecretSource::Store { env, path } => match env::var(env) {
+            Ok(value) => value,
+            Err(_) => fs::read_to_string(path).context(format!("Failed to read stored secret {:?}", path))?,
+        },
+        DeploymentSecretSource::Cluster { env, name } => match env::var(env) {
+            Ok(value) => value,
+            Err(_) => secrets::read_k8s_secret(name)
+                .context(format!("Secret environment variable {} not set and Secret {} could not be read from the cluster", env, name))?,
+        },
+        DeploymentSecretSource::Generated { deployment, length, format, state } => {
+            secrets::generated_value(state, deployment, &secret_spec.secret_name, *length, *format)?
         }
-    } else {
-        raw_image
     };
-    Ok(image)
+    Ok(value)
+}
+
+fn resolve_service_image(env_spec: &DeploymentEnvironmentSpec, raw_image: String) -> Result<String> {
+    if let Some(registry_host) = env_spec.registry_host(&raw_image) {
+        return Ok(format!("{}/{}", registry_host, raw_image));
+    }
+    match raw_image.split_once('/') {
+        Some((namespace, _)) if env_spec.env_type != DeploymentEnvType::Local => {
+            let available: Vec<_> = env_spec.registry.keys().collect();
+            Err(anyhow!("Docker registry host for namespace '{}' not found in environment spec. Available namespaces: {:?}", namespace, available))
+        }
+        _ => Ok(raw_image),
+    }
+}
+
+/// `SERVICE_<NAME>_HOST` and `SERVICE_<NAME>_URL` for every service of the
+/// app. The URL uses the first TCP port, so services without one only get a
+/// host: the container port, or on k8s the port of the service's Service.
+/// Undockerized, they point at the port published on localhost.
+fn service_url_vars(app_spec: &AppSpec, deployment: &DeploymentSpec, env_type: &DeploymentEnvType, undockerized: bool) -> Vec<EnvVariable> {
+    let mut vars = Vec::new();
+    for app_service in app_spec.all_services() {
+        let host = if undockerized { "localhost".to_string() } else { app_service.name.clone() };
+        let ports = deployment.service_ports(app_service);
+        let mut tcp_ports = ports.iter().filter(|p| p.protocol == PortProtocol::Tcp);
+        let port = if undockerized {
+            tcp_ports.find_map(|p| p.external)
+        } else if *env_type == DeploymentEnvType::K8S {
+            tcp_ports.next().map(|p| p.service_port())
+        } else {
+            tcp_ports.next().map(|p| p.internal)
+        };
+
+        if let Some(port) = port {
+            vars.push(EnvVariable {
+                name: service_env_var_name(&app_service.name, "URL"),
+                value: format!("http://{}:{}", host, port),
+                sensitive: false,
+            });
+        }
+        vars.push(EnvVariable { name: service_env_var_name(&app_service.name, "HOST"), value: host, sensitive: false });
+    }
+    vars
 }
 
 fn add_unique_var(vars: &mut Vec<EnvVariable>, var: EnvVariable) {
     if let Some(existing) = vars.iter_mut().find(|v| v.name == var.name) {
-        existing.value = var.value;
+        *existing = var;
     } else {
         vars.push(var);
     }
@@ -390,25 +695,58 @@ fn substitute_secret_refs(vars: &[EnvVariable], secrets: &HashMap<String, String
             Ok(EnvVariable {
                 name: v.name.clone(),
                 value: resolve_secret_refs_in_string(&v.value, secrets)?,
+                sensitive: false,
             })
         })
         .collect()
 }
 
+/// Expands `${NAME}` references in `input` with the values in `vars`.
+/// `${NAME:-default}` falls back to `default` when `NAME` is not set and
+/// `${NAME:?message}` fails with `message`. Defaults may reference other
+/// variables, e.g. `${A:-${B}}`. `${secret:NAME}` expands to the secret
+/// given in `vars` as `secret:NAME`. `$${...}` produces a literal `${...}` and
+/// a `$` not followed by `{` is kept as is.
 pub fn resolve_variable_in_string(input: &String, vars: &[EnvVariable]) -> Result<String> {
     let mut result = String::new();
     let mut last_end = 0;
 
     while let Some(start) = input[last_end..].find("${") {
         let absolute_start = last_end + start;
+
+        // `$${...}` is passed through as a literal `${...}`
+        if absolute_start > last_end && input.as_bytes()[absolute_start - 1] == b'$' {
+            result.push_str(&input[last_end..absolute_start - 1]);
+            let literal_end = closing_brace(input, absolute_start + 2).map_or(input.len(), |end| end + 1);
+            result.push_str(&input[absolute_start..literal_end]);
+            last_end = literal_end;
+            continue;
+        }
         result.push_str(&input[last_end..absolute_start]);
 
-        if let Some(end_offset) = input[absolute_start..].find('}') {
-            let absolute_end = absolute_start + end_offset;
-            let var_name = &input[absolute_start + 2..absolute_end];
+        if let Some(absolute_end) = closing_brace(input, absolute_start + 2) {
+            let reference = &input[absolute_start + 2..absolute_end];
+            // secrets are in `vars` as `secret:NAME`
+            if let Some(secret_name) = reference.strip_prefix("secret:") {
+                let secret = vars.iter().find(|v| v.name == reference)
+                    .ok_or_else(|| anyhow!("Undefined secret: {}", secret_name))?;
+                result.push_str(&secret.value);
+                last_end = absolute_end + 1;
+                continue;
+            }
+            let (var_name, fallback) = match reference.find(':') {
+                Some(colon) => (&reference[..colon], Some(&reference[colon + 1..])),
+                None => (reference, None),
+            };
 
             if let Some(var) = vars.iter().find(|v| v.name == var_name) {
                 result.push_str(&var.value);
+            } else if let Some(default) = fallback.and_then(|f| f.strip_prefix('-')) {
+                result.push_str(&resolve_variable_in_string(&default.to_string(), vars)?);
+            } else if let Some(message) = fallback.and_then(|f| f.strip_prefix('?')) {
+                return Err(anyhow!("{}: {} (in {})", var_name, message, input));
+            } else if let Some(f) = fallback {
+                return Err(anyhow!("Invalid variable reference: ${{{}:{}}} (expected :- or :?)", var_name, f));
             } else {
                 return Err(anyhow!("Undefined variable: {}", var_name));
             }
@@ -423,11 +761,40 @@ pub fn resolve_variable_in_string(input: &String, vars: &[EnvVariable]) -> Resul
     Ok(result)
 }
 
+// Index of the `}` closing a reference whose body starts at `from`, skipping
+// references nested in a default.
+fn closing_brace(input: &str, from: usize) -> Option<usize> {
+    let bytes = input.as_bytes();
+    let mut depth = 1;
+    let mut i = from;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'$' if bytes.get(i + 1) == Some(&b'{') => {
+                depth += 1;
+                i += 1;
+            }
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
 fn resolve_app_env_vars(
     app_spec: &AppSpec,
+    service_vars: &[EnvVariable],
     deployment_values: &[EnvVariable],
+    // a service's own deployment values, which replace app-defined ones
+    service_values: &[EnvVariable],
     host_domain_name: Option<&String>,
     use_tls: bool,
+    profile_only_env_vars: &HashSet<String>,
 ) -> Result<Vec<EnvVariable>> {
     let mut environment_variables = Vec::new();
 
@@ -439,7 +806,10 @@ fn resolve_app_env_vars(
              .or_else(|| external.default.clone());
 
          if let Some(v) = val {
-              add_unique_var(&mut environment_variables, EnvVariable{ name: external.name.clone(), value:v });
+              add_unique_var(&mut environment_variables, EnvVariable{ name: external.name.clone(), value:v, sensitive: false });
+         } else if profile_only_env_vars.contains(&external.name) {
+              // Only profiled services use it; they start without the value.
+              continue;
          } else {
               return Err(anyhow!("Missing external env variable: {}", external.name));
          }
@@ -452,44 +822,164 @@ fn resolve_app_env_vars(
             .map(|e| e.value.clone());
 
         if let Some(v) = val {
-            add_unique_var(&mut environment_variables, EnvVariable{ name: optional.name.clone(), value:v });
+            add_unique_var(&mut environment_variables, EnvVariable{ name: optional.name.clone(), value:v, sensitive: false });
         }
     }
 
-    // Relative
-    for relative in &app_spec.environment.relative {
-         if let Some(h) = host_domain_name {
-              let scheme = if use_tls { "https" } else { "http" };
-              let url = format!("{}://{}{}", scheme, h, relative.relative_value);
-              let value = resolve_variable_in_string(&url, &environment_variables)
-                  .context(format!("Failed to resolve relative env variable {}", relative.name))?;
-              add_unique_var(&mut environment_variables, EnvVariable{
-                  name:relative.name.clone(),
-                  value,
-              });
-         }
+    // Relative and internal, each after the variables it references
+    let scheme = if use_tls { "https" } else { "http" };
+    let mut templates: Vec<(&String, String, &str)> = Vec::new();
+    if let Some(h) = host_domain_name {
+        for relative in &app_spec.environment.relative {
+            templates.push((&relative.name, format!("{}://{}{}", scheme, h, relative.relative_value), "relative"));
+        }
     }
-
-    // Internal
     for internal in &app_spec.environment.internal {
-        let value = resolve_variable_in_string(&internal.value, &environment_variables)
-            .context(format!("Failed to resolve internal env variable {}", internal.name))?;
-        add_unique_var(&mut environment_variables, EnvVariable{
-            name: internal.name.clone(),
-            value,
-        });
+        templates.push((&internal.name, internal.value.clone(), "internal"));
+    }
+
+    for index in resolution_order(&templates)? {
+        let (name, template, kind) = &templates[index];
+        if let Some(value) = service_values.iter().find(|v| &v.name == *name) {
+            add_unique_var(&mut environment_variables, value.clone());
+            continue;
+        }
+        let vars = with_service_vars(service_vars, &environment_variables);
+        let value = resolve_variable_in_string(template, &vars)
+            .context(format!("Failed to resolve {} env variable {}", kind, name))?;
+        let sensitive = references_sensitive(template, &vars);
+        add_unique_var(&mut environment_variables, EnvVariable{ name: (*name).clone(), value, sensitive });
+    }
+
+    for var in service_values {
+        if !environment_variables.iter().any(|v| v.name == var.name) {
+            environment_variables.push(var.clone());
+        }
     }
 
     Ok(environment_variables)
 }
 
+// Renders `${NAME}` references in a templated config file; `$${` stays a
+// literal `${`. Secrets must be mounted, not copied into a config.
+fn render_config_file(file: &ConfigResolvedFile, vars: &[EnvVariable]) -> Result<ConfigResolvedFile> {
+    let text = std::str::from_utf8(&file.content)
+        .map_err(|_| anyhow!("Config file {} is not UTF-8 text, so it cannot be a template", file.name))?
+        .to_string();
+    if references_sensitive(&text, vars) || !secret_references(&text).is_empty() {
+        return Err(anyhow!("Config file {} references a secret; mount the secret instead of rendering it into a config", file.name));
+    }
+    let content = resolve_variable_in_string(&text, vars).context(format!("Failed to render config file {}", file.name))?;
+    Ok(ConfigResolvedFile { name: file.name.clone(), content: content.into_bytes() })
+}
+
+// Indices of `templates` ordered so that every variable comes after the
+// templated variables it references. A cycle is reported as `A -> B -> A`.
+fn resolution_order(templates: &[(&String, String, &str)]) -> Result<Vec<usize>> {
+    fn visit(index: usize, templates: &[(&String, String, &str)], done: &mut Vec<usize>, path: &mut Vec<usize>) -> Result<()> {
+        if done.contains(&index) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|&i| i == index) {
+            let chain: Vec<&str> = path[start..].iter().chain([&index]).map(|&i| templates[i].0.as_str()).collect();
+            return Err(anyhow!("Env variables reference each other in a cycle: {}", chain.join(" -> ")));
+        }
+        path.push(index);
+        for name in referenced_names(&templates[index].1) {
+            if let Some(dependency) = templates.iter().position(|(n, _, _)| n.as_str() == name) {
+                visit(dependency, templates, done, path)?;
+            }
+        }
+        path.pop();
+        done.push(index);
+        Ok(())
+    }
+
+    let mut done = Vec::new();
+    for index in 0..templates.len() {
+        visit(index, templates, &mut done, &mut Vec::new())?;
+    }
+    Ok(done)
+}
+
+/// Names referenced by `${...}` in `input`, including those in defaults;
+/// `$${...}` literals are skipped and secrets are returned as `secret:NAME`.
+pub fn referenced_names(input: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut last_end = 0;
+    while let Some(start) = input[last_end..].find("${") {
+        let absolute_start = last_end + start;
+        let Some(end) = closing_brace(input, absolute_start + 2) else {
+            break;
+        };
+        if absolute_start == last_end || input.as_bytes()[absolute_start - 1] != b'$' {
+            let reference = &input[absolute_start + 2..end];
+            match reference.split_once(':') {
+                Some(("secret", _)) => names.push(reference),
+                Some((name, fallback)) => {
+                    names.push(name);
+                    names.extend(referenced_names(fallback));
+                }
+                None => names.push(reference),
+            }
+        }
+        last_end = end + 1;
+    }
+    names
+}
+
+/// Names `input` cannot be resolved without: `${NAME}` and `${NAME:?message}`
+/// references, but not those with a `:-` default, nor secrets.
+pub fn required_references(input: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut last_end = 0;
+    while let Some(start) = input[last_end..].find("${") {
+        let absolute_start = last_end + start;
+        let Some(end) = closing_brace(input, absolute_start + 2) else {
+            break;
+        };
+        if absolute_start == last_end || input.as_bytes()[absolute_start - 1] != b'$' {
+            match input[absolute_start + 2..end].split_once(':') {
+                Some((name, fallback)) if name != "secret" && !fallback.starts_with('-') => names.push(name),
+                Some(_) => {}
+                None => names.push(&input[absolute_start + 2..end]),
+            }
+        }
+        last_end = end + 1;
+    }
+    names
+}
+
+/// Secrets referenced as `${secret:NAME}` in `input`.
+pub fn secret_references(input: &str) -> Vec<&str> {
+    referenced_names(input).into_iter().filter_map(|name| name.strip_prefix("secret:")).collect()
+}
+
+// Whether `template` references a secret or a variable that embeds one.
+fn references_sensitive(template: &str, vars: &[EnvVariable]) -> bool {
+    referenced_names(template).into_iter()
+        .any(|name| vars.iter().any(|v| v.name == name && v.sensitive))
+}
+
+// Variables to resolve references against: the generated service variables,
+// unless the app defines a variable of the same name.
+fn with_service_vars(service_vars: &[EnvVariable], vars: &[EnvVariable]) -> Vec<EnvVariable> {
+    let mut all = service_vars.to_vec();
+    for var in vars {
+        add_unique_var(&mut all, var.clone());
+    }
+    all
+}
+
 fn filter_service_env_vars(
     app_service: &ServiceSpec,
-    all_env_vars: &[EnvVariable]
+    env_options: &[ServiceEnvOption],
+    all_env_vars: &[EnvVariable],
+    service_vars: &[EnvVariable],
 ) -> Result<Vec<EnvVariable>> {
     let mut final_service_env_vars = Vec::new();
 
-    for svc_env_opt in &app_service.environment {
+    for svc_env_opt in env_options {
          match svc_env_opt {
              ServiceEnvOption::All => {
                  for env_var in all_env_vars {
@@ -497,17 +987,23 @@ fn filter_service_env_vars(
                  }
              }
              ServiceEnvOption::Simple(name) => {
-                 if let Some(env_var) = all_env_vars.iter().find(|e| &e.name == name) {
+                 let env_var = all_env_vars.iter().chain(service_vars).find(|e| &e.name == name);
+                 if let Some(env_var) = env_var {
                      add_unique_var(&mut final_service_env_vars, env_var.clone());
+                 } else if let Some(profile) = &app_service.profile {
+                     diagnostics::warn("profile_env_missing", format!("Service {} (profile {}) references env var {} which is not provided by the deployment",
+                         app_service.name, profile, name));
                  } else {
                      return Err(anyhow!("Service {} references undefined env var {}", app_service.name, name));
                  }
              }
              ServiceEnvOption::WithValue(k, v) => {
+                 let vars = with_service_vars(service_vars, all_env_vars);
                  add_unique_var(&mut final_service_env_vars,EnvVariable{
                      name: k.clone(),
-                     value: resolve_variable_in_string(v, all_env_vars)
-                         .context(format!("{}: Failed to resolve env var {}={}", app_service.name, k, v))?
+                     value: resolve_variable_in_string(v, &vars)
+                         .context(format!("{}: Failed to resolve env var {}={}", app_service.name, k, v))?,
+                     sensitive: references_sensitive(v, &vars),
                  });
              }
          }
@@ -525,6 +1021,509 @@ mod tests {
         m
     }
 
+    fn vars() -> Vec<EnvVariable> {
+        vec![
+            EnvVariable { name: "DB_HOST".to_string(), value: "postgres".to_string(), sensitive: false },
+            EnvVariable { name: "EMPTY".to_string(), value: String::new(), sensitive: false },
+        ]
+    }
+
+    fn resolve(input: &str) -> Result<String> {
+        resolve_variable_in_string(&input.to_string(), &vars())
+    }
+
+    #[test]
+    fn applies_defaults_to_unset_variables() {
+        assert_eq!(resolve("${LOG_FORMAT_OVERRIDE:-json}").unwrap(), "json");
+        assert_eq!(resolve("${DB_HOST:-localhost}:5432").unwrap(), "postgres:5432");
+        assert_eq!(resolve("${MISSING:-${DB_HOST}}/db").unwrap(), "postgres/db");
+        assert_eq!(resolve("${MISSING:-}x").unwrap(), "x");
+        // set but empty is not unset
+        assert_eq!(resolve("${EMPTY:-default}").unwrap(), "");
+    }
+
+    #[test]
+    fn required_variables_report_their_message() {
+        assert_eq!(resolve("${DB_HOST:?set DB_HOST}").unwrap(), "postgres");
+        let err = resolve("tcp://${QUEUE:?QUEUE must point at the broker}").unwrap_err();
+        assert_eq!(err.to_string(), "QUEUE: QUEUE must point at the broker (in tcp://${QUEUE:?QUEUE must point at the broker})");
+        assert!(resolve("${MISSING}").unwrap_err().to_string().contains("Undefined variable: MISSING"));
+        assert!(resolve("${MISSING:-${DB_HOST}").unwrap_err().to_string().contains("Invalid variable reference"));
+    }
+
+    #[test]
+    fn keeps_literal_dollars() {
+        assert_eq!(resolve("$5 for ${DB_HOST}$").unwrap(), "$5 for postgres$");
+        assert_eq!(resolve("$HOME/$(pwd)").unwrap(), "$HOME/$(pwd)");
+    }
+
+    #[test]
+    fn escaped_references_are_passed_through() {
+        assert_eq!(resolve("$${server.port}").unwrap(), "${server.port}");
+        assert_eq!(resolve("$${FOO:-bar} ${DB_HOST}").unwrap(), "${FOO:-bar} postgres");
+        assert_eq!(resolve("$${FOO:-${DB_HOST}}").unwrap(), "${FOO:-${DB_HOST}}");
+        assert_eq!(resolve("${MISSING:-$${DB_HOST}}").unwrap(), "${DB_HOST}");
+        assert_eq!(resolve("$$$${DB_HOST}").unwrap(), "$$${DB_HOST}");
+    }
+
+    fn app_env_vars(environment: &str) -> Result<Vec<EnvVariable>> {
+        let app_yaml: crate::spec_yaml::AppSpecYaml = serde_yaml::from_str(&format!("name: shop\nversion: 1.0.0\nenvironment:\n{}", environment)).unwrap();
+        let app_spec = crate::transform::convert_app_spec(app_yaml, None).unwrap();
+        resolve_app_env_vars(&app_spec, &[], &[], &[], Some(&"shop.example.com".to_string()), true, &HashSet::new())
+    }
+
+    #[test]
+    fn resolves_forward_references() {
+        let vars = app_env_vars("  relative:\n    - API_URL=/${API_PREFIX}\n  internal:\n    - API_PREFIX=api/${API_VERSION}\n    - API_VERSION=v2\n").unwrap();
+        let value = |name: &str| vars.iter().find(|v| v.name == name).map(|v| v.value.as_str());
+        assert_eq!(value("API_URL"), Some("https://shop.example.com/api/v2"));
+        assert_eq!(value("API_PREFIX"), Some("api/v2"));
+    }
+
+    #[test]
+    fn resolves_diamond_dependencies() {
+        let vars = app_env_vars("  internal:\n    - ALL=${LEFT},${RIGHT}\n    - LEFT=l-${BASE}\n    - RIGHT=r-${MISSING:-${BASE}}\n    - BASE=b\n").unwrap();
+        let names: Vec<&str> = vars.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["BASE", "LEFT", "RIGHT", "ALL"]);
+        assert_eq!(vars[3].value, "l-b,r-b");
+    }
+
+    #[test]
+    fn reports_reference_cycles_with_their_chain() {
+        let err = app_env_vars("  internal:\n    - A=${B}\n    - B=${C}\n    - C=${A}\n").unwrap_err();
+        assert_eq!(err.to_string(), "Env variables reference each other in a cycle: A -> B -> C -> A");
+        // escaped references are not dependencies
+        assert!(app_env_vars("  internal:\n    - A=$${A}\n").is_ok());
+    }
+
+    #[test]
+    fn generates_service_url_variables() {
+        use crate::{spec_yaml, transform};
+        let env_yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_str(r#"
+type: local
+gateway:
+  hosts:
+    web: localhost:8080
+deployments:
+  dev:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      web:
+        prefix: /
+        ports:
+          - "8081:80"
+      user-api:
+        ports:
+          - "9000/udp"
+          - "8082:8080"
+"#).unwrap();
+        let app_yaml: spec_yaml::AppSpecYaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+environment:
+  internal:
+    - USERS_URL=${SERVICE_USER_API_URL}/v1
+app_services:
+  web:
+    type: public
+    image: shop/web
+    environment:
+      - USERS_URL
+      - SERVICE_USER_API_HOST
+      - QUEUE=${SERVICE_QUEUE_URL:-none}
+  user-api:
+    type: internal
+    image: shop/user-api
+    environment:
+      - $all
+"#).unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = transform::convert_env_spec(env_yaml, root.path()).unwrap();
+        let app_spec = transform::convert_app_spec(app_yaml, Some(&env_spec)).unwrap();
+        let spec = super::resolve(&env_spec, &app_spec, "dev").unwrap();
+
+        let service = |name: &str| spec.current_deployment.services.iter().find(|s| s.full_name == name).unwrap();
+        let value = |vars: &[EnvVariable], name: &str| vars.iter().find(|v| v.name == name).map(|v| v.value.clone());
+        let web = service("web");
+        assert_eq!(value(&web.environment_variables, "USERS_URL").as_deref(), Some("http://user-api:8080/v1"));
+        assert_eq!(value(&web.environment_variables, "SERVICE_USER_API_HOST").as_deref(), Some("user-api"));
+        assert_eq!(value(&web.environment_variables, "QUEUE").as_deref(), Some("none"));
+        assert_eq!(value(&web.undockerized_environment_variables, "USERS_URL").as_deref(), Some("http://localhost:8082/v1"));
+        assert_eq!(value(&web.undockerized_environment_variables, "SERVICE_USER_API_HOST").as_deref(), Some("localhost"));
+
+        // only passed to services that ask for them
+        assert!(value(&service("user-api").environment_variables, "SERVICE_WEB_URL").is_none());
+
+        // on k8s the Service listens on the published port
+        let env_yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_str(&crate::test_support::env_spec("k8s", r#"
+registry:
+  shop: reg.example.com
+deployments:
+  prod:
+    services:
+      web:
+        prefix: /
+      user-api:
+        ports:
+          - "8082:8080"
+"#)).unwrap();
+        let env_spec = transform::convert_env_spec(env_yaml, root.path()).unwrap();
+        let spec = super::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let web = spec.current_deployment.services.iter().find(|s| s.full_name == "web").unwrap();
+        assert_eq!(value(&web.environment_variables, "USERS_URL").as_deref(), Some("http://user-api:8082/v1"));
+    }
+
+    #[test]
+    fn public_domain_is_advertised_and_routed_alone() {
+        use crate::{spec_yaml, transform};
+        let env_yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web:
+      - shop.example.com
+      - staging.shop.example.com
+  tls:
+    disable: true
+registry:
+  acme: reg.example.com
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      web:
+        prefix: /
+  staging:
+    primary_host: web
+    public_domain: staging.shop.example.com
+    application:
+      name: shop
+    services:
+      web:
+        prefix: /
+"#).unwrap();
+        let app_yaml: spec_yaml::AppSpecYaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+environment:
+  relative:
+    - CALLBACK_URL=/callback
+app_services:
+  web:
+    type: public
+    image: acme/web
+    environment:
+      - CALLBACK_URL
+"#).unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = transform::convert_env_spec(env_yaml, root.path()).unwrap();
+        let app_spec = transform::convert_app_spec(app_yaml, Some(&env_spec)).unwrap();
+
+        for (deployment, domain) in [("prod", "shop.example.com"), ("staging", "staging.shop.example.com")] {
+            let spec = super::resolve(&env_spec, &app_spec, deployment).unwrap();
+            let web = &spec.current_deployment.services[0];
+            assert_eq!(web.service_host, domain);
+            assert_eq!(web.environment_variables[0].value, format!("http://{}/callback", domain));
+
+            let routes: Vec<(&str, &str)> = spec.ingress.rules.iter()
+                .flat_map(|rule| rule.services.iter().map(move |s| (rule.domain_name.as_str(), s.deployment_name.as_str())))
+                .collect();
+            assert_eq!(routes, [("shop.example.com", "prod"), ("staging.shop.example.com", "staging")]);
+        }
+    }
+
+    #[test]
+    fn local_gateway_routes_to_the_published_port() {
+        use crate::{spec_yaml, transform};
+        let env_yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_str(r#"
+type: local
+gateway:
+  hosts:
+    web: localhost:8080
+deployments:
+  dev:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      api:
+        prefix: /api
+        ports:
+          - "8081:3000"
+"#).unwrap();
+        let app_yaml: spec_yaml::AppSpecYaml = serde_yaml::from_str("name: shop\nversion: 1.0.0\napp_services:\n  api:\n    type: public\n    image: shop/api\n").unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = transform::convert_env_spec(env_yaml, root.path()).unwrap();
+        let app_spec = transform::convert_app_spec(app_yaml, Some(&env_spec)).unwrap();
+        let spec = super::resolve(&env_spec, &app_spec, "dev").unwrap();
+
+        // the local gateway runs on the host and proxies to localhost:<port>
+        let target = &spec.ingress.rules[0].services[0];
+        assert_eq!((target.port, target.container_port), (8081, 3000));
+    }
+
+    #[test]
+    fn templated_configs_are_rendered_with_the_service_variables() {
+        use crate::{spec_yaml, transform};
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("nginx")).unwrap();
+        let env_yaml = || -> spec_yaml::DeploymentEnvironmentSpecYaml { serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: shop.example.com
+  tls:
+    disable: true
+registry:
+  acme: reg.example.com
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    environment:
+      - UPSTREAM=api:8080
+    configs:
+      nginx:
+        path: nginx
+        template: true
+    services:
+      web:
+        prefix: /
+"#).unwrap() };
+        let app_yaml = || -> spec_yaml::AppSpecYaml { serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+environment:
+  external:
+    - UPSTREAM
+  relative:
+    - PUBLIC_URL=/
+configs:
+  nginx: [site.conf]
+app_services:
+  web:
+    type: public
+    image: acme/web
+    configs:
+      - nginx: /etc/nginx/conf.d
+"#).unwrap() };
+        let render = |content: &[u8]| {
+            fs::write(root.path().join("nginx/site.conf"), content).unwrap();
+            let env_spec = transform::convert_env_spec(env_yaml(), root.path()).unwrap();
+            let app_spec = transform::convert_app_spec(app_yaml(), Some(&env_spec)).unwrap();
+            super::resolve(&env_spec, &app_spec, "prod")
+                .map(|spec| String::from_utf8(spec.current_deployment.configs[0].files[0].content.clone()).unwrap())
+                .map_err(|e| format!("{:#}", e))
+        };
+
+        assert_eq!(render(b"proxy_pass http://${UPSTREAM}; # ${PUBLIC_URL}\nset $path $${uri};\n").unwrap(),
+            "proxy_pass http://api:8080; # http://shop.example.com/\nset $path ${uri};\n");
+        let err = render(b"listen ${PORT};").unwrap_err();
+        assert!(err.contains("service \"web\": config nginx: Failed to render config file site.conf: Undefined variable: PORT"), "{}", err);
+        let err = render(&[0xff, 0xfe, b'$']).unwrap_err();
+        assert!(err.contains("Config file site.conf is not UTF-8 text, so it cannot be a template"), "{}", err);
+    }
+
+    #[test]
+    fn inline_configs_mix_with_files_on_disk() {
+        use crate::{spec_yaml, transform, validator};
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("nginx")).unwrap();
+        fs::write(root.path().join("nginx/site.conf"), "listen 80;").unwrap();
+        let env_yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: shop.example.com
+  tls:
+    disable: true
+registry:
+  acme: reg.example.com
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    configs:
+      nginx:
+        - nginx
+        - gzip.conf: "gzip on;"
+      fluent:
+        parsers.conf: "[PARSER]"
+"#).unwrap();
+        let app_yaml: spec_yaml::AppSpecYaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+configs:
+  nginx: [site.conf, gzip.conf]
+  fluent:
+    fluent-bit.conf: |
+      [INPUT]
+          Name tail
+    parsers.conf: "replaced by the deployment"
+app_services:
+  web:
+    image: acme/web
+    configs:
+      - nginx: /etc/nginx/conf.d
+      - fluent: /fluent-bit/etc
+"#).unwrap();
+        let env_spec = transform::convert_env_spec(env_yaml, root.path()).unwrap();
+        let app_spec = transform::convert_app_spec(app_yaml, Some(&env_spec)).unwrap();
+        validator::validate(&env_spec, &app_spec, "prod").unwrap();
+        let spec = super::resolve(&env_spec, &app_spec, "prod").unwrap();
+
+        let files = |config: &str| -> Vec<(String, String)> {
+            spec.current_deployment.configs.iter().find(|c| c.name == config).unwrap().files.iter()
+                .map(|f| (f.name.clone(), String::from_utf8(f.content.clone()).unwrap()))
+                .collect()
+        };
+        assert_eq!(files("shop-nginx"), [("gzip.conf".to_string(), "gzip on;".to_string()), ("site.conf".to_string(), "listen 80;".to_string())]);
+        assert_eq!(files("shop-fluent"), [("fluent-bit.conf".to_string(), "[INPUT]\n    Name tail\n".to_string()), ("parsers.conf".to_string(), "[PARSER]".to_string())]);
+    }
+
+    #[test]
+    fn config_files_over_the_size_limit_are_rejected() {
+        use crate::{spec_yaml, test_support, transform};
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("geoip")).unwrap();
+        fs::write(root.path().join("geoip/countries.mmdb"), vec![0xffu8; 4096]).unwrap();
+        let resolve_with = |env_type: &str, limit: &str| {
+            let env_yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_str(&test_support::env_spec(env_type, &format!("{}deployments:\n  prod:\n    configs:\n      geoip: geoip\n", limit))).unwrap();
+            let app_yaml: spec_yaml::AppSpecYaml = serde_yaml::from_str(&test_support::app_spec(r#"
+configs:
+  geoip: [countries.mmdb]
+app_services:
+  web:
+    image: acme/web
+    configs:
+      - geoip: /usr/share/geoip
+"#)).unwrap();
+            let env_spec = transform::convert_env_spec(env_yaml, root.path()).unwrap();
+            let app_spec = transform::convert_app_spec(app_yaml, Some(&env_spec)).unwrap();
+            super::resolve(&env_spec, &app_spec, "prod").map_err(|e| format!("{:#}", e))
+        };
+
+        let err = resolve_with("docker", "max_config_file_size: 2Ki\n").unwrap_err();
+        assert!(err.contains("Config file countries.mmdb of config geoip is 4096 bytes, over the 2Ki max_config_file_size"), "{}", err);
+        // the 768Ki default only applies to Kubernetes
+        fs::write(root.path().join("geoip/countries.mmdb"), vec![0xffu8; 1024 * 1024]).unwrap();
+        resolve_with("docker", "").unwrap();
+        let err = resolve_with("k8s", "").unwrap_err();
+        assert!(err.contains("is 1048576 bytes, over the 768Ki max_config_file_size"), "{}", err);
+        fs::write(root.path().join("geoip/countries.mmdb"), vec![0xffu8; 4096]).unwrap();
+        // binary content is kept byte for byte
+        let spec = resolve_with("docker", "").unwrap();
+        assert_eq!(spec.current_deployment.configs[0].files[0].content, vec![0xffu8; 4096]);
+    }
+
+    #[test]
+    fn app_images_get_the_version_and_pinned_extra_images_are_kept() {
+        use crate::{spec_yaml, transform};
+        let env_yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: shop.example.com
+  tls:
+    disable: true
+registry:
+  acme: reg.example.com
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      api:
+        prefix: /
+"#).unwrap();
+        let digest = format!("sha256:{}", "0f".repeat(32));
+        let app_yaml: spec_yaml::AppSpecYaml = serde_yaml::from_str(&format!(
+            "name: shop\nversion: 1.2.3\napp_services:\n  api:\n    type: public\n    image: acme/api:dev\nextra_services:\n  db:\n    image: postgres:16@{}\n", digest)).unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = transform::convert_env_spec(env_yaml, root.path()).unwrap();
+        let app_spec = transform::convert_app_spec(app_yaml, Some(&env_spec)).unwrap();
+        let spec = super::resolve(&env_spec, &app_spec, "prod").unwrap();
+
+        let image = |name: &str| spec.current_deployment.services.iter().find(|s| s.full_name == name).unwrap().image.clone();
+        assert_eq!(image("api"), "reg.example.com/acme/api:1.2.3");
+        assert_eq!(image("db"), format!("postgres:16@{}", digest));
+
+        let pinned_app: spec_yaml::AppSpecYaml = serde_yaml::from_str(&format!("name: shop\nversion: 1.2.3\napp_services:\n  api:\n    image: acme/api@{}\n", digest)).unwrap();
+        let err = transform::convert_app_spec(pinned_app, Some(&env_spec)).unwrap_err();
+        assert!(format!("{:#}", err).contains("cannot be pinned by digest"), "{:#}", err);
+    }
+
+    #[test]
+    fn service_environment_overrides_the_deployment_for_that_service() {
+        use crate::{spec_yaml, transform, validator};
+        let env_yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: shop.example.com
+  tls:
+    disable: true
+registry:
+  acme: reg.example.com
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    environment:
+      - QUEUE_NAME=jobs
+    services:
+      worker:
+        environment:
+          - QUEUE_NAME=priority
+          - SHARD=7
+          - MODE=fast
+"#).unwrap();
+        let app_yaml: spec_yaml::AppSpecYaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+environment:
+  external:
+    - QUEUE_NAME
+  internal:
+    - QUEUE_URL=amqp://mq/${QUEUE_NAME}
+    - MODE=normal
+app_services:
+  api:
+    image: acme/api
+    environment:
+      - QUEUE_URL
+      - MODE
+  worker:
+    image: acme/worker
+    environment:
+      - QUEUE_URL
+      - MODE
+      - SHARD
+      - LABEL=${QUEUE_NAME}-${SHARD}
+"#).unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = transform::convert_env_spec(env_yaml, root.path()).unwrap();
+        let app_spec = transform::convert_app_spec(app_yaml, Some(&env_spec)).unwrap();
+        validator::validate(&env_spec, &app_spec, "prod").unwrap();
+        let spec = super::resolve(&env_spec, &app_spec, "prod").unwrap();
+
+        let vars = |name: &str| -> Vec<(String, String)> {
+            spec.current_deployment.services.iter().find(|s| s.full_name == name).unwrap()
+                .environment_variables.iter().map(|v| (v.name.clone(), v.value.clone())).collect()
+        };
+        let pairs = |list: &[(&str, &str)]| -> Vec<(String, String)> { list.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect() };
+        assert_eq!(vars("api"), pairs(&[("QUEUE_URL", "amqp://mq/jobs"), ("MODE", "normal")]));
+        assert_eq!(vars("worker"), pairs(&[("QUEUE_URL", "amqp://mq/priority"), ("MODE", "fast"), ("SHARD", "7"), ("LABEL", "priority-7")]));
+    }
+
     #[test]
     fn expands_secret_reference() {
         let out = resolve_secret_refs_in_string(
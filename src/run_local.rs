@@ -1,58 +1,886 @@
 use crate::resolved_spec::*;
 use crate::docker_compose::*;
+use crate::local_certs;
+use crate::diagnostics;
+use crate::generated::{GeneratedFiles, OnConflict};
+use crate::local_ingress::{self, IngressHandle};
+use crate::spec::{EnvVariable, PortProtocol, ServiceType};
 use anyhow::{Result, Context, anyhow};
+use notify::{EventKind, RecursiveMode, Watcher};
 use std::fs;
-use std::path::Path;
-use std::process::Command;
-use std::collections::HashMap;
-use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::collections::BTreeMap;
+use serde::Deserialize;
 
 
 
-pub fn run(spec: &EnvironmentResolvedSpec, exclude: &[String]) -> Result<()> {
-    run_filtered(spec, |s| !exclude.iter().any(|e| e == &s.full_name))
+pub const OUTPUT_DIR: &str = "local_env";
+const COMPOSE_FILE: &str = "docker-compose.yaml";
+const TRASH_DIR: &str = ".trash";
+const INTERRUPT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// Options of `simpled local run`
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    pub exclude: Vec<String>,
+    // services kept by --only; empty keeps all of them
+    pub only: Vec<String>,
+    pub profiles: Vec<String>,
+    // start containers with `docker compose up -d` and return once they are up
+    pub detach: bool,
+    // service the developer runs natively instead of in docker
+    pub undockerized: Option<String>,
+    // command that runs the undockerized service, started with its environment
+    pub exec: Option<String>,
+    // leave the containers running when the CLI exits
+    pub keep_running: bool,
+    // skip the check that published ports are free
+    pub ignore_port_conflicts: bool,
+    // rebuild images of services with a `build` section
+    pub build: bool,
+    // start the environment without running job services first
+    pub skip_jobs: bool,
+    // move files of services that no longer exist out of local_env
+    pub clean: bool,
+    // append missing gateway domains to the hosts file
+    pub add_hosts: bool,
+    // --env/--env-file overrides of the deployment environment, later entries win
+    pub env: Vec<EnvVariable>,
+    // every service of the deployment, before --only narrowed it; their
+    // local_env/ files are not stale
+    pub deployment_services: Vec<String>,
+    pub ingress: local_ingress::IngressOptions,
+}
+
+/// Verifies that every port the local environment publishes — service ports
+/// and gateway ports — is free, reporting all conflicts at once so nothing is
+/// half-started. Ports held by this environment's own containers (e.g. from a
+/// detached run) are not conflicts, compose recreates those containers.
+pub fn check_ports(spec: &EnvironmentResolvedSpec, options: &RunOptions, only_extra: bool) -> Result<()> {
+    if options.ignore_port_conflicts {
+        return Ok(());
+    }
+
+    let conflicts = port_conflicts(&required_ports(spec, options, only_extra), &own_published_ports());
+    if !conflicts.is_empty() {
+        return Err(anyhow!(
+            "{}\nStop the processes holding these ports or pass --ignore-port-conflicts",
+            conflicts.join("\n")
+        ));
+    }
+
+    Ok(())
+}
+
+// Ports the run publishes, with their owner. Services that will not start —
+// excluded, app services with only_extra, or in a profile that is not
+// enabled — need none.
+fn required_ports(spec: &EnvironmentResolvedSpec, options: &RunOptions, only_extra: bool) -> Vec<(IpAddr, u16, PortProtocol, String)> {
+    // Docker publishes service ports on all interfaces; the gateway binds
+    // only its configured address.
+    let all_interfaces = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    let mut required: Vec<(IpAddr, u16, PortProtocol, String)> = local_ingress::listen_ports(&spec.ingress, &spec.current_deployment.name)
+        .into_iter()
+        .map(|port| (options.ingress.bind, port, PortProtocol::Tcp, "the local gateway".to_string()))
+        .collect();
+    for service in &spec.current_deployment.services {
+        if (only_extra && service.is_app_service) || options.exclude.contains(&service.full_name) || !profile_enabled(service, options) {
+            continue;
+        }
+        for port in &service.ports {
+            if let Some(external) = port.external {
+                required.push((all_interfaces, external, port.protocol, service.full_name.clone()));
+            }
+        }
+    }
+    required
+}
+
+// One message per required port taken by something other than `own_ports`.
+fn port_conflicts(required: &[(IpAddr, u16, PortProtocol, String)], own_ports: &[u16]) -> Vec<String> {
+    required.iter()
+        .filter(|(addr, port, protocol, _)| !own_ports.contains(port) && !is_free(*addr, *port, *protocol))
+        .map(|(_, port, protocol, owner)| match protocol {
+            PortProtocol::Tcp => format!("port {} required by {} is already in use", port, owner),
+            PortProtocol::Udp => format!("UDP port {} required by {} is already in use", port, owner),
+        })
+        .collect()
+}
+
+fn is_free(addr: IpAddr, port: u16, protocol: PortProtocol) -> bool {
+    match protocol {
+        PortProtocol::Tcp => TcpListener::bind((addr, port)).is_ok(),
+        PortProtocol::Udp => UdpSocket::bind((addr, port)).is_ok(),
+    }
+}
+
+/// Host ports published by containers of the compose project in `local_env/`.
+fn own_published_ports() -> Vec<u16> {
+    if !Path::new(OUTPUT_DIR).join(COMPOSE_FILE).exists() {
+        return Vec::new();
+    }
+    let Ok(output) = Command::new("docker")
+        .current_dir(OUTPUT_DIR)
+        .args(["compose", "ps", "--format", "json"])
+        .output() else {
+        return Vec::new();
+    };
+    parse_compose_ps(&String::from_utf8_lossy(&output.stdout))
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|c| c.publishers.iter().map(|p| p.published_port))
+        .collect()
+}
+
+pub fn run(spec: &EnvironmentResolvedSpec, options: &RunOptions) -> Result<()> {
+    let Some(name) = &options.undockerized else {
+        return run_filtered(spec, options, |s| !options.exclude.iter().any(|e| e == &s.full_name));
+    };
+
+    let native = find_service(spec, name)?;
+    let env_vars = prepare_undockerized(spec, native)?;
+
+    // The native command needs the terminal, so compose runs in the background.
+    let compose_options = RunOptions { detach: options.detach || options.exec.is_some(), ..options.clone() };
+    run_filtered(spec, &compose_options, |s| {
+        s.full_name != native.full_name && !options.exclude.iter().any(|e| e == &s.full_name)
+    })?;
+    if interrupted() {
+        return Ok(());
+    }
+
+    if let Some(exec) = &options.exec {
+        log::info!("Running {}: {}", native.full_name, exec);
+
+        #[cfg(unix)]
+        let mut command = { let mut c = Command::new("sh"); c.args(["-c", exec]); c };
+        #[cfg(windows)]
+        let mut command = { let mut c = Command::new("cmd"); c.args(["/C", exec]); c };
+
+        if let Some(working_dir) = &native.working_dir {
+            command.current_dir(working_dir);
+        }
+        let mut child = command
+            .envs(env_vars.iter().map(|v| (&v.name, &v.value)))
+            .spawn()
+            .with_context(|| format!("Failed to run '{}'", exec))?;
+        let status = wait_interruptible(&mut child)?;
+
+        shut_down(options)?;
+        if !status.success() && !interrupted() {
+            return Err(anyhow!("'{}' exited with {}", exec, status));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that nothing in docker publishes the ports of the service that runs
+/// natively, writes its environment to `local_env/<service>/undockerized.env`
+/// and prints how to start it.
+fn prepare_undockerized(spec: &EnvironmentResolvedSpec, native: &ServiceResolvedSpec) -> Result<Vec<EnvVariable>> {
+    check_native_ports(spec, native)?;
+
+    let env_vars = undockerized_env(native, spec);
+    let env_path = Path::new(OUTPUT_DIR).join(&native.full_name).join("undockerized.env");
+    fs::create_dir_all(env_path.parent().unwrap()).context("Failed to create service directory")?;
+    write_env_file(&env_path, &env_vars)?;
+
+    let ports = native.ports.iter().filter_map(|p| p.external).map(|p| p.to_string()).collect::<Vec<_>>().join(", ");
+    log::info!("{} runs outside docker; the gateway forwards its routes to localhost:{}", native.full_name, ports);
+    // names only: values may embed secrets and end up in CI logs
+    let names: Vec<&str> = env_vars.iter().map(|v| v.name.as_str()).collect();
+    log::info!("Its environment is in {:?}: {}", env_path, names.join(", "));
+
+    Ok(env_vars)
+}
+
+// The native process binds the service's published ports itself.
+fn check_native_ports(spec: &EnvironmentResolvedSpec, native: &ServiceResolvedSpec) -> Result<()> {
+    for port in native.ports.iter().filter(|p| p.external.is_some()) {
+        if let Some(other) = spec.current_deployment.services.iter()
+            .find(|s| s.full_name != native.full_name && s.ports.iter().any(|p| p.external == port.external && p.protocol == port.protocol))
+        {
+            return Err(anyhow!(
+                "Port {} of undockerized service {} is also published by {}; it must stay free for the native process",
+                port.service_port(), native.full_name, other.full_name
+            ));
+        }
+    }
+    Ok(())
 }
 
 pub fn run_only_extra(spec: &EnvironmentResolvedSpec) -> Result<()> {
-    run_filtered(spec, |s| !s.is_app_service)
+    run_filtered(spec, &RunOptions::default(), |s| !s.is_app_service)
+}
+
+/// Runs the environment detached and keeps it in sync with the specs.
+///
+/// Every change under `paths` re-runs `resolve`; on success the compose file
+/// and service files are regenerated and `docker compose up -d` restarts only
+/// the services that changed. The ingress is restarted when its rules change.
+/// A failed reload is reported and the previous environment keeps running.
+pub fn watch<R>(
+    mut spec: EnvironmentResolvedSpec,
+    options: &RunOptions,
+    mut ingress: IngressHandle,
+    paths: &[PathBuf],
+    resolve: R,
+) -> Result<()>
+where
+    R: Fn() -> Result<EnvironmentResolvedSpec>,
+{
+    let options = RunOptions { detach: true, ..options.clone() };
+    run(&spec, &options)?;
+    if interrupted() {
+        ingress.stop();
+        return Ok(());
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("Failed to start file watcher")?;
+    for path in paths {
+        watcher.watch(path, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {:?}", path))?;
+    }
+
+    log::info!("Watching for changes, press Ctrl-C to stop");
+
+    loop {
+        if interrupted() {
+            ingress.stop();
+            return shut_down(&options);
+        }
+        match rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(event) if is_change(&event) => {},
+            Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        // Editors save in several steps; let the burst settle before reloading.
+        while rx.recv_timeout(Duration::from_millis(300)).is_ok() {}
+
+        log::info!("Change detected, reloading...");
+        let new_spec = match resolve() {
+            Ok(new_spec) => new_spec,
+            Err(e) => {
+                log::error!("Reload failed, keeping the previous environment running: {:#}", e);
+                continue;
+            }
+        };
+
+        if new_spec.ingress != spec.ingress {
+            ingress.stop();
+            ingress = local_ingress::run(new_spec.ingress.clone(), &new_spec.current_deployment.name, &options.ingress)?;
+        }
+
+        if let Err(e) = run(&new_spec, &options) {
+            log::error!("Reload failed: {:#}", e);
+        }
+        if interrupted() {
+            ingress.stop();
+            return Ok(());
+        }
+        spec = new_spec;
+
+        // Drop the events caused by our own writes during the reload.
+        while rx.try_recv().is_ok() {}
+    }
+}
+
+// Only writes to the watched specs trigger a reload, reading them does not.
+fn is_change(event: &notify::Result<notify::Event>) -> bool {
+    matches!(event, Ok(e) if matches!(e.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)))
+}
+
+/// Stops the local environment started by `run`.
+///
+/// Works purely on the compose file already in `local_env/`, so the services
+/// are torn down even if the appspec or envspec changed since they started.
+pub fn down() -> Result<()> {
+    let output_dir = Path::new(OUTPUT_DIR);
+    if !output_dir.join(COMPOSE_FILE).exists() {
+        log::info!("No {}/{} found, nothing to stop", OUTPUT_DIR, COMPOSE_FILE);
+        return Ok(());
+    }
+
+    log::info!("Running docker compose down...");
+    let status = Command::new("docker")
+        .current_dir(output_dir)
+        .args(["compose", "down", "--remove-orphans"])
+        .status()
+        .context("Failed to run docker compose")?;
+
+    if !status.success() {
+        return Err(anyhow!("docker compose down failed"));
+    }
+
+    Ok(())
 }
 
 pub fn generate_config(spec: &EnvironmentResolvedSpec) -> Result<()> {
     write_compose(spec, |_| true)
 }
 
-fn run_filtered<F>(spec: &EnvironmentResolvedSpec, filter: F) -> Result<()>
+/// Shows `docker compose logs` for the whole environment or a single service.
+pub fn logs(spec: &EnvironmentResolvedSpec, service: Option<&str>, follow: bool) -> Result<()> {
+    let mut args = vec!["compose".to_string(), "logs".to_string()];
+    if follow {
+        args.push("--follow".to_string());
+    }
+    if let Some(name) = service {
+        args.push(find_service(spec, name)?.full_name.clone());
+    }
+
+    let status = Command::new("docker")
+        .current_dir(OUTPUT_DIR)
+        .args(&args)
+        .status()
+        .context("Failed to run docker compose")?;
+
+    if !status.success() {
+        return Err(anyhow!("docker compose logs failed"));
+    }
+
+    Ok(())
+}
+
+/// Prints a table of the deployment's services with their container state,
+/// published ports and the gateway URL of public services.
+pub fn status(spec: &EnvironmentResolvedSpec) -> Result<()> {
+    let output = Command::new("docker")
+        .current_dir(OUTPUT_DIR)
+        .args(["compose", "ps", "--all", "--format", "json"])
+        .output()
+        .context("Failed to run docker compose")?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "docker compose ps failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let containers = parse_compose_ps(&String::from_utf8_lossy(&output.stdout))?;
+
+    let mut rows = vec![["SERVICE".to_string(), "IMAGE".to_string(), "STATE".to_string(), "PORTS".to_string(), "URL".to_string()]];
+    for service in &spec.current_deployment.services {
+        let container = containers.iter().find(|c| c.service == service.full_name);
+        let image = container.map(|c| c.image.clone()).unwrap_or_else(|| service.image.clone());
+        let state = container.map(|c| c.state.clone()).unwrap_or_else(|| "not created".to_string());
+        let ports = container
+            .map(|c| c.publishers.iter()
+                .filter(|p| p.published_port != 0)
+                .map(|p| format!("{}->{}/{}", p.published_port, p.target_port, p.protocol))
+                .collect::<Vec<_>>()
+                .join(", "))
+            .unwrap_or_default();
+        let urls = service_urls(spec, &service.full_name).join(", ");
+
+        rows.push([service.full_name.clone(), image, state, ports, urls]);
+    }
+
+    let mut widths = [0usize; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in &rows {
+        let line = row.iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+fn find_service<'a>(spec: &'a EnvironmentResolvedSpec, name: &str) -> Result<&'a ServiceResolvedSpec> {
+    let services = &spec.current_deployment.services;
+    services.iter()
+        .find(|s| s.full_name == name)
+        .ok_or_else(|| {
+            let names = services.iter().map(|s| s.full_name.as_str()).collect::<Vec<_>>().join(", ");
+            anyhow!("Unknown service '{}'. Valid services: {}", name, names)
+        })
+}
+
+/// Narrows the current deployment to the services named by `--only`, and the
+/// gateway to their routes. An empty list keeps everything.
+pub fn retain_only(spec: &mut EnvironmentResolvedSpec, only: &[String]) -> Result<()> {
+    if only.is_empty() {
+        return Ok(());
+    }
+    for name in only {
+        find_service(spec, name)?;
+    }
+
+    let deployment = spec.current_deployment.name.clone();
+    spec.current_deployment.services.retain(|s| only.contains(&s.full_name));
+    for rule in &mut spec.ingress.rules {
+        rule.services.retain(|svc| svc.deployment_name != deployment || only.contains(&svc.service_name));
+        if matches!(&rule.default_backend, Some(DefaultBackendResolvedSpec::Service(svc)) if svc.deployment_name == deployment && !only.contains(&svc.service_name)) {
+            rule.default_backend = None;
+        }
+    }
+
+    for service in &spec.current_deployment.services {
+        if matches!(service.service_type, ServiceType::Public) && service_urls(spec, &service.full_name).is_empty() {
+            return Err(anyhow!("Public service '{}' has no gateway route left after --only", service.full_name));
+        }
+    }
+    Ok(())
+}
+
+/// Waits until every service started by a detached run is ready: healthy
+/// when it has a healthcheck, otherwise running with its published ports
+/// accepting connections. The gateway ports must accept connections too.
+/// Once ready, the gateway URL of every public service is printed as a JSON
+/// object on the last line of stdout, for test suites driving the environment.
+pub fn wait_healthy(spec: &EnvironmentResolvedSpec, options: &RunOptions, timeout: Duration) -> Result<()> {
+    let expected = awaited_services(spec, options);
+
+    log::info!("Waiting up to {}s for services to become ready...", timeout.as_secs());
+    let deadline = Instant::now() + timeout;
+    loop {
+        let pending = not_ready(spec, &expected, options)?;
+        if pending.is_empty() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow!("Timed out after {}s waiting for: {}", timeout.as_secs(), pending.join(", ")));
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    log::info!("All services are ready");
+
+    let urls: BTreeMap<&str, String> = expected.iter()
+        .filter_map(|s| service_urls(spec, &s.full_name).into_iter().next().map(|url| (s.full_name.as_str(), url)))
+        .collect();
+    println!("{}", serde_json::to_string(&urls)?);
+    Ok(())
+}
+
+// Services a detached run keeps running in docker: jobs have finished and
+// the undockerized service runs outside of it.
+fn awaited_services<'a>(spec: &'a EnvironmentResolvedSpec, options: &RunOptions) -> Vec<&'a ServiceResolvedSpec> {
+    spec.current_deployment.services.iter()
+        .filter(|s| !matches!(s.service_type, ServiceType::Job)
+            && profile_enabled(s, options)
+            && !options.exclude.contains(&s.full_name)
+            && options.undockerized.as_ref() != Some(&s.full_name))
+        .collect()
+}
+
+/// Names of the expected services (and gateway ports) that are not ready
+/// yet. A service whose container has exited fails the wait right away.
+fn not_ready(spec: &EnvironmentResolvedSpec, expected: &[&ServiceResolvedSpec], options: &RunOptions) -> Result<Vec<String>> {
+    let output = Command::new("docker")
+        .current_dir(OUTPUT_DIR)
+        .args(["compose", "ps", "--all", "--format", "json"])
+        .output()
+        .context("Failed to run docker compose")?;
+    let containers = parse_compose_ps(&String::from_utf8_lossy(&output.stdout))?;
+
+    let mut pending = Vec::new();
+    for service in expected {
+        let ready = match containers.iter().find(|c| c.service == service.full_name) {
+            None => false,
+            Some(c) if c.state == "exited" || c.state == "dead" => {
+                return Err(anyhow!("Service {} stopped before it became ready, see `simpled local logs {}`", service.full_name, service.full_name));
+            }
+            Some(c) if !c.health.is_empty() => c.health == "healthy",
+            Some(c) => c.state == "running" && probed_ports(c)
+                .all(|port| accepts_connections(IpAddr::V4(Ipv4Addr::LOCALHOST), port)),
+        };
+        if !ready {
+            pending.push(service.full_name.clone());
+        }
+    }
+
+    let gateway = if options.ingress.bind.is_unspecified() { IpAddr::V4(Ipv4Addr::LOCALHOST) } else { options.ingress.bind };
+    for port in local_ingress::listen_ports(&spec.ingress, &spec.current_deployment.name) {
+        if !accepts_connections(gateway, port) {
+            pending.push(format!("gateway on port {}", port));
+        }
+    }
+    Ok(pending)
+}
+
+// Published TCP ports of a container; UDP ports cannot be probed by connecting
+fn probed_ports(container: &ComposePsEntry) -> impl Iterator<Item = u16> + '_ {
+    container.publishers.iter()
+        .filter(|p| p.published_port != 0 && p.protocol == "tcp")
+        .map(|p| p.published_port)
+}
+
+fn accepts_connections(addr: IpAddr, port: u16) -> bool {
+    TcpStream::connect_timeout(&SocketAddr::new(addr, port), Duration::from_millis(500)).is_ok()
+}
+
+/// Gateway URLs routed to the service in the current deployment.
+fn service_urls(spec: &EnvironmentResolvedSpec, full_name: &str) -> Vec<String> {
+    let self_signed = spec.ingress.tls.as_ref().is_some_and(|tls| tls.self_signed);
+    let mut urls = Vec::new();
+    for rule in &spec.ingress.rules {
+        let scheme = if self_signed && spec.ingress.domain_tls(&rule.domain_name) { "https" } else { "http" };
+        for svc in &rule.services {
+            if svc.service_name == full_name && svc.deployment_name == spec.current_deployment.name {
+                urls.push(format!("{}://{}{}", scheme, rule.domain_name, svc.prefix));
+            }
+        }
+    }
+    urls
+}
+
+// One container from `docker compose ps --format json`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ComposePsEntry {
+    service: String,
+    #[serde(default)]
+    image: String,
+    #[serde(default)]
+    state: String,
+    // "healthy", "unhealthy" or "starting"; empty without a healthcheck
+    #[serde(default)]
+    health: String,
+    #[serde(default)]
+    publishers: Vec<ComposePsPublisher>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ComposePsPublisher {
+    #[serde(default)]
+    target_port: u16,
+    #[serde(default)]
+    published_port: u16,
+    #[serde(default)]
+    protocol: String,
+}
+
+/// Older compose versions print a single JSON array, newer ones print one
+/// JSON object per line.
+fn parse_compose_ps(output: &str) -> Result<Vec<ComposePsEntry>> {
+    let trimmed = output.trim();
+    if trimmed.starts_with('[') {
+        return serde_json::from_str(trimmed).context("Failed to parse docker compose ps output");
+    }
+    trimmed.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).context("Failed to parse docker compose ps output"))
+        .collect()
+}
+
+fn run_filtered<F>(spec: &EnvironmentResolvedSpec, options: &RunOptions, filter: F) -> Result<()>
 where
     F: Fn(&crate::resolved_spec::ServiceResolvedSpec) -> bool,
 {
+    handle_interrupts();
+    tidy_output_dir(spec, &options.deployment_services, Path::new(OUTPUT_DIR), options.clean)?;
     write_compose(spec, &filter)?;
 
-    let output_dir = Path::new("local_env");
-    println!("Running docker compose up...");
+    if !options.skip_jobs {
+        run_jobs(spec, options, &filter)?;
+        if interrupted() {
+            return shut_down(options);
+        }
+    }
+
+    let output_dir = Path::new(OUTPUT_DIR);
+    log::info!("Running docker compose up...");
 
-    let status = Command::new("docker")
+    let mut child = Command::new("docker")
         .current_dir(output_dir)
-        .args(&["compose", "up", "--remove-orphans"])
-        .status()
+        .args(up_args(options))
+        .spawn()
         .context("Failed to run docker compose")?;
+    let status = wait_interruptible(&mut child)?;
 
+    if interrupted() {
+        return shut_down(options);
+    }
     if !status.success() {
         return Err(anyhow!("docker compose failed"));
     }
 
+    if options.keep_running && !options.detach {
+        let mut logs = Command::new("docker")
+            .current_dir(output_dir)
+            .args(["compose", "logs", "--follow"])
+            .spawn()
+            .context("Failed to run docker compose")?;
+        wait_interruptible(&mut logs)?;
+        log::info!("Services keep running, stop them with `simpled local down`");
+    }
+
     Ok(())
 }
 
+/// `docker compose` with the profiles enabled for this run.
+fn compose_args(options: &RunOptions) -> Vec<String> {
+    let mut args = vec!["compose".to_string()];
+    for profile in &options.profiles {
+        args.push("--profile".to_string());
+        args.push(profile.clone());
+    }
+    args
+}
+
+/// `docker compose ... up` for this run.
+fn up_args(options: &RunOptions) -> Vec<String> {
+    let mut args = compose_args(options);
+    args.extend(["up".to_string(), "--remove-orphans".to_string()]);
+    if options.build {
+        args.push("--build".to_string());
+    }
+    // A foreground `compose up` stops the containers on Ctrl-C, so with
+    // --keep-running they are started detached and only the logs are followed.
+    if options.detach || options.keep_running {
+        args.push("-d".to_string());
+    }
+    args
+}
+
+fn profile_enabled(service: &ServiceResolvedSpec, options: &RunOptions) -> bool {
+    service.profile.as_ref().is_none_or(|p| options.profiles.contains(p))
+}
+
+/// Runs the job services to completion with `docker compose run --rm` before
+/// the rest of the environment comes up. Extra services (databases, caches)
+/// are started first since jobs such as migrations usually need them. A
+/// failing job stops the environment and aborts the run.
+fn run_jobs<F>(spec: &EnvironmentResolvedSpec, options: &RunOptions, filter: &F) -> Result<()>
+where
+    F: Fn(&ServiceResolvedSpec) -> bool,
+{
+    let services: Vec<&ServiceResolvedSpec> = spec.current_deployment.services.iter()
+        .filter(|s| filter(s) && profile_enabled(s, options))
+        .collect();
+    let jobs: Vec<&str> = services.iter()
+        .filter(|s| matches!(s.service_type, ServiceType::Job))
+        .map(|s| s.full_name.as_str())
+        .collect();
+    if jobs.is_empty() {
+        return Ok(());
+    }
+
+    let output_dir = Path::new(OUTPUT_DIR);
+    let support: Vec<&str> = services.iter()
+        .filter(|s| !s.is_app_service && !matches!(s.service_type, ServiceType::Job))
+        .map(|s| s.full_name.as_str())
+        .collect();
+    if !support.is_empty() {
+        log::info!("Starting {} for jobs...", support.join(", "));
+        let mut args = compose_args(options);
+        args.extend(["up".to_string(), "-d".to_string()]);
+        if options.build {
+            args.push("--build".to_string());
+        }
+        args.extend(support.iter().map(|s| s.to_string()));
+        let mut child = Command::new("docker")
+            .current_dir(output_dir)
+            .args(&args)
+            .spawn()
+            .context("Failed to run docker compose")?;
+        let status = wait_interruptible(&mut child)?;
+        if interrupted() {
+            return Ok(());
+        }
+        if !status.success() {
+            return Err(anyhow!("docker compose failed to start services needed by jobs"));
+        }
+    }
+
+    let mut results = Vec::new();
+    for job in jobs {
+        log::info!("Running job {}...", job);
+        let mut args = compose_args(options);
+        args.extend(["run".to_string(), "--rm".to_string()]);
+        if options.build {
+            args.push("--build".to_string());
+        }
+        args.push(job.to_string());
+        let mut child = Command::new("docker")
+            .current_dir(output_dir)
+            .args(&args)
+            .spawn()
+            .context("Failed to run docker compose")?;
+        let status = wait_interruptible(&mut child)?;
+        if interrupted() {
+            return Ok(());
+        }
+        results.push((job, status));
+        if !status.success() {
+            break;
+        }
+    }
+
+    log::info!("Jobs:");
+    for (job, status) in &results {
+        match status.code() {
+            Some(code) => log::info!("  {}: exit code {}", job, code),
+            None => log::info!("  {}: terminated by a signal", job),
+        }
+    }
+
+    if let Some((job, _)) = results.iter().find(|(_, status)| !status.success()) {
+        shut_down(options)?;
+        return Err(anyhow!("Job {} failed, the rest of the environment was not started", job));
+    }
+    Ok(())
+}
+
+/// Compose profile of local job services without a profile of their own.
+const JOBS_PROFILE: &str = "jobs";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Replaces the default Ctrl-C behaviour (terminating immediately) with a flag
+/// the run loops check, so they can tear the environment down before exiting.
+fn handle_interrupts() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        if let Err(e) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst)) {
+            diagnostics::warn("interrupt_handler", format!("failed to install Ctrl-C handler: {}", e));
+        }
+    });
+}
+
+/// Whether the user pressed Ctrl-C during a local run.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Waits for `child`. On Ctrl-C the child (which received the signal as well)
+/// gets a grace period to exit on its own before it is killed.
+fn wait_interruptible(child: &mut Child) -> Result<ExitStatus> {
+    wait_with_grace(child, interrupted, INTERRUPT_GRACE_PERIOD)
+}
+
+fn wait_with_grace(child: &mut Child, interrupted: impl Fn() -> bool, grace_period: Duration) -> Result<ExitStatus> {
+    let mut deadline = None;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if interrupted() {
+            let deadline = *deadline.get_or_insert_with(|| Instant::now() + grace_period);
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                return Ok(child.wait()?);
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Tears the environment down when the CLI exits, unless --keep-running.
+fn shut_down(options: &RunOptions) -> Result<()> {
+    if options.keep_running {
+        log::info!("Services keep running, stop them with `simpled local down`");
+        Ok(())
+    } else {
+        down()
+    }
+}
+
 fn write_compose<F>(spec: &EnvironmentResolvedSpec, filter: F) -> Result<()>
 where
     F: Fn(&crate::resolved_spec::ServiceResolvedSpec) -> bool,
 {
-    let output_dir = Path::new("local_env");
+    let output_dir = Path::new(OUTPUT_DIR);
     fs::create_dir_all(output_dir).context("Failed to create local_env directory")?;
 
-    println!("Starting services for deployment: {}", spec.current_deployment.name);
+    log::info!("Starting services for deployment: {}", spec.current_deployment.name);
 
-    let mut services_map = HashMap::new();
+    let compose = build_compose(spec, filter, output_dir)?;
+
+    // Written to a temporary file and renamed, so an interrupted run never
+    // leaves a half-written compose file behind for `local down`.
+    let compose_path = output_dir.join(COMPOSE_FILE);
+    let tmp_path = output_dir.join(format!("{}.tmp", COMPOSE_FILE));
+    let yaml = serde_yaml::to_string(&compose)?;
+    fs::write(&tmp_path, yaml)?;
+    fs::rename(&tmp_path, &compose_path).context("Failed to replace the compose file")?;
+
+    log::info!("Generated docker-compose.yaml at {:?}", compose_path);
+
+    Ok(())
+}
+
+/// Entries of `local_env/` that belong to no service: the compose file,
+/// gateway state, certificates, named volume data and the trash itself.
+fn is_workspace_entry(name: &str) -> bool {
+    [COMPOSE_FILE, local_ingress::PID_FILE, local_ingress::LOG_FILE, local_certs::CERTS_DIR, VOLUMES_DIR, TRASH_DIR]
+        .contains(&name)
+}
+
+/// Finds entries in `output_dir` left over from services that no longer
+/// exist (renamed or removed), whose stale env files could otherwise still be
+/// mounted. Services of `spec` and those in `known`, the ones `--only` left
+/// out, are not stale. With `clean` stale entries are moved to `.trash/`,
+/// replacing older trash; otherwise they are only reported. Returns the stale
+/// entry names.
+fn tidy_output_dir(spec: &EnvironmentResolvedSpec, known: &[String], output_dir: &Path, clean: bool) -> Result<Vec<String>> {
+    let Ok(entries) = fs::read_dir(output_dir) else {
+        return Ok(Vec::new());
+    };
+
+    let mut stale = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        let is_service = spec.current_deployment.services.iter().any(|s| s.full_name == name) || known.contains(&name);
+        if !is_service && !is_workspace_entry(&name) {
+            stale.push(name);
+        }
+    }
+    stale.sort();
+    if stale.is_empty() {
+        return Ok(stale);
+    }
+
+    if !clean {
+        diagnostics::warn("stale_service_files", format!(
+            "{} contains files of services that no longer exist: {}. Move them to {}/{} with --clean",
+            output_dir.display(), stale.join(", "), output_dir.display(), TRASH_DIR
+        ));
+        return Ok(stale);
+    }
+
+    let trash = output_dir.join(TRASH_DIR);
+    fs::create_dir_all(&trash).context("Failed to create trash directory")?;
+    for name in &stale {
+        let target = trash.join(name);
+        if target.is_dir() {
+            fs::remove_dir_all(&target)?;
+        } else if target.exists() {
+            fs::remove_file(&target)?;
+        }
+        fs::rename(output_dir.join(name), &target)
+            .with_context(|| format!("Failed to move {} to {}", name, trash.display()))?;
+    }
+    log::info!("Moved stale {} to {}", stale.join(", "), trash.display());
+    Ok(stale)
+}
+
+/// Builds the local compose file and writes the per-service files it
+/// references into `output_dir`.
+fn build_compose<F>(spec: &EnvironmentResolvedSpec, filter: F, output_dir: &Path) -> Result<DockerCompose>
+where
+    F: Fn(&crate::resolved_spec::ServiceResolvedSpec) -> bool,
+{
+    let mut services_map = BTreeMap::new();
+    let mut files = GeneratedFiles::default();
 
     for service in spec.current_deployment.services.iter() {
         // Host-run services (working_dir set) get their `.env` and secrets even
@@ -60,22 +888,378 @@ where
         write_working_dir(service, spec)?;
 
         if filter(service) {
-            let docker_service = prepare_service(service, spec, output_dir)?;
+            let mut docker_service = prepare_service(service, spec, output_dir, &mut files)?;
+            // Jobs are one-shot containers started by `run_jobs`; the profile
+            // keeps `compose up` from starting them again.
+            if matches!(service.service_type, ServiceType::Job) {
+                docker_service.restart = Some("no".to_string());
+                if docker_service.profiles.is_empty() {
+                    docker_service.profiles.push(JOBS_PROFILE.to_string());
+                }
+            }
+            docker_service.networks.insert("default".to_string(), ServiceNetwork {
+                aliases: vec![service.full_name.clone()],
+            });
             services_map.insert(service.full_name.clone(), docker_service);
         }
     }
 
-    let compose = DockerCompose {
+    files.commit(OnConflict::Overwrite)?;
+
+    // All local containers share one network and reach each other by their
+    // resolved `full_name`, the same way they do in docker deployments.
+    let mut networks = BTreeMap::new();
+    networks.insert("default".to_string(), DockerComposeNetwork {
+        external: false,
+        name: format!("simpled_{}", spec.current_deployment.name),
+    });
+
+    Ok(DockerCompose {
         services: services_map,
-        networks: HashMap::new(),
-    };
+        networks,
+        configs: BTreeMap::new(),
+    })
+}
 
-    let compose_path = output_dir.join("docker-compose.yaml");
-    let yaml = serde_yaml::to_string(&compose)?;
-    fs::write(&compose_path, yaml)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{resolver, spec_yaml, transform};
+    use crate::spec::ServicePort;
 
-    println!("Generated docker-compose.yaml at {:?}", compose_path);
+    fn local_spec() -> EnvironmentResolvedSpec {
+        let env_yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_str(r#"
+type: local
+gateway:
+  hosts:
+    web: localhost:8080
+deployments:
+  dev:
+    primary_host: web
+    application:
+      name: shop
+    environment:
+      - DB_HOST=db
+    services:
+      api:
+        host: web
+        prefix: /api
+        ports:
+          - "8081:80"
+      db:
+        ports:
+          - "5432:5432"
+"#).unwrap();
+        let app_yaml: spec_yaml::AppSpecYaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+environment:
+  external:
+    - DB_HOST
+app_services:
+  api:
+    type: public
+    image: shop/api
+    environment:
+      - DB_HOST
+extra_services:
+  db:
+    type: internal
+    image: postgres:16
+"#).unwrap();
 
-    Ok(())
-}
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = transform::convert_env_spec(env_yaml, root.path()).unwrap();
+        let app_spec = transform::convert_app_spec(app_yaml, Some(&env_spec)).unwrap();
+        resolver::resolve(&env_spec, &app_spec, "dev").unwrap()
+    }
+
+    #[test]
+    fn only_keeps_named_services_and_their_routes() {
+        let mut spec = local_spec();
+        retain_only(&mut spec, &["db".to_string()]).unwrap();
+
+        let names: Vec<&str> = spec.current_deployment.services.iter().map(|s| s.full_name.as_str()).collect();
+        assert_eq!(names, ["db"]);
+        assert!(spec.ingress.rules.iter().all(|rule| rule.services.is_empty()));
+
+        assert!(retain_only(&mut local_spec(), &["web".to_string()]).is_err());
+    }
+
+    #[test]
+    fn ports_of_services_that_do_not_start_are_not_checked() {
+        let mut spec = local_spec();
+        spec.current_deployment.services.iter_mut().find(|s| s.full_name == "db").unwrap().profile = Some("storage".to_string());
+        let owners = |options: &RunOptions, only_extra: bool| -> Vec<(u16, String)> {
+            required_ports(&spec, options, only_extra).into_iter().map(|(_, port, _, owner)| (port, owner)).collect()
+        };
+        let owned = |port: u16, owner: &str| (port, owner.to_string());
+
+        let options = RunOptions::default();
+        assert_eq!(owners(&options, false), [owned(8080, "the local gateway"), owned(8081, "api")]);
+        assert_eq!(owners(&options, true), [owned(8080, "the local gateway")]);
+        let options = RunOptions { profiles: vec!["storage".to_string()], exclude: vec!["api".to_string()], ..Default::default() };
+        assert_eq!(owners(&options, false), [owned(8080, "the local gateway"), owned(5432, "db")]);
+    }
+
+    #[test]
+    fn detached_runs_start_compose_in_the_background() {
+        let up = |options: RunOptions| up_args(&options).join(" ");
+
+        assert_eq!(up(RunOptions::default()), "compose up --remove-orphans");
+        assert_eq!(up(RunOptions { detach: true, ..Default::default() }), "compose up --remove-orphans -d");
+        assert_eq!(up(RunOptions { keep_running: true, ..Default::default() }), "compose up --remove-orphans -d");
+        assert_eq!(
+            up(RunOptions { detach: true, build: true, profiles: vec!["storage".to_string()], ..Default::default() }),
+            "compose --profile storage up --remove-orphans --build -d"
+        );
+    }
+
+    #[test]
+    fn only_writes_to_the_specs_trigger_a_reload() {
+        use notify::event::{AccessKind, CreateKind, ModifyKind, RemoveKind};
+        let event = |kind| Ok(notify::Event::new(kind));
+
+        assert!(is_change(&event(EventKind::Create(CreateKind::File))));
+        assert!(is_change(&event(EventKind::Modify(ModifyKind::Any))));
+        assert!(is_change(&event(EventKind::Remove(RemoveKind::File))));
+        assert!(!is_change(&event(EventKind::Access(AccessKind::Read))));
+        assert!(!is_change(&Err(notify::Error::generic("watch failed"))));
+    }
+
+    #[test]
+    fn undockerized_ports_must_not_be_published_by_docker() {
+        let mut spec = local_spec();
+        let api = find_service(&spec, "api").unwrap().clone();
+        assert!(check_native_ports(&spec, &api).is_ok());
+
+        let db = spec.current_deployment.services.iter_mut().find(|s| s.full_name == "db").unwrap();
+        db.ports[0].external = Some(8081);
+        let err = check_native_ports(&spec, &api).unwrap_err().to_string();
+        assert!(err.contains("undockerized service api is also published by db"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interrupted_children_are_killed_after_the_grace_period() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let started = Instant::now();
+        let status = wait_with_grace(&mut child, || true, Duration::from_millis(200)).unwrap();
+        assert!(!status.success());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // a child that exits within the grace period keeps its own status
+        let mut child = Command::new("sh").args(["-c", "sleep 0.1"]).spawn().unwrap();
+        assert!(wait_with_grace(&mut child, || true, Duration::from_secs(10)).unwrap().success());
+
+        let mut child = Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        assert_eq!(wait_with_grace(&mut child, || false, Duration::ZERO).unwrap().code(), Some(3));
+    }
+
+    #[test]
+    fn taken_ports_are_conflicts_unless_held_by_the_environment() {
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let tcp = TcpListener::bind((loopback, 0)).unwrap();
+        let udp = UdpSocket::bind((loopback, 0)).unwrap();
+        let tcp_port = tcp.local_addr().unwrap().port();
+        let udp_port = udp.local_addr().unwrap().port();
+        let required = [
+            (loopback, tcp_port, PortProtocol::Tcp, "api".to_string()),
+            (loopback, udp_port, PortProtocol::Udp, "dns".to_string()),
+        ];
+
+        assert_eq!(port_conflicts(&required, &[]), [
+            format!("port {} required by api is already in use", tcp_port),
+            format!("UDP port {} required by dns is already in use", udp_port),
+        ]);
+        assert_eq!(port_conflicts(&required, &[tcp_port]).len(), 1);
+
+        drop((tcp, udp));
+        assert!(port_conflicts(&required, &[]).is_empty());
+    }
+
+    #[test]
+    fn waits_for_services_left_running_in_docker() {
+        let mut spec = local_spec();
+        let mut migrate = find_service(&spec, "db").unwrap().clone();
+        migrate.full_name = "migrate".to_string();
+        migrate.service_type = ServiceType::Job;
+        migrate.ports.clear();
+        spec.current_deployment.services.push(migrate);
+        let awaited = |options: RunOptions| -> Vec<String> {
+            awaited_services(&spec, &options).iter().map(|s| s.full_name.clone()).collect()
+        };
 
+        assert_eq!(awaited(RunOptions::default()), ["api", "db"]);
+        assert_eq!(awaited(RunOptions { undockerized: Some("api".to_string()), ..Default::default() }), ["db"]);
+        assert_eq!(awaited(RunOptions { exclude: vec!["db".to_string()], ..Default::default() }), ["api"]);
+
+        assert_eq!(service_urls(&spec, "api"), ["http://localhost:8080/api"]);
+        assert!(service_urls(&spec, "db").is_empty());
+    }
+
+    #[test]
+    fn build_context_is_absolute_in_compose() {
+        let mut spec = local_spec();
+        let api = spec.current_deployment.services.iter_mut().find(|s| s.full_name == "api").unwrap();
+        api.build = Some(crate::spec::ServiceBuild {
+            context: "api".to_string(),
+            dockerfile: Some("Dockerfile.dev".to_string()),
+            args: Default::default(),
+        });
+
+        let output = tempfile::tempdir().unwrap();
+        let compose = build_compose(&spec, |_| true, output.path()).unwrap();
+        let build = compose.services["api"].build.as_ref().unwrap();
+
+        assert!(Path::new(&build.context).is_absolute());
+        assert!(build.context.ends_with("/api"));
+        assert_eq!(build.dockerfile.as_deref(), Some("Dockerfile.dev"));
+        assert!(compose.services["db"].build.is_none());
+    }
+
+    #[test]
+    fn dev_volumes_are_created_and_mounted_by_absolute_path() {
+        let source = tempfile::tempdir().unwrap();
+        let host_path = source.path().join("src");
+
+        let mut spec = local_spec();
+        let api = spec.current_deployment.services.iter_mut().find(|s| s.full_name == "api").unwrap();
+        api.dev_volumes = vec![crate::spec::DevVolume {
+            host_path: host_path.to_string_lossy().to_string(),
+            mount_path: "/app/src".to_string(),
+        }];
+
+        let output = tempfile::tempdir().unwrap();
+        let compose = build_compose(&spec, |_| true, output.path()).unwrap();
+
+        assert!(host_path.is_dir());
+        let expected = format!("{}:/app/src", host_path.to_string_lossy().replace('\\', "/"));
+        assert!(compose.services["api"].volumes.contains(&expected));
+    }
+
+    #[test]
+    fn expose_only_ports_are_not_published() {
+        let mut spec = local_spec();
+        let db = spec.current_deployment.services.iter_mut().find(|s| s.full_name == "db").unwrap();
+        db.ports = vec![ServicePort { external: None, internal: 5432, protocol: PortProtocol::Tcp }];
+
+        let output = tempfile::tempdir().unwrap();
+        let compose = build_compose(&spec, |_| true, output.path()).unwrap();
+        assert!(compose.services["db"].ports.is_empty());
+        assert_eq!(compose.services["db"].expose, ["5432"]);
+        assert_eq!(compose.services["api"].ports, ["8081:80"]);
+    }
+
+    #[test]
+    fn jobs_are_one_shot_and_kept_out_of_up() {
+        let mut spec = local_spec();
+        let api = spec.current_deployment.services.iter_mut().find(|s| s.full_name == "api").unwrap();
+        api.service_type = ServiceType::Job;
+
+        let output = tempfile::tempdir().unwrap();
+        let compose = build_compose(&spec, |_| true, output.path()).unwrap();
+
+        let job = &compose.services["api"];
+        assert_eq!(job.restart.as_deref(), Some("no"));
+        assert_eq!(job.profiles, [JOBS_PROFILE]);
+        assert!(compose.services["db"].restart.is_none());
+    }
+
+    #[test]
+    fn clean_moves_stale_service_dirs_to_trash() {
+        let output = tempfile::tempdir().unwrap();
+        for dir in ["api", "old-api", "volumes", "certs"] {
+            fs::create_dir_all(output.path().join(dir)).unwrap();
+        }
+        fs::write(output.path().join(COMPOSE_FILE), "services: {}").unwrap();
+        let spec = local_spec();
+
+        let stale = tidy_output_dir(&spec, &[], output.path(), false).unwrap();
+        assert_eq!(stale, ["old-api"]);
+        assert!(output.path().join("old-api").exists());
+
+        tidy_output_dir(&spec, &[], output.path(), true).unwrap();
+        assert!(!output.path().join("old-api").exists());
+        assert!(output.path().join(TRASH_DIR).join("old-api").is_dir());
+        assert!(output.path().join("api").is_dir());
+        assert!(output.path().join("volumes").is_dir());
+        assert!(tidy_output_dir(&spec, &[], output.path(), true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn clean_keeps_files_of_services_left_out_by_only() {
+        let output = tempfile::tempdir().unwrap();
+        for dir in ["api", "db", "old-api"] {
+            fs::create_dir_all(output.path().join(dir)).unwrap();
+        }
+        let mut spec = local_spec();
+        let all: Vec<String> = spec.current_deployment.services.iter().map(|s| s.full_name.clone()).collect();
+        retain_only(&mut spec, &["db".to_string()]).unwrap();
+
+        assert_eq!(tidy_output_dir(&spec, &all, output.path(), true).unwrap(), ["old-api"]);
+        assert!(output.path().join("api").is_dir() && output.path().join("db").is_dir());
+    }
+
+    #[test]
+    fn local_compose_snapshot() {
+        let output = tempfile::tempdir().unwrap();
+        let compose = build_compose(&local_spec(), |_| true, output.path()).unwrap();
+
+        let actual = serde_yaml::to_value(&compose).unwrap();
+
+        let expected: serde_yaml::Value = serde_yaml::from_str(r#"
+services:
+  api:
+    image: shop/api:latest
+    container_name: api
+    ports:
+      - "8081:80"
+    env_file:
+      - ./api/.env
+    networks:
+      default:
+        aliases:
+          - api
+  db:
+    image: postgres:16
+    container_name: db
+    ports:
+      - "5432:5432"
+    env_file:
+      - ./db/.env
+    networks:
+      default:
+        aliases:
+          - db
+networks:
+  default:
+    name: simpled_dev
+"#).unwrap();
+        assert_eq!(actual, expected);
+        assert_eq!(fs::read_to_string(output.path().join("api/.env")).unwrap(), "DB_HOST=db");
+    }
+
+    #[test]
+    fn parses_compose_ps_array_and_line_formats() {
+        let line = r#"{"Service":"api","Image":"myapp/api:1.0.0","State":"running","Health":"healthy","Publishers":[{"URL":"0.0.0.0","TargetPort":80,"PublishedPort":8080,"Protocol":"tcp"}]}"#;
+
+        let from_lines = parse_compose_ps(&format!("{}\n{}\n", line, line.replace("api", "worker"))).unwrap();
+        assert_eq!(from_lines.len(), 2);
+        assert_eq!(from_lines[1].service, "worker");
+
+        let from_array = parse_compose_ps(&format!("[{}]", line)).unwrap();
+        assert_eq!(from_array.len(), 1);
+        assert_eq!(from_array[0].state, "running");
+        assert_eq!(from_array[0].health, "healthy");
+        assert_eq!(from_array[0].publishers[0].published_port, 8080);
+
+        let udp = line.replace(r#"}]}"#, r#"},{"URL":"0.0.0.0","TargetPort":53,"PublishedPort":5353,"Protocol":"udp"}]}"#);
+        let with_udp = parse_compose_ps(&udp).unwrap();
+        assert_eq!(probed_ports(&with_udp[0]).collect::<Vec<_>>(), [8080]);
+        assert_eq!(from_array[0].publishers[0].target_port, 80);
+
+        assert!(parse_compose_ps("").unwrap().is_empty());
+    }
+}
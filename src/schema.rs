@@ -0,0 +1,86 @@
+use anyhow::{Result, bail};
+use serde_json::Value;
+
+use crate::spec_yaml::{AppSpecYaml, DeploymentEnvironmentSpecYaml};
+
+/// JSON Schema of `appspec.yaml` or `envspec.yaml`/`localenv.yaml`, for
+/// editors that validate YAML against a schema.
+pub fn generate(kind: &str) -> Result<Value> {
+    let schema = match kind {
+        "appspec" => schemars::schema_for!(AppSpecYaml),
+        "envspec" => schemars::schema_for!(DeploymentEnvironmentSpecYaml),
+        other => bail!("Unknown schema: {} (expected appspec or envspec)", other),
+    };
+    let mut schema = schema.to_value();
+    untagged_as_one_of(&mut schema);
+    Ok(schema)
+}
+
+// schemars describes untagged enums with `anyOf`. Their variants never accept
+// the same value (string vs list vs map), so `oneOf` states the same and reads
+// as "one of these forms" in editors.
+fn untagged_as_one_of(schema: &mut Value) {
+    let Some(defs) = schema.get_mut("$defs").and_then(Value::as_object_mut) else {
+        return;
+    };
+    for def in defs.values_mut().filter_map(Value::as_object_mut) {
+        if let Some(variants) = def.remove("anyOf") {
+            def.insert("oneOf".to_string(), variants);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Complete specs from docs/examples.md: the yaml block after a
+    // **`appspec.yaml`** / **`envspec.yaml`** / **`localenv.yaml`** label
+    // that has all required top-level fields. Shorter snippets are excerpts.
+    fn examples(kind: &str) -> Vec<serde_json::Value> {
+        let docs = include_str!("../docs/examples.md");
+        let labels: &[&str] = if kind == "appspec" { &["appspec.yaml"] } else { &["envspec.yaml", "localenv.yaml"] };
+        let required: &[&str] = if kind == "appspec" { &["name:", "version:"] } else { &["gateway:", "deployments:"] };
+
+        let mut found = Vec::new();
+        let mut lines = docs.lines();
+        while let Some(line) = lines.next() {
+            if !labels.iter().any(|l| line.starts_with(&format!("**`{}`**", l))) {
+                continue;
+            }
+            if lines.next() != Some("```yaml") {
+                continue;
+            }
+            let block: Vec<&str> = lines.by_ref().take_while(|l| *l != "```").collect();
+            if required.iter().all(|r| block.iter().any(|l| l.starts_with(r))) {
+                let yaml: serde_yaml::Value = serde_yaml::from_str(&block.join("\n")).unwrap();
+                found.push(serde_json::to_value(yaml).unwrap());
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn doc_examples_match_the_schema() {
+        for kind in ["appspec", "envspec"] {
+            let schema = generate(kind).unwrap();
+            let validator = jsonschema::validator_for(&schema).unwrap();
+            let examples = examples(kind);
+            assert!(!examples.is_empty(), "no complete {} examples found", kind);
+            for example in &examples {
+                let errors: Vec<String> = validator.iter_errors(example).map(|e| e.to_string()).collect();
+                assert!(errors.is_empty(), "{} example does not match the schema: {:?}\n{}", kind, errors, example);
+            }
+        }
+    }
+
+    #[test]
+    fn untagged_enums_are_one_of() {
+        let app = generate("appspec").unwrap();
+        assert!(app["$defs"]["AppSecretsYaml"]["oneOf"].is_array());
+        let env = generate("envspec").unwrap();
+        assert!(env["$defs"]["HostSpecYaml"]["oneOf"].is_array());
+        assert!(env["$defs"]["DeploymentSecretSpecExYaml"]["oneOf"].is_array());
+        assert!(env["properties"]["type"].is_object());
+    }
+}
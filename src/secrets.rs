@@ -0,0 +1,558 @@
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine as _, engine::general_purpose};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+
+use crate::spec::{DeploymentEnvType, DeploymentSecretSource, DeploymentSpec, GeneratedSecretFormat, GENERATED_SECRETS_DIR, SECRET_STORE_DIR};
+use crate::{diagnostics, resolver, spec_loader};
+
+// `--secrets-state`: state file of generated secrets used for every deployment
+static STATE_FILE: OnceLock<PathBuf> = OnceLock::new();
+// `--rotate-secret`: generated secrets to replace with a new value
+static ROTATE: OnceLock<Vec<String>> = OnceLock::new();
+// (state file, deployment, secret) already rotated in this run; also
+// serializes the read-modify-write of state files
+static ROTATED: Mutex<BTreeSet<(PathBuf, String, String)>> = Mutex::new(BTreeSet::new());
+
+pub fn set_generated_options(state_file: Option<&Path>, rotate: &[String]) {
+    if let Some(path) = state_file {
+        let _ = STATE_FILE.set(path.to_path_buf());
+    }
+    let _ = ROTATE.set(rotate.to_vec());
+}
+
+/// Fails when a `--rotate-secret` name is not a `generate:` secret of the
+/// deployment, listing the ones that are.
+pub fn check_rotated(deployment: &DeploymentSpec) -> Result<()> {
+    check_rotated_names(deployment, ROTATE.get().map_or(&[], Vec::as_slice))
+}
+
+fn check_rotated_names(deployment: &DeploymentSpec, rotate: &[String]) -> Result<()> {
+    let generated: Vec<&str> = deployment.secrets.iter()
+        .filter(|s| matches!(s.source, DeploymentSecretSource::Generated { .. }))
+        .map(|s| s.secret_name.as_str())
+        .collect();
+    let unknown: Vec<&str> = rotate.iter()
+        .map(String::as_str)
+        .filter(|name| !generated.contains(name))
+        .collect();
+    if !unknown.is_empty() {
+        let available = if generated.is_empty() { "none".to_string() } else { generated.join(", ") };
+        bail!("--rotate-secret {} is not a generated secret of deployment {}; generated secrets: {}", unknown.join(", "), deployment.name, available);
+    }
+    Ok(())
+}
+
+/// State file holding the generated secrets of `deployment`:
+/// `.simpled/secrets.<deployment>.json` next to the env spec unless
+/// `--secrets-state` names one.
+pub fn state_file(root: &Path, deployment: &str) -> PathBuf {
+    match STATE_FILE.get() {
+        Some(path) => path.clone(),
+        None => root.join(GENERATED_SECRETS_DIR).join(format!("secrets.{}.json", deployment)),
+    }
+}
+
+/// Value of a `generate:` secret. It is generated on first use and saved in
+/// the state file, keyed by deployment and secret, so later runs reuse it;
+/// `--rotate-secret` replaces it once per run.
+pub fn generated_value(state: &Path, deployment: &str, secret: &str, length: usize, format: GeneratedSecretFormat) -> Result<String> {
+    generated_value_with(state, deployment, secret, length, format, ROTATE.get().map_or(&[], Vec::as_slice))
+}
+
+// `generated_value` with `rotate` as the `--rotate-secret` names
+fn generated_value_with(state: &Path, deployment: &str, secret: &str, length: usize, format: GeneratedSecretFormat, rotate: &[String]) -> Result<String> {
+    let mut rotated = ROTATED.lock().unwrap_or_else(|e| e.into_inner());
+    let mut values: BTreeMap<String, BTreeMap<String, String>> = match fs::read_to_string(state) {
+        Ok(content) => serde_json::from_str(&content).context(format!("Failed to parse secrets state file {:?}", state))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e).context(format!("Failed to read secrets state file {:?}", state)),
+    };
+
+    let key = (state.to_path_buf(), deployment.to_string(), secret.to_string());
+    let rotate = rotate.iter().any(|n| n == secret) && !rotated.contains(&key);
+    if let Some(value) = values.get(deployment).and_then(|v| v.get(secret)) {
+        if !rotate {
+            return Ok(value.clone());
+        }
+    }
+
+    let value = generate_secret(length, format)?;
+    values.entry(deployment.to_string()).or_default().insert(secret.to_string(), value.clone());
+    if let Some(dir) = state.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).context(format!("Failed to create {:?}", dir))?;
+        // the default state directory keeps itself out of version control
+        if dir.file_name().is_some_and(|n| n == GENERATED_SECRETS_DIR) {
+            ignore_in_git(dir)?;
+        }
+    }
+    write_private(state, &serde_json::to_string_pretty(&values)?)?;
+    if rotate {
+        rotated.insert(key);
+        log::info!("Rotated generated secret {} of deployment {}", secret, deployment);
+    } else {
+        log::info!("Generated secret {} of deployment {} in {:?}", secret, deployment, state);
+    }
+    Ok(value)
+}
+
+// `length` random bytes as hex or base64, or `length` letters and digits
+fn generate_secret(length: usize, format: GeneratedSecretFormat) -> Result<String> {
+    const ALNUM: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    let mut bytes = vec![0u8; length];
+    getrandom::fill(&mut bytes).map_err(|e| anyhow!("Failed to generate a random secret: {}", e))?;
+    Ok(match format {
+        GeneratedSecretFormat::Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        GeneratedSecretFormat::Base64 => general_purpose::STANDARD.encode(&bytes),
+        GeneratedSecretFormat::Alnum => {
+            // bytes past the largest multiple of 62 are redrawn to keep characters uniform
+            let limit = 256 - 256 % ALNUM.len();
+            let mut value = String::with_capacity(length);
+            while value.len() < length {
+                for b in &bytes {
+                    if (*b as usize) < limit && value.len() < length {
+                        value.push(ALNUM[*b as usize % ALNUM.len()] as char);
+                    }
+                }
+                getrandom::fill(&mut bytes).map_err(|e| anyhow!("Failed to generate a random secret: {}", e))?;
+            }
+            value
+        }
+    })
+}
+
+/// Name of the Kubernetes Secret holding `secret` of `app`, as generated and
+/// mounted by the k8s generator.
+pub fn k8s_secret_name(app: &str, secret: &str) -> String {
+    format!("{}-{}", app, secret)
+}
+
+/// Sets values of the deployment's `env:` secrets. On Kubernetes each value is
+/// applied to the Secret the generated manifests mount, and read back from it
+/// when the environment variable is not set; elsewhere it is saved in the
+/// secret store next to the env spec, which later deployments read when the
+/// environment variable is not set.
+///
+/// `assignments` are `key=value`, `key=@file` or a bare `key`, which prompts.
+pub fn set(env_name: &str, path: Option<&str>, assignments: &[String]) -> Result<()> {
+    set_with(env_name, path, assignments, apply_k8s_secret)
+}
+
+// `set`, applying Kubernetes Secrets with `apply_k8s`
+fn set_with(env_name: &str, path: Option<&str>, assignments: &[String], mut apply_k8s: impl FnMut(&str, &str) -> Result<()>) -> Result<()> {
+    let root = Path::new(path.unwrap_or("."));
+    let env_spec = spec_loader::load_env_spec(root)?;
+    let deployment = env_spec.deployments.iter()
+        .find(|d| d.name == env_name)
+        .context(format!("Deployment {} not found", env_name))?;
+
+    if assignments.is_empty() {
+        bail!("Nothing to set, pass secrets with -f key=value, -f key=@file or -f key");
+    }
+    warn_undeclared(assignments);
+
+    for assignment in assignments {
+        let (key, value) = assignment.split_once('=').map_or((assignment.as_str(), None), |(k, v)| (k, Some(v)));
+        check_env_secret(deployment, key)?;
+        let value = match value {
+            Some(value) => read_value(value)?,
+            None => prompt(key)?,
+        };
+
+        match env_spec.env_type {
+            DeploymentEnvType::K8S => apply_k8s(&k8s_secret_name(&deployment.application.name, key), &value)?,
+            DeploymentEnvType::Docker(_) | DeploymentEnvType::Local => {
+                let path = store_secret(&env_spec.root, &deployment.name, key, &value)?;
+                log::info!("Stored secret {} in {:?}", key, path);
+            }
+        }
+    }
+    Ok(())
+}
+
+// A secret the deployment must provide, as reported by `secrets list`
+struct SecretStatus {
+    name: String,
+    source: String,
+    // why the value cannot be resolved; None when it can
+    problem: Option<String>,
+}
+
+/// Prints every secret of the deployment with its source and whether its
+/// value can currently be resolved. Values are never printed.
+pub fn list(env_name: &str, path: Option<&str>) -> Result<()> {
+    let statuses = secret_statuses(env_name, path)?;
+    let name_width = statuses.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    let source_width = statuses.iter().map(|s| s.source.len()).max().unwrap_or(0);
+    for status in &statuses {
+        let state = match &status.problem {
+            None => "ok".to_string(),
+            Some(problem) => format!("missing: {}", problem),
+        };
+        println!("{:name_width$}  {:source_width$}  {}", status.name, status.source, state);
+    }
+    Ok(())
+}
+
+/// Fails listing every secret of the deployment whose value cannot be resolved.
+pub fn verify(env_name: &str, path: Option<&str>) -> Result<()> {
+    let statuses = secret_statuses(env_name, path)?;
+    let missing: Vec<String> = statuses.iter()
+        .filter_map(|s| s.problem.as_ref().map(|problem| format!("  {}: {}", s.name, problem)))
+        .collect();
+    if !missing.is_empty() {
+        bail!("{} of {} secrets of deployment {} are missing:\n{}", missing.len(), statuses.len(), env_name, missing.join("\n"));
+    }
+    log::info!("All {} secrets of deployment {} are available", statuses.len(), env_name);
+    Ok(())
+}
+
+// Secrets of the deployment plus those the appspec (when present) declares
+// but the deployment does not provide.
+fn secret_statuses(env_name: &str, path: Option<&str>) -> Result<Vec<SecretStatus>> {
+    let root = Path::new(path.unwrap_or("."));
+    let env_spec = spec_loader::load_env_spec(root)?;
+    let deployment = env_spec.deployments.iter()
+        .find(|d| d.name == env_name)
+        .context(format!("Deployment {} not found", env_name))?;
+
+    let mut statuses: Vec<SecretStatus> = resolver::check_secret_values(deployment).into_iter()
+        .map(|(secret, available)| SecretStatus {
+            name: secret.secret_name.clone(),
+            source: describe_source(&secret.source),
+            problem: available.err().map(|e| format!("{:#}", e)),
+        })
+        .collect();
+
+    if let Ok(app_spec) = spec_loader::load_project_app_spec(None) {
+        for secret in &app_spec.secrets {
+            if !statuses.iter().any(|s| s.name == secret.secret_name) {
+                statuses.push(SecretStatus {
+                    name: secret.secret_name.clone(),
+                    source: "-".to_string(),
+                    problem: Some(format!("not provided by deployment {}", env_name)),
+                });
+            }
+        }
+    }
+    statuses.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(statuses)
+}
+
+pub fn describe_source(source: &DeploymentSecretSource) -> String {
+    match source {
+        DeploymentSecretSource::EnvVariable(var) => format!("env {}", var),
+        DeploymentSecretSource::FilePath(path) => format!("file {}", path),
+        DeploymentSecretSource::Embedded(_) => "inline".to_string(),
+        DeploymentSecretSource::Store { env, .. } => format!("env {} (stored)", env),
+        DeploymentSecretSource::Cluster { env, name } => format!("env {} or Secret {}", env, name),
+        DeploymentSecretSource::Generated { format, length, .. } => format!("generated {:?} {}", format, length).to_lowercase(),
+    }
+}
+
+// A value of `@path` is read from that file, trailing newline excluded.
+fn read_value(value: &str) -> Result<String> {
+    match value.strip_prefix('@') {
+        Some(file) => {
+            let content = fs::read_to_string(file).context(format!("Failed to read secret value from {:?}", file))?;
+            Ok(content.trim_end_matches(['\n', '\r']).to_string())
+        }
+        None => Ok(value.to_string()),
+    }
+}
+
+// Reads a value from the terminal with echo turned off where `stty` exists.
+fn prompt(key: &str) -> Result<String> {
+    eprint!("Value for {}: ", key);
+    io::stderr().flush()?;
+    let echo_off = Command::new("stty").arg("-echo")
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success());
+
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line);
+
+    if echo_off {
+        let _ = Command::new("stty").arg("echo").stdin(Stdio::inherit()).status();
+        eprintln!();
+    }
+    read.context(format!("Failed to read value for {}", key))?;
+    Ok(line.trim_end_matches(['\n', '\r']).to_string())
+}
+
+// The appspec is optional here: env repositories usually don't contain one.
+fn warn_undeclared(assignments: &[String]) {
+    let Ok(app_spec) = spec_loader::load_project_app_spec(None) else {
+        return;
+    };
+    for assignment in assignments {
+        let key = assignment.split_once('=').map_or(assignment.as_str(), |(key, _)| key);
+        if !app_spec.secrets.iter().any(|s| s.secret_name == key) {
+            diagnostics::warn("secret_not_declared", format!("secret {} is not declared in the appspec", key));
+        }
+    }
+}
+
+fn check_env_secret(deployment: &DeploymentSpec, key: &str) -> Result<()> {
+    let secret = deployment.secrets.iter()
+        .find(|s| s.secret_name == key)
+        .ok_or_else(|| anyhow!("Secret {} is not defined in deployment {}", key, deployment.name))?;
+    match secret.source {
+        DeploymentSecretSource::EnvVariable(_) | DeploymentSecretSource::Store { .. } | DeploymentSecretSource::Cluster { .. } => Ok(()),
+        _ => bail!("Secret {} of deployment {} is not read from an env variable and cannot be set", key, deployment.name),
+    }
+}
+
+// Same shape as the Secrets the k8s generator writes: the value under `value`
+fn k8s_secret_manifest(name: &str, value: &str) -> String {
+    format!(
+        "apiVersion: v1\nkind: Secret\nmetadata:\n  name: {}\ntype: Opaque\ndata:\n  value: {}\n",
+        name,
+        general_purpose::STANDARD.encode(value),
+    )
+}
+
+fn apply_k8s_secret(name: &str, value: &str) -> Result<()> {
+    let manifest = k8s_secret_manifest(name, value);
+    let mut child = Command::new("kubectl")
+        .args(["apply", "-f", "-"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Failed to execute kubectl")?;
+    child.stdin.take().context("kubectl stdin is not available")?.write_all(manifest.as_bytes())?;
+    if !child.wait()?.success() {
+        bail!("kubectl apply failed for secret {}", name);
+    }
+    Ok(())
+}
+
+/// Value of the Secret `name` in the current kubectl context, as applied by
+/// `simpled secrets set`.
+pub fn read_k8s_secret(name: &str) -> Result<String> {
+    let output = Command::new("kubectl")
+        .args(["get", "secret", name, "-o", "jsonpath={.data.value}"])
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to execute kubectl")?;
+    if !output.status.success() {
+        bail!("kubectl get secret {} failed", name);
+    }
+    let encoded = String::from_utf8_lossy(&output.stdout);
+    let value = general_purpose::STANDARD.decode(encoded.trim()).context(format!("Secret {} has no base64 value", name))?;
+    String::from_utf8(value).context(format!("Secret {} is not UTF-8", name))
+}
+
+// Stored values are readable by the owner only.
+fn store_secret(root: &Path, deployment: &str, key: &str, value: &str) -> Result<PathBuf> {
+    let dir = root.join(SECRET_STORE_DIR).join(deployment);
+    fs::create_dir_all(&dir).context(format!("Failed to create {:?}", dir))?;
+    // the store sits next to the env spec, which usually is in git
+    ignore_in_git(&root.join(SECRET_STORE_DIR))?;
+    let path = dir.join(key);
+    write_private(&path, value)?;
+    Ok(path)
+}
+
+// Keeps everything in `dir` out of version control, unless a `.gitignore`
+// there says otherwise
+fn ignore_in_git(dir: &Path) -> Result<()> {
+    let ignore = dir.join(".gitignore");
+    if !ignore.exists() {
+        fs::write(&ignore, "*\n").context(format!("Failed to write {:?}", ignore))?;
+    }
+    Ok(())
+}
+
+/// Writes `value` to `path`, readable only by the current user.
+pub fn write_private(path: &Path, value: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+        .and_then(|mut file| {
+            // the mode only applies to new files
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            }
+            file.write_all(value.as_bytes())
+        })
+        .context(format!("Failed to write {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_report_every_missing_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("envspec.yaml"), r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    secrets:
+      a_token:
+        env: SIMPLED_TEST_UNSET_TOKEN
+      b_cert:
+        file: ./does-not-exist.pem
+      c_key:
+        env: SIMPLED_TEST_STORED_KEY
+      d_session:
+        generate: {}
+"#).unwrap();
+        let root = dir.path().to_str().unwrap();
+        store_secret(dir.path(), "prod", "c_key", "stored").unwrap();
+
+        let statuses = secret_statuses("prod", Some(root)).unwrap();
+        let missing: Vec<&str> = statuses.iter().filter(|s| s.problem.is_some()).map(|s| s.name.as_str()).collect();
+        assert_eq!(missing, vec!["a_token", "b_cert"]);
+        assert_eq!(statuses[2].source, "env SIMPLED_TEST_STORED_KEY (stored)");
+        assert!(!dir.path().join(GENERATED_SECRETS_DIR).exists(), "checking generates nothing");
+
+        let err = verify("prod", Some(root)).unwrap_err().to_string();
+        assert!(err.contains("a_token") && err.contains("b_cert") && !err.contains("c_key"));
+    }
+
+    #[test]
+    fn generated_secrets_are_kept_until_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("envspec.yaml"), r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    secrets:
+      session_key:
+        generate: {}
+      api_token:
+        generate:
+          length: 20
+          format: alnum
+"#).unwrap();
+        let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+        let deployment = &env_spec.deployments[0];
+        let state = dir.path().join(".simpled/secrets.prod.json");
+        assert!(matches!(&deployment.secrets[1].source, DeploymentSecretSource::Generated { state: s, length: 32, format: GeneratedSecretFormat::Hex, .. } if *s == state));
+
+        let values: Vec<String> = resolver::resolve_secret_values(deployment).into_iter().map(|(_, v)| v.unwrap()).collect();
+        assert!(values[0].len() == 20 && values[0].bytes().all(|b| b.is_ascii_alphanumeric()));
+        assert!(values[1].len() == 64 && values[1].bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(fs::read_to_string(dir.path().join(".simpled/.gitignore")).unwrap(), "*\n");
+        let again: Vec<String> = resolver::resolve_secret_values(deployment).into_iter().map(|(_, v)| v.unwrap()).collect();
+        assert_eq!(again, values);
+
+        let base64 = generate_secret(16, GeneratedSecretFormat::Base64).unwrap();
+        assert_eq!(general_purpose::STANDARD.decode(&base64).unwrap().len(), 16);
+
+        let rotate = ["session_key".to_string()];
+        let rotated_state = dir.path().join("ci-state.json");
+        let first = generated_value_with(&rotated_state, "prod", "session_key", 32, GeneratedSecretFormat::Hex, &rotate).unwrap();
+        let second = generated_value_with(&rotated_state, "prod", "session_key", 32, GeneratedSecretFormat::Hex, &rotate).unwrap();
+        assert_eq!(first, second, "a secret is rotated once per run");
+        assert_ne!(generated_value_with(&state, "prod", "session_key", 32, GeneratedSecretFormat::Hex, &rotate).unwrap(), values[1]);
+
+        check_rotated_names(deployment, &rotate).unwrap();
+        let err = check_rotated_names(deployment, &["db_password".to_string()]).unwrap_err().to_string();
+        assert_eq!(err, "--rotate-secret db_password is not a generated secret of deployment prod; generated secrets: api_token, session_key");
+    }
+
+    #[test]
+    fn set_reads_values_from_files_and_only_sets_env_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("envspec.yaml"), r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    secrets:
+      api_token:
+        env: SIMPLED_TEST_SET_TOKEN
+      session_key:
+        generate: {}
+"#).unwrap();
+        let root = dir.path().to_str().unwrap();
+        let value_file = dir.path().join("token.txt");
+        fs::write(&value_file, "from-file\n").unwrap();
+
+        set("prod", Some(root), &[format!("api_token=@{}", value_file.display())]).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join(SECRET_STORE_DIR).join("prod/api_token")).unwrap(), "from-file");
+        assert_eq!(fs::read_to_string(dir.path().join(SECRET_STORE_DIR).join(".gitignore")).unwrap(), "*\n");
+
+        let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+        let deployment = &env_spec.deployments[0];
+        check_env_secret(deployment, "api_token").unwrap();
+        let err = check_env_secret(deployment, "session_key").unwrap_err().to_string();
+        assert!(err.contains("is not read from an env variable"), "{}", err);
+        let err = check_env_secret(deployment, "missing").unwrap_err().to_string();
+        assert_eq!(err, "Secret missing is not defined in deployment prod");
+        assert!(set("prod", Some(root), &["session_key=x".to_string()]).is_err());
+    }
+
+    #[test]
+    fn k8s_set_applies_the_secret_the_manifests_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("envspec.yaml"), r#"
+type: k8s
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    secrets:
+      api_token:
+        env: SIMPLED_TEST_K8S_TOKEN
+"#).unwrap();
+        let mut applied = Vec::new();
+        set_with("prod", dir.path().to_str(), &["api_token=s3cret".to_string()], |name, value| {
+            applied.push((name.to_string(), value.to_string()));
+            Ok(())
+        }).unwrap();
+        assert_eq!(applied, vec![("shop-api_token".to_string(), "s3cret".to_string())]);
+        assert!(!dir.path().join(SECRET_STORE_DIR).exists());
+
+        // resolution falls back to the same Secret when the variable is unset
+        let env_spec = spec_loader::load_env_spec(dir.path()).unwrap();
+        let DeploymentSecretSource::Cluster { env, name } = &env_spec.deployments[0].secrets[0].source else { panic!("expected a cluster secret") };
+        assert_eq!((env.as_str(), name.as_str()), ("SIMPLED_TEST_K8S_TOKEN", applied[0].0.as_str()));
+        assert!(k8s_secret_manifest(name, "s3cret").ends_with("data:\n  value: czNjcmV0\n"));
+    }
+}
@@ -1,5 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
 use serde::Serialize;
+use crate::quantities::{Cpu, Memory};
+use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
 pub struct AppSpec {
@@ -17,12 +19,106 @@ impl AppSpec {
     pub fn all_services(&self) -> impl Iterator<Item = &ServiceSpec> {
         self.app_services.iter().chain(self.extra_services.iter())
     }
+
+    /// External variables that only services matching `skipped` consume, such
+    /// as profiled services, whose profile may never be enabled, or services a
+    /// deployment disables. The deployment is not required to provide these.
+    ///
+    /// A variable stays required when any other service references it (by
+    /// name or through `$all`), or when a relative/internal variable uses it,
+    /// since those are resolved for every service.
+    pub fn env_vars_used_only_by(&self, skipped: impl Fn(&ServiceSpec) -> bool) -> HashSet<String> {
+        let references = |svc: &ServiceSpec, name: &str| {
+            let pattern = format!("${{{}}}", name);
+            svc.environment.iter().any(|opt| match opt {
+                ServiceEnvOption::All => true,
+                ServiceEnvOption::Simple(n) => n == name,
+                ServiceEnvOption::WithValue(_, v) => v.contains(&pattern),
+            })
+        };
+
+        self.environment.external.iter()
+            .map(|e| e.name.as_str())
+            .filter(|name| {
+                let pattern = format!("${{{}}}", name);
+                let used_globally = self.environment.relative.iter().any(|r| r.relative_value.contains(&pattern))
+                    || self.environment.internal.iter().any(|i| i.value.contains(&pattern));
+                let mut used_by_skipped = false;
+                let mut used_by_others = false;
+                for svc in self.all_services().filter(|s| references(s, name)) {
+                    if skipped(svc) {
+                        used_by_skipped = true;
+                    } else {
+                        used_by_others = true;
+                    }
+                }
+                used_by_skipped && !used_by_others && !used_globally
+            })
+            .map(str::to_string)
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ConfigSpec {
     pub name: String,
-    pub files: Vec<String>,
+    pub files: Vec<ConfigFileSpec>,
+    // files are rendered with the mounting service's variables
+    pub template: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigFileSpec {
+    // A file or directory on disk; in the appspec, the name of a file the
+    // deployment must provide
+    PathRef(String),
+    // A file below a subdirectory of a deployment's config directory, named
+    // by its path relative to that directory, e.g. `dashboards/app.json`
+    DirFile { name: String, path: String },
+    // A file whose content is given in the spec
+    Inline { name: String, content: String },
+    // A file the app ships in its bundle's `configs/<config>/` directory
+    Bundled { name: String, content: Vec<u8> },
+}
+
+impl ConfigSpec {
+    /// Whether an appspec config lists the file `name`, by name or pattern,
+    /// among the files it needs.
+    pub fn lists(&self, name: &str) -> bool {
+        self.files.iter().any(|f| matches!(f, ConfigFileSpec::PathRef(entry) if config_entry_matches(config_entry_optional(entry).0, name)))
+    }
+
+    /// Whether the app ships a file for the appspec entry `entry`, a name or
+    /// pattern.
+    pub fn ships(&self, entry: &str) -> bool {
+        self.files.iter().any(|f| match f {
+            ConfigFileSpec::DirFile { name, .. } | ConfigFileSpec::Bundled { name, .. } => config_entry_matches(entry, name),
+            ConfigFileSpec::PathRef(_) | ConfigFileSpec::Inline { .. } => false,
+        })
+    }
+}
+
+/// A config file entry without its optional marker: a trailing `?` makes a
+/// path, pattern or required file name that matches nothing acceptable.
+pub fn config_entry_optional(entry: &str) -> (&str, bool) {
+    match entry.strip_suffix('?') {
+        Some(entry) => (entry, true),
+        None => (entry, false),
+    }
+}
+
+/// Whether a config file entry is a glob pattern rather than a plain name.
+pub fn is_glob_pattern(entry: &str) -> bool {
+    entry.contains(['*', '?', '['])
+}
+
+/// Whether a config file entry, a plain name or a glob pattern, names `name`.
+pub fn config_entry_matches(entry: &str, name: &str) -> bool {
+    if is_glob_pattern(entry) {
+        glob::Pattern::new(entry).is_ok_and(|p| p.matches(name))
+    } else {
+        entry == name
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -61,19 +157,51 @@ pub struct AppSecretOption {
     pub secret_name: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ServicePort {
-    pub external: u16,
+    // host port; unset for a port only exposed to other containers
+    pub external: Option<u16>,
     pub internal: u16,
+    pub protocol: PortProtocol,
 }
 
-#[derive(Debug, Clone)]
+impl ServicePort {
+    /// `external:internal` as docker `-p` and compose `ports` take it, or just
+    /// `internal` for an expose-only port, with a `/udp` suffix for UDP ports.
+    pub fn mapping(&self) -> String {
+        let ports = match self.external {
+            Some(external) => format!("{}:{}", external, self.internal),
+            None => self.internal.to_string(),
+        };
+        match self.protocol {
+            PortProtocol::Tcp => ports,
+            PortProtocol::Udp => format!("{}/udp", ports),
+        }
+    }
+
+    /// Port the service answers on for other services: the published port,
+    /// or the container port when it is not published.
+    pub fn service_port(&self) -> u16 {
+        self.external.unwrap_or(self.internal)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ServiceVolumeType {
     Named(String),
     Path(String),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ServiceVolume {
     pub name: ServiceVolumeType,
     pub mount_path: String,
@@ -84,7 +212,8 @@ pub struct ServiceSpec {
     pub name: String,
     pub service_type: ServiceType,
     pub is_app_service: bool,
-    pub image: ImageSpec,
+    // unset only for services that are built from source in local environments
+    pub image: Option<ImageSpec>,
     pub environment: Vec<ServiceEnvOption>,
     pub configs: Vec<ServiceConfigOption>,
     pub secrets: Vec<ServiceSecret>,
@@ -96,6 +225,49 @@ pub struct ServiceSpec {
     pub entrypoint: Option<ServiceCommand>,
     // Container health probe, same as docker-compose `healthcheck`.
     pub healthcheck: Option<Healthcheck>,
+    // Routing defaults from `export:`, used where the deployment does not set them.
+    pub export: Option<ServiceExport>,
+    // Working directory inside the container, same as docker-compose `working_dir`.
+    pub working_dir: Option<String>,
+    // User the container runs as, same as docker-compose `user`.
+    pub user: Option<String>,
+    // Compose profile the service belongs to; unset means always started.
+    pub profile: Option<String>,
+    // Env var that must have a value in the deployment for the service to run.
+    pub when: Option<String>,
+    // Local only: build the image from source, same as docker-compose `build`.
+    pub build: Option<ServiceBuild>,
+    // Local only: host directories bind-mounted for live reload.
+    pub dev_volumes: Vec<DevVolume>,
+    // baseline requests and limits, under the deployment's
+    pub resources: ResourceHints,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceExport {
+    // gateway host alias
+    pub host: Option<String>,
+    // stripped, like a deployment's `prefix`
+    pub prefix: Option<String>,
+}
+
+// Bind mount of a host path (relative to the appspec directory) used only by
+// local environments.
+#[derive(Debug, Clone, Serialize)]
+pub struct DevVolume {
+    pub host_path: String,
+    pub mount_path: String,
+}
+
+// Source of a locally built image. Mirrors docker-compose `build`; `context`
+// and `dockerfile` are relative to the appspec directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceBuild {
+    pub context: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dockerfile: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub args: BTreeMap<String, String>,
 }
 
 // Overrides the default command/entrypoint of a service's image. Mirrors
@@ -184,6 +356,26 @@ impl Healthcheck {
     }
 }
 
+/// Name of a variable the resolver generates for a service, e.g.
+/// `SERVICE_USER_API_URL` for service `user-api` and suffix `URL`: the service
+/// name uppercased, with every character other than a letter or digit
+/// replaced by `_`.
+pub fn service_env_var_name(service_name: &str, suffix: &str) -> String {
+    let name: String = service_name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("SERVICE_{}_{}", name, suffix)
+}
+
+/// Parse a `uid[:gid]` user spec into numeric ids. Names such as `nginx` or
+/// `app:staff` return `None`; Kubernetes only accepts numeric ids.
+pub fn parse_numeric_user(user: &str) -> Option<(u32, Option<u32>)> {
+    match user.split_once(':') {
+        Some((uid, gid)) => Some((uid.parse().ok()?, Some(gid.parse().ok()?))),
+        None => Some((user.parse().ok()?, None)),
+    }
+}
+
 /// Parse a compose duration (e.g. "30s", "1m30s", "1h") into whole seconds.
 /// Supports `h`, `m`, `s`, `ms` and `us`/`µs` segments; sub-second parts round up.
 pub fn parse_duration_secs(input: &str) -> Option<u64> {
@@ -236,7 +428,7 @@ pub enum ServiceEnvOption {
     WithValue(String, String),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ServiceConfigOption {
     pub config_name: String,
     pub mount_path: String,
@@ -247,6 +439,10 @@ pub struct ServiceConfigOption {
 pub struct ImageVariant {
     pub variant_name: String,
     pub image: String,
+    // applied after the service's own entries
+    pub environment: Vec<ServiceEnvOption>,
+    // replace the service's port on the same container port, or are added
+    pub ports: Vec<ServicePort>,
 }
 
 #[derive(Debug, Clone)]
@@ -255,42 +451,56 @@ pub enum ImageSpec {
     Variants(Vec<ImageVariant>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ServiceType {
     Public,
     Internal,
     Job,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct  ServiceSecret {
     pub name: String,
     pub mount: SecretMount,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SecretMount {
     FilePath(String),
     EnvVariable(String),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DeploymentEnvType {
     K8S,
     Docker(DockerSpecificSpec),
     Local,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum  DockerIngressType {
     Nginx,
     Traefik,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct DockerSpecificSpec {
     pub ingress_type: DockerIngressType,
     pub swarm_mode: bool,
+    pub script_format: ScriptFormat,
+}
+
+// Which shell the generated deploy/undeploy scripts are written for
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptFormat {
+    Bash,
+    PowerShell,
+    Both,
 }
 
 // DeploymentEnvironmentSpec definitions
@@ -298,8 +508,58 @@ pub struct DockerSpecificSpec {
 pub struct DeploymentEnvironmentSpec {
     pub env_type: DeploymentEnvType,
     pub ingress: IngressSpec,
-    pub registry: HashMap<String, String>,
+    pub registry: BTreeMap<String, String>,
+    // extra service images go through `registry` like app images
+    pub mirror_extra_services: bool,
+    pub bundle_repo: Option<BundleRepoSpec>,
+    // Config files above this size are rejected; they belong in a volume.
+    // Kubernetes has a default, from the ConfigMap size limit; elsewhere only
+    // a limit the env spec sets applies.
+    pub max_config_file_size: Option<Memory>,
     pub deployments: Vec<DeploymentSpec>,
+    // Directory of the env spec. Relative paths in it (configs, env and secret
+    // files, extra specs) and the files simpled keeps next to it (secret
+    // store, bundle cache) resolve against this.
+    pub root: PathBuf,
+}
+
+impl DeploymentEnvironmentSpec {
+    /// Registry host an image is pulled from: the entry for its namespace (the
+    /// part before the first `/`), else the `*` entry. An image without a
+    /// namespace only goes through `*`.
+    pub fn registry_host(&self, image: &str) -> Option<&str> {
+        image.split_once('/')
+            .and_then(|(namespace, _)| self.registry.get(namespace))
+            .or_else(|| self.registry.get("*"))
+            .map(|host| host.strip_suffix('/').unwrap_or(host))
+    }
+
+    /// Whether the image of a service is pulled through `registry`.
+    pub fn mirrors(&self, service: &ServiceSpec) -> bool {
+        service.is_app_service || self.mirror_extra_services
+    }
+}
+
+// Release repository app bundles are downloaded from by version
+#[derive(Debug, Clone)]
+pub struct BundleRepoSpec {
+    pub provider: BundleRepoProvider,
+    // GitHub owner/repo, or OCI repository like registry.example.com/org/app
+    pub repo: String,
+    pub tag_prefix: Option<String>,
+    // env variable the API token is read from
+    pub token_env: String,
+    // ed25519 key bundles must be signed with, when set
+    pub public_key: Option<Vec<u8>>,
+    // limit on each request, and how often a failed one is tried again
+    pub timeout: std::time::Duration,
+    pub retries: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BundleRepoProvider {
+    Github,
+    Oci,
 }
 
 #[derive(Debug, Clone)]
@@ -307,43 +567,222 @@ pub struct IngressSpec {
     pub name: String,
     pub hosts: Vec<HostSpec>,
     pub tls: Option<IngressTlsSpec>,
+    pub allow_nested_prefixes: bool,
+    pub redirects: Vec<RedirectSpec>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RedirectSpec {
+    // domain the gateway answers with a permanent redirect
+    pub from: String,
+    pub to: String,
+    pub preserve_path: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct IngressTlsSpec {
     pub secret: Option<String>,
     pub letsencrypt: Option<LetsEncryptSpec>,
+    pub self_signed: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct LetsEncryptSpec {
     pub server: Option<String>,
     pub email: String,
+    pub dns01: Option<Dns01Spec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Dns01Spec {
+    pub provider: Dns01Provider,
+    // k8s: name of the Secret with the API token; docker: env file for Traefik
+    pub credentials: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dns01Provider {
+    Cloudflare,
+    DigitalOcean,
+}
+
+impl Dns01Provider {
+    /// Provider name in Traefik's `dnsChallenge`, and the env variable with its token.
+    pub fn traefik(self) -> (&'static str, &'static str) {
+        match self {
+            Dns01Provider::Cloudflare => ("cloudflare", "CF_DNS_API_TOKEN"),
+            Dns01Provider::DigitalOcean => ("digitalocean", "DO_AUTH_TOKEN"),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct HostSpec {
     pub name: String,
     pub domain_names: Vec<String>,
+    // false when the host opted out of the gateway's TLS
+    pub tls: bool,
+    pub default_backend: Option<DefaultBackendSpec>,
+}
+
+#[derive(Debug, Clone)]
+pub enum DefaultBackendSpec {
+    Service(String),
+    // inline HTML
+    Page(String),
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
 pub struct EnvVariable {
     pub name: String,
     pub value: String,
+    // the value embeds a secret (`${secret:NAME}`); generators keep it out of
+    // plain env files and manifests
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub sensitive: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct DeploymentSpec {
     pub name: String,
+    // the deployment this one extends, whose settings are merged in already
+    pub extends: Option<String>,
     pub primary_host: String,
+    // one of the primary host's domains; unset means its first one
+    pub public_domain: Option<String>,
     pub application: DeploymentAppSpec,
     pub environment: Vec<EnvVariable>,
     pub undockerized_environment: Vec<EnvVariable>,
     pub configs: Vec<ConfigSpec>,
     pub secrets: Vec<DeploymentSecretSpec>,
     pub defaults: ResourcesSpec,
-    pub services: Option<HashMap<String, DeploymentServiceSpec>>,
+    // what `defaults.resources` sets, over the appspec's resource hints
+    pub explicit_defaults: ResourceHints,
+    pub services: Option<BTreeMap<String, DeploymentServiceSpec>>,
+    // the gateway serves the maintenance page on the deployment's domains
+    pub maintenance: bool,
+}
+
+impl DeploymentSpec {
+    /// Gateway host alias and prefixes of an app service. The deployment's
+    /// `services:` entry wins; the appspec's `export:` fills in what it leaves
+    /// unset, and the host defaults to the primary host.
+    pub fn service_route(&self, service: &ServiceSpec) -> (String, Vec<Prefix>) {
+        let deployment_service = self.services.as_ref().and_then(|s| s.get(&service.name));
+        let export = service.export.as_ref();
+        let host = deployment_service.and_then(|ds| ds.host.clone())
+            .or_else(|| export.and_then(|e| e.host.clone()))
+            .unwrap_or_else(|| self.primary_host.clone());
+        let prefixes = match deployment_service {
+            Some(ds) if !ds.prefixes.is_empty() => ds.prefixes.clone(),
+            _ => export.and_then(|e| e.prefix.clone())
+                .map(|prefix| vec![Prefix { prefix, strip: true, cors: deployment_service.and_then(|ds| ds.cors.clone()) }])
+                .unwrap_or_default(),
+        };
+        (host, prefixes)
+    }
+
+    /// Whether the deployment runs the service; see [`Self::service_skip_reason`].
+    pub fn service_enabled(&self, service: &ServiceSpec) -> bool {
+        self.service_skip_reason(service).is_none()
+    }
+
+    /// Why the deployment leaves the service out entirely: `enabled: false`
+    /// under its `services:` entry, or a `when:` variable with no value in the
+    /// deployment's environment.
+    pub fn service_skip_reason(&self, service: &ServiceSpec) -> Option<String> {
+        if self.services.as_ref().and_then(|s| s.get(&service.name)).is_some_and(|ds| !ds.enabled) {
+            return Some("enabled: false in the deployment".to_string());
+        }
+        let var = service.when.as_ref()?;
+        let has_value = self.environment.iter()
+            .chain(self.service_environment(&service.name))
+            .any(|v| &v.name == var && !v.value.is_empty());
+        (!has_value).then(|| format!("when: {} has no value in the deployment", var))
+    }
+
+    /// Env values the deployment sets for one service only.
+    pub fn service_environment(&self, service_name: &str) -> &[EnvVariable] {
+        self.services.as_ref().and_then(|s| s.get(service_name)).map_or(&[], |ds| &ds.environment)
+    }
+
+    /// The image variant the deployment selects for a service with variants,
+    /// `default` unless its `services:` entry names one.
+    pub fn service_variant<'a>(&self, service: &'a ServiceSpec) -> Option<&'a ImageVariant> {
+        let Some(ImageSpec::Variants(variants)) = &service.image else { return None };
+        let selected = self.services.as_ref().and_then(|s| s.get(&service.name))
+            .and_then(|ds| ds.variant.as_deref())
+            .unwrap_or("default");
+        variants.iter().find(|v| v.variant_name == selected)
+    }
+
+    /// Environment entries of a service: the appspec's, then those of the
+    /// selected variant.
+    pub fn service_env_options(&self, service: &ServiceSpec) -> Vec<ServiceEnvOption> {
+        let variant = self.service_variant(service).map_or(&[][..], |v| &v.environment);
+        service.environment.iter().chain(variant).cloned().collect()
+    }
+
+    /// Ports of an app service: the deployment's override, or the appspec's,
+    /// with the selected variant's ports over them.
+    pub fn service_ports(&self, service: &ServiceSpec) -> Vec<ServicePort> {
+        let mut ports = match self.services.as_ref().and_then(|s| s.get(&service.name)) {
+            Some(ds) => ds.ports.clone(),
+            None => service.ports.clone(),
+        };
+        for port in self.service_variant(service).map_or(&[][..], |v| &v.ports) {
+            match ports.iter_mut().find(|p| p.internal == port.internal && p.protocol == port.protocol) {
+                Some(existing) => *existing = port.clone(),
+                None => ports.push(port.clone()),
+            }
+        }
+        ports
+    }
+
+    /// Resources of a service, field by field: the deployment's service entry,
+    /// its `defaults`, the appspec's hints, then the built-in defaults. A limit
+    /// nobody sets is raised to the request, so an app's request alone doesn't
+    /// end up above the built-in limit.
+    pub fn service_resources(&self, service: &ServiceSpec) -> ResourcesSpec {
+        let ds = self.services.as_ref().and_then(|s| s.get(&service.name));
+        let layers = self.resource_layers(service);
+        let memory = |pick: fn(&ResourceHints) -> Option<Memory>| layers.iter().find_map(|l| pick(l));
+        let cpu = |pick: fn(&ResourceHints) -> Option<Cpu>| layers.iter().find_map(|l| pick(l));
+        let requests = ResourceLimits {
+            memory: memory(|l| l.requests.memory).unwrap_or(self.defaults.requests.memory),
+            cpu: cpu(|l| l.requests.cpu).unwrap_or(self.defaults.requests.cpu),
+        };
+        let limits = ResourceLimits {
+            memory: memory(|l| l.limits.memory)
+                .unwrap_or_else(|| Memory::from_bytes(self.defaults.limits.memory.bytes().max(requests.memory.bytes()))),
+            cpu: cpu(|l| l.limits.cpu)
+                .unwrap_or_else(|| Cpu::from_millicores(self.defaults.limits.cpu.millicores().max(requests.cpu.millicores()))),
+        };
+        ResourcesSpec {
+            replicas: ds.map_or(self.defaults.replicas, |ds| ds.resources.replicas),
+            requests,
+            limits,
+        }
+    }
+
+    /// Limits of a service that the deployment or the appspec set, without
+    /// the built-in defaults.
+    pub fn service_explicit_limits(&self, service: &ServiceSpec) -> LimitHints {
+        let layers = self.resource_layers(service);
+        LimitHints {
+            memory: layers.iter().find_map(|l| l.limits.memory),
+            cpu: layers.iter().find_map(|l| l.limits.cpu),
+        }
+    }
+
+    // Resource hints of a service, the one that wins first
+    fn resource_layers<'a>(&'a self, service: &'a ServiceSpec) -> Vec<&'a ResourceHints> {
+        let ds = self.services.as_ref().and_then(|s| s.get(&service.name));
+        ds.map(|ds| &ds.explicit_resources).into_iter()
+            .chain([&self.explicit_defaults, &service.resources])
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -357,8 +796,33 @@ pub enum DeploymentSecretSource {
     EnvVariable(String),
     FilePath(String),
     Embedded(String),
+    // an `env:` secret whose value was saved by `simpled secrets set`; the
+    // environment variable still wins when it is set
+    Store { env: String, path: PathBuf },
+    // an `env:` secret of a Kubernetes deployment; when the environment
+    // variable is not set, the value `simpled secrets set` applied to the
+    // cluster Secret `name` is read back
+    Cluster { env: String, name: String },
+    // a random value generated on first use and kept, per deployment, in the
+    // JSON state file `state`
+    Generated { deployment: String, length: usize, format: GeneratedSecretFormat, state: PathBuf },
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeneratedSecretFormat {
+    Hex,
+    Base64,
+    Alnum,
+}
+
+// Directory next to the env spec holding the state files of generated
+// secrets, `secrets.<deployment>.json`, unless `--secrets-state` is set
+pub const GENERATED_SECRETS_DIR: &str = ".simpled";
+
+// Directory next to the env spec where `simpled secrets set` keeps secret
+// values, one file per secret in a folder per deployment
+pub const SECRET_STORE_DIR: &str = "secrets";
+
 #[derive(Debug, Clone)]
 pub struct DeploymentAppSpec {
     pub name: String,
@@ -366,26 +830,49 @@ pub struct DeploymentAppSpec {
     pub extra: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct ResourcesSpec {
     pub replicas: u32,
     pub requests: ResourceLimits,
     pub limits: ResourceLimits,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct ResourceLimits {
-    pub memory: String,
-    pub cpu: String,
+    pub memory: Memory,
+    pub cpu: Cpu,
+}
+
+// Resources a spec sets itself; unset ones come from the next layer down
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResourceHints {
+    pub requests: LimitHints,
+    pub limits: LimitHints,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LimitHints {
+    pub memory: Option<Memory>,
+    pub cpu: Option<Cpu>,
 }
 
 #[derive(Debug, Clone)]
 pub struct DeploymentServiceSpec {
+    // false leaves the service out of the deployment
+    pub enabled: bool,
     pub variant: Option<String>,
+    // image used instead of the appspec's
+    pub image: Option<String>,
     pub host: Option<String>,
     pub prefixes: Vec<Prefix>,
+    // CORS of the prefixes the appspec's `export` gives the service
+    pub cors: Option<CorsSpec>,
     pub resources: ResourcesSpec,
+    // what the service's `resources` sets, over `defaults` and the appspec
+    pub explicit_resources: ResourceHints,
     pub ports: Vec<ServicePort>,
+    // values for this service only, over the deployment's environment
+    pub environment: Vec<EnvVariable>,
     // local-only: working directory of a host-run (non-dockerized) service.
     pub working_dir: Option<String>,
 }
@@ -394,12 +881,37 @@ pub struct DeploymentServiceSpec {
 pub struct Prefix {
     pub prefix: String,
     pub strip: bool,
+    pub cors: Option<CorsSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CorsSpec {
+    // allowed origins, or `*` for any
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+    pub credentials: bool,
+    pub max_age: u32,
+}
+
+impl CorsSpec {
+    pub fn any_origin(&self) -> bool {
+        self.origins.iter().any(|o| o == "*")
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn parses_numeric_users() {
+        assert_eq!(parse_numeric_user("1000"), Some((1000, None)));
+        assert_eq!(parse_numeric_user("1000:1000"), Some((1000, Some(1000))));
+        assert_eq!(parse_numeric_user("nginx"), None);
+        assert_eq!(parse_numeric_user("1000:staff"), None);
+    }
+
     #[test]
     fn parses_simple_durations() {
         assert_eq!(parse_duration_secs("30s"), Some(30));
@@ -444,6 +956,82 @@ mod tests {
         assert_eq!(cmd_shell.probe_argv(), Some(vec!["/bin/sh".into(), "-c".into(), "curl -f localhost".into()]));
     }
 
+    fn service(name: &str, profile: Option<&str>, environment: Vec<ServiceEnvOption>) -> ServiceSpec {
+        ServiceSpec {
+            name: name.into(),
+            service_type: ServiceType::Internal,
+            is_app_service: false,
+            image: Some(ImageSpec::Exact("img".into())),
+            environment,
+            configs: vec![],
+            secrets: vec![],
+            ports: vec![],
+            volumes: vec![],
+            command: None,
+            entrypoint: None,
+            healthcheck: None,
+            export: None,
+            working_dir: None,
+            user: None,
+            profile: profile.map(str::to_string),
+            when: None,
+            build: None,
+            dev_volumes: vec![],
+            resources: ResourceHints::default(),
+        }
+    }
+
+    fn app(external: &[&str], extra_services: Vec<ServiceSpec>) -> AppSpec {
+        AppSpec {
+            name: "app".into(),
+            version: semver::Version::new(1, 0, 0),
+            environment: AppEnvironment {
+                external: external.iter().map(|n| ExternalEnvVariable { name: n.to_string(), default: None }).collect(),
+                optional: vec![],
+                relative: vec![],
+                internal: vec![InternalEnvVariable { name: "DSN".into(), value: "${DB_HOST}/db".into() }],
+            },
+            app_services: vec![],
+            extra_services,
+            configs: vec![],
+            secrets: vec![],
+            volumes: vec![],
+        }
+    }
+
+    #[test]
+    fn profile_only_env_vars_exempts_vars_used_only_by_profiled_services() {
+        let spec = app(
+            &["MAIL_KEY", "SHARED", "DB_HOST"],
+            vec![
+                service("mailhog", Some("debug"), vec![
+                    ServiceEnvOption::Simple("MAIL_KEY".into()),
+                    ServiceEnvOption::Simple("SHARED".into()),
+                    ServiceEnvOption::Simple("DB_HOST".into()),
+                ]),
+                service("api", None, vec![ServiceEnvOption::WithValue("S".into(), "${SHARED}".into())]),
+            ],
+        );
+        let exempt = spec.env_vars_used_only_by(|s| s.profile.is_some());
+        assert!(exempt.contains("MAIL_KEY"));
+        // used by an unprofiled service
+        assert!(!exempt.contains("SHARED"));
+        // used by an internal variable, which every service resolves
+        assert!(!exempt.contains("DB_HOST"));
+    }
+
+    #[test]
+    fn profile_only_env_vars_respects_all_in_unprofiled_services() {
+        let spec = app(
+            &["MAIL_KEY"],
+            vec![
+                service("mailhog", Some("debug"), vec![ServiceEnvOption::Simple("MAIL_KEY".into())]),
+                service("api", None, vec![ServiceEnvOption::All]),
+            ],
+        );
+        assert!(spec.env_vars_used_only_by(|s| s.profile.is_some()).is_empty());
+    }
+
     #[test]
     fn disabled_healthcheck_has_no_probe() {
         let by_flag = Healthcheck {
@@ -1,65 +1,398 @@
-use anyhow::{Context, Result, bail};
-use std::fs::File;
-use std::path::Path;
+use anyhow::{Context, Result, anyhow, bail};
+use std::fs::{self, File};
+use std::io::Read;
+use serde_path_to_error::Segment;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 use flate2::read::GzDecoder;
 use tar::Archive;
 
+use crate::diagnostics;
+use crate::error;
 use crate::spec;
 use crate::spec_yaml;
 use crate::transform;
 
+// `--appspec` / `--envspec`, used instead of looking for the spec files
+static APP_SPEC_PATH: OnceLock<PathBuf> = OnceLock::new();
+static ENV_SPEC_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// Directory next to the appspec, and in a bundle, with the files an app ships
+// for its configs, `configs/<config>/<file>`
+pub const SHIPPED_CONFIGS_DIR: &str = "configs";
+
+// `--lenient`: unknown fields and duplicate keys in spec files are warnings
+static LENIENT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_lenient(lenient: bool) {
+    LENIENT.store(lenient, Ordering::Relaxed);
+}
+
+/// Makes every command use these spec files instead of looking for
+/// appspec.yaml in the working directory and envspec.yaml in its root.
+/// An env spec path may also name the directory containing it.
+pub fn set_spec_paths(app_spec: Option<&Path>, env_spec: Option<&Path>) {
+    if let Some(path) = app_spec {
+        let _ = APP_SPEC_PATH.set(path.to_path_buf());
+    }
+    if let Some(path) = env_spec {
+        let _ = ENV_SPEC_PATH.set(path.to_path_buf());
+    }
+}
+
+/// The application's appspec: the `--appspec` file, or appspec.yaml /
+/// appspec.yml in the working directory.
+pub fn project_app_spec_path() -> Result<PathBuf> {
+    match APP_SPEC_PATH.get() {
+        Some(path) => Ok(path.clone()),
+        None => app_spec_path(Path::new(".")),
+    }
+}
+
+pub fn load_project_app_spec(env_spec: Option<&spec::DeploymentEnvironmentSpec>) -> Result<spec::AppSpec> {
+    project_app_spec_path()
+        .and_then(|path| load_app_spec_from_file(&path, env_spec))
+        .map_err(error::loading)
+}
+
+// Directory of the env spec: the one containing `--envspec`, or `root`
+fn env_spec_dir(root: &Path) -> PathBuf {
+    match ENV_SPEC_PATH.get() {
+        Some(path) if path.is_file() => path.parent().map_or_else(|| PathBuf::from("."), Path::to_path_buf),
+        Some(dir) => dir.clone(),
+        None => root.to_path_buf(),
+    }
+}
+
 pub fn load_app_spec(app_bundle_path: &Path, env_spec: Option<&spec::DeploymentEnvironmentSpec>) -> Result<spec::AppSpec> {
+    read_app_spec(app_bundle_path, env_spec).map_err(error::loading)
+}
+
+fn read_app_spec(app_bundle_path: &Path, env_spec: Option<&spec::DeploymentEnvironmentSpec>) -> Result<spec::AppSpec> {
     if app_bundle_path.is_dir() {
-        return load_app_spec_from_dir(app_bundle_path, env_spec);
+        return load_app_spec_from_file(&app_spec_path(app_bundle_path)?, env_spec);
     } else if let Some(ext) = app_bundle_path.extension() {
         if ext == "gz" {
             return load_app_spec_from_tar_gz(app_bundle_path, env_spec);
         }
+        if ext == "yaml" || ext == "yml" {
+            return load_app_spec_from_file(app_bundle_path, env_spec);
+        }
     }
 
-    bail!("Invalid app bundle can be either a directory or a tar.gz file");
+    bail!("Invalid app bundle can be either a directory, a tar.gz file or an appspec file");
 }
 
-pub fn load_app_spec_from_dir(dir: &Path, env_spec: Option<&spec::DeploymentEnvironmentSpec>) -> Result<spec::AppSpec> {
+/// Path of the appspec file in `dir`, preferring appspec.yaml over appspec.yml.
+pub fn app_spec_path(dir: &Path) -> Result<PathBuf> {
     let path_yaml = dir.join("appspec.yaml");
     let path_yml = dir.join("appspec.yml");
 
-    let path = if path_yaml.exists() {
-        path_yaml
+    if path_yaml.exists() {
+        Ok(path_yaml)
     } else if path_yml.exists() {
-        path_yml
+        Ok(path_yml)
     } else {
         bail!("Could not find appspec.yaml or appspec.yml in {:?}", dir);
+    }
+}
+
+fn load_app_spec_from_file(path: &Path, env_spec: Option<&spec::DeploymentEnvironmentSpec>) -> Result<spec::AppSpec> {
+    let content = fs::read_to_string(path).context(format!("Failed to open {:?}", path))?;
+    let yaml: spec_yaml::AppSpecYaml = parse_yaml(&content, path)?;
+    let mut app_spec = transform::convert_app_spec(yaml, env_spec).context(format!("Failed to process app spec {}", path.display()))?;
+    add_shipped_configs(&mut app_spec, path.parent().unwrap_or(Path::new(".")))?;
+    Ok(app_spec)
+}
+
+// The files in `configs/<config>/` next to the appspec that the config lists
+// by name or pattern are shipped with the app
+fn add_shipped_configs(app_spec: &mut spec::AppSpec, dir: &Path) -> Result<()> {
+    for config in &mut app_spec.configs {
+        let config_dir = dir.join(SHIPPED_CONFIGS_DIR).join(&config.name);
+        if !config_dir.is_dir() {
+            continue;
+        }
+        let shipped: Vec<spec::ConfigFileSpec> = config_dir_files(&config_dir)?.into_iter()
+            .filter(|(name, _)| config.lists(name))
+            .map(|(name, path)| spec::ConfigFileSpec::DirFile { name, path: path.to_string_lossy().to_string() })
+            .collect();
+        config.files.extend(shipped);
+    }
+    Ok(())
+}
+
+/// Parses a spec file. Invalid YAML is reported with the file and, where
+/// serde_yaml knows it, the line and column, e.g. `envspec.yaml:12:5`.
+/// Unknown fields and duplicate mapping keys are errors unless `--lenient`
+/// is set, which warns and ignores them.
+pub fn parse_yaml<T: serde::de::DeserializeOwned>(content: &str, path: &Path) -> Result<T> {
+    parse_yaml_with(content, path, LENIENT.load(Ordering::Relaxed))
+}
+
+fn parse_yaml_with<T: serde::de::DeserializeOwned>(content: &str, path: &Path, lenient: bool) -> Result<T> {
+    let failed = |e: serde_yaml::Error| {
+        let location = e.location().map(|l| format!(":{}:{}", l.line(), l.column())).unwrap_or_default();
+        anyhow::Error::new(e).context(format!("Failed to parse {}{}", path.display(), location))
     };
 
-    load_app_spec_from_file(&path, env_spec)
+    // Structs reject duplicate fields themselves, but maps keep the last of
+    // two equal keys; a generic `Value` rejects them
+    if let Err(e) = serde_yaml::from_str::<serde_yaml::Value>(content) {
+        if !lenient || !e.to_string().contains("duplicate entry") {
+            return Err(failed(e));
+        }
+        diagnostics::warn("duplicate_key", format!("{}: {}, the last one is used", path.display(), e));
+    }
+
+    match serde_yaml::from_str(content) {
+        Ok(parsed) => Ok(parsed),
+        Err(e) if lenient && (e.to_string().contains("unknown field `") || e.to_string().contains(UNTAGGED_MISMATCH)) => {
+            parse_ignoring_unknown_fields(content, path)
+        }
+        Err(e) if e.to_string().contains(UNTAGGED_MISMATCH) => {
+            let location = e.location().map(|l| format!(":{}:{}", l.line(), l.column())).unwrap_or_default();
+            let explained = serde_yaml::from_str(content).ok().and_then(|value| deserialize_error::<T>(&value));
+            match explained {
+                Some((at, message)) => Err(anyhow!("{}: {}", format_path(&at), message).context(format!("Failed to parse {}{}", path.display(), location))),
+                None => Err(failed(e)),
+            }
+        }
+        Err(e) => Err(failed(e)),
+    }
 }
 
-fn load_app_spec_from_file(path: &Path, env_spec: Option<&spec::DeploymentEnvironmentSpec>) -> Result<spec::AppSpec> {
-    let file = File::open(path).context(format!("Failed to open {:?}", path))?;
-    let yaml: spec_yaml::AppSpecYaml = serde_yaml::from_reader(file).context(format!("Failed to parse {:?}", path))?;
-    transform::convert_app_spec(yaml, env_spec).context("Failed to process app spec")
+// Serde's error for an untagged enum none of whose variants match, which
+// does not say why
+const UNTAGGED_MISMATCH: &str = "did not match any variant of untagged enum ";
+
+// `--lenient`: removes unknown fields one by one, warning about each
+fn parse_ignoring_unknown_fields<T: serde::de::DeserializeOwned>(content: &str, path: &Path) -> Result<T> {
+    let mut value: serde_yaml::Value = serde_yaml::from_str(content).with_context(|| format!("Failed to parse {}", path.display()))?;
+    loop {
+        let (at, message) = match deserialize_error::<T>(&value) {
+            None => return serde_yaml::from_value(value).with_context(|| format!("Failed to parse {}", path.display())),
+            Some(error) => error,
+        };
+        // the path ends with the unknown field, a key of the mapping before it
+        let removed = at.split_last().and_then(|(field, parents)| {
+            let parent = parents.iter().try_fold(&mut value, |node, segment| match segment {
+                Segment::Map { key } => node.get_mut(key.as_str()),
+                Segment::Seq { index } => node.get_mut(*index),
+                _ => Some(node),
+            })?;
+            match field {
+                Segment::Map { key } if message.contains("unknown field `") => parent.as_mapping_mut()?.remove(key.as_str()),
+                _ => None,
+            }
+        });
+        if removed.is_none() {
+            return Err(anyhow!("{}: {}", format_path(&at), message).context(format!("Failed to parse {}", path.display())));
+        }
+        diagnostics::warn("unknown_field", format!("{}: ignoring unknown field {}", path.display(), format_path(&at)));
+    }
+}
+
+// Where and why `value` is not a `T`, None when it is. When no variant of an
+// untagged enum matches, its struct variant is parsed again so the error
+// names the field at fault, e.g. a typo under a detailed host.
+fn deserialize_error<T: serde::de::DeserializeOwned>(value: &serde_yaml::Value) -> Option<(Vec<Segment>, String)> {
+    let e = serde_path_to_error::deserialize::<_, T>(value.clone()).err()?;
+    let mut at: Vec<Segment> = e.path().iter().cloned().collect();
+    let mut message = e.into_inner().to_string();
+    let enum_name = message.split_once(UNTAGGED_MISMATCH).and_then(|(_, rest)| rest.split_whitespace().next()).map(str::to_string);
+    let node = at.iter().try_fold(value, |node, segment| match segment {
+        Segment::Map { key } => node.get(key.as_str()),
+        Segment::Seq { index } => node.get(*index),
+        _ => Some(node),
+    });
+    if let (Some(enum_name), Some(node)) = (enum_name, node) {
+        if let Some((inner, inner_message)) = struct_variant_error(&enum_name, node) {
+            at.extend(inner);
+            message = inner_message;
+        }
+    }
+    Some((at, message))
+}
+
+// The error of parsing `node` as the struct variant of the untagged enum
+// `enum_name`, the one a mapping is meant to be
+fn struct_variant_error(enum_name: &str, node: &serde_yaml::Value) -> Option<(Vec<Segment>, String)> {
+    use spec_yaml::*;
+    use std::collections::BTreeMap;
+    if node.is_sequence() {
+        return match enum_name {
+            "PrefixesYaml" => deserialize_error::<Vec<PrefixEntryYaml>>(node),
+            _ => None,
+        };
+    }
+    if !node.is_mapping() {
+        return None;
+    }
+    match enum_name {
+        "HostSpecYaml" => deserialize_error::<HostDetailsYaml>(node),
+        "RedirectSpecYaml" => deserialize_error::<RedirectDetailsYaml>(node),
+        "ConfigPathsYaml" => deserialize_error::<DeploymentConfigYaml>(node),
+        "AppConfigYaml" => deserialize_error::<AppConfigDetailsYaml>(node),
+        "ServiceSecretYaml" => deserialize_error::<BTreeMap<String, Option<SecretConfigYaml>>>(node),
+        "DeploymentSecretSpecExYaml" => deserialize_error::<DeploymentSecretSpecYaml>(node),
+        "PrefixesYaml" => deserialize_error::<BTreeMap<String, PrefixOptionsYaml>>(node),
+        _ => None,
+    }
+}
+
+// `gateway.hosts.web.tls`, as serde_path_to_error shows paths
+fn format_path(segments: &[Segment]) -> String {
+    let mut path = String::new();
+    for segment in segments {
+        if !path.is_empty() && !matches!(segment, Segment::Seq { .. }) {
+            path.push('.');
+        }
+        path.push_str(&segment.to_string());
+    }
+    if path.is_empty() { ".".to_string() } else { path }
 }
 
 fn load_app_spec_from_tar_gz(path: &Path, env_spec: Option<&spec::DeploymentEnvironmentSpec>) -> Result<spec::AppSpec> {
-    let file = File::open(path).context(format!("Failed to open {:?}", path))?;
-    let tar = GzDecoder::new(file);
-    let mut archive = Archive::new(tar);
+    let archive = read_archive(path)?;
+    let mut app_spec = transform::convert_app_spec(archive.app_spec, env_spec).context("Failed to process app spec")?;
+    for config in &mut app_spec.configs {
+        let prefix = format!("{}{}/{}/", archive.app_spec_dir, SHIPPED_CONFIGS_DIR, config.name);
+        let bundled: Vec<spec::ConfigFileSpec> = archive.config_files.iter()
+            .filter_map(|(entry_path, content)| entry_path.strip_prefix(&prefix).map(|name| (name, content)))
+            .filter(|(name, _)| config.lists(name))
+            .map(|(name, content)| spec::ConfigFileSpec::Bundled { name: name.to_string(), content: content.clone() })
+            .collect();
+        config.files.extend(bundled);
+    }
+    Ok(app_spec)
+}
+
+/// Reads a tar.gz app bundle without unpacking it: its appspec and the paths
+/// of the other files it contains.
+pub fn read_bundle(path: &Path) -> Result<(spec::AppSpec, Vec<String>)> {
+    let archive = read_archive(path).map_err(error::loading)?;
+    let app_spec = transform::convert_app_spec(archive.app_spec, None)
+        .context(format!("Invalid appspec in bundle {:?}", path))
+        .map_err(error::loading)?;
+    Ok((app_spec, archive.files))
+}
+
+// What a bundle holds: its appspec, the directory of the appspec in the
+// archive (`""` or ending with `/`), the paths of the other files and the
+// content of those below a `configs` directory
+struct BundleArchive {
+    app_spec: spec_yaml::AppSpecYaml,
+    app_spec_dir: String,
+    files: Vec<String>,
+    config_files: Vec<(String, Vec<u8>)>,
+}
 
-    for entry in archive.entries()? {
-        let entry = entry?;
-        let path = entry.path()?;
-        if let Some(name) = path.file_name() {
-             if name == "appspec.yaml" || name == "appspec.yml" {
-                  let yaml: spec_yaml::AppSpecYaml = serde_yaml::from_reader(entry).context("Failed to parse appspec from tar.gz")?;
-                  return transform::convert_app_spec(yaml, env_spec).context("Failed to process app spec");
-             }
+fn read_archive(path: &Path) -> Result<BundleArchive> {
+    let file = File::open(path).context(format!("Failed to open bundle {:?}", path))?;
+    let mut archive = Archive::new(GzDecoder::new(file));
+    let corrupted = || format!("Bundle {:?} is not a valid tar.gz archive", path);
+
+    let mut app_spec = None;
+    let mut app_spec_dir = String::new();
+    let mut files = Vec::new();
+    let mut config_files = Vec::new();
+    for entry in archive.entries().with_context(corrupted)? {
+        let mut entry = entry.with_context(corrupted)?;
+        let raw_path = entry.path().with_context(corrupted)?.to_path_buf();
+        // Nothing in a bundle may point outside of it
+        let mut components = Vec::new();
+        for component in raw_path.components() {
+            match component {
+                Component::Normal(part) => components.push(part.to_string_lossy().to_string()),
+                Component::CurDir => {}
+                _ => bail!("Bundle {:?} has an entry outside the bundle: {}", path, raw_path.display()),
+            }
+        }
+        let entry_path = components.join("/");
+        let name = components.last().cloned();
+        let is_app_spec = matches!(name.as_deref(), Some("appspec.yaml" | "appspec.yml"));
+        if is_app_spec && app_spec.is_none() {
+            let mut content = String::new();
+            entry.read_to_string(&mut content).with_context(corrupted)?;
+            let yaml: spec_yaml::AppSpecYaml = parse_yaml(&content, &path.join(&entry_path))?;
+            app_spec = Some(yaml);
+            app_spec_dir = components[..components.len() - 1].iter().map(|c| format!("{}/", c)).collect();
+        } else if entry.header().entry_type().is_file() {
+            if components.iter().any(|c| c == SHIPPED_CONFIGS_DIR) {
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).with_context(corrupted)?;
+                config_files.push((entry_path.clone(), content));
+            }
+            files.push(entry_path);
+        }
+    }
+    let app_spec = app_spec.with_context(|| format!("appspec.yaml not found in bundle {:?}", path))?;
+    files.sort();
+    Ok(BundleArchive { app_spec, app_spec_dir, files, config_files })
+}
+
+/// Files below a config directory, named by their `/`-separated path relative
+/// to it and sorted by name. Symlinks are followed; one leading back to a
+/// directory already being walked is skipped with a warning.
+pub fn config_dir_files(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    fn walk(dir: &Path, prefix: &str, ancestors: &mut Vec<PathBuf>, files: &mut Vec<(String, PathBuf)>) -> Result<()> {
+        let canonical = fs::canonicalize(dir).with_context(|| format!("Failed to read config directory {:?}", dir))?;
+        if ancestors.contains(&canonical) {
+            diagnostics::warn("config_symlink_cycle", format!("Config directory {:?} links back to {:?}; skipping it", dir, canonical));
+            return Ok(());
+        }
+        ancestors.push(canonical);
+        for entry in fs::read_dir(dir).with_context(|| format!("Failed to read config directory {:?}", dir))? {
+            let path = entry?.path();
+            let name = format!("{}{}", prefix, path.file_name().unwrap_or_default().to_string_lossy());
+            if path.is_dir() {
+                walk(&path, &format!("{}/", name), ancestors, files)?;
+            } else if path.is_file() {
+                files.push((name, path));
+            }
         }
+        ancestors.pop();
+        Ok(())
     }
-    bail!("appspec.yaml not found in archive {:?}", path);
+    let mut files = Vec::new();
+    walk(dir, "", &mut Vec::new(), &mut files)?;
+    files.sort();
+    Ok(files)
 }
 
-pub fn load_env_spec(root: &Path, selected_deployment: Option<&str>) -> Result<spec::DeploymentEnvironmentSpec> {
+pub fn load_env_spec(root: &Path) -> Result<spec::DeploymentEnvironmentSpec> {
+    read_env_spec(root).map_err(error::loading)
+}
+
+fn read_env_spec(root: &Path) -> Result<spec::DeploymentEnvironmentSpec> {
+    let root = &env_spec_dir(root);
+    let (path, is_local_env) = match ENV_SPEC_PATH.get() {
+        Some(path) if path.is_file() => {
+            let is_local_env = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("localenv."));
+            (path.clone(), is_local_env)
+        }
+        Some(path) if !path.is_dir() => bail!("Env spec {:?} does not exist", path),
+        _ => find_env_spec(root)?,
+    };
+
+    let content = fs::read_to_string(&path).context(format!("Failed to open {:?}", path))?;
+    let mut yaml: spec_yaml::DeploymentEnvironmentSpecYaml = parse_yaml(&content, &path)?;
+
+    if yaml.env_type.is_none() {
+        if is_local_env {
+            yaml.env_type = Some(spec_yaml::DeploymentEnvTypeYaml::Local);
+        } else {
+            anyhow::bail!("'type' field is required in {:?}", path);
+        }
+    }
+
+    let env_spec = transform::convert_env_spec(yaml, root).context(format!("Failed to process env spec {}", path.display()))?;
+    Ok(env_spec)
+}
+
+fn find_env_spec(root: &Path) -> Result<(PathBuf, bool)> {
     let candidates: &[(&str, bool)] = &[
         ("envspec.yaml", false),
         ("envspec.yml", false),
@@ -67,26 +400,159 @@ pub fn load_env_spec(root: &Path, selected_deployment: Option<&str>) -> Result<s
         ("localenv.yml", true),
     ];
 
-    let (file_name, is_local_env) = candidates
+    candidates
         .iter()
         .find(|(name, _)| root.join(name).exists())
+        .map(|(name, is_local_env)| (root.join(name), *is_local_env))
         .ok_or_else(|| anyhow::anyhow!(
             "Could not find envspec.yaml, envspec.yml, localenv.yaml, or localenv.yml in {:?}", root
-        ))?;
+        ))
+}
 
-    let path = root.join(file_name);
-    let file = File::open(&path).context(format!("Failed to open {:?}", path))?;
-    let mut yaml: spec_yaml::DeploymentEnvironmentSpecYaml = serde_yaml::from_reader(file)
-        .context(format!("Failed to parse {:?}", path))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver;
+    use std::fs;
 
-    if yaml.env_type.is_none() {
-        if *is_local_env {
-            yaml.env_type = Some(spec_yaml::DeploymentEnvTypeYaml::Local);
-        } else {
-            anyhow::bail!("'type' field is required in {:?}", path);
+    // The specs live outside the working directory (the crate root while
+    // testing), so every relative path must resolve against their directory.
+    #[test]
+    fn env_spec_paths_resolve_against_its_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("configs/nginx")).unwrap();
+        fs::write(root.join("configs/nginx/site.conf"), "listen 80;").unwrap();
+        fs::create_dir(root.join("keys")).unwrap();
+        fs::write(root.join("keys/db"), "hunter2").unwrap();
+        fs::write(root.join("extra.yaml"), "environment:\n  external:\n    - REGION\n").unwrap();
+        fs::write(root.join("envspec.yml"), r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+registry:
+  shop: reg.example.com
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+      extra:
+        - extra.yaml
+    environment:
+      - REGION=eu
+    configs:
+      nginx: configs/nginx
+    secrets:
+      db_password:
+        file: keys/db
+"#).unwrap();
+        fs::write(root.join("appspec.yaml"), "name: shop\nversion: 1.0.0\nconfigs:\n  nginx:\n    - site.conf\nsecrets:\n  - db_password\n").unwrap();
+
+        let env_spec = load_env_spec(root).unwrap();
+        let app_spec = load_app_spec(&root.join("appspec.yaml"), Some(&env_spec)).unwrap();
+        assert!(app_spec.environment.external.iter().any(|v| v.name == "REGION"));
+
+        let resolved = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let deployment = &resolved.current_deployment;
+        assert_eq!(deployment.configs[0].files[0].content, b"listen 80;");
+        assert_eq!(deployment.secrets[0].value, "hunter2");
+    }
+
+    #[test]
+    fn bundle_entries_outside_the_bundle_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shop.1.0.0.tar.gz");
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::default()));
+        for (name, content) in [("appspec.yaml", &b"name: shop\nversion: 1.0.0\n"[..]), ("../configs/nginx/site.conf", b"listen 80;")] {
+            // set_path refuses `..`, so the name is written as a raw header
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append(&header, content).unwrap();
         }
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let err = load_app_spec(&path, None).unwrap_err();
+        assert!(format!("{:#}", err).contains("has an entry outside the bundle: ../configs/nginx/site.conf"), "{:#}", err);
     }
 
-    let env_spec = transform::convert_env_spec(yaml, root, selected_deployment).context("Failed to process env spec")?;
-    Ok(env_spec)
+    #[test]
+    fn errors_name_the_file_deployment_and_service() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("envspec.yaml"), "type: docker\ngateway:\n  hosts: [web]\n").unwrap();
+        let err = load_env_spec(root).unwrap_err();
+        assert!(format!("{:#}", err).contains("envspec.yaml:3:10"), "{:#}", err);
+
+        fs::write(root.join("envspec.yaml"), r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+registry:
+  shop: reg.example.com
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+"#).unwrap();
+        fs::write(root.join("appspec.yaml"), "name: shop\nversion: 1.0.0\nenvironment:\n  internal:\n    - API_URL=${FOO}/api\napp_services:\n  api:\n    image: shop/api\n    environment:\n      - API_URL\n").unwrap();
+        let env_spec = load_env_spec(root).unwrap();
+        let app_spec = load_app_spec(root, Some(&env_spec)).unwrap();
+        let err = resolver::resolve(&env_spec, &app_spec, "prod").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains(r#"deployment "prod": service "api": Failed to resolve internal env variable API_URL: Undefined variable: FOO"#),
+            "{:#}", err
+        );
+    }
+
+    #[test]
+    fn unknown_fields_and_duplicate_keys_are_rejected_unless_lenient() {
+        let path = Path::new("appspec.yaml");
+        let parse = |content: &str, lenient: bool| parse_yaml_with::<spec_yaml::AppSpecYaml>(content, path, lenient);
+
+        let typo = "name: shop\nversion: 1.0.0\napp_services:\n  api:\n    image: shop/api\n    enviroment:\n      - A\n";
+        let err = format!("{:#}", parse(typo, false).unwrap_err());
+        assert!(err.starts_with("Failed to parse appspec.yaml:6:5: app_services.api: unknown field `enviroment`, expected one of"), "{}", err);
+
+        let duplicate = "name: shop\nversion: 1.0.0\napp_services:\n  api:\n    image: shop/api\n  api:\n    image: shop/api2\n";
+        let err = format!("{:#}", parse(duplicate, false).unwrap_err());
+        assert!(err.starts_with("Failed to parse appspec.yaml:4:3: app_services: duplicate entry with key \"api\""), "{}", err);
+
+        let spec = parse(typo, true).unwrap();
+        assert!(spec.app_services.unwrap()["api"].environment.is_none());
+        assert_eq!(parse(duplicate, true).unwrap().app_services.unwrap()["api"].image.as_deref(), Some("shop/api2"));
+        let warnings: Vec<String> = diagnostics::warnings().into_iter().map(|w| w.message).collect();
+        assert!(warnings.contains(&"appspec.yaml: ignoring unknown field app_services.api.enviroment".to_string()), "{:?}", warnings);
+    }
+
+    #[test]
+    fn typos_under_untagged_variants_name_the_field() {
+        let path = Path::new("envspec.yaml");
+        let parse = |content: &str, lenient: bool| parse_yaml_with::<spec_yaml::DeploymentEnvironmentSpecYaml>(content, path, lenient);
+        let typo = "type: docker\ngateway:\n  hosts:\n    web: example.com\n    admin:\n      domains: [admin.example.com]\n      tsl: false\ndeployments: {}\n";
+
+        let err = format!("{:#}", parse(typo, false).unwrap_err());
+        assert!(err.contains(": gateway.hosts.admin.tsl: unknown field `tsl`, expected one of `domains`, `tls`, `default_backend`"), "{}", err);
+
+        let spec = parse(typo, true).unwrap();
+        let Some(spec_yaml::HostSpecYaml::Detailed(admin)) = spec.gateway.unwrap().hosts.remove("admin") else { panic!("expected a detailed host") };
+        assert_eq!((admin.domains, admin.tls), (vec!["admin.example.com".to_string()], None));
+        let warnings: Vec<String> = diagnostics::warnings().into_iter().map(|w| w.message).collect();
+        assert!(warnings.contains(&"envspec.yaml: ignoring unknown field gateway.hosts.admin.tsl".to_string()), "{:?}", warnings);
+
+        // a mismatch that is not a typo still fails in lenient mode
+        let wrong_type = "type: docker\ngateway:\n  hosts:\n    admin:\n      domains: admin.example.com\ndeployments: {}\n";
+        let err = format!("{:#}", parse(wrong_type, true).unwrap_err());
+        assert!(err.contains("gateway.hosts.admin.domains: invalid type: string"), "{}", err);
+    }
 }
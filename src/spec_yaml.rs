@@ -1,39 +1,43 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 fn default_gateway_name() -> String {
     "gateway".to_string()
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct AppSpecYaml {
     pub name: String,
     pub version: String,
     pub environment: Option<AppEnvironmentYaml>,
-    pub app_services: Option<HashMap<String, ServiceSpecYaml>>,
-    pub extra_services: Option<HashMap<String, ServiceSpecYaml>>,
-    pub configs: Option<HashMap<String, Vec<String>>>,
+    pub app_services: Option<BTreeMap<String, ServiceSpecYaml>>,
+    pub extra_services: Option<BTreeMap<String, ServiceSpecYaml>>,
+    pub configs: Option<BTreeMap<String, AppConfigYaml>>,
     pub secrets: Option<AppSecretsYaml>,
     pub volumes: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ExtraAppSpecYaml {
-    pub extra_services: Option<HashMap<String, ServiceSpecYaml>>,
+    pub extra_services: Option<BTreeMap<String, ServiceSpecYaml>>,
     pub environment: Option<AppEnvironmentYaml>,
-    pub configs: Option<HashMap<String, Vec<String>>>,
+    pub configs: Option<BTreeMap<String, AppConfigYaml>>,
     pub secrets: Option<AppSecretsYaml>,
     pub volumes: Option<Vec<String>>,   
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum AppSecretsYaml {
     Simple(Vec<String>),
-    Detailed(HashMap<String, Option<serde_yaml::Value>>),
+    Detailed(#[schemars(with = "BTreeMap<String, Option<serde_json::Value>>")] BTreeMap<String, Option<serde_yaml::Value>>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct AppEnvironmentYaml {
     pub external: Option<Vec<String>>,
     pub optional: Option<Vec<String>>,
@@ -41,16 +45,17 @@ pub struct AppEnvironmentYaml {
     pub internal: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ServiceSpecYaml {
     // default is internal
     #[serde(rename = "type")]
     pub service_type: Option<ServiceTypeYaml>,
     pub image: Option<String>,
-    pub variants: Option<HashMap<String, ImageVariantYaml>>,
+    pub variants: Option<BTreeMap<String, ImageVariantYaml>>,
     pub export: Option<ExportSpecYaml>,
     pub environment: Option<Vec<String>>,
-    pub configs: Option<Vec<HashMap<String, String>>>,
+    pub configs: Option<Vec<BTreeMap<String, String>>>,
     pub secrets: Option<Vec<ServiceSecretYaml>>,
     pub ports: Option<Vec<String>>,
     pub volumes: Option<Vec<String>>,
@@ -60,16 +65,42 @@ pub struct ServiceSpecYaml {
     pub entrypoint: Option<ServiceCommandYaml>,
     // Container health probe, same as docker-compose `healthcheck`.
     pub healthcheck: Option<HealthcheckYaml>,
+    // Working directory inside the container, same as docker-compose `working_dir`.
+    pub working_dir: Option<String>,
+    // User the container runs as, `user` or `user:group` like docker-compose `user`.
+    pub user: Option<String>,
+    // Compose profile the service belongs to. Profiled services only start when
+    // the profile is enabled, e.g. `docker compose --profile debug up`.
+    pub profile: Option<String>,
+    // Env var name; the service is skipped in deployments that give it no value.
+    pub when: Option<String>,
+    // Builds the image from source in local environments; other environments
+    // ignore it and use `image`.
+    pub build: Option<ServiceBuildYaml>,
+    // Local only: host source directories mounted into the container for
+    // live reload, e.g. `./src:/app/src`. Host paths are relative to the appspec.
+    pub dev_volumes: Option<Vec<String>>,
+    // baseline requests and limits; deployments override them
+    pub resources: Option<ResourcesSpecYaml>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceBuildYaml {
+    pub context: String,
+    pub dockerfile: Option<String>,
+    pub args: Option<BTreeMap<String, String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum ServiceCommandYaml {
     Shell(String),
     Exec(Vec<String>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct HealthcheckYaml {
     pub test: Option<HealthcheckTestYaml>,
     pub interval: Option<String>,
@@ -79,25 +110,32 @@ pub struct HealthcheckYaml {
     pub disable: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum HealthcheckTestYaml {
     Shell(String),
     Exec(Vec<String>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ExportSpecYaml {
     pub host: Option<String>,
     pub prefix: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ImageVariantYaml {
     pub image: String,
+    // Entries added to the service's `environment`, replacing a `NAME=value`
+    // of the same name.
+    pub environment: Option<Vec<String>>,
+    // Ports added to the service's, replacing one on the same container port.
+    pub ports: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum ServiceTypeYaml {
     Public,
@@ -105,20 +143,21 @@ pub enum ServiceTypeYaml {
     Job,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum ServiceSecretYaml {
     Simple(String),
-    Detailed(HashMap<String, Option<SecretConfigYaml>>),
+    Detailed(BTreeMap<String, Option<SecretConfigYaml>>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct SecretConfigYaml {
     pub path: Option<String>,
     pub variable: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum DeploymentEnvTypeYaml {
     K8S,
@@ -127,86 +166,256 @@ pub enum DeploymentEnvTypeYaml {
 }
 
 // DeploymentEnvironmentSpecYaml definitions
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DeploymentEnvironmentSpecYaml {
     #[serde(alias = "type")]
+    #[schemars(rename = "type")]
     pub env_type: Option<DeploymentEnvTypeYaml>,
     // if env_type is Docker, swarm_mode can be set. In other cases it will cause an error
     pub swarm_mode: Option<bool>,
+    // if env_type is Docker, script_format can be bash(default), powershell or both. In other cases it will cause an error
+    pub script_format: Option<String>,
     pub gateway: Option<IngressSpecYaml>,
     // deprecated: use gateway instead
     pub ingress: Option<IngressSpecYaml>,
-    pub registry: Option<HashMap<String, String>>,
-    pub deployments: HashMap<String, DeploymentSpecYaml>,
+    // image namespace -> registry host; "*" applies to every other namespace
+    pub registry: Option<BTreeMap<String, String>>,
+    // also pull extra services (databases, caches, ...) through `registry`
+    pub mirror_extra_services: Option<bool>,
+    // where prepare-deployment --version downloads app bundles from
+    pub bundle_repo: Option<BundleRepoSpecYaml>,
+    // largest config file a deployment may carry, a memory quantity like `2Mi`
+    pub max_config_file_size: Option<String>,
+    pub deployments: BTreeMap<String, DeploymentSpecYaml>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BundleRepoSpecYaml {
+    // "github" (GitHub releases) or "oci" (artifacts in an OCI registry)
+    pub provider: String,
+    // owner/repo, for github
+    pub repo: Option<String>,
+    // registry/path like registry.example.com/org/app, for oci
+    pub repository: Option<String>,
+    pub tag_prefix: Option<String>,
+    // env variable holding the API token, GITHUB_TOKEN or OCI_TOKEN unless set
+    pub token_env: Option<String>,
+    // base64 ed25519 public key; bundles without a matching signature are refused
+    pub public_key: Option<String>,
+    // limit on each request, a duration like `2m`; default 120s
+    pub timeout: Option<String>,
+    // how often a request failing with a connection error or 5xx is tried again; default 3
+    pub retries: Option<u32>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct IngressTlsSpecYaml {
     pub disable: Option<bool>,
     pub secret: Option<String>,
     pub letsencrypt: Option<LetsEncryptSpecYaml>,
+    // local only: serve https with a generated self-signed certificate
+    pub self_signed: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct IngressSpecYaml {
     #[serde(default = "default_gateway_name")]
     pub name: String,
-    pub hosts: HashMap<String, HostSpecYaml>,
+    pub hosts: BTreeMap<String, HostSpecYaml>,
     pub tls: Option<IngressTlsSpecYaml>,
+    // Allows a prefix inside another on the same host, e.g. `/api` and
+    // `/api/v1`; the most specific one is matched first.
+    pub allow_nested_prefixes: Option<bool>,
+    // source domain -> domain it permanently redirects to
+    pub redirects: Option<BTreeMap<String, RedirectSpecYaml>>,
 
     // if env_type is Docker, ingress_type can be nginx or traefik(default). In other cases it will cause an error
     #[serde(rename = "type")]
     pub ingress_type: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct LetsEncryptSpecYaml {
     pub server: Option<String>,
     pub email: String,
+    // DNS-01 challenge instead of HTTP-01; wildcard domains need it
+    pub dns01: Option<Dns01SpecYaml>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Dns01SpecYaml {
+    pub provider: Dns01ProviderYaml,
+    // k8s: Secret with the provider's API token; docker: env file with the
+    // provider's variables for Traefik
+    pub credentials: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Dns01ProviderYaml {
+    Cloudflare,
+    Digitalocean,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// A directory, or directories and files merged in order, later entries
+// replacing files of the same name
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ConfigPathsYaml {
+    Single(String),
+    Multiple(Vec<ConfigSourceYaml>),
+    Detailed(DeploymentConfigYaml),
+    Inline(BTreeMap<String, String>),
+}
+
+// A path, or file names mapped to their content
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ConfigSourceYaml {
+    Path(String),
+    Inline(BTreeMap<String, String>),
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DeploymentConfigYaml {
+    pub path: Box<ConfigPathsYaml>,
+    // render `${NAME}` references in the files
+    pub template: Option<bool>,
+}
+
+// The files a config must contain, file names mapped to their content, or
+// `{ files: [...], content: {...}, template: true }`
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum AppConfigYaml {
+    Files(Vec<String>),
+    Detailed(AppConfigDetailsYaml),
+    Inline(BTreeMap<String, String>),
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AppConfigDetailsYaml {
+    pub files: Option<Vec<String>>,
+    pub content: Option<BTreeMap<String, String>>,
+    // render `${NAME}` references in the files
+    pub template: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum HostSpecYaml {
     Single(String),
     Multiple(Vec<String>),
+    Detailed(HostDetailsYaml),
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum RedirectSpecYaml {
+    Target(String),
+    Detailed(RedirectDetailsYaml),
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectDetailsYaml {
+    pub to: String,
+    // keep the path and query of the request, true by default
+    pub preserve_path: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HostDetailsYaml {
+    pub domains: Vec<String>,
+    // false serves the host over plain http even when the gateway has TLS
+    pub tls: Option<bool>,
+    // answers requests that no service prefix matches
+    pub default_backend: Option<DefaultBackendYaml>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// One of `service` and `page`
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultBackendYaml {
+    pub service: Option<String>,
+    // inline HTML
+    pub page: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DeploymentSpecYaml {
-    pub primary_host: String,
-    pub application: DeploymentAppSpecYaml,
+    // another deployment whose settings this one starts from
+    pub extends: Option<String>,
+    // required, here or in the deployment it extends
+    pub primary_host: Option<String>,
+    // Domain of the primary host this deployment is advertised and routed on,
+    // instead of the host's first domain.
+    pub public_domain: Option<String>,
+    // required, here or in the deployment it extends
+    pub application: Option<DeploymentAppSpecYaml>,
     pub environment: Option<DeploymentEnvVariablesYaml>,
     pub undockerized_environment: Option<DeploymentEnvVariablesYaml>,
-    pub configs: Option<HashMap<String, String>>,
-    pub secrets: Option<HashMap<String, DeploymentSecretSpecExYaml>>,
+    pub configs: Option<BTreeMap<String, ConfigPathsYaml>>,
+    pub secrets: Option<BTreeMap<String, DeploymentSecretSpecExYaml>>,
     pub defaults: Option<DefaultsSpecYaml>,
-    pub services: Option<HashMap<String, DeploymentServiceSpecYaml>>,
+    pub services: Option<BTreeMap<String, DeploymentServiceSpecYaml>>,
     // local-only: folder to load secret values from when a secret value is empty
     pub secrets_folder: Option<String>,
+    // the gateway answers every request on the deployment's domains with the
+    // maintenance page; the services keep running
+    pub maintenance: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DeploymentSecretSpecYaml {
     pub env: Option<String>,
     pub file: Option<String>,
+    pub generate: Option<GeneratedSecretYaml>,
+}
+
+// A random value generated on first use and kept in the secrets state file
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct GeneratedSecretYaml {
+    // random bytes for hex and base64, characters for alnum; 32 by default
+    pub length: Option<usize>,
+    pub format: Option<GeneratedSecretFormatYaml>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum GeneratedSecretFormatYaml {
+    Hex,
+    Base64,
+    Alnum,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum DeploymentSecretSpecExYaml {
     Local(Option<String>), // for local configurations we can put secrets directly into the deployment spec
     Detailed(DeploymentSecretSpecYaml),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum DeploymentEnvVariablesYaml {
     FromEnvFile(String),
     FromList(Vec<EnvVariableEntryYaml>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum EnvVariableEntryYaml {
     // an inline variable as a string, e.g. "SOME_VAR=some_value"
@@ -215,50 +424,65 @@ pub enum EnvVariableEntryYaml {
     //     environment:
     //       - MAIN_SERVICE_DB:
     //           file: /path/to/file
-    FromFile(HashMap<String, EnvVariableSourceYaml>),
+    FromFile(BTreeMap<String, EnvVariableSourceYaml>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct EnvVariableSourceYaml {
     pub file: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DeploymentAppSpecYaml {
     pub name: String,
     pub version: Option<String>,
     pub extra: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DefaultsSpecYaml {
     pub replicas: Option<u32>,
     pub resources: Option<ResourcesSpecYaml>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ResourcesSpecYaml {
     pub requests: Option<ResourceLimitsYaml>,
     pub limits: Option<ResourceLimitsYaml>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ResourceLimitsYaml {
     pub memory: Option<String>,
     pub cpu: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DeploymentServiceSpecYaml {
+    // `false` leaves the appspec service out of this deployment
+    pub enabled: Option<bool>,
     pub variant: Option<String>,
+    // image used instead of the appspec's, e.g. a hotfix build
+    pub image: Option<String>,
     pub host: Option<String>,
     pub prefix: Option<String>,
     pub strip_prefix: Option<bool>,
-    pub prefixes: Option<HashMap<String, PrefixOptionsYaml>>,
+    pub prefixes: Option<PrefixesYaml>,
+    // CORS headers the gateway adds on the service's prefixes
+    pub cors: Option<CorsSpecYaml>,
     pub replicas: Option<u32>,
     pub resources: Option<ResourcesSpecYaml>,
-    // ports are a vector of strings in the form "external:internal"
+    // ports are a vector of strings in the form "external:internal", or
+    // "internal" for a port that is not published on the host
     pub ports: Option<Vec<String>>,
+    // values for this service only, over the deployment's `environment`
+    pub environment: Option<DeploymentEnvVariablesYaml>,
 
     // local-only: directory of a host-run (non-dockerized) service. When set,
     // the undockerized environment is written there as `.env` and the service's
@@ -266,7 +490,39 @@ pub struct DeploymentServiceSpecYaml {
     pub working_dir: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// A list keeps the declared order; a map is ordered by path
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum PrefixesYaml {
+    List(Vec<PrefixEntryYaml>),
+    Map(BTreeMap<String, PrefixOptionsYaml>),
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PrefixEntryYaml {
+    pub path: String,
+    pub strip: Option<bool>,
+    // overrides the service's `cors` for this prefix
+    pub cors: Option<CorsSpecYaml>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct PrefixOptionsYaml {
     pub strip: Option<bool>,
+    // overrides the service's `cors` for this prefix
+    pub cors: Option<CorsSpecYaml>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CorsSpecYaml {
+    // allowed origins, e.g. `https://app.example.com`, or `*`
+    pub origins: Vec<String>,
+    pub methods: Option<Vec<String>>,
+    pub headers: Option<Vec<String>>,
+    pub credentials: Option<bool>,
+    // seconds a browser may cache the preflight response
+    pub max_age: Option<u32>,
 }
@@ -0,0 +1,53 @@
+use serde_yaml::Value;
+
+/// Env spec of deployment `prod` of app `shop`, serving host `web` at
+/// example.com (localhost for `local`), with `extra` merged over it key by
+/// key. Docker and k8s specs also get the registry `acme` and TLS disabled,
+/// unless `extra` sets `gateway.tls`. A `~` in `extra` removes the key.
+pub fn env_spec(env_type: &str, extra: &str) -> String {
+    let extra = parse(extra);
+    let mut base = format!("type: {}\ngateway:\n  hosts:\n    web: {}\n", env_type, if env_type == "local" { "localhost" } else { "example.com" });
+    if env_type != "local" {
+        if extra.get("gateway").and_then(|g| g.get("tls")).is_none() {
+            base.push_str("  tls:\n    disable: true\n");
+        }
+        base.push_str("registry:\n  acme: reg.example.com\n");
+    }
+    base.push_str("deployments:\n  prod:\n    primary_host: web\n    application:\n      name: shop\n");
+    merged(&base, extra)
+}
+
+/// Appspec of app `shop` version 1.0.0 with `extra` merged over it.
+pub fn app_spec(extra: &str) -> String {
+    merged("name: shop\nversion: 1.0.0\n", parse(extra))
+}
+
+fn parse(yaml: &str) -> Value {
+    serde_yaml::from_str(yaml).unwrap()
+}
+
+fn merged(base: &str, extra: Value) -> String {
+    let mut base = parse(base);
+    merge(&mut base, extra);
+    serde_yaml::to_string(&base).unwrap()
+}
+
+fn merge(base: &mut Value, extra: Value) {
+    match (base, extra) {
+        (_, Value::Null) => {}
+        (Value::Mapping(base), Value::Mapping(extra)) => {
+            for (key, value) in extra {
+                match base.get_mut(&key) {
+                    Some(_) if value.is_null() => {
+                        base.remove(&key);
+                    }
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, extra) => *base = extra,
+    }
+}
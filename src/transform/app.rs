@@ -1,10 +1,20 @@
 use crate::spec::*;
 use crate::spec_yaml::*;
 use crate::env_loader::parse_env_string;
-use crate::spec;
-use anyhow::{Context, Result, anyhow};
-use std::collections::HashMap;
+use crate::image_ref::ImageRef;
+use crate::{diagnostics, spec, spec_loader};
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// `--lenient-names`: invalid env variable names are warnings instead of errors
+static LENIENT_NAMES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_lenient_names(lenient: bool) {
+    LENIENT_NAMES.store(lenient, Ordering::Relaxed);
+}
 
 pub fn convert_app_spec(yaml: AppSpecYaml, env_spec: Option<&spec::DeploymentEnvironmentSpec>) -> Result<AppSpec> {
     let version = semver::Version::parse(&yaml.version)
@@ -27,14 +37,7 @@ pub fn convert_app_spec(yaml: AppSpecYaml, env_spec: Option<&spec::DeploymentEnv
         vec![]
     };
 
-    let mut configs: Vec<ConfigSpec> = if let Some(conf) = yaml.configs {
-        conf.into_iter().map(|(k, v)| ConfigSpec {
-            name: k,
-            files: v,
-        }).collect()
-    } else {
-        vec![]
-    };
+    let mut configs: Vec<ConfigSpec> = yaml.configs.unwrap_or_default().into_iter().map(convert_config).collect();
 
     let app_services = if let Some(services) = yaml.app_services {
         convert_services(services, true)?
@@ -42,19 +45,26 @@ pub fn convert_app_spec(yaml: AppSpecYaml, env_spec: Option<&spec::DeploymentEnv
         vec![]
     };
 
-    let mut combined_extra_services = HashMap::new();
+    // Services of the appspec, then of the extra spec files; a name may only
+    // be defined once
+    let mut combined_extra_services = yaml.extra_services.unwrap_or_default();
     let mut volumes: Vec<String> = yaml.volumes.unwrap_or_default();
+    let mut defined_in: BTreeMap<String, String> = app_services.iter().map(|s| (s.name.clone(), "appspec app_services".to_string()))
+        .chain(combined_extra_services.keys().map(|name| (name.clone(), "appspec extra_services".to_string())))
+        .collect();
 
     if let Some(env) = env_spec {
         if let Some(deployment) = env.deployments.iter().find(|d| d.application.name == yaml.name) {
             for extra_file in &deployment.application.extra {
                 let content = fs::read_to_string(extra_file)
-                    .with_context(|| format!("Failed to read extra spec file {}", extra_file))?;
-                let extra_yaml: ExtraAppSpecYaml = serde_yaml::from_str(&content)
-                    .with_context(|| format!("Failed to parse extra spec file {}", extra_file))?;
-
-                if let Some(services) = extra_yaml.extra_services {
-                    combined_extra_services.extend(services);
+                    .with_context(|| format!("Failed to read extra spec file {} of deployment {}", extra_file, deployment.name))?;
+                let extra_yaml: ExtraAppSpecYaml = spec_loader::parse_yaml(&content, Path::new(extra_file))?;
+
+                for (name, service) in extra_yaml.extra_services.unwrap_or_default() {
+                    if let Some(other) = defined_in.insert(name.clone(), format!("extra spec file {}", extra_file)) {
+                        bail!("Service {} of extra spec file {} (deployment {}) is already defined in {}", name, extra_file, deployment.name, other);
+                    }
+                    combined_extra_services.insert(name, service);
                 }
                 if let Some(extra_env) = extra_yaml.environment {
                     let converted = convert_environment(extra_env)?;
@@ -62,9 +72,10 @@ pub fn convert_app_spec(yaml: AppSpecYaml, env_spec: Option<&spec::DeploymentEnv
                     environment.optional.extend(converted.optional);
                     environment.relative.extend(converted.relative);
                     environment.internal.extend(converted.internal);
+                    check_env_names(&environment).context(format!("Extra spec file {}", extra_file))?;
                 }
                 if let Some(extra_configs) = extra_yaml.configs {
-                    configs.extend(extra_configs.into_iter().map(|(k, v)| ConfigSpec { name: k, files: v }));
+                    configs.extend(extra_configs.into_iter().map(convert_config));
                 }
                 if let Some(extra_secrets) = extra_yaml.secrets {
                     secrets.extend(convert_secrets(extra_secrets)?);
@@ -76,10 +87,6 @@ pub fn convert_app_spec(yaml: AppSpecYaml, env_spec: Option<&spec::DeploymentEnv
         }
     }
 
-    if let Some(services) = yaml.extra_services {
-        combined_extra_services.extend(services);
-    }
-
     let extra_services = convert_services(combined_extra_services, false)?;
 
     for svc in app_services.iter().chain(extra_services.iter()) {
@@ -107,18 +114,54 @@ pub fn convert_app_spec(yaml: AppSpecYaml, env_spec: Option<&spec::DeploymentEnv
     })
 }
 
+// Env variable names follow POSIX: letters, digits and `_`, not starting
+// with a digit. Docker and Kubernetes reject or mangle anything else.
+fn check_env_name(name: &str, location: &str) -> Result<()> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        return Ok(());
+    }
+    let message = format!("Invalid env variable name {:?} in {}: use letters, digits and _, not starting with a digit", name, location);
+    if LENIENT_NAMES.load(Ordering::Relaxed) {
+        diagnostics::warn("invalid_env_name", message);
+        return Ok(());
+    }
+    bail!(message)
+}
+
+// A name may only be declared once: which declaration wins would otherwise
+// depend on resolution order
+fn check_env_names(environment: &AppEnvironment) -> Result<()> {
+    let names = environment.external.iter().map(|v| (&v.name, "external"))
+        .chain(environment.optional.iter().map(|v| (&v.name, "optional")))
+        .chain(environment.relative.iter().map(|v| (&v.name, "relative")))
+        .chain(environment.internal.iter().map(|v| (&v.name, "internal")));
+    let mut sections = HashMap::new();
+    for (name, section) in names {
+        if let Some(other) = sections.insert(name, section) {
+            if other == section {
+                bail!("Env variable {} is declared twice in environment.{}", name, section);
+            }
+            bail!("Env variable {} is declared in both environment.{} and environment.{}", name, other, section);
+        }
+    }
+    Ok(())
+}
+
 fn convert_environment(yaml: AppEnvironmentYaml) -> Result<AppEnvironment> {
     let external = yaml.external.unwrap_or_default().into_iter().map(|s| {
         let desc = parse_env_string(&s)?;
+        check_env_name(&desc.name, "environment.external")?;
         Ok(ExternalEnvVariable {
             name: desc.name,
             default: desc.default,
         })
     }).collect::<Result<Vec<_>>>()?;
 
-    // TODO: check if optional variable is not defined in external
     let optional = yaml.optional.unwrap_or_default().into_iter().map(|s| {
         let desc = parse_env_string(&s)?;
+        check_env_name(&desc.name, "environment.optional")?;
         if desc.default.is_some() {
             return Err(anyhow!("Optional env variable {} cannot have a default value", desc.name));
         }
@@ -130,6 +173,7 @@ fn convert_environment(yaml: AppEnvironmentYaml) -> Result<AppEnvironment> {
     let relative = yaml.relative.unwrap_or_default().into_iter().map(|s| {
         let desc = parse_env_string(&s)
             .map_err(|_| anyhow!("Invalid relative env variable format: {}", s))?;
+        check_env_name(&desc.name, "environment.relative")?;
         let val = desc.default.ok_or_else(|| anyhow!("Invalid relative env variable format (no value): {}", s))?;
         if !val.starts_with('/') {
             return Err(anyhow!("Relative URL for {} must start with /", desc.name));
@@ -143,6 +187,7 @@ fn convert_environment(yaml: AppEnvironmentYaml) -> Result<AppEnvironment> {
     let internal = yaml.internal.unwrap_or_default().into_iter().map(|s| {
         let desc = parse_env_string(&s)
             .map_err(|_| anyhow!("Invalid internal env variable format: {}", s))?;
+        check_env_name(&desc.name, "environment.internal")?;
         if let Some(val) = desc.default {
             Ok(InternalEnvVariable {
                 name: desc.name,
@@ -153,12 +198,14 @@ fn convert_environment(yaml: AppEnvironmentYaml) -> Result<AppEnvironment> {
         }
     }).collect::<Result<Vec<_>>>()?;
 
-    Ok(AppEnvironment {
+    let environment = AppEnvironment {
         external,
         optional,
         relative,
         internal,
-    })
+    };
+    check_env_names(&environment)?;
+    Ok(environment)
 }
 
 fn convert_secrets(yaml: AppSecretsYaml) -> Result<Vec<AppSecretOption>> {
@@ -172,12 +219,50 @@ fn convert_secrets(yaml: AppSecretsYaml) -> Result<Vec<AppSecretOption>> {
     }
 }
 
-fn convert_services(yaml: HashMap<String, ServiceSpecYaml>, is_app_service: bool) -> Result<Vec<ServiceSpec>> {
+fn convert_services(yaml: BTreeMap<String, ServiceSpecYaml>, is_app_service: bool) -> Result<Vec<ServiceSpec>> {
     yaml.into_iter()
-        .map(|(name, svc)| convert_service(name, svc, is_app_service))
+        .map(|(name, svc)| {
+            let context = format!("service \"{}\"", name);
+            convert_service(name, svc, is_app_service).context(context)
+        })
         .collect()
 }
 
+fn convert_config((name, yaml): (String, AppConfigYaml)) -> ConfigSpec {
+    match yaml {
+        AppConfigYaml::Files(files) => ConfigSpec { name, files: files.into_iter().map(ConfigFileSpec::PathRef).collect(), template: false },
+        AppConfigYaml::Inline(content) => ConfigSpec { name, files: inline_files(content), template: false },
+        AppConfigYaml::Detailed(details) => ConfigSpec {
+            name,
+            files: details.files.unwrap_or_default().into_iter().map(ConfigFileSpec::PathRef)
+                .chain(inline_files(details.content.unwrap_or_default()))
+                .collect(),
+            template: details.template.unwrap_or(false),
+        },
+    }
+}
+
+fn inline_files(content: BTreeMap<String, String>) -> Vec<ConfigFileSpec> {
+    content.into_iter().map(|(name, content)| ConfigFileSpec::Inline { name, content }).collect()
+}
+
+// `$all`, `NAME` or `NAME=value` entries of a service's environment
+fn convert_service_env(entries: Vec<String>, location: &str) -> Result<Vec<ServiceEnvOption>> {
+    entries.into_iter().map(|s| {
+        let option = if s == "$all" {
+            ServiceEnvOption::All
+        } else if let Some((k, v)) = s.split_once('=') {
+            ServiceEnvOption::WithValue(k.trim().to_string(), v.trim().to_string())
+        } else {
+            ServiceEnvOption::Simple(s)
+        };
+        if let ServiceEnvOption::Simple(name) | ServiceEnvOption::WithValue(name, _) = &option {
+            check_env_name(name, location)?;
+        }
+        Ok(option)
+    }).collect()
+}
+
 fn convert_service(name: String, yaml: ServiceSpecYaml, is_app_service: bool) -> Result<ServiceSpec> {
     let service_type = match yaml.service_type {
         Some(ServiceTypeYaml::Public) => ServiceType::Public,
@@ -187,26 +272,49 @@ fn convert_service(name: String, yaml: ServiceSpecYaml, is_app_service: bool) ->
     };
 
     let image = match (yaml.image, yaml.variants) {
-        (Some(img), None) => ImageSpec::Exact(img),
-        (None, Some(variants)) => ImageSpec::Variants(
-            variants.into_iter().map(|(variant_name, v)| ImageVariant {
-                variant_name,
-                image: v.image,
-            }).collect()
-        ),
+        (Some(img), None) => Some(ImageSpec::Exact(img)),
+        (None, Some(variants)) => Some(ImageSpec::Variants(
+            variants.into_iter().map(|(variant_name, v)| {
+                let location = format!("variants.{}", variant_name);
+                Ok(ImageVariant {
+                    environment: convert_service_env(v.environment.unwrap_or_default(), &format!("{}.environment", location))?,
+                    ports: super::parse_ports(&v.ports).context(format!("{}.ports", location))?,
+                    variant_name,
+                    image: v.image,
+                })
+            }).collect::<Result<Vec<_>>>()?
+        )),
         (Some(_), Some(_)) => return Err(anyhow!("Service '{}' cannot have both 'image' and 'variants'", name)),
-        (None, None) => return Err(anyhow!("Service '{}' must specify either 'image' or 'variants'", name)),
+        (None, None) if yaml.build.is_some() => None,
+        (None, None) => return Err(anyhow!("Service '{}' must specify either 'image', 'variants' or 'build'", name)),
     };
-
-    let environment = yaml.environment.unwrap_or_default().into_iter().map(|s| {
-        if s == "$all" {
-            ServiceEnvOption::All
-        } else if let Some((k, v)) = s.split_once('=') {
-            ServiceEnvOption::WithValue(k.trim().to_string(), v.trim().to_string())
-        } else {
-            ServiceEnvOption::Simple(s)
+    let images = match &image {
+        Some(ImageSpec::Exact(img)) => vec![img],
+        Some(ImageSpec::Variants(variants)) => variants.iter().map(|v| &v.image).collect(),
+        None => vec![],
+    };
+    for img in images {
+        let parsed = ImageRef::parse(img)?;
+        // the app version becomes the tag, a digest would pin it away
+        if is_app_service && parsed.digest.is_some() {
+            bail!("App service image {} cannot be pinned by digest; it is tagged with the app version", img);
         }
-    }).collect();
+    }
+
+    let dev_volumes = yaml.dev_volumes.unwrap_or_default().iter()
+        .map(|s| parse_dev_volume(s))
+        .collect::<Result<Vec<_>>>()?;
+
+    let build = yaml.build.map(|b| ServiceBuild {
+        context: b.context,
+        dockerfile: b.dockerfile,
+        args: b.args.unwrap_or_default().into_iter().collect(),
+    });
+
+    let environment = convert_service_env(yaml.environment.unwrap_or_default(), "environment")?;
+    if let Some(var) = &yaml.when {
+        check_env_name(var, "when")?;
+    }
 
     let configs = yaml.configs.unwrap_or_default().into_iter().flat_map(|map| {
         map.into_iter().map(|(k, v)| ServiceConfigOption {
@@ -243,7 +351,15 @@ fn convert_service(name: String, yaml: ServiceSpecYaml, is_app_service: bool) ->
         command,
         entrypoint,
         healthcheck,
+        export: yaml.export.map(|e| ServiceExport { host: e.host, prefix: e.prefix }),
+        working_dir: yaml.working_dir,
+        user: yaml.user,
+        profile: yaml.profile,
+        when: yaml.when,
+        build,
+        dev_volumes,
         is_app_service,
+        resources: super::convert_resource_hints(yaml.resources.as_ref(), "resources")?,
     })
 }
 
@@ -289,6 +405,7 @@ fn convert_service_secrets(yaml: Vec<ServiceSecretYaml>) -> Result<Vec<ServiceSe
                         if let Some(p) = c.path {
                             SecretMount::FilePath(p)
                         } else if let Some(e) = c.variable {
+                            check_env_name(&e, &format!("secret {} variable", name))?;
                             SecretMount::EnvVariable(e)
                         } else {
                             return Err(anyhow!("Secret {} must have either path: or variable: specified, or neigher", name));
@@ -304,6 +421,17 @@ fn convert_service_secrets(yaml: Vec<ServiceSecretYaml>) -> Result<Vec<ServiceSe
     Ok(secrets)
 }
 
+fn parse_dev_volume(s: &str) -> Result<DevVolume> {
+    // Split on the last colon so Windows host paths like `C:\src` keep theirs.
+    let (host_path, mount_path) = s.rsplit_once(':')
+        .filter(|(host, mount)| !host.is_empty() && mount.starts_with('/'))
+        .ok_or_else(|| anyhow!("Invalid dev volume '{}'. Expected './host/path:/container/path'", s))?;
+    Ok(DevVolume {
+        host_path: host_path.to_string(),
+        mount_path: mount_path.to_string(),
+    })
+}
+
 fn parse_service_volume(s: &str) -> Result<ServiceVolume> {
     let (vol_str, mount_path) = s.split_once(':')
         .ok_or_else(|| anyhow!("Invalid volume format '{}'. Expected 'name:mount_path' or './path:mount_path'", s))?;
@@ -319,3 +447,56 @@ fn parse_service_volume(s: &str) -> Result<ServiceVolume> {
         mount_path: mount_path.to_string(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_variables_are_declared_once() {
+        let convert = |yaml: &str| convert_environment(serde_yaml::from_str(yaml).unwrap()).map(|_| ()).map_err(|e| e.to_string());
+
+        assert_eq!(convert("external:\n  - TOKEN\noptional:\n  - TOKEN\n"), Err("Env variable TOKEN is declared in both environment.external and environment.optional".into()));
+        assert_eq!(convert("relative:\n  - URL=/a\ninternal:\n  - URL=http://b\n"), Err("Env variable URL is declared in both environment.relative and environment.internal".into()));
+        assert_eq!(convert("internal:\n  - A=1\n  - A=2\n"), Err("Env variable A is declared twice in environment.internal".into()));
+        assert_eq!(convert("external:\n  - A\noptional:\n  - B\n"), Ok(()));
+    }
+
+    #[test]
+    fn env_variable_names_must_be_identifiers() {
+        let service = |yaml: &str| convert_service("api".into(), serde_yaml::from_str(yaml).unwrap(), true).map(|_| ()).map_err(|e| e.to_string());
+
+        assert_eq!(convert_environment(serde_yaml::from_str("internal:\n  - MY VAR=1\n").unwrap()).unwrap_err().to_string(),
+            "Invalid env variable name \"MY VAR\" in environment.internal: use letters, digits and _, not starting with a digit");
+        assert_eq!(service("image: shop/api\nenvironment:\n  - 1ST=a\n"),
+            Err("Invalid env variable name \"1ST\" in environment: use letters, digits and _, not starting with a digit".into()));
+        assert_eq!(service("image: shop/api\nsecrets:\n  - token:\n      variable: my-secret-var\n"),
+            Err("Invalid env variable name \"my-secret-var\" in secret token variable: use letters, digits and _, not starting with a digit".into()));
+        assert_eq!(service("image: shop/api\nenvironment:\n  - $all\n  - _PRIVATE\n  - Url2=x\n"), Ok(()));
+    }
+
+    #[test]
+    fn extra_spec_files_are_checked_and_must_not_redefine_services() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("extra.yaml"), "extra_services:\n  redis:\n    image: redis:7\n").unwrap();
+        let env_spec = |extra: &str| {
+            let raw = format!("type: local\ngateway:\n  hosts:\n    web: localhost:8080\ndeployments:\n  dev:\n    primary_host: web\n    application:\n      name: shop\n      extra: [{}]\n", extra);
+            crate::transform::convert_env_spec(serde_yaml::from_str(&raw).unwrap(), root.path())
+        };
+        let app = |extra_services: &str| -> AppSpecYaml {
+            serde_yaml::from_str(&format!("name: shop\nversion: 1.0.0\napp_services:\n  api:\n    image: shop/api\n{}", extra_services)).unwrap()
+        };
+
+        let err = env_spec("missing.yaml").unwrap_err();
+        assert!(format!("{:#}", err).contains("deployment \"dev\": Extra spec file missing.yaml does not exist"), "{:#}", err);
+
+        let env = env_spec("extra.yaml").unwrap();
+        assert!(convert_app_spec(app(""), Some(&env)).is_ok());
+        let err = convert_app_spec(app("extra_services:\n  redis:\n    image: redis:6\n"), Some(&env)).unwrap_err().to_string();
+        assert!(err.starts_with("Service redis of extra spec file ") && err.ends_with("(deployment dev) is already defined in appspec extra_services"), "{}", err);
+
+        std::fs::write(root.path().join("extra.yaml"), "extra_services:\n  api:\n    image: other/api:1\n").unwrap();
+        let err = convert_app_spec(app(""), Some(&env)).unwrap_err().to_string();
+        assert!(err.ends_with("is already defined in appspec app_services"), "{}", err);
+    }
+}
@@ -1,22 +1,31 @@
 use crate::spec::*;
 use crate::spec_yaml::*;
-use crate::{env_loader, spec};
+use crate::error::MissingFile;
+use crate::image_ref::ImageRef;
+use crate::quantities::{Cpu, Memory};
+use crate::{diagnostics, env_loader, secrets, spec, spec_loader};
 use anyhow::{Context, Result, anyhow};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
 use std::path::Path;
 
-const DEFAULT_MEMORY: &str = "128Mi";
-const DEFAULT_CPU: &str = "100m";
+const DEFAULT_MEMORY: Memory = Memory::from_bytes(128 * 1024 * 1024);
+const DEFAULT_CPU: Cpu = Cpu::from_millicores(100);
+// base64-encoded, the 1MiB Kubernetes allows for a ConfigMap
+const DEFAULT_MAX_CONFIG_FILE_SIZE: Memory = Memory::from_bytes(768 * 1024);
+const DEFAULT_CORS_METHODS: [&str; 6] = ["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+const DEFAULT_CORS_HEADERS: [&str; 2] = ["Authorization", "Content-Type"];
+const DEFAULT_CORS_MAX_AGE: u32 = 600;
 
-pub fn convert_env_spec(yaml: DeploymentEnvironmentSpecYaml, root: &Path, selected_deployment: Option<&str>) -> Result<DeploymentEnvironmentSpec> {
+pub fn convert_env_spec(yaml: DeploymentEnvironmentSpecYaml, root: &Path) -> Result<DeploymentEnvironmentSpec> {
     let env_type_yaml = yaml.env_type
         .ok_or_else(|| anyhow!("'type' field is required in env spec"))?;
     let swarm_mode_opt = yaml.swarm_mode;
+    let script_format_str = yaml.script_format.clone();
     let gateway_yaml = match (yaml.gateway, yaml.ingress) {
         (Some(g), _) => g,
         (None, Some(i)) => {
-            eprintln!("Warning: 'ingress' in env spec is deprecated; rename it to 'gateway'");
+            diagnostics::warn("deprecated_ingress", "'ingress' in env spec is deprecated; rename it to 'gateway'");
             i
         }
         (None, None) => return Err(anyhow!("'gateway' field is required in env spec")),
@@ -24,10 +33,23 @@ pub fn convert_env_spec(yaml: DeploymentEnvironmentSpecYaml, root: &Path, select
     let ingress_type_str = gateway_yaml.ingress_type.clone();
     let ingress = convert_ingress(gateway_yaml, &env_type_yaml)?;
     let registry = yaml.registry.unwrap_or_default();
+    let bundle_repo = yaml.bundle_repo.as_ref().map(convert_bundle_repo).transpose()?;
 
     let mut deployments = Vec::new();
     for (name, dep) in &yaml.deployments {
-        deployments.push(convert_deployment(name.clone(), dep, root, &env_type_yaml)?);
+        let deployment = inheritance_chain(name, &yaml.deployments)
+            .and_then(|layers| {
+                let merged;
+                let effective = if layers.len() > 1 {
+                    merged = merge_deployments(&layers)?;
+                    &merged
+                } else {
+                    dep
+                };
+                convert_deployment(name.clone(), effective, &layers, &ingress, root, &env_type_yaml)
+            })
+            .context(format!("deployment \"{}\"", name))?;
+        deployments.push(deployment);
     }
 
     let env_type = match env_type_yaml {
@@ -35,6 +57,9 @@ pub fn convert_env_spec(yaml: DeploymentEnvironmentSpecYaml, root: &Path, select
             if swarm_mode_opt.is_some() {
                 return Err(anyhow!("swarm_mode cannot be set for K8S environment"));
             }
+            if script_format_str.is_some() {
+                return Err(anyhow!("script_format cannot be set for K8S environment"));
+            }
             if ingress_type_str.is_some() {
                 return Err(anyhow!("ingress_type cannot be set for K8S environment"));
             }
@@ -53,6 +78,12 @@ pub fn convert_env_spec(yaml: DeploymentEnvironmentSpecYaml, root: &Path, select
                 Some("traefik") | None => DockerIngressType::Traefik,
                 Some(other) => return Err(anyhow!("Unknown ingress type: {}", other)),
             };
+            let script_format = match script_format_str.as_deref() {
+                Some("bash") | None => ScriptFormat::Bash,
+                Some("powershell") => ScriptFormat::PowerShell,
+                Some("both") => ScriptFormat::Both,
+                Some(other) => return Err(anyhow!("Unknown script format: {} (expected bash, powershell or both)", other)),
+            };
             if yaml.deployments.values().any(|d| d.secrets_folder.is_some()) {
                 return Err(anyhow!("secrets_folder cannot be set for Docker environment"));
             }
@@ -62,12 +93,16 @@ pub fn convert_env_spec(yaml: DeploymentEnvironmentSpecYaml, root: &Path, select
             DeploymentEnvType::Docker(DockerSpecificSpec {
                 swarm_mode,
                 ingress_type,
+                script_format,
             })
         },
         DeploymentEnvTypeYaml::Local => {
             if swarm_mode_opt.is_some() {
                 return Err(anyhow!("swarm_mode cannot be set for Local environment"));
             }
+            if script_format_str.is_some() {
+                return Err(anyhow!("script_format cannot be set for Local environment"));
+            }
             if ingress_type_str.is_some() {
                 return Err(anyhow!("ingress_type cannot be set for Local environment"));
             }
@@ -77,26 +112,23 @@ pub fn convert_env_spec(yaml: DeploymentEnvironmentSpecYaml, root: &Path, select
             if deployments.is_empty() {
                 return Err(anyhow!("For Local environment at least one deployment must be specified"));
             }
-            // Multiple deployments are allowed, but only one runs at a time. When more
-            // than one is defined the caller must pick which with --deployment.
-            if deployments.len() > 1 && selected_deployment.is_none() {
-                return Err(anyhow!(
-                    "For Local environment with multiple deployments, specify which one with --deployment"
-                ));
-            }
+            // Multiple deployments are allowed, but only one runs at a time; local
+            // commands pick it with --deployment (see select_deployment in main).
 
             // Port uniqueness is checked per deployment: different deployments may reuse
-            // the same external ports since only one runs at a time.
+            // the same external ports since only one runs at a time. TCP and UDP ports
+            // don't clash, and expose-only ports aren't published at all.
             for dep in &deployments {
                 let mut ports_seen = HashSet::new();
                 if let Some(services) = &dep.services {
-                    for (svc_name, svc_spec) in services {
+                    for (svc_name, svc_spec) in services.iter().filter(|(_, s)| s.enabled) {
                         if svc_spec.ports.is_empty() {
                             return Err(anyhow!("In Local environment, service {} must have at least one port", svc_name));
                         }
                         for port in &svc_spec.ports {
-                            if !ports_seen.insert(port.external) {
-                                return Err(anyhow!("Duplicate external port {} in deployment {}", port.external, dep.name));
+                            let Some(external) = port.external else { continue };
+                            if !ports_seen.insert((external, port.protocol)) {
+                                return Err(anyhow!("Duplicate external port {} in deployment {}", port.mapping(), dep.name));
                             }
                         }
                     }
@@ -107,15 +139,59 @@ pub fn convert_env_spec(yaml: DeploymentEnvironmentSpecYaml, root: &Path, select
         },
     };
 
+    let max_config_file_size = match yaml.max_config_file_size.as_deref() {
+        Some(size) => Some(Memory::parse(size).context("max_config_file_size")?),
+        None if env_type == DeploymentEnvType::K8S => Some(DEFAULT_MAX_CONFIG_FILE_SIZE),
+        None => None,
+    };
+
     Ok(DeploymentEnvironmentSpec {
         env_type,
         ingress,
         registry,
+        mirror_extra_services: yaml.mirror_extra_services.unwrap_or(false),
+        bundle_repo,
+        max_config_file_size,
         deployments,
+        root: root.to_path_buf(),
+    })
+}
+
+fn convert_bundle_repo(yaml: &BundleRepoSpecYaml) -> Result<BundleRepoSpec> {
+    let (provider, repo) = match (yaml.provider.as_str(), &yaml.repo, &yaml.repository) {
+        ("github", Some(repo), None) => {
+            if !repo.contains('/') {
+                return Err(anyhow!("bundle_repo.repo must be in the form owner/repo, got {}", repo));
+            }
+            (BundleRepoProvider::Github, repo.clone())
+        }
+        ("github", _, _) => return Err(anyhow!("bundle_repo with provider github takes repo: owner/repo, not repository")),
+        ("oci", None, Some(repository)) => {
+            crate::bundle_repo::oci::Reference::parse(repository).context("bundle_repo.repository")?;
+            (BundleRepoProvider::Oci, repository.clone())
+        }
+        ("oci", _, _) => return Err(anyhow!("bundle_repo with provider oci takes repository: registry/path, not repo")),
+        (other, _, _) => return Err(anyhow!("Unknown bundle_repo provider: {} (expected github or oci)", other)),
+    };
+    Ok(BundleRepoSpec {
+        provider,
+        repo,
+        tag_prefix: yaml.tag_prefix.clone(),
+        token_env: yaml.token_env.clone().unwrap_or_else(|| crate::bundle_repo::default_token_env(provider).to_string()),
+        public_key: yaml.public_key.as_deref().map(crate::bundle_repo::integrity::parse_public_key).transpose()
+            .context("bundle_repo.public_key")?,
+        timeout: match &yaml.timeout {
+            Some(timeout) => crate::spec::parse_duration_secs(timeout)
+                .filter(|secs| *secs > 0)
+                .map(std::time::Duration::from_secs)
+                .ok_or_else(|| anyhow!("bundle_repo.timeout must be a duration like 90s or 2m, got {}", timeout))?,
+            None => crate::bundle_repo::DEFAULT_TIMEOUT,
+        },
+        retries: yaml.retries.unwrap_or(crate::bundle_repo::DEFAULT_RETRIES),
     })
 }
 
-fn any_service_has_working_dir(deployments: &HashMap<String, DeploymentSpecYaml>) -> bool {
+fn any_service_has_working_dir(deployments: &BTreeMap<String, DeploymentSpecYaml>) -> bool {
     deployments.values().any(|d| {
         d.services
             .as_ref()
@@ -124,41 +200,129 @@ fn any_service_has_working_dir(deployments: &HashMap<String, DeploymentSpecYaml>
 }
 
 fn convert_ingress(yaml: IngressSpecYaml, env_type: &DeploymentEnvTypeYaml) -> Result<IngressSpec> {
+    // A wildcard stands for the leftmost label only, e.g. `*.tenant.example.com`
+    for domain in yaml.hosts.values().flat_map(|h| match h {
+        HostSpecYaml::Single(s) => std::slice::from_ref(s),
+        HostSpecYaml::Multiple(v) => v.as_slice(),
+        HostSpecYaml::Detailed(d) => d.domains.as_slice(),
+    }).filter(|d| d.contains('*')) {
+        if !domain.starts_with("*.") || domain[2..].contains('*') || !domain[2..].contains('.') {
+            return Err(anyhow!("Wildcard domain {} must have `*` as its leftmost label only, e.g. *.tenant.example.com", domain));
+        }
+        if matches!(env_type, DeploymentEnvTypeYaml::Local) {
+            return Err(anyhow!("Wildcard domain {} is not supported for local environments", domain));
+        }
+    }
+
     let mut hosts = Vec::new();
     for (name, host) in yaml.hosts {
         match host {
-            HostSpecYaml::Single(s) => hosts.push(HostSpec { name, domain_names: vec![s] }),
-            HostSpecYaml::Multiple(v) => hosts.push(HostSpec { name, domain_names: v }),
+            HostSpecYaml::Single(s) => hosts.push(HostSpec { name, domain_names: vec![s], tls: true, default_backend: None }),
+            HostSpecYaml::Multiple(v) => hosts.push(HostSpec { name, domain_names: v, tls: true, default_backend: None }),
+            HostSpecYaml::Detailed(d) => {
+                if d.domains.is_empty() {
+                    return Err(anyhow!("Host {} has no domains", name));
+                }
+                let default_backend = match d.default_backend {
+                    None => None,
+                    Some(DefaultBackendYaml { service: Some(service), page: None }) => Some(DefaultBackendSpec::Service(service)),
+                    Some(DefaultBackendYaml { service: None, page: Some(page) }) => Some(DefaultBackendSpec::Page(page)),
+                    Some(_) => return Err(anyhow!("Host {}: default_backend must set one of service and page", name)),
+                };
+                hosts.push(HostSpec { name, domain_names: d.domains, tls: d.tls.unwrap_or(true), default_backend })
+            }
         }
     }
 
     let tls = match (yaml.tls, env_type) {
         (None, DeploymentEnvTypeYaml::Local) => None,
         (None, _) => return Err(anyhow!("Ingress TLS configuration is required for non-local environments. If you want to disable TLS explicitly set 'disable: true' in tls section")),
-        (Some(t), _) => {
-            // TODO: if this local env and tls is enabled rise error.
+        (Some(t), env_type) => {
+            let self_signed = t.self_signed.unwrap_or(false);
             if t.disable == Some(true) {
                 None
             } else {
-                let letsencrypt = t.letsencrypt.map(|le| LetsEncryptSpec {
-                    server: le.server,
-                    email: le.email,
-                });
+                match env_type {
+                    DeploymentEnvTypeYaml::Local => {
+                        if !self_signed || t.secret.is_some() || t.letsencrypt.is_some() {
+                            return Err(anyhow!("Local environment supports only 'self_signed: true' or 'disable: true' in tls section"));
+                        }
+                    }
+                    _ => {
+                        if self_signed {
+                            return Err(anyhow!("'self_signed' tls is only supported for Local environment"));
+                        }
+                    }
+                }
+                let letsencrypt = t.letsencrypt.map(convert_letsencrypt).transpose()?;
+                if letsencrypt.is_some() && t.secret.is_some() {
+                    return Err(anyhow!("TLS sets both 'secret' and 'letsencrypt'; use one of them"));
+                }
                 Some(IngressTlsSpec {
                     secret: t.secret,
                     letsencrypt,
+                    self_signed,
                 })
             }
         }
     };
 
+    let mut redirects = Vec::new();
+    for (from, redirect) in yaml.redirects.unwrap_or_default() {
+        let (to, preserve_path) = match redirect {
+            RedirectSpecYaml::Target(to) => (to, true),
+            RedirectSpecYaml::Detailed(d) => (d.to, d.preserve_path.unwrap_or(true)),
+        };
+        if matches!(env_type, DeploymentEnvTypeYaml::Local) {
+            return Err(anyhow!("Redirect from {}: gateway redirects are not supported for local environments", from));
+        }
+        if let Some(host) = hosts.iter().find(|h| h.domain_names.contains(&from)) {
+            return Err(anyhow!("Redirect from {}: the domain is also a domain of host {}, which routes it to services", from, host.name));
+        }
+        if to.is_empty() || to.contains('/') || to == from {
+            return Err(anyhow!("Redirect from {}: target {:?} must be another domain, without a scheme or path", from, to));
+        }
+        redirects.push(RedirectSpec { from, to, preserve_path });
+    }
+
     Ok(IngressSpec {
         name: yaml.name,
         hosts,
         tls,
+        allow_nested_prefixes: yaml.allow_nested_prefixes.unwrap_or(false),
+        redirects,
     })
 }
 
+// The email and server are only used once certbot or cert-manager runs,
+// so mistakes in them are caught here
+fn convert_letsencrypt(yaml: LetsEncryptSpecYaml) -> Result<LetsEncryptSpec> {
+    let plausible_email = yaml.email.split_once('@').is_some_and(|(local, domain)| {
+        !local.is_empty() && !domain.contains('@')
+            && domain.contains('.') && !domain.starts_with('.') && !domain.ends_with('.')
+    }) && !yaml.email.chars().any(char::is_whitespace);
+    if !plausible_email {
+        return Err(anyhow!("Let's Encrypt email {:?} is not an email address", yaml.email));
+    }
+    if let Some(server) = &yaml.server {
+        let url = reqwest::Url::parse(server).map_err(|e| anyhow!("Let's Encrypt server {:?} is not a URL: {}", server, e))?;
+        if url.scheme() != "https" || url.host().is_none() {
+            return Err(anyhow!("Let's Encrypt server {:?} must be an https URL", server));
+        }
+    }
+    let dns01 = yaml.dns01.map(|d| {
+        if d.credentials.trim().is_empty() {
+            return Err(anyhow!("Let's Encrypt dns01 credentials must name the provider's credentials"));
+        }
+        let provider = match d.provider {
+            Dns01ProviderYaml::Cloudflare => Dns01Provider::Cloudflare,
+            Dns01ProviderYaml::Digitalocean => Dns01Provider::DigitalOcean,
+        };
+        Ok(Dns01Spec { provider, credentials: d.credentials })
+    }).transpose()?;
+    Ok(LetsEncryptSpec { server: yaml.server, email: yaml.email, dns01 })
+}
+
 fn convert_env_variables(yaml: &Option<DeploymentEnvVariablesYaml>, root: &Path) -> Result<Vec<spec::EnvVariable>> {
     match yaml {
         Some(DeploymentEnvVariablesYaml::FromEnvFile(env_file)) => env_loader::load_env_file(env_file),
@@ -173,7 +337,7 @@ fn convert_env_variables(yaml: &Option<DeploymentEnvVariablesYaml>, root: &Path)
 
 fn convert_env_entry(entry: &EnvVariableEntryYaml, root: &Path) -> Result<spec::EnvVariable> {
     match entry {
-        EnvVariableEntryYaml::Inline(s) => env_loader::parse_env_variable(s),
+        EnvVariableEntryYaml::Inline(s) => env_loader::parse_env_variable(s).context(format!("Invalid environment entry: {}", s)),
         EnvVariableEntryYaml::FromFile(map) => {
             if map.len() != 1 {
                 return Err(anyhow!(
@@ -183,6 +347,9 @@ fn convert_env_entry(entry: &EnvVariableEntryYaml, root: &Path) -> Result<spec::
             }
             let (name, source) = map.iter().next().unwrap();
             let file_path = root.join(&source.file);
+            if !file_path.is_file() {
+                return Err(MissingFile(format!("File {:?} with the value of env variable '{}' does not exist", file_path, name)).into());
+            }
             let value = fs::read_to_string(&file_path)
                 .with_context(|| format!("Failed to read value for env variable '{}' from {:?}", name, file_path))?;
             // Files commonly end with a trailing newline that is not part of the value.
@@ -190,16 +357,80 @@ fn convert_env_entry(entry: &EnvVariableEntryYaml, root: &Path) -> Result<spec::
             Ok(spec::EnvVariable {
                 name: name.clone(),
                 value,
+                sensitive: false,
             })
         }
     }
 }
 
-fn convert_deployment(name: String, yaml: &DeploymentSpecYaml, root: &Path, env_type: &DeploymentEnvTypeYaml) -> Result<DeploymentSpec> {
+// The deployment and those it extends, the farthest ancestor first
+fn inheritance_chain<'a>(name: &'a str, deployments: &'a BTreeMap<String, DeploymentSpecYaml>) -> Result<Vec<&'a DeploymentSpecYaml>> {
+    let mut names = vec![name];
+    let mut layers = vec![&deployments[name]];
+    while let Some(parent) = &layers[layers.len() - 1].extends {
+        if names.contains(&parent.as_str()) {
+            names.push(parent);
+            return Err(anyhow!("Deployments extend each other in a cycle: {}", names.join(" -> ")));
+        }
+        let deployment = deployments.get(parent)
+            .ok_or_else(|| anyhow!("extends {}, which is not a deployment of the env spec", parent))?;
+        names.push(parent);
+        layers.push(deployment);
+    }
+    layers.reverse();
+    Ok(layers)
+}
+
+// Settings of a deployment merged over those of the deployments it extends:
+// `secrets` and `configs` are merged by name, `services` entries field by
+// field, and any other setting a layer gives replaces the inherited one.
+// Environment lists are merged by variable name in `convert_deployment`.
+fn merge_deployments(layers: &[&DeploymentSpecYaml]) -> Result<DeploymentSpecYaml> {
+    use serde_yaml::{Mapping, Value};
+    let mut merged = Mapping::new();
+    for layer in layers {
+        let Value::Mapping(fields) = serde_yaml::to_value(layer)? else { continue };
+        for (key, value) in fields.into_iter().filter(|(_, v)| !v.is_null()) {
+            let inherited = merged.entry(key.clone()).or_insert_with(|| Value::Mapping(Mapping::new()));
+            match (key.as_str(), inherited, value) {
+                (Some("secrets" | "configs"), Value::Mapping(inherited), Value::Mapping(entries)) => inherited.extend(entries),
+                (Some("services"), Value::Mapping(inherited), Value::Mapping(entries)) => {
+                    for (service, entry) in entries {
+                        let Value::Mapping(entry) = entry else { continue };
+                        let Value::Mapping(fields) = inherited.entry(service).or_insert_with(|| Value::Mapping(Mapping::new())) else { continue };
+                        fields.extend(entry.into_iter().filter(|(_, v)| !v.is_null()));
+                    }
+                }
+                (_, inherited, value) => *inherited = value,
+            }
+        }
+    }
+    Ok(serde_yaml::from_value(Value::Mapping(merged))?)
+}
+
+// Variables of each layer, a later layer's value replacing an earlier one of
+// the same name
+fn layered_env_variables(layers: &[&DeploymentSpecYaml], root: &Path, field: impl Fn(&DeploymentSpecYaml) -> &Option<DeploymentEnvVariablesYaml>) -> Result<Vec<spec::EnvVariable>> {
+    let mut variables = convert_env_variables(field(layers[0]), root)?;
+    for layer in &layers[1..] {
+        for var in convert_env_variables(field(layer), root)? {
+            match variables.iter_mut().find(|v| v.name == var.name) {
+                Some(existing) => *existing = var,
+                None => variables.push(var),
+            }
+        }
+    }
+    Ok(variables)
+}
+
+fn convert_deployment(name: String, yaml: &DeploymentSpecYaml, layers: &[&DeploymentSpecYaml], ingress: &IngressSpec, root: &Path, env_type: &DeploymentEnvTypeYaml) -> Result<DeploymentSpec> {
     let secrets_folder = yaml.secrets_folder.as_deref().map(|s| root.join(s));
-    let application = convert_deployment_app(&yaml.application)?;
-    let environment = convert_env_variables(&yaml.environment, root)?;
-    let mut undockerized_environment = convert_env_variables(&yaml.undockerized_environment, root)?;
+    let primary_host = convert_primary_host(yaml.primary_host.as_ref(), ingress)?;
+    let application = convert_deployment_app(yaml.application.as_ref().ok_or_else(|| anyhow!("application is required"))?, root)?;
+    let environment = layered_env_variables(layers, root, |l| &l.environment)
+        .context("environment")?;
+    let mut undockerized_environment = layered_env_variables(layers, root, |l| &l.undockerized_environment)
+        .context("undockerized_environment")?;
 
     // For local runs, a `.env.local` file in the project root overrides
     // `undockerized_environment` variables, letting each developer tweak the
@@ -222,21 +453,9 @@ fn convert_deployment(name: String, yaml: &DeploymentSpecYaml, root: &Path, env_
     let configs = if let Some(conf) = &yaml.configs {
         let mut specs = Vec::new();
         for (k, v) in conf {
-            let path = root.join(Path::new(&v));
-            let files = if path.is_dir() {
-                let mut files = Vec::new();
-                for entry in fs::read_dir(path).with_context(|| format!("Failed to read config directory {}", v))? {
-                    let entry = entry?;
-                    let p = entry.path();
-                    if p.is_file() {
-                        files.push(p.to_string_lossy().to_string());
-                    }
-                }
-                files
-            } else {
-                return Err(anyhow!("Config path {} is not a directory", v));
-            };
-            specs.push(ConfigSpec { name: k.clone(), files });
+            let files = config_files(v, root).context(format!("config {}", k))?;
+            let template = matches!(v, ConfigPathsYaml::Detailed(d) if d.template == Some(true));
+            specs.push(ConfigSpec { name: k.clone(), files, template });
         }
         specs
     } else {
@@ -248,15 +467,34 @@ fn convert_deployment(name: String, yaml: &DeploymentSpecYaml, root: &Path, env_
         for (k, v) in sec {
             match v {
                 DeploymentSecretSpecExYaml::Detailed(v) => {
-                    if v.env.is_some() && v.file.is_some() {
-                        return Err(anyhow!("Secret {} cannot have both env and file sources", k));
+                    let sources = [v.env.is_some(), v.file.is_some(), v.generate.is_some()].iter().filter(|s| **s).count();
+                    if sources > 1 {
+                        return Err(anyhow!("Secret {} must have only one of env, file and generate sources", k));
                     }
+                    let stored = root.join(SECRET_STORE_DIR).join(&name).join(k);
                     let source = if let Some(env) = &v.env {
-                        DeploymentSecretSource::EnvVariable(env.clone())
+                        if matches!(env_type, DeploymentEnvTypeYaml::K8S) {
+                            DeploymentSecretSource::Cluster { env: env.clone(), name: secrets::k8s_secret_name(&application.name, k) }
+                        } else if stored.is_file() {
+                            DeploymentSecretSource::Store { env: env.clone(), path: stored }
+                        } else {
+                            DeploymentSecretSource::EnvVariable(env.clone())
+                        }
                     } else if let Some(file) = &v.file {
-                        DeploymentSecretSource::FilePath(file.clone())
+                        DeploymentSecretSource::FilePath(root.join(file).to_string_lossy().to_string())
+                    } else if let Some(generate) = &v.generate {
+                        let length = generate.length.unwrap_or(32);
+                        if !(1..=4096).contains(&length) {
+                            return Err(anyhow!("Secret {}: generate length must be between 1 and 4096, got {}", k, length));
+                        }
+                        let format = match generate.format.unwrap_or(GeneratedSecretFormatYaml::Hex) {
+                            GeneratedSecretFormatYaml::Hex => GeneratedSecretFormat::Hex,
+                            GeneratedSecretFormatYaml::Base64 => GeneratedSecretFormat::Base64,
+                            GeneratedSecretFormatYaml::Alnum => GeneratedSecretFormat::Alnum,
+                        };
+                        DeploymentSecretSource::Generated { deployment: name.clone(), length, format, state: secrets::state_file(root, &name) }
                     } else {
-                        return Err(anyhow!("Secret {} must have either env or file source", k));
+                        return Err(anyhow!("Secret {} must have one of env, file and generate sources", k));
                     };
                     list.push(DeploymentSecretSpec {
                         secret_name: k.clone(),
@@ -271,6 +509,9 @@ fn convert_deployment(name: String, yaml: &DeploymentSpecYaml, root: &Path, env_
                                 anyhow!("Secret '{}' has no value but secrets_folder is not configured", k)
                             })?;
                             let secret_path = folder.join(k);
+                            if !secret_path.is_file() {
+                                return Err(MissingFile(format!("Secret '{}' not found in secrets_folder, looked for {:?}", k, secret_path)).into());
+                            }
                             fs::read_to_string(&secret_path)
                                 .context(format!("Failed to read secret '{}' from {:?}", k, secret_path))?
                         }
@@ -292,15 +533,19 @@ fn convert_deployment(name: String, yaml: &DeploymentSpecYaml, root: &Path, env_
     } else {
         ResourcesSpec {
             replicas: 1,
-            requests: ResourceLimits { memory: DEFAULT_MEMORY.into(), cpu: DEFAULT_CPU.into() },
-            limits: ResourceLimits { memory: DEFAULT_MEMORY.into(), cpu: DEFAULT_CPU.into() },
+            requests: ResourceLimits { memory: DEFAULT_MEMORY, cpu: DEFAULT_CPU },
+            limits: ResourceLimits { memory: DEFAULT_MEMORY, cpu: DEFAULT_CPU },
         }
     };
 
     let services = if let Some(svcs) = &yaml.services {
-        let mut map = HashMap::new();
+        let mut map = BTreeMap::new();
         for (k, v) in svcs {
-            map.insert(k.clone(), convert_deployment_service(v, &defaults)?);
+            let service = convert_deployment_service(v, &defaults, root).context(format!("service \"{}\"", k))?;
+            if matches!(env_type, DeploymentEnvTypeYaml::Local) && (service.cors.is_some() || service.prefixes.iter().any(|p| p.cors.is_some())) {
+                return Err(anyhow!("service \"{}\": cors is not supported for local environments", k));
+            }
+            map.insert(k.clone(), service);
         }
         Some(map)
     } else {
@@ -308,7 +553,9 @@ fn convert_deployment(name: String, yaml: &DeploymentSpecYaml, root: &Path, env_
     };
 
     Ok(DeploymentSpec {
-        primary_host: yaml.primary_host.clone(),
+        extends: yaml.extends.clone(),
+        primary_host,
+        public_domain: yaml.public_domain.clone(),
         name,
         application,
         environment,
@@ -316,21 +563,115 @@ fn convert_deployment(name: String, yaml: &DeploymentSpecYaml, root: &Path, env_
         configs,
         secrets,
         defaults,
+        explicit_defaults: super::convert_resource_hints(yaml.defaults.as_ref().and_then(|d| d.resources.as_ref()), "defaults.resources")?,
         services,
+        maintenance: yaml.maintenance.unwrap_or(false),
     })
 }
 
-fn convert_deployment_app(yaml: &DeploymentAppSpecYaml) -> Result<DeploymentAppSpec> {
+// Files of a deployment config, by file name; a later path or inline file
+// replaces files of the same name from earlier ones.
+fn config_files(paths: &ConfigPathsYaml, root: &Path) -> Result<Vec<ConfigFileSpec>> {
+    let single;
+    let sources = match paths {
+        ConfigPathsYaml::Single(path) => {
+            single = [ConfigSourceYaml::Path(path.clone())];
+            single.as_slice()
+        }
+        ConfigPathsYaml::Multiple(sources) => sources.as_slice(),
+        ConfigPathsYaml::Detailed(details) => return config_files(&details.path, root),
+        ConfigPathsYaml::Inline(content) => {
+            single = [ConfigSourceYaml::Inline(content.clone())];
+            single.as_slice()
+        }
+    };
+    let mut files: BTreeMap<String, ConfigFileSpec> = BTreeMap::new();
+    for source in sources {
+        let v = match source {
+            ConfigSourceYaml::Path(path) => path,
+            ConfigSourceYaml::Inline(content) => {
+                for (name, content) in content {
+                    files.insert(name.clone(), ConfigFileSpec::Inline { name: name.clone(), content: content.clone() });
+                }
+                continue;
+            }
+        };
+        let (pattern, optional) = spec::config_entry_optional(v);
+        let paths = if spec::is_glob_pattern(pattern) {
+            let full = format!("{}/{}", glob::Pattern::escape(&root.to_string_lossy()), pattern);
+            let mut matched = Vec::new();
+            for path in glob::glob(&full).with_context(|| format!("Invalid config pattern {}", v))? {
+                matched.push(path.with_context(|| format!("Failed to read files matching config pattern {}", v))?);
+            }
+            if matched.is_empty() && !optional {
+                return Err(MissingFile(format!("Config pattern {} matches no files; append `?` if that is fine", v)).into());
+            }
+            matched
+        } else {
+            let path = root.join(Path::new(pattern));
+            if !path.exists() && !optional {
+                return Err(MissingFile(format!("Config path {} does not exist", v)).into());
+            }
+            vec![path]
+        };
+        let mut entries = Vec::new();
+        for path in paths {
+            if path.is_dir() {
+                entries.extend(spec_loader::config_dir_files(&path).with_context(|| format!("Failed to read config directory {}", path.display()))?);
+            } else if path.is_file() {
+                entries.push((path.file_name().unwrap_or_default().to_string_lossy().to_string(), path));
+            }
+        }
+        for (name, p) in entries {
+            let file = if name.contains('/') {
+                ConfigFileSpec::DirFile { name: name.clone(), path: p.to_string_lossy().to_string() }
+            } else {
+                ConfigFileSpec::PathRef(p.to_string_lossy().to_string())
+            };
+            // a file matched by several patterns is listed once
+            if files.get(&name) == Some(&file) {
+                continue;
+            }
+            if let Some(previous) = files.insert(name, file) {
+                log::debug!("Config file {:?} replaces {:?}", p, previous);
+            }
+        }
+    }
+    Ok(files.into_values().collect())
+}
+
+// A gateway with a single host needs no explicit choice
+fn convert_primary_host(primary_host: Option<&String>, ingress: &IngressSpec) -> Result<String> {
+    let hosts: Vec<&str> = ingress.hosts.iter().map(|h| h.name.as_str()).collect();
+    match (primary_host, hosts.as_slice()) {
+        (Some(host), _) if hosts.contains(&host.as_str()) => Ok(host.clone()),
+        (Some(host), _) => Err(anyhow!("primary_host {} is not a gateway host; gateway hosts: {}", host, hosts.join(", "))),
+        (None, [host]) => Ok(host.to_string()),
+        (None, []) => Err(anyhow!("primary_host is required, and the gateway has no hosts")),
+        (None, _) => Err(anyhow!("primary_host is required when the gateway has several hosts; gateway hosts: {}", hosts.join(", "))),
+    }
+}
+
+fn convert_deployment_app(yaml: &DeploymentAppSpecYaml, root: &Path) -> Result<DeploymentAppSpec> {
     let version = if let Some(v) = &yaml.version {
         Some(semver::VersionReq::parse(v)?)
     } else {
         None
     };
 
+    let mut extra = Vec::new();
+    for file in yaml.extra.iter().flatten() {
+        let path = root.join(file);
+        if !path.is_file() {
+            return Err(MissingFile(format!("Extra spec file {} does not exist (looked for {:?})", file, path)).into());
+        }
+        extra.push(path.to_string_lossy().to_string());
+    }
+
     Ok(DeploymentAppSpec {
         name: yaml.name.clone(),
         version,
-        extra: yaml.extra.clone().unwrap_or_default(),
+        extra,
     })
 }
 
@@ -338,80 +679,123 @@ fn convert_defaults(yaml: &DefaultsSpecYaml) -> Result<ResourcesSpec> {
     let replicas = yaml.replicas.unwrap_or(1);
     let (requests, limits) = if let Some(res) = &yaml.resources {
         (
-            convert_limits(res.requests.as_ref()),
-            convert_limits(res.limits.as_ref()),
+            convert_limits(res.requests.as_ref(), "defaults.resources.requests")?,
+            convert_limits(res.limits.as_ref(), "defaults.resources.limits")?,
         )
     } else {
         (
-            ResourceLimits { memory: DEFAULT_MEMORY.into(), cpu: DEFAULT_CPU.into() },
-            ResourceLimits { memory: DEFAULT_MEMORY.into(), cpu: DEFAULT_CPU.into() },
+            ResourceLimits { memory: DEFAULT_MEMORY, cpu: DEFAULT_CPU },
+            ResourceLimits { memory: DEFAULT_MEMORY, cpu: DEFAULT_CPU },
         )
     };
 
     Ok(ResourcesSpec { replicas, requests, limits })
 }
 
-fn convert_limits(yaml: Option<&ResourceLimitsYaml>) -> ResourceLimits {
-    if let Some(l) = yaml {
-        ResourceLimits {
-            memory: l.memory.clone().unwrap_or_else(|| DEFAULT_MEMORY.into()),
-            cpu: l.cpu.clone().unwrap_or_else(|| DEFAULT_CPU.into()),
-        }
-    } else {
-        ResourceLimits { memory: DEFAULT_MEMORY.into(), cpu: DEFAULT_CPU.into() }
-    }
+// `field` is where the limits are in the envspec, for errors
+fn convert_limits(yaml: Option<&ResourceLimitsYaml>, field: &str) -> Result<ResourceLimits> {
+    let hints = super::convert_limit_hints(yaml, field)?;
+    Ok(ResourceLimits {
+        memory: hints.memory.unwrap_or(DEFAULT_MEMORY),
+        cpu: hints.cpu.unwrap_or(DEFAULT_CPU),
+    })
 }
 
-fn convert_deployment_service(yaml: &DeploymentServiceSpecYaml, defaults: &ResourcesSpec) -> Result<DeploymentServiceSpec> {
-    let mut prefixes = if let Some(p) = &yaml.prefixes {
-        p.iter().map(|(k, v)| Prefix {
-            prefix: k.clone(),
-            strip: v.strip.unwrap_or(false),
-        }).collect()
-    } else {
-        Vec::new()
+fn convert_deployment_service(yaml: &DeploymentServiceSpecYaml, defaults: &ResourcesSpec, root: &Path) -> Result<DeploymentServiceSpec> {
+    let cors = yaml.cors.as_ref().map(convert_cors).transpose().context("cors")?;
+    let entries: Vec<(&String, Option<bool>, Option<&CorsSpecYaml>)> = match &yaml.prefixes {
+        None => Vec::new(),
+        Some(PrefixesYaml::List(list)) => list.iter().map(|p| (&p.path, p.strip, p.cors.as_ref())).collect(),
+        Some(PrefixesYaml::Map(map)) => map.iter().map(|(path, p)| (path, p.strip, p.cors.as_ref())).collect(),
     };
+    let mut prefixes: Vec<Prefix> = Vec::new();
+    for (path, strip, prefix_cors) in entries {
+        let prefix_cors = prefix_cors.map(convert_cors).transpose().context(format!("prefixes {}: cors", path))?;
+        prefixes.push(Prefix {
+            prefix: path.clone(),
+            strip: strip.unwrap_or(false),
+            cors: prefix_cors.or_else(|| cors.clone()),
+        });
+    }
 
     if let Some(prefix) = &yaml.prefix {
-        prefixes.push(Prefix { prefix: prefix.clone(), strip: yaml.strip_prefix.unwrap_or(true) });
+        prefixes.push(Prefix { prefix: prefix.clone(), strip: yaml.strip_prefix.unwrap_or(true), cors: cors.clone() });
+    }
+    // `/api` and `/api/` are the same location
+    for (i, prefix) in prefixes.iter().enumerate() {
+        if prefixes[..i].iter().any(|p| p.prefix.trim_end_matches('/') == prefix.prefix.trim_end_matches('/')) {
+            return Err(anyhow!("Prefix {} is listed more than once", prefix.prefix));
+        }
     }
 
     let resources = if let Some(res) = &yaml.resources {
         ResourcesSpec {
             replicas: yaml.replicas.unwrap_or(defaults.replicas),
-            requests: convert_limits(res.requests.as_ref()),
-            limits: convert_limits(res.limits.as_ref()),
+            requests: convert_limits(res.requests.as_ref(), "resources.requests")?,
+            limits: convert_limits(res.limits.as_ref(), "resources.limits")?,
         }
     } else {
         ResourcesSpec {
             replicas: yaml.replicas.unwrap_or(defaults.replicas),
-            requests: ResourceLimits {
-                memory: defaults.requests.memory.clone(),
-                cpu: defaults.requests.cpu.clone(),
-            },
-            limits: ResourceLimits {
-                memory: defaults.limits.memory.clone(),
-                cpu: defaults.limits.cpu.clone(),
-            },
+            requests: defaults.requests.clone(),
+            limits: defaults.limits.clone(),
         }
     };
 
+    let explicit_resources = super::convert_resource_hints(yaml.resources.as_ref(), "resources")?;
+
     let ports = super::parse_ports(&yaml.ports)?;
+    if let Some(image) = &yaml.image {
+        ImageRef::parse(image).context("image")?;
+    }
+    let environment = convert_env_variables(&yaml.environment, root).context("environment")?;
 
     Ok(DeploymentServiceSpec {
+        enabled: yaml.enabled.unwrap_or(true),
         variant: yaml.variant.clone(),
+        image: yaml.image.clone(),
         host: yaml.host.clone(),
         prefixes,
+        cors,
         resources,
+        explicit_resources,
         ports,
+        environment,
         working_dir: yaml.working_dir.clone(),
     })
 }
 
+// Browsers refuse credentialed responses that allow any origin, so that
+// combination would only fail at runtime
+fn convert_cors(yaml: &CorsSpecYaml) -> Result<CorsSpec> {
+    if yaml.origins.is_empty() {
+        return Err(anyhow!("origins must list at least one origin"));
+    }
+    for origin in &yaml.origins {
+        let plausible = origin == "*" || origin.split_once("://")
+            .is_some_and(|(scheme, host)| (scheme == "http" || scheme == "https") && !host.is_empty() && !host.contains('/'));
+        if !plausible {
+            return Err(anyhow!("origin {:?} must be `*` or a scheme and host, e.g. https://app.example.com", origin));
+        }
+    }
+    let credentials = yaml.credentials.unwrap_or(false);
+    if credentials && yaml.origins.iter().any(|o| o == "*") {
+        return Err(anyhow!("origin `*` cannot be combined with credentials: true, browsers reject such responses; list the allowed origins instead"));
+    }
+    Ok(CorsSpec {
+        origins: yaml.origins.clone(),
+        methods: yaml.methods.clone().unwrap_or_else(|| DEFAULT_CORS_METHODS.iter().map(|m| m.to_string()).collect()),
+        headers: yaml.headers.clone().unwrap_or_else(|| DEFAULT_CORS_HEADERS.iter().map(|h| h.to_string()).collect()),
+        credentials,
+        max_age: yaml.max_age.unwrap_or(DEFAULT_CORS_MAX_AGE),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::io::Write;
+    use std::path::PathBuf;
 
     fn local_env_yaml() -> DeploymentEnvironmentSpecYaml {
         let raw = r#"
@@ -449,7 +833,7 @@ deployments:
         writeln!(f, "DB_HOST=localhost").unwrap();
         writeln!(f, "EXTRA=value").unwrap();
 
-        let spec = convert_env_spec(local_env_yaml(), root.path(), None).unwrap();
+        let spec = convert_env_spec(local_env_yaml(), root.path()).unwrap();
         let vars = undockerized(&spec);
 
         let db = vars.iter().find(|v| v.name == "DB_HOST").unwrap();
@@ -488,7 +872,7 @@ deployments:
           - "8080:80"
 "#;
         let yaml: DeploymentEnvironmentSpecYaml = serde_yaml::from_str(raw).unwrap();
-        let spec = convert_env_spec(yaml, root.path(), None).unwrap();
+        let spec = convert_env_spec(yaml, root.path()).unwrap();
         let vars = undockerized(&spec);
 
         assert_eq!(vars.iter().find(|v| v.name == "PLAIN").unwrap().value, "value");
@@ -498,12 +882,324 @@ deployments:
         );
     }
 
+    #[test]
+    fn config_paths_are_merged_by_file_name() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("shared")).unwrap();
+        fs::create_dir_all(root.path().join("staging")).unwrap();
+        fs::write(root.path().join("shared/app.yaml"), "app").unwrap();
+        fs::write(root.path().join("shared/logging.yaml"), "shared").unwrap();
+        fs::write(root.path().join("staging/logging.yaml"), "staging").unwrap();
+
+        let spec_with = |configs: &str| {
+            let raw = format!("type: local\ngateway:\n  hosts:\n    web: localhost:8080\ndeployments:\n  staging:\n    primary_host: web\n    application:\n      name: app\n    configs:\n      app: {}\n", configs);
+            convert_env_spec(serde_yaml::from_str(&raw).unwrap(), root.path())
+        };
+        let spec = spec_with("[shared, staging/logging.yaml]").unwrap();
+        let files: Vec<PathBuf> = spec.deployments[0].configs[0].files.iter().map(|f| match f {
+            ConfigFileSpec::PathRef(path) | ConfigFileSpec::DirFile { path, .. } => PathBuf::from(path),
+            ConfigFileSpec::Inline { name, .. } | ConfigFileSpec::Bundled { name, .. } => PathBuf::from(name),
+        }).collect();
+        assert_eq!(files, [root.path().join("shared/app.yaml"), root.path().join("staging/logging.yaml")]);
+
+        // inline files merge with directories by name too
+        let spec = spec_with("[shared, {logging.yaml: inline}]").unwrap();
+        assert_eq!(spec.deployments[0].configs[0].files[1], ConfigFileSpec::Inline { name: "logging.yaml".into(), content: "inline".into() });
+
+        let err = spec_with("[shared, staging/missing.yaml]").unwrap_err();
+        assert!(format!("{:#}", err).contains("deployment \"staging\": config app: Config path staging/missing.yaml does not exist"), "{:#}", err);
+    }
+
+    #[test]
+    fn config_paths_accept_glob_patterns() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("shared")).unwrap();
+        for name in ["b.conf", "a.conf", "logo.png"] {
+            fs::write(root.path().join("shared").join(name), name).unwrap();
+        }
+
+        let files_of = |configs: &str| {
+            let raw = format!("type: local\ngateway:\n  hosts:\n    web: localhost:8080\ndeployments:\n  staging:\n    application:\n      name: app\n    configs:\n      app: {}\n", configs);
+            convert_env_spec(serde_yaml::from_str(&raw).unwrap(), root.path()).map(|spec| {
+                spec.deployments[0].configs[0].files.iter().map(|f| match f {
+                    ConfigFileSpec::PathRef(path) => Path::new(path).strip_prefix(root.path()).unwrap().to_string_lossy().to_string(),
+                    other => panic!("{:?}", other),
+                }).collect::<Vec<_>>()
+            }).map_err(|e| format!("{:#}", e))
+        };
+
+        assert_eq!(files_of("'shared/*.conf'"), Ok(vec!["shared/a.conf".to_string(), "shared/b.conf".to_string()]));
+        assert_eq!(files_of("['shared/a.*', 'shared/?.conf']"), Ok(vec!["shared/a.conf".to_string(), "shared/b.conf".to_string()]));
+        assert_eq!(files_of("['shared/*.conf', 'shared/*.yaml?', 'local.yaml?']"), files_of("'shared/*.conf'"));
+        assert_eq!(files_of("'shared/*.yaml'"), Err("deployment \"staging\": config app: Config pattern shared/*.yaml matches no files; append `?` if that is fine".to_string()));
+    }
+
     #[test]
     fn missing_env_local_leaves_undockerized_vars_unchanged() {
         let root = tempfile::tempdir().unwrap();
-        let spec = convert_env_spec(local_env_yaml(), root.path(), None).unwrap();
+        let spec = convert_env_spec(local_env_yaml(), root.path()).unwrap();
         let vars = undockerized(&spec);
         assert_eq!(vars.len(), 2);
         assert_eq!(vars.iter().find(|v| v.name == "DB_HOST").unwrap().value, "docker-db");
     }
+
+    #[test]
+    fn script_format_defaults_to_bash_and_is_docker_only() {
+        let root = tempfile::tempdir().unwrap();
+        let docker = |extra: &str| {
+            let raw = format!(r#"
+type: docker
+{}
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments: {{}}
+"#, extra);
+            serde_yaml::from_str::<DeploymentEnvironmentSpecYaml>(&raw).unwrap()
+        };
+
+        let spec = convert_env_spec(docker(""), root.path()).unwrap();
+        let DeploymentEnvType::Docker(docker_spec) = spec.env_type else { panic!("expected docker env") };
+        assert_eq!(docker_spec.script_format, ScriptFormat::Bash);
+
+        let spec = convert_env_spec(docker("script_format: both"), root.path()).unwrap();
+        let DeploymentEnvType::Docker(docker_spec) = spec.env_type else { panic!("expected docker env") };
+        assert_eq!(docker_spec.script_format, ScriptFormat::Both);
+
+        assert!(convert_env_spec(docker("script_format: zsh"), root.path()).is_err());
+
+        let mut local = local_env_yaml();
+        local.script_format = Some("powershell".to_string());
+        assert!(convert_env_spec(local, root.path()).is_err());
+    }
+
+    #[test]
+    fn self_signed_tls_is_local_only() {
+        let root = tempfile::tempdir().unwrap();
+        let tls = |raw: &str| Some(serde_yaml::from_str::<IngressTlsSpecYaml>(raw).unwrap());
+
+        let mut local = local_env_yaml();
+        local.gateway.as_mut().unwrap().tls = tls("self_signed: true");
+        let spec = convert_env_spec(local, root.path()).unwrap();
+        assert!(spec.ingress.tls.is_some_and(|t| t.self_signed));
+
+        let mut local = local_env_yaml();
+        local.gateway.as_mut().unwrap().tls = tls("secret: tls-secret");
+        assert!(convert_env_spec(local, root.path()).is_err());
+
+        let mut docker = local_env_yaml();
+        docker.env_type = Some(DeploymentEnvTypeYaml::Docker);
+        docker.gateway.as_mut().unwrap().tls = tls("self_signed: true");
+        assert!(convert_env_spec(docker, root.path()).is_err());
+    }
+
+    #[test]
+    fn letsencrypt_email_and_server_are_checked() {
+        let root = tempfile::tempdir().unwrap();
+        let convert = |tls: &str| {
+            let mut docker = local_env_yaml();
+            docker.env_type = Some(DeploymentEnvTypeYaml::Docker);
+            docker.gateway.as_mut().unwrap().tls = Some(serde_yaml::from_str::<IngressTlsSpecYaml>(tls).unwrap());
+            convert_env_spec(docker, root.path()).map(|_| ()).map_err(|e| e.to_string())
+        };
+
+        assert_eq!(convert("letsencrypt: { email: ops@example.com, server: 'https://acme-staging-v02.api.letsencrypt.org/directory' }"), Ok(()));
+        for email in ["ops", "ops@localhost", "@example.com", "ops@example.com.", "o ps@example.com"] {
+            assert_eq!(convert(&format!("letsencrypt: {{ email: '{}' }}", email)), Err(format!("Let's Encrypt email {:?} is not an email address", email)));
+        }
+        assert_eq!(convert("letsencrypt: { email: ops@example.com, server: 'http://acme.example.com' }"), Err("Let's Encrypt server \"http://acme.example.com\" must be an https URL".into()));
+        assert!(convert("letsencrypt: { email: ops@example.com, server: 'acme-v02' }").unwrap_err().contains("is not a URL"));
+        assert_eq!(convert("{ secret: tls, letsencrypt: { email: ops@example.com } }"), Err("TLS sets both 'secret' and 'letsencrypt'; use one of them".into()));
+    }
+
+    #[test]
+    fn primary_host_defaults_to_the_only_gateway_host() {
+        let root = tempfile::tempdir().unwrap();
+        let convert = |hosts: &str, primary_host: &str| {
+            let yaml = serde_yaml::from_str(&format!("type: local\ngateway:\n  hosts:\n{}deployments:\n  dev:\n{}    application:\n      name: shop\n", hosts, primary_host)).unwrap();
+            convert_env_spec(yaml, root.path()).map(|spec| spec.deployments[0].primary_host.clone()).map_err(|e| format!("{:#}", e))
+        };
+        let one = "    web: localhost:8080\n";
+        let two = "    web: localhost:8080\n    admin: localhost:8081\n";
+
+        assert_eq!(convert(one, ""), Ok("web".to_string()));
+        assert_eq!(convert(two, "    primary_host: admin\n"), Ok("admin".to_string()));
+        assert_eq!(convert(two, ""), Err("deployment \"dev\": primary_host is required when the gateway has several hosts; gateway hosts: admin, web".to_string()));
+        assert_eq!(convert(one, "    primary_host: api\n"), Err("deployment \"dev\": primary_host api is not a gateway host; gateway hosts: web".to_string()));
+    }
+
+    #[test]
+    fn prefixes_keep_the_order_of_a_list() {
+        let root = tempfile::tempdir().unwrap();
+        let convert = |service: &str| {
+            let yaml = serde_yaml::from_str(&format!("type: docker\ngateway:\n  hosts:\n    web: example.com\n  tls:\n    disable: true\ndeployments:\n  dev:\n    application:\n      name: shop\n    services:\n      api:\n        {}\n", service)).unwrap();
+            convert_env_spec(yaml, root.path()).map(|spec| {
+                spec.deployments[0].services.as_ref().unwrap()["api"].prefixes.iter().map(|p| (p.prefix.clone(), p.strip)).collect::<Vec<_>>()
+            }).map_err(|e| format!("{:#}", e))
+        };
+        let owned = |prefixes: &[(&str, bool)]| prefixes.iter().map(|(p, s)| (p.to_string(), *s)).collect::<Vec<_>>();
+
+        assert_eq!(convert("prefixes: [{ path: /v2, strip: true }, { path: /api }]"), Ok(owned(&[("/v2", true), ("/api", false)])));
+        assert_eq!(convert("prefixes: { /v2: { strip: true }, /api: {} }"), Ok(owned(&[("/api", false), ("/v2", true)])));
+        assert_eq!(convert("{ prefix: /api, prefixes: [{ path: /api/ }] }"), Err("deployment \"dev\": service \"api\": Prefix /api is listed more than once".to_string()));
+    }
+
+    #[test]
+    fn bundle_repo_requires_known_provider_and_owner_repo() {
+        let root = tempfile::tempdir().unwrap();
+        let repo = |raw: &str| Some(serde_yaml::from_str::<BundleRepoSpecYaml>(raw).unwrap());
+
+        let mut yaml = local_env_yaml();
+        yaml.bundle_repo = repo("{ provider: github, repo: acme/shop, tag_prefix: v }");
+        let spec = convert_env_spec(yaml, root.path()).unwrap();
+        let bundle_repo = spec.bundle_repo.unwrap();
+        assert_eq!(bundle_repo.provider, BundleRepoProvider::Github);
+        assert_eq!(bundle_repo.tag_prefix.as_deref(), Some("v"));
+        assert_eq!(bundle_repo.token_env, "GITHUB_TOKEN");
+
+        let mut yaml = local_env_yaml();
+        yaml.bundle_repo = repo("{ provider: github, repo: acme/shop, token_env: GH_BUNDLE_TOKEN }");
+        assert_eq!(convert_env_spec(yaml, root.path()).unwrap().bundle_repo.unwrap().token_env, "GH_BUNDLE_TOKEN");
+
+        let mut yaml = local_env_yaml();
+        yaml.bundle_repo = repo("{ provider: gitlab, repo: acme/shop }");
+        assert!(convert_env_spec(yaml, root.path()).is_err());
+
+        let mut yaml = local_env_yaml();
+        yaml.bundle_repo = repo("{ provider: github, repo: shop }");
+        assert!(convert_env_spec(yaml, root.path()).is_err());
+
+        let mut yaml = local_env_yaml();
+        yaml.bundle_repo = repo("{ provider: oci, repository: registry.example.com/acme/shop, timeout: 2m, retries: 5 }");
+        let bundle_repo = convert_env_spec(yaml, root.path()).unwrap().bundle_repo.unwrap();
+        assert_eq!((bundle_repo.provider, bundle_repo.repo.as_str(), bundle_repo.token_env.as_str()), (BundleRepoProvider::Oci, "registry.example.com/acme/shop", "OCI_TOKEN"));
+        assert_eq!((bundle_repo.timeout.as_secs(), bundle_repo.retries), (120, 5));
+
+        for raw in ["{ provider: github, repo: acme/shop, timeout: soon }", "{ provider: oci, repo: acme/shop }", "{ provider: oci, repository: acme/shop }", "{ provider: github, repository: registry.example.com/acme/shop }"] {
+            let mut yaml = local_env_yaml();
+            yaml.bundle_repo = repo(raw);
+            assert!(convert_env_spec(yaml, root.path()).is_err(), "{}", raw);
+        }
+
+        let mut yaml = local_env_yaml();
+        yaml.bundle_repo = repo("{ provider: github, repo: acme/shop, public_key: c2hvcnQ= }");
+        let err = format!("{:#}", convert_env_spec(yaml, root.path()).unwrap_err());
+        assert!(err.contains("bundle_repo.public_key: Expected a 32-byte ed25519 key, got 5 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn stored_secret_backs_env_source() {
+        let root = tempfile::tempdir().unwrap();
+        let raw = |env: &str| format!("{{ db_password: {{ env: {} }} }}", env);
+
+        let mut yaml = local_env_yaml();
+        yaml.deployments.get_mut("app_local").unwrap().secrets = Some(serde_yaml::from_str(&raw("DB_PASSWORD")).unwrap());
+        let spec = convert_env_spec(yaml, root.path()).unwrap();
+        assert!(matches!(spec.deployments[0].secrets[0].source, DeploymentSecretSource::EnvVariable(_)));
+
+        let store = root.path().join(SECRET_STORE_DIR).join("app_local");
+        fs::create_dir_all(&store).unwrap();
+        fs::write(store.join("db_password"), "stored").unwrap();
+
+        let mut yaml = local_env_yaml();
+        yaml.deployments.get_mut("app_local").unwrap().secrets = Some(serde_yaml::from_str(&raw("DB_PASSWORD")).unwrap());
+        let spec = convert_env_spec(yaml, root.path()).unwrap();
+        let DeploymentSecretSource::Store { env, path } = &spec.deployments[0].secrets[0].source else { panic!("expected stored secret") };
+        assert_eq!(env, "DB_PASSWORD");
+        assert_eq!(fs::read_to_string(path).unwrap(), "stored");
+    }
+
+    #[test]
+    fn deployments_inherit_from_the_deployment_they_extend() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml: DeploymentEnvironmentSpecYaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+    staging: staging.example.com
+  tls:
+    disable: true
+deployments:
+  base:
+    primary_host: web
+    application:
+      name: shop
+    environment:
+      - LOG_LEVEL=info
+      - QUEUE=jobs
+    secrets:
+      db_password:
+        env: DB_PASSWORD
+      api_key:
+        env: API_KEY
+    defaults:
+      replicas: 2
+    services:
+      api:
+        prefix: /api
+        replicas: 3
+  prod:
+    extends: base
+    environment:
+      - QUEUE=prod-jobs
+      - SENTRY=on
+    secrets:
+      api_key:
+        file: ./prod.key
+    services:
+      api:
+        replicas: 5
+  staging:
+    extends: prod
+    primary_host: staging
+    environment:
+      - LOG_LEVEL=debug
+    defaults:
+      replicas: 1
+"#).unwrap();
+        let spec = convert_env_spec(yaml, root.path()).unwrap();
+        let deployment = |name: &str| spec.deployments.iter().find(|d| d.name == name).unwrap();
+        let env = |name: &str| deployment(name).environment.iter().map(|v| format!("{}={}", v.name, v.value)).collect::<Vec<_>>();
+
+        // child over parent
+        let prod = deployment("prod");
+        assert_eq!((prod.extends.as_deref(), prod.primary_host.as_str(), prod.application.name.as_str()), (Some("base"), "web", "shop"));
+        assert_eq!(env("prod"), ["LOG_LEVEL=info", "QUEUE=prod-jobs", "SENTRY=on"]);
+        let sources: Vec<String> = prod.secrets.iter().map(|s| format!("{}: {}", s.secret_name, crate::secrets::describe_source(&s.source))).collect();
+        assert_eq!(sources[0], "api_key: file ".to_string() + &root.path().join("./prod.key").to_string_lossy());
+        assert_eq!(sources[1], "db_password: env DB_PASSWORD");
+        let api = &prod.services.as_ref().unwrap()["api"];
+        assert_eq!((api.prefixes[0].prefix.as_str(), api.resources.replicas), ("/api", 5));
+        assert_eq!(prod.defaults.replicas, 2);
+
+        // three levels
+        let staging = deployment("staging");
+        assert_eq!(staging.primary_host, "staging");
+        assert_eq!(env("staging"), ["LOG_LEVEL=debug", "QUEUE=prod-jobs", "SENTRY=on"]);
+        assert_eq!(staging.secrets.len(), 2);
+        assert_eq!(staging.services.as_ref().unwrap()["api"].resources.replicas, 5);
+        assert_eq!(staging.defaults.replicas, 1);
+
+        let cyclic: DeploymentEnvironmentSpecYaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments:
+  a:
+    extends: c
+  b:
+    extends: a
+  c:
+    extends: b
+    primary_host: web
+"#).unwrap();
+        let err = convert_env_spec(cyclic, root.path()).unwrap_err();
+        assert_eq!(format!("{:#}", err), "deployment \"a\": Deployments extend each other in a cycle: a -> c -> b -> a");
+    }
 }
@@ -1,23 +1,77 @@
 mod app;
 mod env;
 
-pub use app::convert_app_spec;
+pub use app::{convert_app_spec, set_lenient_names};
 pub use env::convert_env_spec;
 
-use anyhow::{Result, anyhow};
-use crate::spec::ServicePort;
+use anyhow::{Context, Result, anyhow};
+use crate::quantities::{Cpu, Memory};
+use crate::spec::{LimitHints, PortProtocol, ResourceHints, ServicePort};
+use crate::spec_yaml::{ResourceLimitsYaml, ResourcesSpecYaml};
+
+// `field` is where the resources are in the spec, for errors
+fn convert_resource_hints(yaml: Option<&ResourcesSpecYaml>, field: &str) -> Result<ResourceHints> {
+    let Some(res) = yaml else {
+        return Ok(ResourceHints::default());
+    };
+    Ok(ResourceHints {
+        requests: convert_limit_hints(res.requests.as_ref(), &format!("{}.requests", field))?,
+        limits: convert_limit_hints(res.limits.as_ref(), &format!("{}.limits", field))?,
+    })
+}
+
+fn convert_limit_hints(yaml: Option<&ResourceLimitsYaml>, field: &str) -> Result<LimitHints> {
+    let Some(l) = yaml else {
+        return Ok(LimitHints::default());
+    };
+    Ok(LimitHints {
+        memory: l.memory.as_deref().map(Memory::parse).transpose().context(format!("{}.memory", field))?,
+        cpu: l.cpu.as_deref().map(Cpu::parse).transpose().context(format!("{}.cpu", field))?,
+    })
+}
 
 fn parse_ports(ports: &Option<Vec<String>>) -> Result<Vec<ServicePort>> {
     let Some(ports_yaml) = ports else {
         return Ok(Vec::new());
     };
     ports_yaml.iter().map(|s| {
-        let (ext_str, int_str) = s.split_once(':')
-            .ok_or_else(|| anyhow!("Invalid port format '{}'. Expected format 'external:internal'", s))?;
-        let external = ext_str.parse::<u16>()
-            .map_err(|_| anyhow!("Invalid external port '{}' in '{}'", ext_str, s))?;
-        let internal = int_str.parse::<u16>()
-            .map_err(|_| anyhow!("Invalid internal port '{}' in '{}'", int_str, s))?;
-        Ok(ServicePort { external, internal })
+        let (mapping, protocol) = match s.split_once('/') {
+            None => (s.as_str(), PortProtocol::Tcp),
+            Some((mapping, "tcp")) => (mapping, PortProtocol::Tcp),
+            Some((mapping, "udp")) => (mapping, PortProtocol::Udp),
+            Some((_, other)) => return Err(anyhow!("Invalid port protocol '{}' in '{}'. Expected tcp or udp", other, s)),
+        };
+        let (ext_str, int_str) = match mapping.split_once(':') {
+            Some((ext_str, int_str)) => (Some(ext_str), int_str),
+            None => (None, mapping),
+        };
+        let parse = |port: &str, kind: &str| match port.parse::<u64>() {
+            Ok(number @ 1..=65535) => Ok(number as u16),
+            Ok(_) => Err(anyhow!("Invalid {} port '{}' in '{}': ports are 1-65535", kind, port, s)),
+            Err(_) => Err(anyhow!("Invalid {} port '{}' in '{}'", kind, port, s)),
+        };
+        let external = ext_str.map(|ext_str| parse(ext_str, "external")).transpose()?;
+        let internal = parse(int_str, "internal")?;
+        Ok(ServicePort { external, internal, protocol })
     }).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ports_take_an_optional_protocol() {
+        let ports = parse_ports(&Some(vec!["80:8080".into(), "5000:5000/udp".into(), "53:53/tcp".into(), "9090".into()])).unwrap();
+        let mappings: Vec<String> = ports.iter().map(|p| p.mapping()).collect();
+        assert_eq!(mappings, vec!["80:8080", "5000:5000/udp", "53:53", "9090"]);
+        assert_eq!((ports[3].external, ports[3].service_port()), (None, 9090));
+        assert_eq!(ports[1].protocol, PortProtocol::Udp);
+
+        let err = parse_ports(&Some(vec!["5000:5000/sctp".into()])).unwrap_err();
+        assert!(err.to_string().contains("Expected tcp or udp"), "{}", err);
+        for (port, expected) in [("0:8080", "Invalid external port '0' in '0:8080': ports are 1-65535"), ("99999:80", "Invalid external port '99999' in '99999:80': ports are 1-65535"), ("80:x", "Invalid internal port 'x' in '80:x'")] {
+            assert_eq!(parse_ports(&Some(vec![port.into()])).unwrap_err().to_string(), expected);
+        }
+    }
+}
@@ -75,8 +75,8 @@ fn fetch_latest_release() -> Result<Release> {
 pub fn check_and_update(check_only: bool) -> Result<()> {
     let current = Version::parse(CURRENT_VERSION).expect("invalid package version");
 
-    println!("Current version: {}", current);
-    println!("Checking for updates...");
+    log::info!("Current version: {}", current);
+    log::info!("Checking for updates...");
 
     let release = fetch_latest_release()?;
     let latest = parse_version(&release.tag_name)?;
@@ -105,7 +105,7 @@ pub fn check_and_update(check_only: bool) -> Result<()> {
             )
         })?;
 
-    println!("Downloading {}...", asset_name);
+    log::info!("Downloading {}...", asset_name);
 
     let mut builder = reqwest::blocking::Client::new()
         .get(&asset.url)
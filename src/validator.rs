@@ -1,71 +1,461 @@
+use crate::diagnostics;
+use crate::error::Failure;
+use crate::local_ingress;
+use crate::resolver;
 use crate::spec::*;
-use anyhow::{Result, anyhow};
-use std::collections::HashSet;
-use std::fs;
+use crate::spec_loader;
+use anyhow::{Context, Result, anyhow};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
 
+// Findings of the envspec-only checks run by `simpled env verify`
+#[derive(Debug, Default)]
+pub struct EnvSpecReport {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Checks an env spec on its own, without an app spec: host references, TLS,
+/// registry entries, ports and deployment names. All findings are collected.
+pub fn check_env_spec(env_spec: &DeploymentEnvironmentSpec) -> EnvSpecReport {
+    let mut report = EnvSpecReport::default();
+    let is_local = env_spec.env_type == DeploymentEnvType::Local;
+    let hosts: HashSet<&String> = env_spec.ingress.hosts.iter().map(|h| &h.name).collect();
+
+    // Hosts
+    for host in &env_spec.ingress.hosts {
+        if host.domain_names.iter().all(|d| d.trim().is_empty()) {
+            report.errors.push(format!("Gateway host {} has no domain name", host.name));
+        }
+    }
+    let mut used_hosts = HashSet::new();
+    for deployment in &env_spec.deployments {
+        if hosts.contains(&deployment.primary_host) {
+            used_hosts.insert(&deployment.primary_host);
+            if let Some(domain) = &deployment.public_domain {
+                let host = env_spec.ingress.hosts.iter().find(|h| h.name == deployment.primary_host);
+                if !host.is_some_and(|h| h.domain_names.contains(domain)) {
+                    report.errors.push(format!("Deployment {} has public_domain {}, which is not a domain of host {}", deployment.name, domain, deployment.primary_host));
+                }
+            }
+        } else {
+            let mut names: Vec<&str> = hosts.iter().map(|h| h.as_str()).collect();
+            names.sort();
+            report.errors.push(format!("Deployment {} has primary_host {}, which is not a gateway host; gateway hosts: {}", deployment.name, deployment.primary_host, names.join(", ")));
+        }
+        let mut services: Vec<_> = deployment.services.iter().flatten().collect();
+        services.sort_by_key(|(name, _)| *name);
+        for (service_name, service) in services {
+            match &service.host {
+                Some(host) if !hosts.contains(host) => report.errors.push(format!(
+                    "Service {} of deployment {} uses host {}, which is not a gateway host", service_name, deployment.name, host)),
+                Some(host) => {
+                    used_hosts.insert(host);
+                }
+                None => {}
+            }
+        }
+    }
+    for host in &env_spec.ingress.hosts {
+        if !used_hosts.contains(&host.name) {
+            report.warnings.push(format!("Gateway host {} is not used by any deployment", host.name));
+        }
+    }
+
+    // TLS
+    match &env_spec.ingress.tls {
+        None if !is_local => report.warnings.push("TLS is disabled, the gateway serves plain HTTP".to_string()),
+        Some(tls) if tls.secret.is_none() && tls.letsencrypt.is_none() && !tls.self_signed => {
+            report.warnings.push(format!("TLS has neither 'secret' nor 'letsencrypt'; the certificate must already exist as {}--tls", env_spec.ingress.name));
+        }
+        _ => {}
+    }
+    let tls_domains = check_tls_domains(env_spec);
+    report.errors.extend(tls_domains.errors);
+    report.warnings.extend(tls_domains.warnings);
+
+    // Registry
+    if !is_local && env_spec.registry.is_empty() {
+        report.errors.push("Registry mapping is required for non-local environments".to_string());
+    }
+    let mut registry: Vec<_> = env_spec.registry.iter().collect();
+    registry.sort();
+    for (namespace, host) in registry {
+        if host.trim().is_empty() {
+            report.errors.push(format!("Registry entry {} has no host", namespace));
+        } else if host.contains("://") {
+            report.errors.push(format!("Registry entry {}: {} must be a host name without a scheme", namespace, host));
+        }
+    }
+
+    // Ports: `domain:port` of gateway hosts, and the ports deployments give services
+    for host in &env_spec.ingress.hosts {
+        for domain in &host.domain_names {
+            if let Some((_, port)) = domain.rsplit_once(':') {
+                if !matches!(port.parse::<u32>(), Ok(1..=65535)) {
+                    report.errors.push(format!("Gateway host {}: domain {} has an invalid port, ports are 1-65535", host.name, domain));
+                }
+            }
+        }
+    }
+    for deployment in &env_spec.deployments {
+        let mut services: Vec<_> = deployment.services.iter().flatten().collect();
+        services.sort_by_key(|(name, _)| *name);
+        let mut published: HashMap<(u16, PortProtocol), &str> = HashMap::new();
+        for (service_name, service) in services {
+            let mut internal = HashSet::new();
+            for port in &service.ports {
+                if !internal.insert((port.internal, port.protocol)) {
+                    report.errors.push(format!("Service {} of deployment {} lists container port {} twice", service_name, deployment.name, port.mapping()));
+                }
+                let Some(external) = port.external else { continue };
+                match published.insert((external, port.protocol), service_name) {
+                    Some(other) if other != service_name => report.errors.push(format!(
+                        "Services {} and {} of deployment {} both publish port {}", other, service_name, deployment.name, external)),
+                    Some(_) => report.errors.push(format!(
+                        "Service {} of deployment {} publishes port {} twice", service_name, deployment.name, external)),
+                    None => {}
+                }
+            }
+        }
+    }
+
+    // Deployment names end up in resource names, which are case-insensitive
+    let mut names = HashMap::new();
+    for deployment in &env_spec.deployments {
+        if let Some(other) = names.insert(deployment.name.to_lowercase(), &deployment.name) {
+            report.errors.push(format!("Deployments {} and {} differ only in case", other, deployment.name));
+        }
+    }
+
+    report
+}
+
+// Whether the gateway's certificate can cover its domains. Let's Encrypt
+// issues wildcard certificates only over DNS-01, so wildcards without it are errors; what an
+// existing `secret` covers is not known, so domains that one certificate for a
+// domain and its direct subdomains would not cover are only warned about.
+fn check_tls_domains(env_spec: &DeploymentEnvironmentSpec) -> EnvSpecReport {
+    let mut report = EnvSpecReport::default();
+    let Some(tls) = &env_spec.ingress.tls else {
+        return report;
+    };
+    // hosts that opted out of TLS need no certificate
+    let domains: Vec<&String> = env_spec.ingress.hosts.iter().filter(|h| h.tls).flat_map(|h| h.domain_names.iter())
+        .chain(env_spec.ingress.redirects.iter().map(|r| &r.from))
+        .collect();
+
+    if let Some(le) = tls.letsencrypt.as_ref().filter(|_| tls.secret.is_none()) {
+        // the nginx gateway's certbot only answers HTTP-01
+        let nginx = matches!(&env_spec.env_type, DeploymentEnvType::Docker(d) if d.ingress_type == DockerIngressType::Nginx);
+        if le.dns01.is_some() && nginx {
+            report.errors.push("Let's Encrypt dns01 is not supported by the nginx gateway; use gateway type traefik or a k8s environment".to_string());
+        } else if le.dns01.is_none() {
+            for domain in domains.iter().filter(|d| d.contains('*')) {
+                report.errors.push(format!(
+                    "Gateway domain {} is a wildcard, which Let's Encrypt does not issue over the HTTP-01 challenge; set tls.letsencrypt.dns01{}",
+                    domain, if nginx { " and use gateway type traefik" } else { "" }
+                ));
+            }
+        }
+    }
+
+    if let Some(secret) = &tls.secret {
+        let parent = |domain: &str| domain.split_once('.').map(|(_, rest)| rest.to_string()).filter(|rest| rest.contains('.'));
+        let covers = |apex: &str, domain: &str| domain == apex || parent(domain).as_deref() == Some(apex);
+        let candidates: Vec<String> = domains.iter().flat_map(|d| std::iter::once(d.to_string()).chain(parent(d))).collect();
+        let best = candidates.iter().max_by_key(|apex| (domains.iter().filter(|d| covers(apex, d)).count(), std::cmp::Reverse(apex.len())));
+        if let Some(apex) = best {
+            for domain in domains.iter().filter(|d| !covers(apex, d)) {
+                report.warnings.push(format!(
+                    "Gateway domain {} is not covered by a certificate for {} and *.{}; check that TLS secret {} includes it",
+                    domain, apex, apex, secret
+                ));
+            }
+        }
+    }
+    report
+}
+
 pub fn validate(env_spec: &DeploymentEnvironmentSpec, app_spec: &AppSpec, env_name: &str) -> Result<()> {
+    validate_deployment(env_spec, app_spec, env_name).context(Failure::Validation)?;
+    for warning in check_tls_domains(env_spec).warnings {
+        diagnostics::warn("tls_coverage", warning);
+    }
+    // Staging certificates are not trusted by browsers
+    let le_server = env_spec.ingress.tls.as_ref().and_then(|t| t.letsencrypt.as_ref()).and_then(|le| le.server.as_ref());
+    if let Some(server) = le_server.filter(|s| s.contains("acme-staging")) {
+        if env_name.to_lowercase().contains("prod") {
+            diagnostics::warn("letsencrypt_staging", format!("Deployment {} uses the Let's Encrypt staging server {}, whose certificates browsers do not trust", env_name, server));
+        }
+    }
+    for unused in unused_values(env_spec, app_spec, env_name) {
+        diagnostics::warn("unused_value", unused);
+    }
+    for warning in resource_warnings(env_spec, env_name) {
+        diagnostics::warn("requests_exceed_limits", warning);
+    }
+    for warning in app_request_warnings(env_spec, app_spec, env_name) {
+        diagnostics::warn("limits_below_app_requests", warning);
+    }
+    for (code, warning) in service_type_warnings(env_spec, app_spec, env_name) {
+        diagnostics::warn(code, warning);
+    }
+    for warning in image_override_warnings(env_spec, app_spec, env_name) {
+        diagnostics::warn("image_override", warning);
+    }
+    if env_spec.deployments.iter().any(|d| d.name == env_name && d.maintenance) {
+        diagnostics::warn("maintenance", format!(
+            "Deployment {} is in maintenance: the gateway answers every request on its domains with the maintenance page while its services keep running", env_name));
+    }
+    Ok(())
+}
+
+// An image set in the deployment bypasses the bundle; it is reported on
+// every run so a hotfix override is not forgotten there
+fn image_override_warnings(env_spec: &DeploymentEnvironmentSpec, app_spec: &AppSpec, env_name: &str) -> Vec<String> {
+    let Some(deployment) = env_spec.deployments.iter().find(|d| d.name == env_name) else {
+        return Vec::new();
+    };
+    app_spec.all_services()
+        .filter(|s| deployment.service_enabled(s))
+        .filter_map(|s| deployment.services.as_ref()?.get(&s.name)?.image.as_ref().map(|image| (s, image)))
+        .map(|(service, image)| format!(
+            "Image override active: deployment {} runs service {} from {} instead of the app bundle's image; remove the override once a release contains the fix",
+            env_name, service.name, image))
+        .collect()
+}
+
+// Ports that look wrong for the service type: a job publishing ports (fine
+// while debugging it), a public service without any, which the gateway then
+// reaches on port 80
+fn service_type_warnings(env_spec: &DeploymentEnvironmentSpec, app_spec: &AppSpec, env_name: &str) -> Vec<(&'static str, String)> {
+    let Some(deployment) = env_spec.deployments.iter().find(|d| d.name == env_name) else {
+        return Vec::new();
+    };
+    let mut warnings = Vec::new();
+    for service in app_spec.all_services().filter(|s| deployment.service_enabled(s)) {
+        let ports = deployment.service_ports(service);
+        match service.service_type {
+            ServiceType::Job => {
+                let published: Vec<String> = ports.iter().filter(|p| p.external.is_some()).map(|p| p.mapping()).collect();
+                if !published.is_empty() {
+                    warnings.push(("job_ports", format!("Job service {} publishes ports {} in deployment {}", service.name, published.join(", "), env_name)));
+                }
+            }
+            // local environments already require a published port
+            ServiceType::Public if ports.is_empty() && env_spec.env_type != DeploymentEnvType::Local => {
+                warnings.push(("public_without_ports", format!(
+                    "Public service {} declares no ports in deployment {}; the gateway routes to port 80", service.name, env_name)));
+            }
+            _ => {}
+        }
+    }
+    warnings
+}
+
+// Requests above limits: Kubernetes rejects such a pod, docker only applies the limit
+fn resource_warnings(env_spec: &DeploymentEnvironmentSpec, env_name: &str) -> Vec<String> {
+    let Some(deployment) = env_spec.deployments.iter().find(|d| d.name == env_name) else {
+        return Vec::new();
+    };
+    let services = deployment.services.iter().flatten().map(|(name, s)| (format!("service {}", name), &s.resources));
+    let mut warnings = Vec::new();
+    for (owner, resources) in std::iter::once(("defaults".to_string(), &deployment.defaults)).chain(services) {
+        let (requests, limits) = (&resources.requests, &resources.limits);
+        if requests.memory.bytes() > limits.memory.bytes() {
+            warnings.push(format!("Deployment {} {}: memory request {} exceeds limit {}", env_name, owner, requests.memory, limits.memory));
+        }
+        if requests.cpu.millicores() > limits.cpu.millicores() {
+            warnings.push(format!("Deployment {} {}: cpu request {} exceeds limit {}", env_name, owner, requests.cpu, limits.cpu));
+        }
+    }
+    warnings
+}
+
+// The appspec's requests are what its authors know a service needs; a
+// deployment limit below them starves or kills the service
+fn app_request_warnings(env_spec: &DeploymentEnvironmentSpec, app_spec: &AppSpec, env_name: &str) -> Vec<String> {
+    let Some(deployment) = env_spec.deployments.iter().find(|d| d.name == env_name) else {
+        return Vec::new();
+    };
+    let mut warnings = Vec::new();
+    for service in app_spec.all_services().filter(|s| deployment.service_enabled(s)) {
+        let limits = deployment.service_resources(service).limits;
+        let requests = &service.resources.requests;
+        if let Some(memory) = requests.memory.filter(|m| m.bytes() > limits.memory.bytes()) {
+            warnings.push(format!("Deployment {} service {}: memory limit {} is below the {} the appspec requests", env_name, service.name, limits.memory, memory));
+        }
+        if let Some(cpu) = requests.cpu.filter(|c| c.millicores() > limits.cpu.millicores()) {
+            warnings.push(format!("Deployment {} service {}: cpu limit {} is below the {} the appspec requests", env_name, service.name, limits.cpu, cpu));
+        }
+    }
+    warnings
+}
+
+/// Values a deployment provides that the appspec never uses: env variables it
+/// neither declares nor references, secrets and configs it does not declare.
+/// Such entries are usually left over from a rename and are reported as
+/// warnings. Services the appspec does not define are validation errors instead.
+pub fn unused_values(env_spec: &DeploymentEnvironmentSpec, app_spec: &AppSpec, env_name: &str) -> Vec<String> {
+    let Some(deployment) = env_spec.deployments.iter().find(|d| d.name == env_name) else {
+        return Vec::new();
+    };
+    let mut unused = Vec::new();
+
+    let declared_env_vars: HashSet<&str> = app_spec.environment.external.iter().map(|e| e.name.as_str())
+        .chain(app_spec.environment.optional.iter().map(|e| e.name.as_str()))
+        .collect();
+    let values: Vec<&String> = app_spec.environment.internal.iter().map(|v| &v.value)
+        .chain(app_spec.environment.relative.iter().map(|v| &v.relative_value))
+        .chain(app_spec.all_services().flat_map(|s| s.environment.iter()).filter_map(|opt| match opt {
+            ServiceEnvOption::WithValue(_, value) => Some(value),
+            _ => None,
+        }))
+        .collect();
+    let referenced_env_vars: HashSet<&str> = values.iter().flat_map(|v| resolver::referenced_names(v)).collect();
+    for var in deployment.environment.iter().chain(&deployment.undockerized_environment) {
+        if !declared_env_vars.contains(var.name.as_str()) && !referenced_env_vars.contains(var.name.as_str()) {
+            unused.push(format!("Deployment {} provides env variable {}, which the application does not use", env_name, var.name));
+        }
+    }
+
+    let app_secrets: HashSet<&String> = app_spec.secrets.iter().map(|s| &s.secret_name).collect();
+    for secret in deployment.secrets.iter().filter(|s| !app_secrets.contains(&s.secret_name)) {
+        unused.push(format!("Deployment {} provides secret {}, which the application does not declare", env_name, secret.secret_name));
+    }
+
+    let app_configs: HashSet<&String> = app_spec.configs.iter().map(|c| &c.name).collect();
+    for config in deployment.configs.iter().filter(|c| !app_configs.contains(&c.name)) {
+        unused.push(format!("Deployment {} provides config {}, which the application does not declare", env_name, config.name));
+    }
+    unused
+}
+
+// Validation failures, grouped by category in the order they were found
+#[derive(Debug, Default)]
+struct Problems {
+    categories: Vec<(&'static str, Vec<String>)>,
+}
+
+impl Problems {
+    fn add(&mut self, category: &'static str, message: String) {
+        match self.categories.iter_mut().find(|(c, _)| *c == category) {
+            Some((_, messages)) => messages.push(message),
+            None => self.categories.push((category, vec![message])),
+        }
+    }
+
+    // Ok when nothing was found, else one error listing every problem
+    fn into_result(self, env_name: &str) -> Result<()> {
+        let count: usize = self.categories.iter().map(|(_, messages)| messages.len()).sum();
+        if count == 0 {
+            return Ok(());
+        }
+        let mut report = format!("{} problem(s) in deployment {}:", count, env_name);
+        let mut number = 0;
+        for (category, messages) in &self.categories {
+            report.push_str(&format!("\n{}:", category));
+            for message in messages {
+                number += 1;
+                report.push_str(&format!("\n  {}. {}", number, message));
+            }
+        }
+        Err(anyhow!(report))
+    }
+}
+
+fn validate_deployment(env_spec: &DeploymentEnvironmentSpec, app_spec: &AppSpec, env_name: &str) -> Result<()> {
     let deployment = env_spec.deployments.iter()
         .find(|d| d.name == env_name)
         .ok_or_else(|| anyhow!("Deployment {} not found in envspec", env_name))?;
+    let mut problems = Problems::default();
 
     // Check application name
     if deployment.application.name != app_spec.name {
-         return Err(anyhow!("Deployment {} expects application {}, but appspec is for {}", 
+         problems.add("Application", format!("Deployment {} expects application {}, but appspec is for {}",
              env_name, deployment.application.name, app_spec.name));
     }
 
     // Check version
     if let Some(req) = &deployment.application.version {
         if !req.matches(&app_spec.version) {
-             return Err(anyhow!("App version {} does not satisfy deployment requirement {}", 
+             problems.add("Application", format!("App version {} does not satisfy deployment requirement {}",
                  app_spec.version, req));
         }
     }
 
+    // Services the deployment disables, or whose `when:` variable it leaves
+    // without a value, are left out; what only they use is not required
+    let disabled: Vec<&ServiceSpec> = app_spec.all_services().filter(|s| !deployment.service_enabled(s)).collect();
+    let enabled = || app_spec.all_services().filter(|s| deployment.service_enabled(s));
+    check_disabled_dependencies(app_spec, deployment, &disabled, &enabled().collect::<Vec<_>>(), env_name, &mut problems);
+
     // Check environment variables
+    // Variables consumed only by profiled services are not required: the profile
+    // may never be enabled for this deployment.
+    let profile_only_env_vars = app_spec.env_vars_used_only_by(|s| s.profile.is_some() || !deployment.service_enabled(s));
     let provided_env_vars: HashSet<&String> = deployment.environment.iter().map(|e| &e.name).collect();
-    let mut missing_env_vars = Vec::new();
     for env_var in &app_spec.environment.external {
-        if !provided_env_vars.contains(&env_var.name) && env_var.default.is_none() {
-             missing_env_vars.push(&env_var.name);
+        if !provided_env_vars.contains(&env_var.name)
+            && env_var.default.is_none()
+            && !profile_only_env_vars.contains(&env_var.name)
+        {
+             problems.add("Environment variables", format!("Environment variable {} required by application is not provided by deployment {}",
+                 env_var.name, env_name));
         }
     }
 
-    if !missing_env_vars.is_empty() {
-        return Err(anyhow!("Environment variables {:?} required by application are not provided by deployment {}", 
-             missing_env_vars, env_name));
-    }
-
     // Check secrets
     let provided_secrets: HashSet<&String> = deployment.secrets.iter().map(|c| &c.secret_name).collect();
+    let secret_users = |service: &ServiceSpec, name: &str| service.secrets.iter().any(|s| s.name == name)
+        || service.environment.iter().any(|opt| matches!(opt, ServiceEnvOption::WithValue(_, v) if resolver::secret_references(v).contains(&name)));
+    let globally_used_secrets: HashSet<&str> = app_spec.environment.internal.iter().map(|v| &v.value)
+        .chain(app_spec.environment.relative.iter().map(|v| &v.relative_value))
+        .flat_map(|v| resolver::secret_references(v))
+        .collect();
     for secret in &app_spec.secrets {
-        if !provided_secrets.contains(&secret.secret_name) {
-             return Err(anyhow!("Secret {} required by application is not provided by deployment {}", 
+        let name = secret.secret_name.as_str();
+        let only_disabled = disabled.iter().any(|s| secret_users(s, name))
+            && !enabled().any(|s| secret_users(s, name)) && !globally_used_secrets.contains(name);
+        if !provided_secrets.contains(&secret.secret_name) && !only_disabled {
+             problems.add("Secrets", format!("Secret {} required by application is not provided by deployment {}",
                  secret.secret_name, env_name));
         }
     }
     
     // Check configs
+    // A config the appspec gives inline content for, or whose files the app
+    // ships, needs nothing more from the deployment
     for config in &app_spec.configs {
-        let deployment_config = deployment.configs.iter().find(|c| c.name == config.name)
-             .ok_or_else(|| anyhow!("Config {} required by application is not provided by deployment {}", config.name, env_name))?;
+        let required: Vec<&String> = config.files.iter().filter_map(|f| match f {
+            ConfigFileSpec::PathRef(name) if !config.ships(config_entry_optional(name).0) => Some(name),
+            _ => None,
+        }).collect();
+        let mounted_by = |service: &&ServiceSpec| service.configs.iter().any(|c| c.config_name == config.name);
+        if disabled.iter().any(mounted_by) && !enabled().any(|s| mounted_by(&s)) {
+            continue;
+        }
+        let Some(deployment_config) = deployment.configs.iter().find(|c| c.name == config.name) else {
+            if !required.is_empty() || config.files.is_empty() {
+                problems.add("Configs", format!("Config {} required by application is not provided by deployment {}", config.name, env_name));
+            }
+            continue;
+        };
 
         let mut available_files = HashSet::new();
-        for file_path in &deployment_config.files {
+        for file in &deployment_config.files {
+             let file_path = match file {
+                 ConfigFileSpec::PathRef(path) => path,
+                 ConfigFileSpec::DirFile { name, .. } | ConfigFileSpec::Inline { name, .. } | ConfigFileSpec::Bundled { name, .. } => {
+                     available_files.insert(name.clone());
+                     continue;
+                 }
+             };
              let path = Path::new(file_path);
              if path.is_dir() {
-                 if let Ok(entries) = fs::read_dir(path) {
-                     for entry in entries {
-                         if let Ok(entry) = entry {
-                              let path = entry.path();
-                              if path.is_file() {
-                                  if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                                      available_files.insert(name.to_string());
-                                  }
-                              }
-                         }
-                     }
+                 if let Ok(entries) = spec_loader::config_dir_files(path) {
+                     available_files.extend(entries.into_iter().map(|(name, _)| name));
                  }
              } else {
                  if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
@@ -74,14 +464,62 @@ pub fn validate(env_spec: &DeploymentEnvironmentSpec, app_spec: &AppSpec, env_na
              }
         }
         
-        for required_file in &config.files {
-             if !available_files.contains(required_file) {
-                 return Err(anyhow!("Config {} requires file {}, but it is not provided by deployment config (checked paths: {:?})", 
-                      config.name, required_file, deployment_config.files));
+        for required_file in required {
+             let (name, optional) = config_entry_optional(required_file);
+             let provided = if is_glob_pattern(name) {
+                 match glob::Pattern::new(name) {
+                     Ok(pattern) => available_files.iter().any(|f| pattern.matches(f)),
+                     Err(e) => {
+                         problems.add("Configs", format!("Config {} has an invalid file pattern {}: {}", config.name, required_file, e));
+                         continue;
+                     }
+                 }
+             } else {
+                 available_files.contains(name)
+             };
+             if !provided && !optional {
+                 let checked: Vec<String> = deployment_config.files.iter().map(|f| match f {
+                     ConfigFileSpec::PathRef(path) | ConfigFileSpec::DirFile { path, .. } => path.clone(),
+                     ConfigFileSpec::Inline { name, .. } => format!("{} (inline)", name),
+                     ConfigFileSpec::Bundled { name, .. } => format!("{} (bundled)", name),
+                 }).collect();
+                 problems.add("Configs", format!("Config {} requires file {}, but it is not provided by deployment config (checked paths: {:?})",
+                      config.name, required_file, checked));
              }
         }
     }
 
+    // Every image pulled through the registry needs a mapping for its
+    // namespace; app images without one have always been used as they are
+    if env_spec.env_type != DeploymentEnvType::Local {
+        let mut unmapped: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for service in enabled().filter(|s| env_spec.mirrors(s)) {
+            let deployment_service = deployment.services.as_ref().and_then(|s| s.get(&service.name));
+            let selected = deployment_service.and_then(|ds| ds.variant.as_deref());
+            let image = match (deployment_service.and_then(|ds| ds.image.as_ref()), &service.image) {
+                (Some(image), _) => image,
+                (None, Some(ImageSpec::Exact(image))) => image,
+                (None, Some(ImageSpec::Variants(variants))) => match variants.iter().find(|v| v.variant_name == selected.unwrap_or("default")) {
+                    Some(variant) => &variant.image,
+                    None => continue,
+                },
+                (None, None) => continue,
+            };
+            if env_spec.registry_host(image).is_some() {
+                continue;
+            }
+            match image.split_once('/') {
+                Some((namespace, _)) => unmapped.entry(format!("namespace {}", namespace)).or_default().push(&service.name),
+                None if !service.is_app_service => unmapped.entry(format!("{} without a namespace", image)).or_default().push(&service.name),
+                None => {}
+            }
+        }
+        if !unmapped.is_empty() {
+            let list: Vec<String> = unmapped.iter().map(|(image, services)| format!("{} (service {})", image, services.join(", "))).collect();
+            problems.add("Registry", format!("No registry is mapped for {}; add them to `registry` or add a \"*\" entry", list.join(", ")));
+        }
+    }
+
     // Check services
     // Identify all available services in app (app_services + extra_services)
     let mut available_services = HashSet::new();
@@ -92,7 +530,144 @@ pub fn validate(env_spec: &DeploymentEnvironmentSpec, app_spec: &AppSpec, env_na
     if let Some(services) = &deployment.services {
         for (svc_name, _) in services {
             if !available_services.contains(svc_name) {
-                 return Err(anyhow!("Deployment configures service {} which is not defined in application", svc_name));
+                 problems.add("Services", format!("Deployment configures service {} which is not defined in application", svc_name));
+            }
+        }
+    }
+
+    // The image variant a deployment selects, `default` unless it sets one,
+    // must be declared by the service
+    for service in enabled() {
+        let selected = deployment.services.as_ref().and_then(|s| s.get(&service.name)).and_then(|ds| ds.variant.as_deref());
+        match (&service.image, selected) {
+            (Some(ImageSpec::Variants(variants)), selected) => {
+                let variant_name = selected.unwrap_or("default");
+                if !variants.iter().any(|v| v.variant_name == variant_name) {
+                    let available = variants.iter().map(|v| v.variant_name.as_str()).collect::<Vec<_>>().join(", ");
+                    problems.add("Services", match selected {
+                        Some(_) => format!("Deployment {} selects variant {} of service {}, which declares only: {}", env_name, variant_name, service.name, available),
+                        None => format!("Service {} has no 'default' variant and deployment {} selects none; set `variant` under the deployment's services to one of: {}", service.name, env_name, available),
+                    });
+                }
+            }
+            (_, Some(variant_name)) => {
+                problems.add("Services", format!("Deployment {} selects variant {} of service {}, which declares no variants", env_name, variant_name, service.name));
+            }
+            _ => {}
+        }
+    }
+
+    // Hosts a service is routed on, from the deployment or the appspec's `export`
+    let hosts: Vec<&str> = env_spec.ingress.hosts.iter().map(|h| h.name.as_str()).collect();
+    for service in enabled() {
+        let deployment_host = deployment.services.as_ref().and_then(|s| s.get(&service.name)).and_then(|ds| ds.host.as_ref());
+        let export_host = service.export.as_ref().and_then(|e| e.host.as_ref());
+        let references = [(deployment_host, format!("deployment {}", env_name)), (export_host, "its export in the appspec".to_string())];
+        for (host, source) in references.iter().filter_map(|(h, source)| h.map(|h| (h, source))) {
+            if !hosts.contains(&host.as_str()) {
+                problems.add("Routing", format!("Service {} uses host {} in {}, which is not a gateway host; gateway hosts: {}",
+                    service.name, host, source, hosts.join(", ")));
+            }
+        }
+    }
+    for error in check_tls_domains(env_spec).errors {
+        problems.add("TLS", error);
+    }
+
+    // Jobs run to completion: nothing routes to them and a second replica
+    // would run them twice
+    for service in enabled().filter(|s| matches!(s.service_type, ServiceType::Job)) {
+        let deployment_service = deployment.services.as_ref().and_then(|s| s.get(&service.name));
+        let export = service.export.as_ref();
+        if deployment_service.is_some_and(|ds| ds.host.is_some() || !ds.prefixes.is_empty()) {
+            problems.add("Services", format!("Job service {} is given a host or prefix in deployment {}; jobs are not routed through the gateway", service.name, env_name));
+        }
+        if export.is_some_and(|e| e.host.is_some() || e.prefix.is_some()) {
+            problems.add("Services", format!("Job service {} declares an export in the appspec; jobs are not routed through the gateway", service.name));
+        }
+        if let Some(replicas) = deployment_service.map(|ds| ds.resources.replicas).filter(|r| *r > 1) {
+            problems.add("Services", format!("Job service {} has {} replicas in deployment {}; a job runs once, set `replicas: 1`", service.name, replicas, env_name));
+        }
+    }
+
+    // A public service without a prefix gets no gateway route at all
+    for service in enabled().filter(|s| matches!(s.service_type, ServiceType::Public)) {
+        let (_, prefixes) = deployment.service_route(service);
+        if prefixes.is_empty() {
+            problems.add("Routing", format!(
+                "Public service {} has no route in deployment {}: set `export.prefix` in the appspec or `prefix` under the deployment's services",
+                service.name, env_name
+            ));
+        }
+    }
+
+    // A prefix inside another one on the same host makes routing depend on
+    // the gateway's rule order, unless the gateway orders them explicitly
+    if !env_spec.ingress.allow_nested_prefixes {
+        let mut routes: Vec<(String, String, &String)> = Vec::new();
+        for service in enabled().filter(|s| matches!(s.service_type, ServiceType::Public)) {
+            let (host, prefixes) = deployment.service_route(service);
+            routes.extend(prefixes.into_iter().map(|p| (host.clone(), p.prefix, &service.name)));
+        }
+        for (outer_host, outer, outer_service) in &routes {
+            for (inner_host, inner, inner_service) in &routes {
+                if outer_host == inner_host && is_nested_prefix(outer, inner) {
+                    problems.add("Routing", format!(
+                        "Prefix {} of service {} contains prefix {} of service {} on host {}: set `gateway.allow_nested_prefixes: true` to route the most specific prefix first",
+                        outer, outer_service, inner, inner_service, outer_host
+                    ));
+                }
+            }
+        }
+    }
+
+    // The local gateway reaches public services on localhost, so they need a
+    // TCP port published on the host; other services may only expose theirs.
+    if env_spec.env_type == DeploymentEnvType::Local {
+        for service in enabled().filter(|s| matches!(s.service_type, ServiceType::Public)) {
+            if !deployment.service_ports(service).iter().any(|p| p.external.is_some() && p.protocol == PortProtocol::Tcp) {
+                problems.add("Routing", format!("Public service {} needs a published TCP port (e.g. \"8080:80\") in Local environment", service.name));
+            }
+        }
+    }
+
+    // Ports the gateway publishes on the host cannot be published by a service too
+    let gateway_ports = match &env_spec.env_type {
+        DeploymentEnvType::Local => local_ingress::gateway_ports(&env_spec.ingress),
+        DeploymentEnvType::Docker(_) if env_spec.ingress.tls.is_some() => vec![80, 443],
+        DeploymentEnvType::Docker(_) => vec![80],
+        DeploymentEnvType::K8S => Vec::new(),
+    };
+    for service in enabled() {
+        for port in deployment.service_ports(service) {
+            if port.protocol == PortProtocol::Tcp && port.external.is_some_and(|p| gateway_ports.contains(&p)) {
+                problems.add("Ports", format!("Service {} of deployment {} publishes port \"{}\", which the gateway listens on",
+                    service.name, env_name, port.mapping()));
+            }
+        }
+    }
+
+    // Kubernetes takes the user as numeric runAsUser/runAsGroup
+    if env_spec.env_type == DeploymentEnvType::K8S {
+        for service in enabled() {
+            if let Some(user) = &service.user {
+                if parse_numeric_user(user).is_none() {
+                    problems.add("Services", format!("Service {} has user '{}', Kubernetes deployments need a numeric uid or uid:gid", service.name, user));
+                }
+            }
+        }
+    }
+
+    // Only local environments build images from source or mount source
+    // directories; the others pull images and must not see dev mounts.
+    if env_spec.env_type != DeploymentEnvType::Local {
+        for service in enabled() {
+            let overridden = deployment.services.as_ref().and_then(|s| s.get(&service.name)).is_some_and(|ds| ds.image.is_some());
+            if service.image.is_none() && !overridden {
+                problems.add("Services", format!("Service {} declares only 'build'; an 'image' is required outside local environments", service.name));
+            }
+            if !service.dev_volumes.is_empty() {
+                problems.add("Services", format!("Service {} declares 'dev_volumes', which are only allowed in local environments", service.name));
             }
         }
     }
@@ -100,24 +675,1040 @@ pub fn validate(env_spec: &DeploymentEnvironmentSpec, app_spec: &AppSpec, env_na
     // Validate service environment variable references
     let mut app_defined_env_vars = HashSet::new();
     for env in &app_spec.environment.external {
-        app_defined_env_vars.insert(&env.name);
+        app_defined_env_vars.insert(env.name.clone());
     }
     for env in &app_spec.environment.relative {
-        app_defined_env_vars.insert(&env.name);
+        app_defined_env_vars.insert(env.name.clone());
     }
     for env in &app_spec.environment.internal {
-        app_defined_env_vars.insert(&env.name);
+        app_defined_env_vars.insert(env.name.clone());
+    }
+    for service in app_spec.all_services() {
+        app_defined_env_vars.insert(service_env_var_name(&service.name, "HOST"));
+        app_defined_env_vars.insert(service_env_var_name(&service.name, "URL"));
     }
 
     for service in app_spec.all_services() {
-        for env_opt in &service.environment {
-            if let ServiceEnvOption::Simple(var_name) = env_opt {
-                if !app_defined_env_vars.contains(var_name) {
-                     return Err(anyhow!("Service {} references undefined environment variable {}", service.name, var_name));
+        let service_values = deployment.service_environment(&service.name);
+        let variants = match &service.image {
+            Some(ImageSpec::Variants(variants)) => variants.iter().map(|v| (format!(" variant {}", v.variant_name), &v.environment)).collect(),
+            _ => Vec::new(),
+        };
+        for (variant, environment) in [(String::new(), &service.environment)].into_iter().chain(variants) {
+            for env_opt in environment {
+                if let ServiceEnvOption::Simple(var_name) = env_opt {
+                    if !app_defined_env_vars.contains(var_name) && !service_values.iter().any(|v| &v.name == var_name) {
+                         problems.add("Environment references", format!("Service {}{} references undefined environment variable {}", service.name, variant, var_name));
+                    }
                 }
             }
         }
     }
 
-    Ok(())
+    // Config and secret mounts of a service must not shadow each other, and
+    // secrets passed as env variables must not clash with its other variables
+    let all_env_vars: Vec<&String> = app_spec.environment.external.iter().map(|e| &e.name)
+        .chain(app_spec.environment.optional.iter().map(|e| &e.name))
+        .chain(app_spec.environment.relative.iter().map(|e| &e.name))
+        .chain(app_spec.environment.internal.iter().map(|e| &e.name))
+        .collect();
+    for service in app_spec.all_services() {
+        let mounts: Vec<(String, &str)> = service.configs.iter()
+            .map(|c| (format!("config {}", c.config_name), c.mount_path.as_str()))
+            .chain(service.secrets.iter().filter_map(|s| match &s.mount {
+                SecretMount::FilePath(path) => Some((format!("secret {}", s.name), path.as_str())),
+                SecretMount::EnvVariable(_) => None,
+            }))
+            .collect();
+        for (i, (first, first_path)) in mounts.iter().enumerate() {
+            for (second, second_path) in &mounts[i + 1..] {
+                if let Some(overlap) = mount_overlap(first_path, second_path) {
+                    problems.add("Mounts", format!("Service {}: {} at {} {} {} at {}",
+                        service.name, first, first_path, overlap, second, second_path));
+                }
+            }
+        }
+
+        let mut env_names: HashSet<&String> = HashSet::new();
+        for opt in &service.environment {
+            match opt {
+                ServiceEnvOption::All => env_names.extend(all_env_vars.iter().copied()),
+                ServiceEnvOption::Simple(name) | ServiceEnvOption::WithValue(name, _) => { env_names.insert(name); }
+            }
+        }
+        let mut secret_env_names: HashMap<&String, &String> = HashMap::new();
+        for secret in &service.secrets {
+            let SecretMount::EnvVariable(var) = &secret.mount else { continue };
+            if let Some(other) = secret_env_names.insert(var, &secret.name) {
+                problems.add("Mounts", format!("Service {}: secrets {} and {} are both passed as env variable {}", service.name, other, secret.name, var));
+            } else if env_names.contains(var) {
+                problems.add("Mounts", format!("Service {}: secret {} is passed as env variable {}, which the service already receives", service.name, secret.name, var));
+            }
+        }
+    }
+
+    check_env_references(app_spec, deployment, &mut problems);
+
+    // `${secret:NAME}` must name a secret of the app, which the deployment
+    // was checked to provide above
+    let app_secrets: HashSet<&str> = app_spec.secrets.iter().map(|s| s.secret_name.as_str()).collect();
+    let values = app_spec.environment.internal.iter().map(|v| (&v.name, &v.value))
+        .chain(app_spec.environment.relative.iter().map(|v| (&v.name, &v.relative_value)))
+        .chain(app_spec.all_services().flat_map(|s| s.environment.iter()).filter_map(|opt| match opt {
+            ServiceEnvOption::WithValue(name, value) => Some((name, value)),
+            _ => None,
+        }));
+    for (name, value) in values {
+        for secret in resolver::secret_references(value).into_iter().filter(|s| !app_secrets.contains(s)) {
+            problems.add("Environment references", format!("Env variable {} references secret {}, which is not declared in the appspec's secrets", name, secret));
+        }
+    }
+
+    problems.into_result(env_name)
+}
+
+// An enabled service that reaches a disabled one through its
+// SERVICE_<NAME>_HOST/_URL, directly or through an app variable every service
+// gets, depends on it
+fn check_disabled_dependencies(app_spec: &AppSpec, deployment: &DeploymentSpec, disabled: &[&ServiceSpec], enabled: &[&ServiceSpec], env_name: &str, problems: &mut Problems) {
+    let env = &app_spec.environment;
+    let global: Vec<(&String, &String)> = env.internal.iter().map(|v| (&v.name, &v.value))
+        .chain(env.relative.iter().map(|v| (&v.name, &v.relative_value)))
+        .collect();
+    for service in disabled {
+        let explicitly_disabled = deployment.services.as_ref().and_then(|s| s.get(&service.name)).is_some_and(|ds| !ds.enabled);
+        let state = match deployment.service_skip_reason(service) {
+            Some(reason) if !explicitly_disabled => format!("skipped in deployment {} ({})", env_name, reason),
+            _ => format!("disabled in deployment {}", env_name),
+        };
+        let names = [service_env_var_name(&service.name, "HOST"), service_env_var_name(&service.name, "URL")];
+        let uses = |value: &str| resolver::referenced_names(value).iter().any(|n| names.iter().any(|name| name == n));
+        let variables: Vec<&str> = global.iter().filter(|(_, v)| uses(v)).map(|(name, _)| name.as_str()).collect();
+        if !variables.is_empty() {
+            problems.add("Services", format!("Service {} is {}, but app variables {} reference its {} or {}",
+                service.name, state, variables.join(", "), names[0], names[1]));
+            continue;
+        }
+        let dependents: Vec<&str> = enabled.iter()
+            .filter(|s| s.environment.iter().any(|opt| match opt {
+                ServiceEnvOption::Simple(name) => names.contains(name),
+                ServiceEnvOption::WithValue(_, value) => uses(value),
+                ServiceEnvOption::All => false,
+            }))
+            .map(|s| s.name.as_str())
+            .collect();
+        if !dependents.is_empty() {
+            let verb = if dependents.len() == 1 { "depends" } else { "depend" };
+            problems.add("Services", format!("Service {} is {}, but {} {} on it through {} or {}",
+                service.name, state, dependents.join(", "), verb, names[0], names[1]));
+        }
+    }
+}
+
+// `${NAME}` references of internal and relative variables and of services'
+// `NAME=value` entries must not form a cycle, and every name they need must
+// be one the resolver provides: a declared app variable or a service's
+// SERVICE_<NAME>_HOST/_URL. Caught here, the resolver's "Undefined variable"
+// never shows up without the name of the variable that referenced it.
+fn check_env_references(app_spec: &AppSpec, deployment: &DeploymentSpec, problems: &mut Problems) {
+    let env = &app_spec.environment;
+    // (label, name, template) of every templated value, app variables first
+    let mut nodes: Vec<(String, &str, &str)> = env.internal.iter().map(|v| (v.name.clone(), v.name.as_str(), v.value.as_str()))
+        .chain(env.relative.iter().map(|v| (v.name.clone(), v.name.as_str(), v.relative_value.as_str())))
+        .collect();
+    let app_templates = nodes.len();
+    let mut entry_services = Vec::new();
+    for service in app_spec.all_services() {
+        let variant = deployment.service_variant(service).map_or(&[][..], |v| &v.environment);
+        for opt in service.environment.iter().chain(variant) {
+            if let ServiceEnvOption::WithValue(name, value) = opt {
+                nodes.push((format!("{} (service {})", name, service.name), name, value));
+                entry_services.push((name.as_str(), service.name.as_str()));
+            }
+        }
+    }
+    let mut defined: HashSet<String> = env.external.iter().map(|e| e.name.clone())
+        .chain(env.optional.iter().map(|e| e.name.clone()))
+        .chain(nodes[..app_templates].iter().map(|(_, name, _)| name.to_string()))
+        .collect();
+    for service in app_spec.all_services() {
+        defined.insert(service_env_var_name(&service.name, "HOST"));
+        defined.insert(service_env_var_name(&service.name, "URL"));
+    }
+
+    // A name resolves to the app variable; only when there is none, a
+    // reference meant a service's own entry, which makes it part of a cycle
+    let targets = |name: &str| -> Vec<usize> {
+        match nodes[..app_templates].iter().position(|(_, n, _)| *n == name) {
+            Some(index) => vec![index],
+            None if defined.contains(name) => Vec::new(),
+            None => (app_templates..nodes.len()).filter(|&i| nodes[i].1 == name).collect(),
+        }
+    };
+
+    fn visit(index: usize, nodes: &[(String, &str, &str)], targets: &dyn Fn(&str) -> Vec<usize>, done: &mut HashSet<usize>, path: &mut Vec<usize>, cycles: &mut Vec<Vec<usize>>) {
+        if let Some(start) = path.iter().position(|&i| i == index) {
+            cycles.push(path[start..].to_vec());
+            return;
+        }
+        if !done.insert(index) {
+            return;
+        }
+        path.push(index);
+        for name in resolver::required_references(nodes[index].2) {
+            for target in targets(name) {
+                visit(target, nodes, targets, done, path, cycles);
+            }
+        }
+        path.pop();
+    }
+    let mut cycles = Vec::new();
+    let mut done = HashSet::new();
+    for index in 0..nodes.len() {
+        visit(index, &nodes, &targets, &mut done, &mut Vec::new(), &mut cycles);
+    }
+    let mut in_cycle = HashSet::new();
+    for cycle in &cycles {
+        in_cycle.extend(cycle.iter().copied());
+        let chain: Vec<&str> = cycle.iter().chain(cycle.first()).map(|&i| nodes[i].0.as_str()).collect();
+        problems.add("Environment references", format!("Env variables reference each other in a cycle: {}", chain.join(" -> ")));
+    }
+
+    let deployment_values: HashSet<&str> = deployment.environment.iter().map(|v| v.name.as_str()).collect();
+    for (index, (label, _, template)) in nodes.iter().enumerate() {
+        // a service's entries also see the values the deployment sets for it
+        let service_values = match index.checked_sub(app_templates) {
+            Some(entry) => deployment.service_environment(entry_services[entry].1),
+            None => &[],
+        };
+        let scoped = |name: &str| service_values.iter().any(|v| v.name == name);
+        for name in resolver::required_references(template).into_iter().filter(|name| !defined.contains(*name) && !scoped(name)) {
+            let services: Vec<&str> = entry_services.iter().filter(|(n, _)| *n == name).map(|(_, s)| *s).collect();
+            if in_cycle.contains(&index) && !services.is_empty() {
+                continue;
+            }
+            problems.add("Environment references", if !services.is_empty() {
+                format!("Env variable {} references {}, which is only set in the environment of service {}", label, name, services.join(", "))
+            } else if deployment_values.contains(name) {
+                format!("Env variable {} references {}, which the deployment sets but the appspec does not declare; add it to environment.external or environment.optional", label, name)
+            } else {
+                format!("Env variable {} references {}, which neither the appspec nor the deployment defines", label, name)
+            });
+        }
+    }
+}
+
+// Whether `inner` is a path below `outer`, e.g. `/api/v1` below `/api` or `/`
+fn is_nested_prefix(outer: &str, inner: &str) -> bool {
+    let outer = outer.trim_end_matches('/');
+    let inner = inner.trim_end_matches('/');
+    outer != inner && inner.strip_prefix(outer).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// How two mount paths collide, if they do: the same path, or one inside the other
+fn mount_overlap(first: &str, second: &str) -> Option<&'static str> {
+    let first = first.trim_end_matches('/');
+    let second = second.trim_end_matches('/');
+    if first == second {
+        Some("uses the same path as")
+    } else if second.strip_prefix(first).is_some_and(|rest| rest.starts_with('/')) {
+        Some("shadows")
+    } else if first.strip_prefix(second).is_some_and(|rest| rest.starts_with('/')) {
+        Some("is shadowed by")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support, transform};
+    use std::fs;
+
+    #[test]
+    fn env_spec_check_collects_errors_and_warnings() {
+        let raw = r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+    admin: admin.example.com
+    status: status.example.com:99999
+  tls:
+    letsencrypt:
+      email: ops@example.com
+deployments:
+  prod:
+    primary_host: web
+    public_domain: shop.example.com
+    application:
+      name: shop
+    services:
+      api:
+        host: api
+        prefix: /api
+        ports:
+          - "9000:80"
+      worker:
+        host: status
+        ports:
+          - "9000:9000"
+          - "9000"
+registry:
+  shop: https://registry.example.com
+"#;
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(raw).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+
+        let report = check_env_spec(&env_spec);
+        assert_eq!(report.errors.len(), 6, "{:?}", report.errors);
+        assert!(report.errors[0].contains("public_domain shop.example.com"));
+        assert!(report.errors[1].contains("uses host api"));
+        assert!(report.errors[2].contains("without a scheme"));
+        assert_eq!(report.errors[3], "Gateway host status: domain status.example.com:99999 has an invalid port, ports are 1-65535");
+        assert_eq!(report.errors[4], "Services api and worker of deployment prod both publish port 9000");
+        assert_eq!(report.errors[5], "Service worker of deployment prod lists container port 9000 twice");
+        assert_eq!(report.warnings, vec!["Gateway host admin is not used by any deployment"]);
+    }
+
+    #[test]
+    fn k8s_needs_numeric_users() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(&test_support::env_spec("k8s", "registry:\n  shop: reg.example.com\n")).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let app = |user: &str| {
+            let yaml = serde_yaml::from_str(&test_support::app_spec(&format!("extra_services:\n  db:\n    image: postgres:16\n    user: \"{}\"\n", user))).unwrap();
+            transform::convert_app_spec(yaml, Some(&env_spec)).unwrap()
+        };
+
+        let app_spec = app("1000:1000");
+        validate(&env_spec, &app_spec, "prod").unwrap();
+        let resolved = crate::resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let files = crate::k8s_generator::render(&resolved, root.path()).unwrap();
+        let manifest = files.get(root.path().join("deployment-db.yaml")).unwrap();
+        assert!(manifest.contains("securityContext:\n          runAsUser: 1000\n          runAsGroup: 1000\n"), "{}", manifest);
+
+        let err = validate(&env_spec, &app("postgres"), "prod").unwrap_err();
+        assert!(format!("{:#}", err).contains("numeric uid"), "{:#}", err);
+    }
+
+    #[test]
+    fn public_services_are_routed_by_their_export() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(&test_support::env_spec("docker", r#"
+gateway:
+  hosts:
+    admin: admin.example.com
+registry:
+  shop: reg.example.com
+deployments:
+  prod:
+    services:
+      web:
+        prefix: /shop
+"#)).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let app = |services: &str| {
+            let yaml = serde_yaml::from_str(&test_support::app_spec(&format!("app_services:\n{}", services))).unwrap();
+            transform::convert_app_spec(yaml, Some(&env_spec)).unwrap()
+        };
+        let exported = "  web:\n    type: public\n    image: shop/web\n    export:\n      prefix: /\n  admin:\n    type: public\n    image: shop/admin\n    export:\n      host: admin\n      prefix: /\n";
+
+        let app_spec = app(exported);
+        validate(&env_spec, &app_spec, "prod").unwrap();
+        let resolved = crate::resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let routes: Vec<(&str, &str, &str)> = resolved.ingress.rules.iter()
+            .flat_map(|rule| rule.services.iter().map(move |s| (rule.domain_name.as_str(), s.service_name.as_str(), s.prefix.as_str())))
+            .collect();
+        // the deployment's prefix wins over the export
+        assert_eq!(routes, [("admin.example.com", "admin", "/"), ("example.com", "web", "/shop")]);
+
+        let unrouted = "  web:\n    type: public\n    image: shop/web\n  admin:\n    type: public\n    image: shop/admin\n";
+        let err = validate(&env_spec, &app(unrouted), "prod").unwrap_err();
+        assert!(format!("{:#}", err).contains("Public service admin has no route"), "{:#}", err);
+    }
+
+    #[test]
+    fn secret_references_must_be_declared() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(&test_support::env_spec("docker", "deployments:\n  prod:\n    secrets:\n      db_password: hunter2\n")).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let app = |secrets: &str| {
+            let yaml = serde_yaml::from_str(&test_support::app_spec(&format!("{}extra_services:\n  db:\n    image: postgres:16\n    environment:\n      - URL=postgres://app:${{secret:db_password}}@db/app\n", secrets))).unwrap();
+            transform::convert_app_spec(yaml, Some(&env_spec)).unwrap()
+        };
+
+        validate(&env_spec, &app("secrets:\n  - db_password\n"), "prod").unwrap();
+        let err = validate(&env_spec, &app(""), "prod").unwrap_err();
+        assert!(format!("{:#}", err).contains("Env variable URL references secret db_password"), "{:#}", err);
+    }
+
+    #[test]
+    fn required_config_files_can_be_patterns() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("nginx")).unwrap();
+        fs::write(root.path().join("nginx/site.conf"), "").unwrap();
+        let yaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+registry:
+  shop: reg.example.com
+deployments:
+  prod:
+    application:
+      name: shop
+    configs:
+      nginx: nginx
+"#).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let app = |files: &str| {
+            let yaml = serde_yaml::from_str(&format!("name: shop\nversion: 1.0.0\nconfigs:\n  nginx: {}\nextra_services:\n  proxy:\n    image: nginx:1.27\n    configs:\n      - nginx: /etc/nginx/conf.d\n", files)).unwrap();
+            transform::convert_app_spec(yaml, Some(&env_spec)).unwrap()
+        };
+
+        validate(&env_spec, &app("['*.conf', 'mime.types?', '*.map?']"), "prod").unwrap();
+        let err = validate(&env_spec, &app("['*.types']"), "prod").unwrap_err();
+        assert!(format!("{:#}", err).contains("Config nginx requires file *.types, but it is not provided"), "{:#}", err);
+    }
+
+    #[test]
+    fn warns_about_unused_deployment_values() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("nginx")).unwrap();
+        fs::create_dir_all(root.path().join("legacy")).unwrap();
+        let yaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    environment:
+      - DB_HOST=db
+      - LOG_LEVEL=debug
+      - TIMEOUT=5
+      - OLD_FLAG=1
+    secrets:
+      db_password: hunter2
+      old_token: abc
+    configs:
+      nginx: nginx
+      legacy: legacy
+"#).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let yaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+environment:
+  external:
+    - DB_HOST
+  optional:
+    - LOG_LEVEL
+secrets:
+  - db_password
+configs:
+  nginx:
+    - nginx.conf
+extra_services:
+  db:
+    image: postgres:16
+    environment:
+      - WAIT=${TIMEOUT}s
+"#).unwrap();
+        let app_spec = transform::convert_app_spec(yaml, Some(&env_spec)).unwrap();
+
+        assert_eq!(unused_values(&env_spec, &app_spec, "prod"), [
+            "Deployment prod provides env variable OLD_FLAG, which the application does not use",
+            "Deployment prod provides secret old_token, which the application does not declare",
+            "Deployment prod provides config legacy, which the application does not declare",
+        ]);
+    }
+
+    #[test]
+    fn mounts_and_secret_variables_must_not_collide() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str("type: local\ngateway:\n  hosts:\n    web: localhost\ndeployments:\n  dev:\n    primary_host: web\n    application:\n      name: shop\n").unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let yaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+environment:
+  external:
+    - DB_PASSWORD
+extra_services:
+  api:
+    image: shop/api
+    environment:
+      - $all
+    configs:
+      - app: /etc/app/
+    secrets:
+      - tls_key:
+          path: /etc/app/key.pem
+      - db_password:
+          variable: DB_PASSWORD
+      - token:
+          variable: TOKEN
+      - old_token:
+          variable: TOKEN
+"#).unwrap();
+        let app_spec = transform::convert_app_spec(yaml, Some(&env_spec)).unwrap();
+
+        let report = validate(&env_spec, &app_spec, "dev").unwrap_err().root_cause().to_string();
+        for expected in [
+            "Service api: config app at /etc/app/ shadows secret tls_key at /etc/app/key.pem",
+            "Service api: secret db_password is passed as env variable DB_PASSWORD, which the service already receives",
+            "Service api: secrets token and old_token are both passed as env variable TOKEN",
+        ] {
+            assert!(report.contains(expected), "{}", report);
+        }
+        assert_eq!(mount_overlap("/etc/app", "/etc/application"), None);
+        assert_eq!(mount_overlap("/run/a", "/run/a/"), Some("uses the same path as"));
+    }
+
+    #[test]
+    fn resource_quantities_are_checked() {
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = |resources: &str| {
+            let yaml = serde_yaml::from_str(&format!("type: k8s\ngateway:\n  hosts:\n    web: example.com\n  tls:\n    disable: true\ndeployments:\n  prod:\n    primary_host: web\n    application:\n      name: shop\n    services:\n      api:\n        resources:\n{}", resources)).unwrap();
+            transform::convert_env_spec(yaml, root.path())
+        };
+
+        let err = env_spec("          limits:\n            memory: 128mb\n").unwrap_err();
+        assert!(format!("{:#}", err).starts_with("deployment \"prod\": service \"api\": resources.limits.memory: Invalid memory quantity \"128mb\""), "{:#}", err);
+        let err = env_spec("          requests:\n            cpu: \"0,5\"\n").unwrap_err();
+        assert!(format!("{:#}", err).contains("resources.requests.cpu: Invalid cpu quantity \"0,5\""), "{:#}", err);
+
+        let env_spec = env_spec("          requests:\n            memory: 1Gi\n            cpu: \"0.5\"\n          limits:\n            memory: 512Mi\n            cpu: \"1\"\n").unwrap();
+        assert_eq!(resource_warnings(&env_spec, "prod"), ["Deployment prod service api: memory request 1Gi exceeds limit 512Mi"]);
+    }
+
+    #[test]
+    fn app_resource_hints_sit_under_the_deployment() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(&test_support::env_spec("k8s", r#"
+deployments:
+  prod:
+    defaults:
+      resources:
+        requests:
+          cpu: 200m
+    services:
+      api:
+        resources:
+          limits:
+            memory: 256Mi
+"#)).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let yaml = serde_yaml::from_str(&test_support::app_spec("app_services:\n  api:\n    image: shop/api\n    resources:\n      requests:\n        memory: 512Mi\n        cpu: 100m\n  worker:\n    image: shop/worker\n    resources:\n      requests:\n        memory: 512Mi\n")).unwrap();
+        let app_spec = transform::convert_app_spec(yaml, Some(&env_spec)).unwrap();
+        let deployment = &env_spec.deployments[0];
+        let resources = |name: &str| {
+            let r = deployment.service_resources(app_spec.all_services().find(|s| s.name == name).unwrap());
+            format!("{}/{} {}/{}", r.requests.memory, r.requests.cpu, r.limits.memory, r.limits.cpu)
+        };
+
+        // the deployment's defaults win over the app's cpu request; an unset
+        // limit is raised to the app's memory request
+        assert_eq!(resources("worker"), "512Mi/200m 512Mi/200m");
+        assert_eq!(resources("api"), "512Mi/200m 256Mi/200m");
+        assert_eq!(app_request_warnings(&env_spec, &app_spec, "prod"), ["Deployment prod service api: memory limit 256Mi is below the 512Mi the appspec requests"]);
+    }
+
+    #[test]
+    fn variants_override_environment_and_ports() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(&test_support::env_spec("docker", r#"
+registry:
+  shop: reg.example.com
+deployments:
+  arm:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      api:
+        variant: arm64
+"#)).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let app_yaml = |debug_env: &str| serde_yaml::from_str(&test_support::app_spec(&format!(r#"
+app_services:
+  api:
+    type: public
+    export:
+      prefix: /
+    ports:
+      - "80"
+    environment:
+      - ARCH=amd64
+      - LOG_LEVEL=info
+    variants:
+      default:
+        image: shop/api
+      arm64:
+        image: shop/api-arm64
+        environment:
+          - ARCH=arm64
+        ports:
+          - "8080:80"
+          - "9229"
+      debug:
+        image: shop/api
+        environment:
+          - {}
+"#, debug_env))).unwrap();
+        let app_spec = transform::convert_app_spec(app_yaml("LOG_LEVEL=debug"), Some(&env_spec)).unwrap();
+
+        let resolved = |deployment: &str| -> (Vec<String>, Vec<String>) {
+            let spec = resolver::resolve(&env_spec, &app_spec, deployment).unwrap();
+            let api = &spec.current_deployment.services[0];
+            (api.environment_variables.iter().map(|v| format!("{}={}", v.name, v.value)).collect(),
+             api.ports.iter().map(|p| p.mapping()).collect())
+        };
+        assert_eq!(resolved("prod"), (vec!["ARCH=amd64".to_string(), "LOG_LEVEL=info".to_string()], vec!["80".to_string()]));
+        assert_eq!(resolved("arm"), (vec!["ARCH=arm64".to_string(), "LOG_LEVEL=info".to_string()], vec!["8080:80".to_string(), "9229".to_string()]));
+
+        // variant entries are checked like the service's own
+        let app_spec = transform::convert_app_spec(app_yaml("TRACE_ENDPOINT"), Some(&env_spec)).unwrap();
+        let report = validate(&env_spec, &app_spec, "prod").unwrap_err().root_cause().to_string();
+        assert!(report.contains("Service api variant debug references undefined environment variable TRACE_ENDPOINT"), "{}", report);
+    }
+
+    #[test]
+    fn selected_image_variants_must_exist() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      api:
+        variant: arm64
+      db:
+        variant: slim
+"#).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let yaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+extra_services:
+  api:
+    variants:
+      default:
+        image: shop/api
+      amd64:
+        image: shop/api-amd64
+  worker:
+    variants:
+      gpu:
+        image: shop/worker-gpu
+  db:
+    image: postgres:16
+"#).unwrap();
+        let app_spec = transform::convert_app_spec(yaml, Some(&env_spec)).unwrap();
+
+        let report = validate(&env_spec, &app_spec, "prod").unwrap_err().root_cause().to_string();
+        for expected in [
+            "Deployment prod selects variant arm64 of service api, which declares only: amd64, default",
+            "Service worker has no 'default' variant and deployment prod selects none",
+            "Deployment prod selects variant slim of service db, which declares no variants",
+        ] {
+            assert!(report.contains(expected), "{}", report);
+        }
+    }
+
+    #[test]
+    fn host_references_and_tls_domains_are_checked() {
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = |tls: &str| {
+            let yaml = serde_yaml::from_str(&format!(r#"
+type: k8s
+gateway:
+  hosts:
+    web:
+      - example.com
+      - www.example.com
+    wild: "*.apps.example.com"
+    partner: shop.partner.org
+  tls:
+    {tls}
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      api:
+        host: backoffice
+"#)).unwrap();
+            transform::convert_env_spec(yaml, root.path()).unwrap()
+        };
+        let yaml = serde_yaml::from_str("name: shop\nversion: 1.0.0\nextra_services:\n  api:\n    image: shop/api\n  admin:\n    image: shop/admin\n    export:\n      host: admin\n").unwrap();
+        let app_spec = transform::convert_app_spec(yaml, Some(&env_spec("disable: true"))).unwrap();
+
+        let report = validate(&env_spec("letsencrypt:\n      email: ops@example.com"), &app_spec, "prod").unwrap_err().root_cause().to_string();
+        for expected in [
+            "Service api uses host backoffice in deployment prod, which is not a gateway host; gateway hosts: partner, web, wild",
+            "Service admin uses host admin in its export in the appspec, which is not a gateway host",
+            "Gateway domain *.apps.example.com is a wildcard, which Let's Encrypt does not issue",
+        ] {
+            assert!(report.contains(expected), "{}", report);
+        }
+
+        let dns01 = "letsencrypt:\n      email: ops@example.com\n      dns01:\n        provider: cloudflare\n        credentials: cloudflare-token";
+        assert!(check_tls_domains(&env_spec(dns01)).errors.is_empty());
+
+        let findings = check_tls_domains(&env_spec("secret: example-tls"));
+        assert!(findings.errors.is_empty());
+        assert_eq!(findings.warnings, [
+            "Gateway domain shop.partner.org is not covered by a certificate for example.com and *.example.com; check that TLS secret example-tls includes it",
+            "Gateway domain *.apps.example.com is not covered by a certificate for example.com and *.example.com; check that TLS secret example-tls includes it",
+        ]);
+    }
+
+    #[test]
+    fn published_ports_must_not_collide_with_the_gateway() {
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = |env: &str, port: &str| {
+            let yaml = serde_yaml::from_str(&format!("{env}\ndeployments:\n  prod:\n    primary_host: web\n    application:\n      name: shop\n    services:\n      api:\n        ports:\n          - \"{port}\"\n")).unwrap();
+            transform::convert_env_spec(yaml, root.path())
+        };
+        let docker = "type: docker\ngateway:\n  hosts:\n    web: example.com\n  tls:\n    letsencrypt:\n      email: ops@example.com";
+        let local = "type: local\ngateway:\n  hosts:\n    web: localhost:8080";
+        let yaml = serde_yaml::from_str("name: shop\nversion: 1.0.0\nextra_services:\n  api:\n    image: shop/api\n").unwrap();
+        let app_spec = transform::convert_app_spec(yaml, None).unwrap();
+
+        for (env, port) in [(docker, "443:8443"), (local, "8080:80")] {
+            let report = validate(&env_spec(env, port).unwrap(), &app_spec, "prod").unwrap_err().root_cause().to_string();
+            assert!(report.contains(&format!("Service api of deployment prod publishes port \"{}\", which the gateway listens on", port)), "{}", report);
+        }
+        validate(&env_spec(local, "8081:80").unwrap(), &app_spec, "prod").unwrap();
+
+        let err = env_spec(local, "0:80").unwrap_err();
+        assert_eq!(format!("{:#}", err), "deployment \"prod\": service \"api\": Invalid external port '0' in '0:80': ports are 1-65535");
+    }
+
+    #[test]
+    fn jobs_are_not_configured_like_web_services() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      migrate:
+        prefix: /migrate
+        replicas: 2
+        ports:
+          - "9229:9229"
+"#).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let yaml = serde_yaml::from_str("name: shop\nversion: 1.0.0\nextra_services:\n  migrate:\n    type: job\n    image: shop/migrate\n    export:\n      host: web\n  web:\n    type: public\n    image: shop/web\n    export:\n      prefix: /\n").unwrap();
+        let app_spec = transform::convert_app_spec(yaml, None).unwrap();
+
+        let report = validate(&env_spec, &app_spec, "prod").unwrap_err().root_cause().to_string();
+        for expected in [
+            "Job service migrate is given a host or prefix in deployment prod; jobs are not routed through the gateway",
+            "Job service migrate declares an export in the appspec; jobs are not routed through the gateway",
+            "Job service migrate has 2 replicas in deployment prod; a job runs once, set `replicas: 1`",
+        ] {
+            assert!(report.contains(expected), "{}", report);
+        }
+        assert_eq!(service_type_warnings(&env_spec, &app_spec, "prod"), [
+            ("job_ports", "Job service migrate publishes ports 9229:9229 in deployment prod".to_string()),
+            ("public_without_ports", "Public service web declares no ports in deployment prod; the gateway routes to port 80".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn disabled_services_are_left_out() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+registry:
+  shop: reg.example.com
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      reporting:
+        enabled: false
+      worker:
+        enabled: false
+  staging:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      reporting:
+        enabled: false
+"#).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let yaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+environment:
+  external:
+    - REPORT_DB
+configs:
+  reports:
+    - report.conf
+secrets:
+  report_key:
+app_services:
+  web:
+    type: public
+    image: shop/web
+    export:
+      prefix: /
+  reporting:
+    type: public
+    image: shop/reporting
+    ports:
+      - "8080"
+    environment:
+      - REPORT_DB
+    configs:
+      - reports: /etc/reports
+    secrets:
+      - report_key
+  worker:
+    image: shop/worker
+    environment:
+      - SERVICE_REPORTING_URL
+"#).unwrap();
+        let app_spec = transform::convert_app_spec(yaml, Some(&env_spec)).unwrap();
+
+        // nothing the disabled services use is required, and reporting needs no route
+        validate(&env_spec, &app_spec, "prod").unwrap();
+        let spec = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let services: Vec<&str> = spec.current_deployment.services.iter().map(|s| s.full_name.as_str()).collect();
+        assert_eq!(services, ["web"]);
+        let routed: Vec<&str> = spec.ingress.rules.iter().flat_map(|r| r.services.iter().map(|s| s.service_name.as_str())).collect();
+        assert_eq!(routed, ["web"]);
+
+        let report = validate(&env_spec, &app_spec, "staging").unwrap_err().root_cause().to_string();
+        assert!(report.contains("Service reporting is disabled in deployment staging, but worker depends on it through SERVICE_REPORTING_HOST or SERVICE_REPORTING_URL"), "{}", report);
+        assert!(report.contains("1 problem(s)"), "{}", report);
+    }
+
+    #[test]
+    fn services_are_skipped_when_their_variable_has_no_value() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(&test_support::env_spec("docker", r#"
+registry:
+  shop: reg.example.com
+deployments:
+  prod:
+    environment:
+      - METRICS_DSN=
+  staging:
+    primary_host: web
+    application:
+      name: shop
+    services:
+      metrics:
+        environment:
+          - METRICS_DSN=https://metrics.example.com
+"#)).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let yaml = serde_yaml::from_str(&test_support::app_spec(r#"
+environment:
+  optional:
+    - METRICS_DSN
+  external:
+    - METRICS_KEY
+app_services:
+  web:
+    type: public
+    image: shop/web
+    export:
+      prefix: /
+  metrics:
+    type: public
+    image: shop/metrics
+    when: METRICS_DSN
+    export:
+      prefix: /metrics
+    environment:
+      - METRICS_KEY
+"#)).unwrap();
+        let app_spec = transform::convert_app_spec(yaml, Some(&env_spec)).unwrap();
+
+        // an empty value skips the service, and METRICS_KEY is not required
+        validate(&env_spec, &app_spec, "prod").unwrap();
+        let spec = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let services: Vec<&str> = spec.current_deployment.services.iter().map(|s| s.full_name.as_str()).collect();
+        assert_eq!(services, ["web"]);
+        let skipped = &spec.current_deployment.skipped_services;
+        assert_eq!((skipped[0].name.as_str(), skipped[0].reason.as_str()), ("metrics", "when: METRICS_DSN has no value in the deployment"));
+        let routed: Vec<&str> = spec.ingress.rules.iter().flat_map(|r| r.services.iter().map(|s| s.service_name.as_str())).collect();
+        assert_eq!(routed, ["web"]);
+
+        // a value for the service alone runs it, with its requirements back
+        let report = validate(&env_spec, &app_spec, "staging").unwrap_err().root_cause().to_string();
+        assert!(report.contains("Environment variable METRICS_KEY required by application is not provided by deployment staging"), "{}", report);
+    }
+
+    #[test]
+    fn deployment_image_overrides_are_resolved_and_reported() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(&test_support::env_spec("docker", r#"
+deployments:
+  prod:
+    services:
+      api:
+        image: acme/api
+      worker:
+        image: acme/worker:hotfix-1
+"#)).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let yaml = serde_yaml::from_str(&test_support::app_spec("version: 1.2.3\napp_services:\n  api:\n    image: acme/shop-api\n  worker:\n    image: acme/shop-worker\n  cron:\n    image: acme/shop-cron\n")).unwrap();
+        let app_spec = transform::convert_app_spec(yaml, Some(&env_spec)).unwrap();
+
+        validate(&env_spec, &app_spec, "prod").unwrap();
+        let spec = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let images: Vec<(&str, &str, bool)> = spec.current_deployment.services.iter().map(|s| (s.full_name.as_str(), s.image.as_str(), s.image_override)).collect();
+        assert_eq!(images, [
+            ("api", "reg.example.com/acme/api:1.2.3", true),
+            ("cron", "reg.example.com/acme/shop-cron:1.2.3", false),
+            ("worker", "reg.example.com/acme/worker:hotfix-1", true),
+        ]);
+        assert_eq!(image_override_warnings(&env_spec, &app_spec, "prod"), [
+            "Image override active: deployment prod runs service api from acme/api instead of the app bundle's image; remove the override once a release contains the fix",
+            "Image override active: deployment prod runs service worker from acme/worker:hotfix-1 instead of the app bundle's image; remove the override once a release contains the fix",
+        ]);
+
+        let yaml = serde_yaml::from_str(&test_support::env_spec("local", "deployments:\n  prod:\n    services:\n      api:\n        image: Acme/api\n        ports:\n          - \"8080\"\n")).unwrap();
+        let err = transform::convert_env_spec(yaml, root.path()).unwrap_err();
+        assert!(format!("{:#}", err).contains("service \"api\": image: Invalid image reference"), "{:#}", err);
+    }
+
+    #[test]
+    fn env_references_are_checked_before_resolving() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str("type: docker\ngateway:\n  hosts:\n    web: example.com\n  tls:\n    disable: true\ndeployments:\n  prod:\n    primary_host: web\n    application:\n      name: shop\n    environment:\n      - TOKEN=abc\n").unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let yaml = serde_yaml::from_str(r#"
+name: shop
+version: 1.0.0
+environment:
+  internal:
+    - BASE=${API}/v1
+    - LOOP_A=${LOOP_B}
+    - LOOP_B=x${LOOP_A}
+    - USES_ONLY=${ONLY}
+    - TOKEN_URL=https://auth/${TOKEN}
+    - MISSING=${NOPE}
+    - FALLBACK=${NOPE:-x}/${SERVICE_WORKER_HOST}
+extra_services:
+  web:
+    image: shop/web
+    environment:
+      - API=${BASE}
+  worker:
+    image: shop/worker
+    environment:
+      - ONLY=1
+"#).unwrap();
+        let app_spec = transform::convert_app_spec(yaml, None).unwrap();
+
+        let report = validate(&env_spec, &app_spec, "prod").unwrap_err().root_cause().to_string();
+        for expected in [
+            "Env variables reference each other in a cycle: BASE -> API (service web) -> BASE",
+            "Env variables reference each other in a cycle: LOOP_A -> LOOP_B -> LOOP_A",
+            "Env variable USES_ONLY references ONLY, which is only set in the environment of service worker",
+            "Env variable TOKEN_URL references TOKEN, which the deployment sets but the appspec does not declare",
+            "Env variable MISSING references NOPE, which neither the appspec nor the deployment defines",
+        ] {
+            assert!(report.contains(expected), "{}", report);
+        }
+        assert!(!report.contains("FALLBACK") && !report.contains("references API"), "{}", report);
+    }
+
+    #[test]
+    fn every_mirrored_image_needs_a_registry() {
+        let root = tempfile::tempdir().unwrap();
+        let env_spec = |registry: &str| {
+            let yaml = serde_yaml::from_str(&format!("type: docker\nmirror_extra_services: true\nregistry:\n{registry}\ngateway:\n  hosts:\n    web: example.com\n  tls:\n    disable: true\ndeployments:\n  prod:\n    primary_host: web\n    application:\n      name: shop\n")).unwrap();
+            transform::convert_env_spec(yaml, root.path()).unwrap()
+        };
+        let yaml = serde_yaml::from_str("name: shop\nversion: 1.0.0\napp_services:\n  api:\n    image: acme/api\n  worker:\n    image: acme/worker\n  web:\n    image: shop/web\nextra_services:\n  db:\n    image: postgres:16\n  cache:\n    image: bitnami/redis\n").unwrap();
+        let app_spec = transform::convert_app_spec(yaml, None).unwrap();
+
+        let report = validate(&env_spec("  shop: reg.example.com"), &app_spec, "prod").unwrap_err().root_cause().to_string();
+        assert!(report.contains("No registry is mapped for namespace acme (service api, worker), namespace bitnami (service cache), postgres:16 without a namespace (service db)"), "{}", report);
+
+        let env_spec = env_spec("  shop: reg.example.com\n  \"*\": mirror.example.com/");
+        validate(&env_spec, &app_spec, "prod").unwrap();
+        let resolved = resolver::resolve(&env_spec, &app_spec, "prod").unwrap();
+        let images: Vec<&str> = resolved.current_deployment.services.iter().map(|s| s.image.as_str()).collect();
+        assert_eq!(images, ["mirror.example.com/acme/api:1.0.0", "reg.example.com/shop/web:1.0.0", "mirror.example.com/acme/worker:1.0.0", "mirror.example.com/bitnami/redis", "mirror.example.com/postgres:16"]);
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let root = tempfile::tempdir().unwrap();
+        let yaml = serde_yaml::from_str(&test_support::env_spec("docker", "deployments:\n  prod:\n    services:\n      worker:\n        replicas: 2\n")).unwrap();
+        let env_spec = transform::convert_env_spec(yaml, root.path()).unwrap();
+        let yaml = serde_yaml::from_str(&test_support::app_spec(r#"
+environment:
+  external:
+    - DB_URL
+secrets:
+  - db_password
+configs:
+  nginx:
+    - nginx.conf
+extra_services:
+  db:
+    image: postgres:16
+"#)).unwrap();
+        let app_spec = transform::convert_app_spec(yaml, Some(&env_spec)).unwrap();
+
+        let report = validate(&env_spec, &app_spec, "prod").unwrap_err().root_cause().to_string();
+        assert!(report.starts_with("4 problem(s) in deployment prod:"), "{}", report);
+        for expected in [
+            "Environment variables:\n  1. Environment variable DB_URL",
+            "Secrets:\n  2. Secret db_password",
+            "Configs:\n  3. Config nginx",
+            "Services:\n  4. Deployment configures service worker",
+        ] {
+            assert!(report.contains(expected), "{}", report);
+        }
+    }
 }
@@ -0,0 +1,67 @@
+use std::fs;
+use std::process::Command;
+
+// `-C` makes a command behave as if started in the project directory: spec
+// discovery, the secret file and config dir, and the output directory.
+#[test]
+fn prepare_deployment_with_chdir_from_another_directory() {
+    let project = tempfile::tempdir().unwrap();
+    let elsewhere = tempfile::tempdir().unwrap();
+    let root = project.path();
+    fs::create_dir_all(root.join("configs/nginx")).unwrap();
+    fs::write(root.join("configs/nginx/site.conf"), "listen 80;").unwrap();
+    fs::create_dir(root.join("keys")).unwrap();
+    fs::write(root.join("keys/db"), "hunter2").unwrap();
+    fs::write(root.join("envspec.yaml"), r#"
+type: docker
+gateway:
+  hosts:
+    web: example.com
+  tls:
+    disable: true
+registry:
+  shop: reg.example.com
+deployments:
+  prod:
+    primary_host: web
+    application:
+      name: shop
+    configs:
+      nginx: configs/nginx
+    secrets:
+      db_password:
+        file: keys/db
+"#).unwrap();
+    fs::write(root.join("appspec.yaml"), r#"
+name: shop
+version: 1.0.0
+configs:
+  nginx:
+    - site.conf
+secrets:
+  - db_password
+app_services:
+  web:
+    type: public
+    image: shop/web
+    export:
+      prefix: /
+    configs:
+      - nginx: /etc/nginx/conf.d
+    secrets:
+      - db_password:
+          path: /run/secrets/db_password
+"#).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_simpled"))
+        .args(["-C", root.to_str().unwrap(), "prepare-deployment", "prod", "--bundle", "."])
+        .current_dir(elsewhere.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(root.join("docker-deploy/deploy.sh").is_file());
+    assert_eq!(fs::read_to_string(root.join("docker-deploy/configs/shop-nginx/site.conf")).unwrap(), "listen 80;");
+    assert_eq!(fs::read_to_string(root.join("docker-deploy/secrets/shop-db_password")).unwrap(), "hunter2");
+    assert!(fs::read_dir(elsewhere.path()).unwrap().next().is_none());
+}